@@ -0,0 +1,42 @@
+//! Pass the version of `probe-rs` which got built to the crate, for `--version --json`
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let version = find_lock_file()
+        .and_then(|lock_path| {
+            println!("cargo:rerun-if-changed={}", lock_path.display());
+            probe_rs_version(&fs::read_to_string(lock_path).ok()?)
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=PROBE_RS_VERSION={version}");
+}
+
+/// The `Cargo.lock` of the build: next to the manifest, or further up in a workspace. As a
+/// dependency the package lives in cargo's registry, so the target directory is searched as well.
+fn find_lock_file() -> Option<PathBuf> {
+    ["CARGO_MANIFEST_DIR", "OUT_DIR"]
+        .into_iter()
+        .filter_map(|var| env::var_os(var).map(PathBuf::from))
+        .find_map(|dir| {
+            dir.ancestors()
+                .map(|dir| dir.join("Cargo.lock"))
+                .find(|path| path.is_file())
+        })
+}
+
+/// The version of the `probe-rs` package in the lock file
+fn probe_rs_version(lock: &str) -> Option<String> {
+    lock.split("[[package]]").find_map(|package| {
+        let mut name = None;
+        let mut version = None;
+        for line in package.lines() {
+            match line.split_once(" = ") {
+                Some(("name", value)) => name = Some(value.trim_matches('"')),
+                Some(("version", value)) => version = Some(value.trim_matches('"')),
+                _ => {}
+            }
+        }
+        (name == Some("probe-rs")).then(|| version.map(str::to_string))?
+    })
+}
@@ -0,0 +1,172 @@
+//! The architecture an ARM ELF was built for, from the build attributes in its `.ARM.attributes`
+//! section (see "Addenda to, and Errata in, the ABI for the Arm Architecture")
+
+use object::{Object as _, ObjectSection as _};
+
+/// The subsection of attributes which apply to the whole file
+const TAG_FILE: u64 = 1;
+const TAG_CPU_ARCH: u64 = 6;
+
+/// The M-profile architectures a Cortex-M program may be built for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Arch {
+    V6M,
+    V7M,
+    V7EM,
+    V8MBase,
+    V8MMain,
+    V81MMain,
+}
+
+impl Arch {
+    fn from_tag_value(value: u64) -> Option<Self> {
+        Some(match value {
+            10 => Arch::V7M,
+            11 | 12 => Arch::V6M,
+            13 => Arch::V7EM,
+            16 => Arch::V8MBase,
+            17 => Arch::V8MMain,
+            21 => Arch::V81MMain,
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Arch::V6M => "ARMv6-M",
+            Arch::V7M => "ARMv7-M",
+            Arch::V7EM => "ARMv7E-M",
+            Arch::V8MBase => "ARMv8-M Baseline",
+            Arch::V8MMain => "ARMv8-M Mainline",
+            Arch::V81MMain => "ARMv8.1-M Mainline",
+        })
+    }
+}
+
+/// The `Tag_CPU_arch` of the ELF, or `None` if it has no build attributes or isn't built for an
+/// M-profile architecture.
+pub fn cpu_arch(elf_bytes: &[u8]) -> Option<Arch> {
+    let elf = object::File::parse(elf_bytes).ok()?;
+    let section = elf.section_by_name(".ARM.attributes")?;
+    let value = parse(section.data().ok()?)?;
+    Arch::from_tag_value(value)
+}
+
+/// Find `Tag_CPU_arch` in the `aeabi` file attributes of a `.ARM.attributes` section.
+fn parse(data: &[u8]) -> Option<u64> {
+    let (&format, mut sections) = data.split_first()?;
+    if format != b'A' {
+        return None;
+    }
+
+    while !sections.is_empty() {
+        let length = read_u32(sections)?;
+        let section = sections.get(4..length)?;
+        sections = &sections[length..];
+
+        let vendor_end = section.iter().position(|byte| *byte == 0)?;
+        if &section[..vendor_end] != b"aeabi" {
+            continue;
+        }
+        let mut subsections = &section[vendor_end + 1..];
+        while !subsections.is_empty() {
+            let tag = subsections[0];
+            let length = read_u32(subsections.get(1..)?)?;
+            let subsection = subsections.get(5..length)?;
+            subsections = &subsections[length..];
+            if u64::from(tag) == TAG_FILE {
+                return find_cpu_arch(subsection);
+            }
+        }
+    }
+    None
+}
+
+fn find_cpu_arch(mut attributes: &[u8]) -> Option<u64> {
+    while !attributes.is_empty() {
+        let tag = read_uleb128(&mut attributes)?;
+        match tag {
+            TAG_CPU_ARCH => return read_uleb128(&mut attributes),
+            // Tag_compatibility: a flag and a vendor name
+            32 => {
+                read_uleb128(&mut attributes)?;
+                skip_string(&mut attributes)?;
+            }
+            // Tag_CPU_raw_name, Tag_CPU_name, Tag_conformance, Tag_also_compatible_with; and
+            // from tag 32 on, odd tags have string values
+            4 | 5 | 65 | 67 => skip_string(&mut attributes)?,
+            tag if tag > 32 && tag % 2 == 1 => skip_string(&mut attributes)?,
+            _ => {
+                read_uleb128(&mut attributes)?;
+            }
+        }
+    }
+    None
+}
+
+fn read_u32(bytes: &[u8]) -> Option<usize> {
+    let bytes = bytes.get(..4)?.try_into().ok()?;
+    usize::try_from(u32::from_le_bytes(bytes)).ok()
+}
+
+fn read_uleb128(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn skip_string(bytes: &mut &[u8]) -> Option<()> {
+    let end = bytes.iter().position(|byte| *byte == 0)?;
+    *bytes = &bytes[end + 1..];
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// A `.ARM.attributes` section with the file attributes `attributes`
+    fn section(attributes: &[u8]) -> Vec<u8> {
+        let mut subsection = vec![TAG_FILE as u8];
+        subsection.extend_from_slice(&(5 + attributes.len() as u32).to_le_bytes());
+        subsection.extend_from_slice(attributes);
+
+        let mut section = b"A".to_vec();
+        section.extend_from_slice(&(4 + 6 + subsection.len() as u32).to_le_bytes());
+        section.extend_from_slice(b"aeabi\0");
+        section.extend_from_slice(&subsection);
+        section
+    }
+
+    #[rstest]
+    // Tag_CPU_name "7E-M", Tag_CPU_arch v7E-M, as LLVM emits them for `thumbv7em-none-eabihf`
+    #[case::after_name(&[5, b'7', b'E', b'-', b'M', 0, 6, 13], Some(13))]
+    // Tag_conformance "2.09", then Tag_CPU_arch v6-M
+    #[case::after_conformance(&[67, b'2', b'.', b'0', b'9', 0, 6, 11], Some(11))]
+    #[case::missing(&[8, 0, 9, 1], None)]
+    fn finds_cpu_arch(#[case] attributes: &[u8], #[case] expected: Option<u64>) {
+        assert_eq!(parse(&section(attributes)), expected);
+    }
+
+    #[test]
+    fn reads_arch_of_elf() {
+        let elf_bytes = std::fs::read("tests/test_elfs/hello-rzcobs").unwrap();
+        assert_eq!(cpu_arch(&elf_bytes), Some(Arch::V7EM));
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        assert_eq!(parse(b"B\0\0\0\0"), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Offline validation of the unwind info (`probe-run check-unwind`)
+
+use std::{collections::BTreeMap, io, ops::Range};
+
+use gimli::{BaseAddresses, CieOrFde, UnwindSection as _};
+use object::{Object as _, ObjectSection as _, ObjectSymbol as _, SectionKind, SymbolKind};
+
+use crate::{
+    cortexm,
+    elf::{self, Elf},
+    theme,
+};
+
+pub struct Report {
+    /// Number of FDEs in `.debug_frame`, not counting those of discarded functions
+    pub num_fdes: usize,
+    /// Number of functions in `.text`
+    pub num_functions: usize,
+    /// FDEs whose address range is not inside an executable section
+    pub stray_fdes: Vec<Range<u32>>,
+    /// Functions not covered by any FDE, by address
+    pub uncovered_functions: BTreeMap<u32, String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.stray_fdes.is_empty() && self.uncovered_functions.is_empty()
+    }
+}
+
+/// Walk all FDEs in `.debug_frame` and match them against the functions in `.text`
+pub fn check(elf: &Elf) -> anyhow::Result<Report> {
+    let text_ranges = elf
+        .sections()
+        .filter(|section| section.kind() == SectionKind::Text)
+        .map(|section| {
+            let start = section.address() as u32;
+            start..start + section.size() as u32
+        })
+        .collect::<Vec<_>>();
+
+    let bases = BaseAddresses::default();
+    let mut entries = elf.debug_frame.entries(&bases);
+    let mut fde_ranges = vec![];
+    while let Some(entry) = entries.next()? {
+        if let CieOrFde::Fde(partial) = entry {
+            let fde = partial
+                .parse(|debug_frame, bases, offset| debug_frame.cie_from_offset(bases, offset))?;
+            let start = fde.initial_address() as u32;
+            // the linker resets the address of FDEs of functions it discarded to 0
+            if start != 0 {
+                fde_ranges.push(start..start + fde.len() as u32);
+            }
+        }
+    }
+
+    let stray_fdes = fde_ranges
+        .iter()
+        .filter(|fde| {
+            !fde.is_empty()
+                && !text_ranges
+                    .iter()
+                    .any(|text| text.start <= fde.start && fde.end <= text.end)
+        })
+        .cloned()
+        .collect();
+
+    let mut functions = BTreeMap::new();
+    for symbol in elf.symbols() {
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if symbol.kind() != SymbolKind::Text
+            || symbol.size() == 0
+            || !elf.live_functions.contains(name)
+            // veneers need no unwind info; see `unwind::target`
+            || elf::veneer_target(name).is_some()
+        {
+            continue;
+        }
+
+        let address = cortexm::clear_thumb_bit(symbol.address() as u32);
+        functions
+            .entry(address)
+            .or_insert_with(|| format!("{:#}", rustc_demangle::demangle(name)));
+    }
+
+    let num_functions = functions.len();
+    let uncovered_functions = functions
+        .into_iter()
+        .filter(|(address, _)| !fde_ranges.iter().any(|fde| fde.contains(address)))
+        .collect();
+
+    Ok(Report {
+        num_fdes: fde_ranges.len(),
+        num_functions,
+        stray_fdes,
+        uncovered_functions,
+    })
+}
+
+pub fn print(report: &Report) -> io::Result<()> {
+    use io::Write as _;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "checked {} FDEs against {} functions",
+        report.num_fdes, report.num_functions
+    )?;
+
+    if !report.uncovered_functions.is_empty() {
+        writeln!(
+            stdout,
+            "{} {} functions have no unwind info; backtraces through them will end early:",
+            theme::current().error.paint("error:"),
+            report.uncovered_functions.len()
+        )?;
+        for (address, name) in &report.uncovered_functions {
+            writeln!(stdout, "  {address:#010x} {name}")?;
+        }
+        writeln!(
+            stdout,
+            "note: this is common for functions written in assembly or C; \
+            assemble them with CFI directives or compile C code with `-g`"
+        )?;
+    }
+
+    if !report.stray_fdes.is_empty() {
+        writeln!(
+            stdout,
+            "{} {} FDEs describe code outside of the executable sections:",
+            theme::current().error.paint("error:"),
+            report.stray_fdes.len()
+        )?;
+        for fde in &report.stray_fdes {
+            writeln!(stdout, "  {:#010x}..{:#010x}", fde.start, fde.end)?;
+        }
+    }
+
+    if report.is_ok() {
+        writeln!(stdout, "all functions have unwind info")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn hello_has_unwind_info() {
+        let path = Path::new("tests/test_elfs/hello-rzcobs");
+        let bytes = std::fs::read(path).unwrap();
+        let elf = Elf::parse_offline(&bytes, path).unwrap();
+
+        let report = check(&elf).unwrap();
+
+        assert_eq!(report.num_fdes, 57);
+        assert_eq!(report.num_functions, 57);
+        assert!(report.is_ok());
+    }
+}
@@ -1,14 +1,28 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::anyhow;
 
-use probe_rs::Core;
 use signal_hook::consts::signal;
 
-use crate::{cli::Opts, elf::Elf, target_info::TargetInfo};
+use crate::{
+    cli::{JsonFormat, Opts},
+    elf::Elf,
+    path_map::PathMap,
+    registers,
+    target_info::TargetInfo,
+    transport::Transport,
+};
 
+pub mod check;
+mod panic_message;
 mod pp;
+mod signature;
 mod symbolicate;
 mod unwind;
 
+use symbolicate::Frame;
+pub use symbolicate::{Cache as SymbolCache, Location, Subroutine};
+
 #[derive(PartialEq, Eq)]
 pub enum BacktraceOptions {
     Auto,
@@ -30,9 +44,13 @@ impl From<&String> for BacktraceOptions {
 pub struct Settings {
     pub backtrace_limit: u32,
     pub backtrace: BacktraceOptions,
+    pub backtrace_stop_at: Vec<String>,
     pub current_dir: PathBuf,
     pub halted_due_to_signal: bool,
     pub include_addresses: bool,
+    /// Print events like the crash signature as JSON lines (`--json-format lines`)
+    pub json_lines: bool,
+    pub path_map: Vec<PathMap>,
     pub shorten_paths: bool,
     pub stack_overflow: bool,
 }
@@ -47,9 +65,12 @@ impl Settings {
         Self {
             backtrace_limit: opts.backtrace_limit,
             backtrace: (&opts.backtrace).into(),
+            backtrace_stop_at: opts.backtrace_stop_at.clone(),
             current_dir,
             halted_due_to_signal,
             include_addresses: opts.verbose > 0,
+            json_lines: opts.json && opts.json_format == JsonFormat::Lines,
+            path_map: opts.path_map.clone(),
             shorten_paths: opts.shorten_paths,
             stack_overflow,
         }
@@ -58,17 +79,51 @@ impl Settings {
     fn panic_present(&self) -> bool {
         self.stack_overflow || self.halted_due_to_signal
     }
+
+    /// Returns `true` if the backtrace ends with `subroutine` (see `--backtrace-stop-at`).
+    fn stops_at(&self, subroutine: &Subroutine) -> bool {
+        if self.backtrace_stop_at.is_empty() {
+            return false;
+        }
+        let crate_name = subroutine.crate_name();
+        self.backtrace_stop_at.iter().any(|stop_at| {
+            subroutine.name.as_deref() == Some(stop_at.as_str())
+                || crate_name.as_deref() == Some(stop_at.replace('-', "_").as_str())
+        })
+    }
+}
+
+/// Symbolicates single addresses to their innermost function and its location
+pub fn subroutines(addresses: &[u32], elf: &Elf, settings: &Settings) -> Vec<Subroutine> {
+    symbolicate::subroutines(addresses, &settings.current_dir, &settings.path_map, elf)
+}
+
+/// Formats `location` the way backtraces show it
+pub fn format_location(location: &Location, settings: &Settings) -> String {
+    pp::location_string(location, settings)
 }
 
 /// (virtually) unwinds the target's program and prints its backtrace
 pub fn print(
-    core: &mut Core,
+    core: &mut dyn Transport,
     elf: &Elf,
     target_info: &TargetInfo,
     settings: &mut Settings,
 ) -> anyhow::Result<Outcome> {
     let mut unwind = unwind::target(core, elf, target_info);
-    let frames = symbolicate::frames(&unwind.raw_frames, &settings.current_dir, elf);
+    let frames = symbolicate::frames(
+        &unwind.raw_frames,
+        &settings.current_dir,
+        &settings.path_map,
+        elf,
+    );
+
+    if unwind.outcome == Outcome::Ok && !settings.halted_due_to_signal {
+        if let Some(location) = unexpected_breakpoint(core, &frames) {
+            log::error!("stopped at unexpected breakpoint at {location}");
+            unwind.outcome = Outcome::UnexpectedBreakpoint;
+        }
+    }
 
     let contains_exception = unwind
         .raw_frames
@@ -78,9 +133,13 @@ pub fn print(
     let print_backtrace = match settings.backtrace {
         BacktraceOptions::Never => false,
         BacktraceOptions::Always => true,
+        // a successful exit is no reason for a backtrace, even though it's an exception
+        BacktraceOptions::Auto if unwind.outcome == Outcome::Exit(0) => false,
         BacktraceOptions::Auto => {
             settings.panic_present()
                 || unwind.outcome == Outcome::StackOverflow
+                || unwind.outcome == Outcome::Panic
+                || unwind.outcome == Outcome::UnexpectedBreakpoint
                 || unwind.corrupted
                 || contains_exception
         }
@@ -91,6 +150,13 @@ pub fn print(
         settings.backtrace_limit = frames.len() as u32;
     }
 
+    if unwind.outcome == Outcome::Panic {
+        match panic_message::read(core, elf) {
+            Ok(panic) => panic_message::print(&panic, settings.json_lines)?,
+            Err(e) => log::debug!("failed to read the panic message from the target: {e:?}"),
+        }
+    }
+
     if print_backtrace && settings.backtrace_limit > 0 {
         pp::backtrace(&frames, settings)?;
 
@@ -105,6 +171,11 @@ pub fn print(
         }
     }
 
+    if unwind.outcome.is_fault() {
+        let signature = signature::compute(&frames, unwind.outcome);
+        signature::print(&signature, unwind.outcome, settings.json_lines)?;
+    }
+
     // if general outcome was OK but the user ctrl-c'ed, that overrides our outcome
     if settings.halted_due_to_signal && unwind.outcome == Outcome::Ok {
         unwind.outcome = Outcome::CtrlC
@@ -113,23 +184,205 @@ pub fn print(
     Ok(unwind.outcome)
 }
 
+/// Encoding of `bkpt #imm8`: `0b1011_1110_iiii_iiii`
+const BKPT_OPCODE: u16 = 0xBE00;
+const BKPT_OPCODE_MASK: u16 = 0xFF00;
+
+/// If the program halted on a `bkpt` instruction and none of the `frames` is a function which
+/// exits the program (see [`is_exit_fn`]), returns the function which hit the breakpoint.
+fn unexpected_breakpoint(core: &mut dyn Transport, frames: &[Frame]) -> Option<String> {
+    let mut instruction = [0; 2];
+    let read = core
+        .read_core_reg(registers::PC)
+        .and_then(|pc| core.read_8(pc.into(), &mut instruction));
+    if let Err(e) = read {
+        log::debug!("failed to read the instruction the program halted at: {e}");
+        return None;
+    }
+    if u16::from_le_bytes(instruction) & BKPT_OPCODE_MASK != BKPT_OPCODE {
+        return None;
+    }
+
+    let subroutines = frames
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Subroutine(subroutine) => Some(subroutine),
+            Frame::Exception => None,
+        })
+        .collect::<Vec<_>>();
+    let named =
+        |subroutine: &&Subroutine, f: fn(&str) -> bool| subroutine.name.as_deref().map_or(false, f);
+    if subroutines
+        .iter()
+        .any(|subroutine| named(subroutine, is_exit_fn))
+    {
+        return None;
+    }
+
+    // `cortex_m::asm::bkpt` and the like only execute the instruction; name their caller
+    let subroutine = subroutines
+        .iter()
+        .find(|subroutine| !named(subroutine, is_bkpt_fn))
+        .or(subroutines.first())?;
+    Some(match &subroutine.name {
+        Some(name) => format!("`{name}` ({:#010x})", subroutine.pc),
+        None => format!("{:#010x}", subroutine.pc),
+    })
+}
+
+/// Whether `name` is a function which ends the program on purpose, like the `exit` of the
+/// knurling app-template, `defmt_test`'s or `semihosting::process::exit`
+fn is_exit_fn(name: &str) -> bool {
+    matches!(last_segment(name), "exit" | "abort")
+}
+
+fn is_bkpt_fn(name: &str) -> bool {
+    matches!(last_segment(name), "bkpt" | "__bkpt")
+}
+
+/// The last path segment of `name`, without generic parameters, e.g. `exit` of `app::exit<T>`
+fn last_segment(name: &str) -> &str {
+    let mut end = name.len();
+    if name.ends_with('>') {
+        let mut depth = 0;
+        for (index, c) in name.char_indices().rev() {
+            match c {
+                '>' => depth += 1,
+                '<' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                end = index;
+                break;
+            }
+        }
+    }
+    let name = &name[..end];
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Prints the backtrace of the program, halted in the middle of its run (see [`crate::snapshot`])
+pub fn print_snapshot(
+    core: &mut dyn Transport,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    settings: &mut Settings,
+) -> anyhow::Result<()> {
+    let unwind = unwind::target(core, elf, target_info);
+    let frames = symbolicate::frames(
+        &unwind.raw_frames,
+        &settings.current_dir,
+        &settings.path_map,
+        elf,
+    );
+
+    if settings.backtrace_limit == 0 {
+        settings.backtrace_limit = frames.len() as u32;
+    }
+    pp::backtrace(&frames, settings)?;
+
+    if let Some(err) = unwind.processing_error {
+        log::warn!("the backtrace may be incomplete: {err:?}");
+    }
+    Ok(())
+}
+
 /// Target program outcome
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Outcome {
+    /// The program requested to abort (see `--svc-exit`)
+    Abort,
+    /// The function given by `--end-symbol` returned
+    EndSymbol,
+    /// The program requested to exit with the given status (see `--svc-exit`)
+    Exit(u32),
     HardFault,
     Ok,
+    /// The panic handler was entered (see `--catch-panics`)
+    Panic,
+    /// The program called the function given by `--run-until`
+    RunUntil,
     StackOverflow,
     /// Control-C was pressed
     CtrlC,
+    /// The program didn't halt within `--timeout`
+    Timeout,
+    /// The program was silent for `--halt-on-silence`
+    Hang,
+    /// The program hit a `bkpt` instruction outside of the functions which exit the program
+    UnexpectedBreakpoint,
 }
 
 impl Outcome {
+    /// Returns `true` if the program ended because something went wrong on the target.
+    pub fn is_fault(&self) -> bool {
+        matches!(
+            self,
+            Outcome::Abort
+                | Outcome::HardFault
+                | Outcome::Panic
+                | Outcome::StackOverflow
+                | Outcome::UnexpectedBreakpoint
+        )
+    }
+
+    /// The name `--exit-code-map` uses for this outcome; `None` for [`Outcome::Exit`], whose
+    /// exit code is the program's status.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Outcome::Abort => Some("abort"),
+            Outcome::EndSymbol => Some("end-symbol"),
+            Outcome::Exit(_) => None,
+            Outcome::HardFault => Some("hardfault"),
+            Outcome::Ok => Some("ok"),
+            Outcome::Panic => Some("panic"),
+            Outcome::RunUntil => Some("run-until"),
+            Outcome::StackOverflow => Some("overflow"),
+            Outcome::CtrlC => Some("ctrlc"),
+            Outcome::Timeout => Some("timeout"),
+            Outcome::Hang => Some("hang"),
+            Outcome::UnexpectedBreakpoint => Some("breakpoint"),
+        }
+    }
+
+    /// The exit code of `probe-run`: the one `map` assigns to the outcome, or the default one.
+    pub fn exit_code(self, map: &[ExitCodeMapping]) -> i32 {
+        map.iter()
+            .rev()
+            .find(|mapping| Some(mapping.outcome) == self.name())
+            .map_or_else(|| self.into(), |mapping| mapping.code)
+    }
+
     pub fn log(&self) {
         match self {
-            Outcome::StackOverflow => log::error!("the program has overflowed its stack"),
-            Outcome::HardFault => log::error!("the program panicked"),
-            Outcome::Ok => log::info!("device halted without error"),
-            Outcome::CtrlC => log::info!("device halted by user"),
+            Outcome::Exit(0)
+            | Outcome::Ok
+            | Outcome::EndSymbol
+            | Outcome::RunUntil
+            | Outcome::CtrlC => {
+                log::info!("{self}")
+            }
+            _ => log::error!("{self}"),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::StackOverflow => f.write_str("the program has overflowed its stack"),
+            Outcome::Abort => f.write_str("the program aborted"),
+            Outcome::Exit(status) => write!(f, "the program exited with status {status}"),
+            Outcome::HardFault | Outcome::Panic => f.write_str("the program panicked"),
+            Outcome::Ok => f.write_str("device halted without error"),
+            Outcome::RunUntil => f.write_str("the program reached the `--run-until` function"),
+            Outcome::EndSymbol => f.write_str("the `--end-symbol` function returned"),
+            Outcome::CtrlC => f.write_str("device halted by user"),
+            Outcome::Timeout => f.write_str("the program didn't halt within `--timeout`"),
+            Outcome::Hang => f.write_str("the program hung without output (`--halt-on-silence`)"),
+            Outcome::UnexpectedBreakpoint => {
+                f.write_str("the program stopped at an unexpected breakpoint")
+            }
         }
     }
 }
@@ -138,9 +391,135 @@ impl Outcome {
 impl From<Outcome> for i32 {
     fn from(outcome: Outcome) -> i32 {
         match outcome {
-            Outcome::HardFault | Outcome::StackOverflow => signal::SIGABRT,
+            Outcome::Abort | Outcome::HardFault | Outcome::Panic | Outcome::StackOverflow => {
+                signal::SIGABRT
+            }
+            Outcome::Exit(status) => status as i32,
             Outcome::CtrlC => signal::SIGINT,
-            Outcome::Ok => 0,
+            Outcome::Timeout | Outcome::Hang => EXIT_TIMEOUT,
+            Outcome::UnexpectedBreakpoint => signal::SIGTRAP,
+            Outcome::Ok | Outcome::EndSymbol | Outcome::RunUntil => 0,
         }
     }
 }
+
+/// The exit code of an [`Outcome::Timeout`] and [`Outcome::Hang`], like the one of coreutils'
+/// `timeout`
+const EXIT_TIMEOUT: i32 = 124;
+
+pub const OUTCOME_NAMES: [&str; 11] = [
+    "abort",
+    "breakpoint",
+    "ctrlc",
+    "end-symbol",
+    "hang",
+    "hardfault",
+    "ok",
+    "overflow",
+    "panic",
+    "run-until",
+    "timeout",
+];
+
+/// An exit code assigned to an outcome with `--exit-code-map`, e.g. `overflow=3`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitCodeMapping {
+    outcome: &'static str,
+    code: i32,
+}
+
+impl FromStr for ExitCodeMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, code) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `<outcome>=<code>`, e.g. `overflow=3`"))?;
+        let outcome = OUTCOME_NAMES
+            .into_iter()
+            .find(|outcome| *outcome == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown outcome `{name}`; expected one of {}",
+                    OUTCOME_NAMES.join(", ")
+                )
+            })?;
+        let code = code
+            .parse()
+            .map_err(|_| anyhow!("`{code}` is not a valid exit code"))?;
+        Ok(Self { outcome, code })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::mapped(Outcome::StackOverflow, 3)]
+    #[case::default(Outcome::Panic, signal::SIGABRT)]
+    #[case::last_wins(Outcome::CtrlC, 130)]
+    #[case::exit_status(Outcome::Exit(7), 7)]
+    #[case::end_symbol(Outcome::EndSymbol, 0)]
+    fn maps_exit_code(#[case] outcome: Outcome, #[case] expected: i32) {
+        let map = ["overflow=3", "ctrlc=1", "ctrlc=130"]
+            .map(|mapping| mapping.parse::<ExitCodeMapping>().unwrap());
+        assert_eq!(outcome.exit_code(&map), expected);
+    }
+
+    #[rstest]
+    #[case::app_template("app::exit", true)]
+    #[case::semihosting("cortex_m_semihosting::debug::exit", true)]
+    #[case::generic("app::exit<app::Board>", true)]
+    #[case::trait_method("<app::Board as app::Exit>::exit", true)]
+    #[case::other("app::check_sensor", false)]
+    #[case::exit_in_path("app::exit::report", false)]
+    fn recognizes_exit_fn(#[case] name: &str, #[case] expected: bool) {
+        assert_eq!(is_exit_fn(name), expected);
+    }
+
+    #[rstest]
+    #[case::dependency(
+        "cortex_m::asm::udf",
+        Some(
+            "/home/user/.cargo/registry/src/github.com-1ecc6299db9ec823/cortex-m-0.7.7/src/asm.rs"
+        ),
+        Some("cortex_m")
+    )]
+    #[case::std(
+        "core::panicking::panic",
+        Some("/rustc/9bc8c42bb2f19e745a63f3445f1ac248fb015e53/library/core/src/panicking.rs"),
+        Some("core")
+    )]
+    #[case::local("app::__cortex_m_rt_main", Some("src/main.rs"), Some("app"))]
+    #[case::trait_method("<T as app::Trait>::f", None, None)]
+    #[case::no_path("main", None, None)]
+    fn attributes_crate(
+        #[case] name: &str,
+        #[case] path: Option<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let subroutine = Subroutine {
+            name: Some(name.to_string()),
+            pc: 0,
+            location: path.map(|path| Location {
+                column: None,
+                path_is_relative: false,
+                line: 1,
+                path: PathBuf::from(path),
+            }),
+            no_unwind_info: false,
+        };
+        assert_eq!(subroutine.crate_name().as_deref(), expected);
+    }
+
+    #[rstest]
+    #[case::unknown_outcome("fault=3")]
+    #[case::no_code("panic")]
+    #[case::invalid_code("panic=four")]
+    fn rejects_invalid_mapping(#[case] input: &str) {
+        assert!(input.parse::<ExitCodeMapping>().is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! The message and location of a panic, read from the target's memory when the program halted in
+//! the panic handler (see `--catch-panics`), so that panics which never reach RTT, e.g. before it
+//! is initialized, still get reported
+//!
+//! The layouts of `PanicInfo`, `fmt::Arguments` and `panic::Location` aren't stable, so they are
+//! taken from the DWARF info. The formatting arguments can't be run on the host; integers and
+//! strings are read from memory, other arguments are shown as `{…}`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write as _},
+};
+
+use anyhow::{anyhow, bail};
+use probe_rs::RegisterId;
+
+use crate::{
+    cortexm,
+    dwarf::{self, attr_string},
+    elf::Elf,
+    registers::PC,
+    transport::Transport,
+};
+
+/// Longest string which gets read from the target
+const MAX_STR_LEN: u32 = 512;
+/// Most pieces or arguments a message is expected to have; more indicate a bad read
+const MAX_PARTS: u32 = 64;
+/// How deep nested `format_args!` get expanded
+const MAX_DEPTH: usize = 2;
+
+pub struct PanicMessage {
+    pub message: String,
+    /// File, line and column
+    pub location: Option<(String, u32, u32)>,
+}
+
+/// Read the message of the panic the core halted at the start of the panic handler for.
+pub fn read(core: &mut dyn Transport, elf: &Elf) -> anyhow::Result<PanicMessage> {
+    let layouts = Layouts::from_dwarf(elf)?;
+    let pc = core.read_core_reg(PC)?;
+    let r0 = core.read_core_reg(RegisterId(0))?;
+
+    // `rust_begin_unwind(info: &PanicInfo)`, or `panic_fmt(fmt: Arguments, location: &Location)`
+    // with the arguments passed by reference
+    let (arguments, location) = match elf.find_symbol("rust_begin_unwind") {
+        Some(handler) if cortexm::clear_thumb_bit(handler.start) == pc => {
+            let info = layouts.get(&layouts.panic_info, "PanicInfo")?;
+            let arguments = core.read_word_32((r0 + info.offset("message")?).into())?;
+            let location = core.read_word_32((r0 + info.offset("location")?).into())?;
+            (arguments, location)
+        }
+        _ => (r0, core.read_core_reg(RegisterId(1))?),
+    };
+    let mut reader = Reader {
+        core,
+        elf,
+        layouts: &layouts,
+    };
+    let message = match arguments {
+        // `PanicInfo::message` was `None`, e.g. for `std::panic::panic_any`
+        0 => "Box<dyn Any>".to_string(),
+        address => reader.arguments(address, 0)?,
+    };
+    let location = match location {
+        0 => None,
+        address => Some(reader.location(address)?),
+    };
+    Ok(PanicMessage { message, location })
+}
+
+pub fn print(panic: &PanicMessage, json_lines: bool) -> io::Result<()> {
+    if json_lines {
+        let (file, line, column) = match &panic.location {
+            Some((file, line, column)) => (Some(file), Some(line), Some(column)),
+            None => (None, None, None),
+        };
+        let event = serde_json::json!({
+            "event": "panic",
+            "message": panic.message,
+            "file": file,
+            "line": line,
+            "column": column,
+        });
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer(&mut stdout, &event)?;
+        writeln!(stdout)?;
+        return stdout.flush();
+    }
+
+    match &panic.location {
+        Some((file, line, column)) => {
+            log::error!("panicked at {file}:{line}:{column}:\n{}", panic.message)
+        }
+        None => log::error!("panicked:\n{}", panic.message),
+    }
+    Ok(())
+}
+
+/// The layout of a struct in the DWARF info
+#[derive(Debug)]
+struct Struct {
+    size: u32,
+    /// Offsets by member name
+    members: HashMap<String, u32>,
+}
+
+impl Struct {
+    fn offset(&self, member: &str) -> anyhow::Result<u32> {
+        self.members
+            .get(member)
+            .copied()
+            .ok_or_else(|| anyhow!("no member `{member}` in the DWARF info"))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Layouts {
+    panic_info: Option<Struct>,
+    arguments: Option<Struct>,
+    location: Option<Struct>,
+    /// `fmt::ArgumentV1`, or the newer `fmt::rt::Argument`
+    argument: Option<Struct>,
+}
+
+impl Layouts {
+    fn from_dwarf(elf: &Elf) -> anyhow::Result<Self> {
+        let sections = dwarf::Sections::load(elf)?;
+        let dwarf = sections.borrow();
+
+        let mut layouts = Layouts::default();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_structure_type {
+                    continue;
+                }
+                let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                    continue;
+                };
+                let name = attr_string(&dwarf, &unit, name)?;
+                let (slot, required) = match &*name {
+                    "PanicInfo" => (&mut layouts.panic_info, &["message", "location"][..]),
+                    "Arguments" => (&mut layouts.arguments, &["pieces", "args", "fmt"][..]),
+                    "Location" => (&mut layouts.location, &["file", "line", "col"][..]),
+                    // the placeholder specification of `fmt::rt::v1` is called `Argument`, too
+                    "ArgumentV1" | "Argument" => (&mut layouts.argument, &[][..]),
+                    _ => continue,
+                };
+                if slot.is_some() {
+                    continue;
+                }
+
+                let size = entry
+                    .attr_value(gimli::DW_AT_byte_size)?
+                    .and_then(|size| size.udata_value())
+                    .unwrap_or(0) as u32;
+                let mut members = HashMap::new();
+                let mut tree = unit.entries_tree(Some(entry.offset()))?;
+                let mut children = tree.root()?.children();
+                while let Some(child) = children.next()? {
+                    let member = child.entry();
+                    if member.tag() != gimli::DW_TAG_member {
+                        continue;
+                    }
+                    let (Some(name), Some(offset)) = (
+                        member.attr_value(gimli::DW_AT_name)?,
+                        member
+                            .attr_value(gimli::DW_AT_data_member_location)?
+                            .and_then(|offset| offset.udata_value()),
+                    ) else {
+                        continue;
+                    };
+                    members.insert(attr_string(&dwarf, &unit, name)?, offset as u32);
+                }
+
+                let is_placeholder = members.contains_key("position");
+                if required.iter().all(|member| members.contains_key(*member)) && !is_placeholder {
+                    *slot = Some(Struct { size, members });
+                }
+            }
+        }
+        Ok(layouts)
+    }
+
+    fn get<'a>(&self, layout: &'a Option<Struct>, name: &str) -> anyhow::Result<&'a Struct> {
+        layout
+            .as_ref()
+            .ok_or_else(|| anyhow!("the layout of `{name}` is not in the DWARF info"))
+    }
+}
+
+struct Reader<'a, 'elf> {
+    core: &'a mut dyn Transport,
+    elf: &'a Elf<'elf>,
+    layouts: &'a Layouts,
+}
+
+impl Reader<'_, '_> {
+    /// Render the `fmt::Arguments` at `address`.
+    fn arguments(&mut self, address: u32, depth: usize) -> anyhow::Result<String> {
+        let layout = self.layouts.get(&self.layouts.arguments, "Arguments")?;
+        let (pieces, num_pieces) = self.slice(address + layout.offset("pieces")?)?;
+        let (args, num_args) = self.slice(address + layout.offset("args")?)?;
+        if num_pieces > MAX_PARTS || num_args > MAX_PARTS {
+            bail!("implausible `fmt::Arguments` at {address:#010X}");
+        }
+        let argument_size = self
+            .layouts
+            .argument
+            .as_ref()
+            .map_or(8, |argument| argument.size);
+
+        let mut strings = vec![];
+        for index in 0..num_pieces {
+            let (ptr, len) = self.slice(pieces + index * 8)?;
+            strings.push(self.str(ptr, len)?);
+        }
+        let mut rendered = vec![];
+        for index in 0..num_args {
+            let arg = args + index * argument_size;
+            let words = [self.word(arg)?, self.word(arg + 4)?];
+            rendered.push(self.argument(words, depth));
+        }
+        // with explicit format specs (`fmt` is `Some`), arguments are assumed to appear in order
+        Ok(interleave(&strings, &rendered))
+    }
+
+    /// Render a formatting argument, given as its value pointer and formatter function in either
+    /// order, if its formatter is known.
+    fn argument(&mut self, words: [u32; 2], depth: usize) -> String {
+        let elf = self.elf;
+        let formatter_name = |word: u32| {
+            let name = elf.function_name(cortexm::clear_thumb_bit(word))?;
+            Some(format!("{:#}", rustc_demangle::demangle(name)))
+        };
+        let (value, formatter) = match (formatter_name(words[1]), formatter_name(words[0])) {
+            (Some(formatter), _) => (words[0], formatter),
+            (None, Some(formatter)) => (words[1], formatter),
+            (None, None) => return "{…}".to_string(),
+        };
+        self.value(value, &formatter, depth)
+            .unwrap_or_else(|| "{…}".to_string())
+    }
+
+    fn value(&mut self, value: u32, formatter: &str, depth: usize) -> Option<String> {
+        if let Some(ty) = integer_type(formatter) {
+            let mut bytes = [0; 8];
+            let size = match ty {
+                "u8" | "i8" => 1,
+                "u16" | "i16" => 2,
+                "u64" | "i64" => 8,
+                _ => 4,
+            };
+            self.core.read_8(value.into(), &mut bytes[..size]).ok()?;
+            let unsigned = u64::from_le_bytes(bytes);
+            return Some(match ty.starts_with('i') {
+                // sign-extend
+                true => {
+                    let shift = 64 - size * 8;
+                    (((unsigned << shift) as i64) >> shift).to_string()
+                }
+                false => unsigned.to_string(),
+            });
+        }
+        match formatter {
+            // most likely a `&str`; other references don't pass as UTF-8 of plausible length
+            "<&T as core::fmt::Display>::fmt" => {
+                let (ptr, len) = self.slice(value).ok()?;
+                let string = self.str(ptr, len).ok()?;
+                (!string.contains('\u{FFFD}')).then_some(string)
+            }
+            "<core::fmt::Arguments as core::fmt::Display>::fmt" if depth < MAX_DEPTH => {
+                self.arguments(value, depth + 1).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the `panic::Location` at `address`.
+    fn location(&mut self, address: u32) -> anyhow::Result<(String, u32, u32)> {
+        let layout = self.layouts.get(&self.layouts.location, "Location")?;
+        let (ptr, len) = self.slice(address + layout.offset("file")?)?;
+        Ok((
+            self.str(ptr, len)?,
+            self.word(address + layout.offset("line")?)?,
+            self.word(address + layout.offset("col")?)?,
+        ))
+    }
+
+    /// Read the data pointer and length of the slice reference at `address`.
+    fn slice(&mut self, address: u32) -> anyhow::Result<(u32, u32)> {
+        Ok((self.word(address)?, self.word(address + 4)?))
+    }
+
+    fn str(&mut self, ptr: u32, len: u32) -> anyhow::Result<String> {
+        let mut bytes = vec![0; len.min(MAX_STR_LEN) as usize];
+        self.core.read_8(ptr.into(), &mut bytes)?;
+        let mut string = String::from_utf8_lossy(&bytes).into_owned();
+        if len > MAX_STR_LEN {
+            string.push('…');
+        }
+        Ok(string)
+    }
+
+    fn word(&mut self, address: u32) -> anyhow::Result<u32> {
+        self.core.read_word_32(address.into())
+    }
+}
+
+/// The integer type a formatter like `core::fmt::num::imp::<impl core::fmt::Display for u32>::fmt`
+/// formats
+fn integer_type(formatter: &str) -> Option<&str> {
+    let ty = formatter
+        .strip_prefix("core::fmt::num::")?
+        .split_once(" for ")?
+        .1
+        .strip_suffix(">::fmt")?;
+    matches!(
+        ty,
+        "u8" | "i8" | "u16" | "i16" | "u32" | "i32" | "u64" | "i64" | "usize" | "isize"
+    )
+    .then_some(ty)
+}
+
+/// Join the literal `pieces` of a message with its rendered `args`, the way `fmt::write` does.
+fn interleave(pieces: &[String], args: &[String]) -> String {
+    let mut message = String::new();
+    for index in 0..pieces.len().max(args.len()) {
+        if let Some(piece) = pieces.get(index) {
+            message.push_str(piece);
+        }
+        if let Some(arg) = args.get(index) {
+            message.push_str(arg);
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn reads_layouts_from_dwarf() {
+        let path = Path::new("tests/test_elfs/panic-rzcobs");
+        let bytes = fs::read(path).unwrap();
+        let elf = Elf::parse_offline(&bytes, path).unwrap();
+
+        let layouts = Layouts::from_dwarf(&elf).unwrap();
+
+        let panic_info = layouts.panic_info.unwrap();
+        assert_eq!(panic_info.offset("message").unwrap(), 8);
+        assert_eq!(panic_info.offset("location").unwrap(), 12);
+        assert_eq!(layouts.arguments.unwrap().offset("pieces").unwrap(), 8);
+        assert_eq!(layouts.argument.unwrap().size, 8);
+    }
+
+    #[rstest]
+    #[case::display(
+        "core::fmt::num::imp::<impl core::fmt::Display for u32>::fmt",
+        Some("u32")
+    )]
+    #[case::debug("core::fmt::num::<impl core::fmt::Debug for i8>::fmt", Some("i8"))]
+    #[case::float("core::fmt::float::<impl core::fmt::Display for f32>::fmt", None)]
+    fn recognizes_integer_formatters(#[case] formatter: &str, #[case] expected: Option<&str>) {
+        assert_eq!(integer_type(formatter), expected);
+    }
+
+    #[test]
+    fn interleaves_pieces_and_args() {
+        let pieces = ["index ", " out of range for slice of length "].map(String::from);
+        let args = ["4", "3"].map(String::from);
+        assert_eq!(
+            interleave(&pieces, &args),
+            "index 4 out of range for slice of length 3"
+        );
+    }
+}
@@ -8,14 +8,21 @@ use std::{
 
 use colored::Colorize as _;
 
-use crate::dep;
+use crate::{dep, hyperlink, theme};
 
-use super::{symbolicate::Frame, Settings};
+use super::{
+    symbolicate::{Frame, Location},
+    Settings,
+};
 
 /// Pretty prints processed backtrace frames up to `backtrace_limit`
 pub fn backtrace(frames: &[Frame], settings: &Settings) -> io::Result<()> {
     let mut stderr = io::stderr().lock();
-    writeln!(stderr, "{}", "stack backtrace:".dimmed())?;
+    writeln!(
+        stderr,
+        "{}",
+        theme::current().backtrace_header.paint("stack backtrace:")
+    )?;
 
     let mut frame_index = 0;
     for frame in frames {
@@ -38,34 +45,27 @@ pub fn backtrace(frames: &[Frame], settings: &Settings) -> io::Result<()> {
                     subroutine.name.as_deref().unwrap_or("<unknown>")
                 )
                 .unwrap();
+                if subroutine.no_unwind_info {
+                    line.push_str(" (no unwind info (asm?))");
+                }
 
                 let colorized_line = if is_local_function {
-                    line.bold()
+                    theme::current().local_frame.paint(&line)
                 } else {
                     line.normal()
                 };
                 writeln!(stderr, "{colorized_line}")?;
 
                 if let Some(location) = &subroutine.location {
-                    let dep_path = dep::Path::from_std_path(&location.path);
-
-                    let path = if settings.shorten_paths {
-                        dep_path.format_short()
-                    } else {
-                        dep_path.format_highlight()
-                    };
-
-                    let line = location.line;
-                    let column = location
-                        .column
-                        .map(|column| Cow::Owned(format!(":{column}")))
-                        .unwrap_or(Cow::Borrowed(""));
-
-                    writeln!(stderr, "        at {path}:{line}{column}")?;
+                    writeln!(stderr, "        at {}", location_string(location, settings))?;
                 }
 
                 frame_index += 1;
 
+                if settings.stops_at(subroutine) {
+                    break;
+                }
+
                 if frame_index >= settings.backtrace_limit {
                     log::warn!(
                         "maximum backtrace length of {} reached; cutting off the rest.",
@@ -81,3 +81,22 @@ pub fn backtrace(frames: &[Frame], settings: &Settings) -> io::Result<()> {
 
     Ok(())
 }
+
+/// Formats `location` as `path:line[:column]`, shortening or highlighting the path
+pub fn location_string(location: &Location, settings: &Settings) -> String {
+    let path = dep::Path::from_std_path(&location.path).format(settings.shorten_paths);
+
+    let line = location.line;
+    let column = location
+        .column
+        .map(|column| Cow::Owned(format!(":{column}")))
+        .unwrap_or(Cow::Borrowed(""));
+
+    hyperlink::wrap(
+        format!("{path}:{line}{column}"),
+        &location.path,
+        &settings.current_dir,
+        line,
+        location.column,
+    )
+}
@@ -0,0 +1,142 @@
+//! A stable "crash signature" of a fault: a hash of its type and top frames, so that identical
+//! crashes from many runs or devices can be deduplicated
+
+use std::io::{self, Write as _};
+
+use super::{symbolicate::Frame, Outcome};
+
+/// How many subroutine frames, from the top, make up the signature
+const NUM_FRAMES: usize = 5;
+
+/// Compute the signature of the fault `outcome` with the backtrace `frames`.
+///
+/// Only the function names count, as addresses and line numbers change with unrelated edits.
+pub fn compute(frames: &[Frame], outcome: Outcome) -> String {
+    let mut hasher = Fnv1a::new();
+    hasher.write(outcome.name().unwrap_or("exit"));
+    let mut num_subroutines = 0;
+    for frame in frames {
+        match frame {
+            Frame::Exception => hasher.write("<exception entry>"),
+            Frame::Subroutine(subroutine) => {
+                let name = subroutine.name.as_deref().unwrap_or("<unknown>");
+                hasher.write(normalize(name));
+                num_subroutines += 1;
+            }
+        }
+        if num_subroutines == NUM_FRAMES {
+            break;
+        }
+    }
+    format!("{:016x}", hasher.0)
+}
+
+pub fn print(signature: &str, outcome: Outcome, json_lines: bool) -> io::Result<()> {
+    if json_lines {
+        let event = serde_json::json!({
+            "event": "crash",
+            "outcome": outcome.name(),
+            "signature": signature,
+        });
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer(&mut stdout, &event)?;
+        writeln!(stdout)?;
+        stdout.flush()
+    } else {
+        log::info!("crash signature: {signature}");
+        Ok(())
+    }
+}
+
+/// Remove the parts of a function name which differ between builds of the same code: the
+/// symbol hash (`::h0123456789abcdef`)
+fn normalize(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+/// 64-bit FNV-1a; unlike `std`'s hashers, its output is the same across Rust versions
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Hash `s` followed by a separator, so that `["ab", "c"]` and `["a", "bc"]` differ.
+    fn write(&mut self, s: &str) {
+        for byte in s.bytes().chain([0]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backtrace::Subroutine;
+
+    use super::*;
+
+    fn frames(names: &[&str], first_pc: u32) -> Vec<Frame> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                Frame::Subroutine(Subroutine {
+                    name: Some(name.to_string()),
+                    pc: first_pc + index as u32 * 4,
+                    location: None,
+                    no_unwind_info: false,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn is_stable() {
+        let mut frames = frames(&["HardFaultTrampoline"], 0);
+        frames.push(Frame::Exception);
+
+        // must not change between releases, or issue trackers lose track of known crashes
+        assert_eq!(compute(&frames, Outcome::StackOverflow), "2b464e283a8231fe");
+    }
+
+    #[test]
+    fn ignores_addresses_and_symbol_hashes() {
+        let before = frames(&["app::f::h0123456789abcdef", "main"], 0x100);
+        let after = frames(&["app::f::hfedcba9876543210", "main"], 0x200);
+
+        assert_eq!(
+            compute(&before, Outcome::Panic),
+            compute(&after, Outcome::Panic)
+        );
+    }
+
+    #[test]
+    fn differs_by_outcome_and_frames() {
+        let panic = compute(&frames(&["app::f", "main"], 0), Outcome::Panic);
+
+        assert_ne!(
+            panic,
+            compute(&frames(&["app::f", "main"], 0), Outcome::HardFault)
+        );
+        assert_ne!(
+            panic,
+            compute(&frames(&["app::g", "main"], 0), Outcome::Panic)
+        );
+    }
+
+    #[test]
+    fn only_hashes_top_frames() {
+        let top = ["a", "b", "c", "d", "e"];
+        let deep = compute(&frames(&[&top[..], &["f"]].concat(), 0), Outcome::Panic);
+        let deeper = compute(&frames(&[&top[..], &["g"]].concat(), 0), Outcome::Panic);
+
+        assert_eq!(deep, deeper);
+    }
+}
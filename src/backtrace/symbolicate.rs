@@ -1,39 +1,48 @@
 //! Turns PC addresses into function names and locations
+//!
+//! The results are memoized per ELF (see [`Cache`]), so that repeated backtraces, e.g. of
+//! snapshots or of the tests of a test manifest, don't rebuild the debug info lookup tables.
 
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::HashMap,
     path::{Path, PathBuf},
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
 use addr2line::fallible_iterator::FallibleIterator as _;
 use gimli::{EndianReader, RunTimeEndian};
-use object::{Object as _, SymbolMap, SymbolMapName};
+use object::{Object as _, ObjectSection as _};
 
-use crate::{cortexm, elf::Elf};
+use crate::{
+    dep,
+    elf::{self, Elf},
+    path_map::{self, PathMap},
+};
 
 use super::unwind::RawFrame;
 
-pub fn frames(raw_frames: &[RawFrame], current_dir: &Path, elf: &Elf) -> Vec<Frame> {
+pub fn frames(
+    raw_frames: &[RawFrame],
+    current_dir: &Path,
+    path_map: &[PathMap],
+    elf: &Elf,
+) -> Vec<Frame> {
     let mut frames = vec![];
 
-    let symtab = elf.symbol_map();
-    let addr2line = addr2line::Context::new(&**elf).ok();
-
     for raw_frame in raw_frames {
         match raw_frame {
             RawFrame::Exception => frames.push(Frame::Exception),
 
-            RawFrame::Subroutine { pc } => {
-                for subroutine in Subroutine::from_pc(
-                    *pc,
-                    addr2line.as_ref(),
-                    &elf.live_functions,
-                    current_dir,
-                    &symtab,
-                ) {
-                    frames.push(Frame::Subroutine(subroutine))
+            RawFrame::Subroutine { pc, no_unwind_info } => {
+                let mut subroutines = elf
+                    .symbol_cache
+                    .subroutines(*pc, elf, current_dir, path_map);
+                // inlined functions share the unwind info of the function they are inlined into
+                if let Some(outermost) = subroutines.last_mut() {
+                    outermost.no_unwind_info = *no_unwind_info;
                 }
+                frames.extend(subroutines.into_iter().map(Frame::Subroutine));
             }
         }
     }
@@ -41,6 +50,90 @@ pub fn frames(raw_frames: &[RawFrame], current_dir: &Path, elf: &Elf) -> Vec<Fra
     frames
 }
 
+/// Symbolicates single addresses, e.g. of an instruction trace, to their innermost function
+pub fn subroutines(
+    addresses: &[u32],
+    current_dir: &Path,
+    path_map: &[PathMap],
+    elf: &Elf,
+) -> Vec<Subroutine> {
+    addresses
+        .iter()
+        .map(|&pc| {
+            elf.symbol_cache
+                .subroutines(pc, elf, current_dir, path_map)
+                .remove(0)
+        })
+        .collect()
+}
+
+/// The `addr2line` context of an ELF, built on first use, and the subroutines of the addresses
+/// symbolicated so far
+#[derive(Default)]
+pub struct Cache(Mutex<CacheState>);
+
+#[derive(Default)]
+struct CacheState {
+    /// `None` until first used; `Some(None)` if the ELF has no usable debug info
+    addr2line: Option<Option<A2lContext>>,
+    /// The current directory and path map the `subroutines` were symbolicated with
+    key: Option<(PathBuf, Vec<PathMap>)>,
+    subroutines: HashMap<u32, Vec<Subroutine>>,
+}
+
+impl Cache {
+    /// The (de-inlined) subroutines at `pc`, innermost first.
+    fn subroutines(
+        &self,
+        pc: u32,
+        elf: &Elf,
+        current_dir: &Path,
+        path_map: &[PathMap],
+    ) -> Vec<Subroutine> {
+        let mut state = self.0.lock().unwrap();
+        let CacheState {
+            addr2line,
+            key,
+            subroutines,
+        } = &mut *state;
+
+        // the locations depend on both
+        let same_key = key
+            .as_ref()
+            .map_or(false, |(dir, map)| dir == current_dir && map == path_map);
+        if !same_key {
+            *key = Some((current_dir.to_path_buf(), path_map.to_vec()));
+            subroutines.clear();
+        }
+
+        subroutines
+            .entry(pc)
+            .or_insert_with(|| {
+                let addr2line = addr2line.get_or_insert_with(|| load_addr2line(elf));
+                Subroutine::from_pc(pc, addr2line.as_ref(), elf, current_dir, path_map)
+            })
+            .clone()
+    }
+}
+
+/// Build an `addr2line` context which can move between threads, unlike the one of
+/// `addr2line::Context::new`, so that [`Elf`] stays `Sync`.
+fn load_addr2line(elf: &Elf) -> Option<A2lContext> {
+    let endian = match elf.is_little_endian() {
+        true => RunTimeEndian::Little,
+        false => RunTimeEndian::Big,
+    };
+    let load_section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+        let data = elf
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[]));
+        Ok(EndianReader::new(Arc::<[u8]>::from(&*data), endian))
+    };
+    let dwarf = gimli::Dwarf::load(load_section).ok()?;
+    addr2line::Context::from_dwarf(dwarf).ok()
+}
+
 /// Processed frame
 #[derive(Debug)]
 pub enum Frame {
@@ -49,36 +142,57 @@ pub enum Frame {
 }
 
 /// "Symbolicated" and de-inlined subroutine frame
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Subroutine {
     pub name: Option<String>,
     pub pc: u32,
     pub location: Option<Location>,
+    /// The function has no unwind info (see [`RawFrame::Subroutine`])
+    pub no_unwind_info: bool,
 }
 
-type A2lContext = addr2line::Context<EndianReader<RunTimeEndian, Rc<[u8]>>>;
+impl Subroutine {
+    /// The crate the subroutine belongs to, with `-` normalized to `_`: taken from the path of
+    /// dependencies, and from the leading path segment of the name otherwise, e.g. for the
+    /// program itself.
+    pub fn crate_name(&self) -> Option<String> {
+        let from_path = self
+            .location
+            .as_ref()
+            .and_then(|location| dep::Path::from_std_path(&location.path).crate_name());
+        let crate_name = match from_path {
+            Some(crate_name) => crate_name,
+            // trait methods (`<T as Trait>::f`) don't start with their crate
+            None => self.name.as_deref()?.split_once("::")?.0,
+        };
+        let is_identifier = crate_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        is_identifier.then(|| crate_name.replace('-', "_"))
+    }
+}
+
+type A2lContext = addr2line::Context<EndianReader<RunTimeEndian, Arc<[u8]>>>;
 
 impl Subroutine {
     fn from_pc(
         pc: u32,
         addr2line: Option<&A2lContext>,
-        live_functions: &HashSet<&str>,
+        elf: &Elf,
         current_dir: &Path,
-        symtab: &SymbolMap<SymbolMapName>,
+        path_map: &[PathMap],
     ) -> Vec<Subroutine> {
         addr2line
-            .and_then(|addr2line| {
-                Self::from_debuginfo(pc, addr2line, live_functions, current_dir, symtab)
-            })
-            .unwrap_or_else(|| vec![Self::from_symtab(pc, symtab)])
+            .and_then(|addr2line| Self::from_debuginfo(pc, addr2line, elf, current_dir, path_map))
+            .unwrap_or_else(|| vec![Self::from_symtab(pc, elf)])
     }
 
     fn from_debuginfo(
         pc: u32,
         addr2line: &A2lContext,
-        live_functions: &HashSet<&str>,
+        elf: &Elf,
         current_dir: &Path,
-        symtab: &SymbolMap<SymbolMapName>,
+        path_map: &[PathMap],
     ) -> Option<Vec<Subroutine>> {
         let frames = addr2line
             .find_frames(pc as u64)
@@ -92,7 +206,7 @@ impl Subroutine {
         let has_valid_debuginfo = if let Some(function) =
             top_subroutine.and_then(|subroutine| subroutine.function.as_ref())
         {
-            live_functions.contains(&*function.raw_name().ok()?)
+            elf.live_functions.contains(&*function.raw_name().ok()?)
         } else {
             false
         };
@@ -120,18 +234,25 @@ impl Subroutine {
 
             // XXX if there was inlining AND there's no function name info we'll report several
             // frames with the same PC
-            let name = demangled_name.or_else(|| name_from_symtab(pc, symtab));
+            let name = demangled_name.or_else(|| name_from_symtab(pc, elf));
 
             let location = if let Some((file, line, column)) =
                 frame.location.as_ref().and_then(|loc| {
                     loc.file
                         .and_then(|file| loc.line.map(|line| (file, line, loc.column)))
                 }) {
-                let fullpath = Path::new(file);
+                let mut fullpath = path_map::remap(path_map, Path::new(file));
+                if fullpath.is_absolute() && !fullpath.exists() {
+                    let local_source =
+                        dep::Path::from_std_path(&fullpath).local_source(dep::Sources::get());
+                    if let Some(local_source) = local_source {
+                        fullpath = local_source.into();
+                    }
+                }
                 let (path, is_local) = if let Ok(relpath) = fullpath.strip_prefix(current_dir) {
                     (relpath, true)
                 } else {
-                    (fullpath, false)
+                    (&*fullpath, false)
                 };
 
                 Some(Location {
@@ -144,37 +265,66 @@ impl Subroutine {
                 None
             };
 
-            subroutines.push(Subroutine { name, pc, location })
+            subroutines.push(Subroutine {
+                name,
+                pc,
+                location,
+                no_unwind_info: false,
+            })
         }
 
         Some(subroutines)
     }
 
-    fn from_symtab(pc: u32, symtab: &SymbolMap<SymbolMapName>) -> Subroutine {
+    fn from_symtab(pc: u32, elf: &Elf) -> Subroutine {
         Subroutine {
-            name: name_from_symtab(pc, symtab),
+            name: name_from_symtab(pc, elf),
             pc,
             location: None,
+            no_unwind_info: false,
         }
     }
 }
 
-fn name_from_symtab(pc: u32, symtab: &SymbolMap<SymbolMapName>) -> Option<String> {
-    // the .symtab appears to use address ranges that have their thumb bits set (e.g.
-    // `0x101..0x200`). Passing the `pc` with the thumb bit cleared (e.g. `0x100`) to the
-    // lookup function sometimes returns the *previous* symbol. Work around the issue by
-    // setting `pc`'s thumb bit before looking it up
-    let address = cortexm::set_thumb_bit(pc) as u64;
+/// Names the function symbol containing `pc`; this also covers code without debug info, e.g.
+/// functions copied to RAM and linker veneers
+fn name_from_symtab(pc: u32, elf: &Elf) -> Option<String> {
+    let name = elf.function_name(pc)?;
+    let demangle = |name: &str| addr2line::demangle_auto(name.into(), None).into_owned();
 
-    symtab
-        .get(address)
-        .map(|symbol| addr2line::demangle_auto(symbol.name().into(), None).into_owned())
+    Some(match elf::veneer_target(name) {
+        Some(target) => format!("<veneer to {}>", demangle(target)),
+        None => demangle(name),
+    })
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Location {
     pub column: Option<u32>,
     pub path_is_relative: bool,
     pub line: u32,
     pub path: PathBuf,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memoizes_subroutines() {
+        let path = Path::new("tests/test_elfs/hello-rzcobs");
+        let bytes = std::fs::read(path).unwrap();
+        let elf = Elf::parse_offline(&bytes, path).unwrap();
+        let reset = elf.vector_table.reset;
+
+        let first = subroutines(&[reset, reset], Path::new("/"), &[], &elf);
+        assert_eq!(first[0].name, first[1].name);
+        assert_eq!(elf.symbol_cache.0.lock().unwrap().subroutines.len(), 1);
+
+        // other paths invalidate the locations
+        subroutines(&[reset], Path::new("/other"), &[], &elf);
+        let state = elf.symbol_cache.0.lock().unwrap();
+        assert_eq!(state.key.as_ref().unwrap().0, Path::new("/other"));
+        assert_eq!(state.subroutines.len(), 1);
+    }
+}
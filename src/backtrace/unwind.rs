@@ -5,7 +5,7 @@ use gimli::{
     BaseAddresses, CieOrFde, DebugFrame, FrameDescriptionEntry, Reader, UnwindContext,
     UnwindSection as _,
 };
-use probe_rs::{config::RamRegion, Core};
+use probe_rs::config::RamRegion;
 
 use crate::{
     backtrace::Outcome,
@@ -13,7 +13,9 @@ use crate::{
     elf::Elf,
     registers::{self, Registers},
     stacked::Stacked,
+    svc,
     target_info::TargetInfo,
+    transport::Transport,
 };
 
 fn missing_debug_info(pc: u32) -> String {
@@ -28,7 +30,7 @@ fn missing_debug_info(pc: u32) -> String {
 ///
 /// This returns as much info as could be collected, even if the collection is interrupted by an error.
 /// If an error occurred during processing, it is stored in `Output::processing_error`.
-pub fn target(core: &mut Core, elf: &Elf, target_info: &TargetInfo) -> Output {
+pub fn target(core: &mut dyn Transport, elf: &Elf, target_info: &TargetInfo) -> Output {
     let mut output = Output {
         corrupted: true,
         outcome: Outcome::Ok,
@@ -52,38 +54,83 @@ pub fn target(core: &mut Core, elf: &Elf, target_info: &TargetInfo) -> Output {
     let mut pc = unwrap_or_return_output!(core.read_core_reg(registers::PC));
     let sp = unwrap_or_return_output!(core.read_core_reg(registers::SP));
     let lr = unwrap_or_return_output!(core.read_core_reg(registers::LR));
+
+    // halted on the breakpoint set by `--svc-exit`
+    if let Some(outcome) =
+        unwrap_or_return_output!(svc::read_call(core, elf)).and_then(svc::SvcCall::outcome)
+    {
+        output.outcome = outcome;
+    }
+
     let base_addresses = BaseAddresses::default();
     let mut unwind_context = UnwindContext::new();
     let mut registers = Registers::new(lr, sp, core);
     let active_ram_region = &target_info.active_ram_region;
 
     loop {
-        if let Some(outcome) =
-            check_hard_fault(pc, &elf.vector_table, &mut output, sp, active_ram_region)
-        {
-            output.outcome = outcome;
+        // symbolicate code executed through a flash alias by its linked address
+        if let Some(remap) = &target_info.remap {
+            pc = remap.linked_address(pc);
         }
 
-        output.raw_frames.push(RawFrame::Subroutine { pc });
-
-        let fde = unwrap_or_return_output!(find_fde(&elf.debug_frame, &base_addresses, pc));
-
-        let uwt_row = unwrap_or_return_output!(fde
-            .unwind_info_for_address(
-                &elf.debug_frame,
-                &base_addresses,
-                &mut unwind_context,
-                pc.into()
-            )
-            .with_context(|| missing_debug_info(pc)));
-
-        log::trace!("uwt row for pc {pc:#010x}: {uwt_row:?}");
+        if let Some(outcome) = check_hard_fault(
+            pc,
+            target_info.hard_fault_handler,
+            &mut output,
+            sp,
+            active_ram_region,
+        ) {
+            output.outcome = outcome;
+        } else if output.raw_frames.is_empty() && elf.panic_fn_address() == Some(pc) {
+            // halted on the breakpoint set by `--catch-panics`
+            output.outcome = Outcome::Panic;
+        } else if output.raw_frames.is_empty() && target_info.run_until == Some(pc) {
+            output.outcome = Outcome::RunUntil;
+        } else if output.raw_frames.is_empty() && target_info.end_symbol_return == Some(pc) {
+            output.outcome = Outcome::EndSymbol;
+        }
 
-        let cfa_changed = unwrap_or_return_output!(registers.update_cfa(uwt_row.cfa()));
+        let veneer_target = elf.veneer_target(pc);
+        let fde = match veneer_target {
+            Some(_) => None,
+            None => unwrap_or_return_output!(find_fde(&elf.debug_frame, &base_addresses, pc)),
+        };
+        let no_unwind_info = veneer_target.is_none() && fde.is_none();
+        output
+            .raw_frames
+            .push(RawFrame::Subroutine { pc, no_unwind_info });
+
+        let cfa_changed = if let Some(target) = veneer_target {
+            // a veneer has no FDE, but it only branches on, leaving the stack and LR alone;
+            // so LR still points into the caller
+            log::debug!("PC={pc:#010X} is in a veneer to `{target}`");
+            false
+        } else if let Some(fde) = fde {
+            let uwt_row = unwrap_or_return_output!(fde
+                .unwind_info_for_address(
+                    &elf.debug_frame,
+                    &base_addresses,
+                    &mut unwind_context,
+                    pc.into()
+                )
+                .with_context(|| missing_debug_info(pc)));
+
+            log::trace!("uwt row for pc {pc:#010x}: {uwt_row:?}");
+
+            let cfa_changed = unwrap_or_return_output!(registers.update_cfa(uwt_row.cfa()));
+
+            for (reg, rule) in uwt_row.registers() {
+                unwrap_or_return_output!(registers.update(reg, rule));
+            }
 
-        for (reg, rule) in uwt_row.registers() {
-            unwrap_or_return_output!(registers.update(reg, rule));
-        }
+            cfa_changed
+        } else {
+            // `#[naked]` functions and hand-written assembly, e.g. the context switch of an RTOS,
+            // often come without CFI; assume they left the stack and LR alone, like a leaf
+            // function or a trampoline which branches on
+            log::debug!("PC={pc:#010X} has no unwind info; assuming LR holds the return address");
+            false
+        };
 
         let lr = unwrap_or_return_output!(registers.get(registers::LR));
 
@@ -98,6 +145,15 @@ pub fn target(core: &mut Core, elf: &Elf, target_info: &TargetInfo) -> Output {
         // If the frame didn't move, and the program counter didn't change, bail out
         // (otherwise we might print the same frame over and over).
         if !cfa_changed && !program_counter_changed {
+            // LR pointed back into the frame without unwind info, so the guess didn't work out
+            if no_unwind_info {
+                output.corrupted = false;
+                output.processing_error = Some(
+                    anyhow!(gimli::Error::NoUnwindInfoForAddress).context(missing_debug_info(pc)),
+                );
+                break;
+            }
+
             // If we do not end up in the reset function the stack is corrupted.
             // If reset_fn_range is empty, we can't detect this and just assume that
             // the stack was not corrupted.
@@ -158,12 +214,12 @@ pub fn target(core: &mut Core, elf: &Elf, target_info: &TargetInfo) -> Output {
 
 fn check_hard_fault(
     pc: u32,
-    vector_table: &cortexm::VectorTable,
+    hard_fault_handler: u32,
     output: &mut Output,
     sp: u32,
     sp_ram_region: &Option<RamRegion>,
 ) -> Option<Outcome> {
-    if cortexm::is_hard_fault(pc, vector_table) {
+    if cortexm::is_hard_fault(pc, hard_fault_handler) {
         assert!(
             output.raw_frames.is_empty(),
             "when present HardFault handler must be the first frame we unwind but wasn't"
@@ -191,7 +247,12 @@ pub struct Output {
 /// Backtrace frame prior to 'symbolication'
 #[derive(Debug)]
 pub enum RawFrame {
-    Subroutine { pc: u32 },
+    Subroutine {
+        pc: u32,
+        /// The function has no CFI, e.g. because it's written in assembly; it was unwound by
+        /// taking LR as its return address
+        no_unwind_info: bool,
+    },
     Exception,
 }
 
@@ -224,7 +285,7 @@ fn find_fde<R: Reader>(
     debug_frame: &DebugFrame<R>,
     bases: &BaseAddresses,
     addr: u32,
-) -> anyhow::Result<FrameDescriptionEntry<R>> {
+) -> anyhow::Result<Option<FrameDescriptionEntry<R>>> {
     let mut entries = debug_frame.entries(bases);
     let mut fdes = Vec::new();
     while let Some(entry) = entries.next()? {
@@ -250,9 +311,8 @@ fn find_fde<R: Reader>(
     }
 
     match fdes.len() {
-        0 => Err(anyhow!(gimli::Error::NoUnwindInfoForAddress))
-            .with_context(|| missing_debug_info(addr)),
-        1 => Ok(fdes.pop().unwrap()),
+        0 => Ok(None),
+        1 => Ok(fdes.pop()),
         n => Err(anyhow!(
             "found {n} frame description entries for address {addr:#010x}, there should only be 1; \
              this is likely a bug in your compiler toolchain; unwinding will stop here",
@@ -0,0 +1,8 @@
+use std::process;
+
+fn main() -> anyhow::Result<()> {
+    probe_run::deprecated();
+
+    #[allow(clippy::redundant_closure)]
+    probe_run::handle_cargo_arguments().map(|code| process::exit(code))
+}
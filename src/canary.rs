@@ -1,11 +1,15 @@
-use std::time::Instant;
+use std::{ops::Range, str::FromStr, time::Instant};
 
-use probe_rs::{Core, MemoryInterface, RegisterId};
+use anyhow::anyhow;
+use probe_rs::{Core, CoreType, MemoryInterface, RegisterId};
 
 use crate::{
-    registers::PC,
+    elf::Elf,
+    registers::{MSP, PSP},
+    routine,
+    run::TIMEOUT,
     target_info::{StackInfo, TargetInfo},
-    Elf, TIMEOUT,
+    warnings::{self, Warning},
 };
 
 /// Canary value
@@ -21,8 +25,8 @@ const CANARY_U32: u32 = u32::from_le_bytes([CANARY_U8, CANARY_U8, CANARY_U8, CAN
 /// The whole stack is initialized to `CANARY_U8` before the target program is started.
 ///
 /// When the programs ends (due to panic or breakpoint) the size of the canary is checked. If more
-/// than 90%  is "touched" (bytes != `CANARY_U8`) then that is considered to be a *potential* stack
-/// overflow. In any case the amount of used stack is reported.
+/// of it than the [`OverflowThreshold`] allows is "touched" (bytes != `CANARY_U8`) then that is
+/// considered to be a *potential* stack overflow. In any case the amount of used stack is reported.
 ///
 /// Before execution:
 /// ``` text
@@ -53,10 +57,85 @@ const CANARY_U32: u32 = u32::from_le_bytes([CANARY_U8, CANARY_U8, CANARY_U8, CAN
 pub struct Canary {
     addr: u32,
     data_below_stack: bool,
+    method: Method,
     size: u32,
     size_kb: f64,
 }
 
+/// How memory gets painted and measured
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Method {
+    /// Run the subroutines on the target; fast, but needs to execute code from RAM.
+    Subroutine,
+    /// Read and write memory with the probe; slow, but works on every target.
+    Probe,
+}
+
+impl Method {
+    /// The subroutines only use 16-bit Thumb instructions, which every ARMv6-M and later
+    /// M-profile core supports.
+    fn for_core(core_type: CoreType) -> Self {
+        match core_type {
+            CoreType::Armv6m | CoreType::Armv7m | CoreType::Armv7em | CoreType::Armv8m => {
+                Method::Subroutine
+            }
+            CoreType::Armv7a | CoreType::Armv8a | CoreType::Riscv => Method::Probe,
+        }
+    }
+}
+
+/// When the stack usage counts as a potential overflow (see `--stack-overflow-threshold`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowThreshold {
+    /// More than this percentage of the stack was used
+    Percent(f64),
+    /// Fewer than this many bytes of the stack were left
+    BytesFree(u32),
+}
+
+impl FromStr for OverflowThreshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Self::Percent(percent)),
+                _ => Err(anyhow!("`{s}` is not a percentage between 0% and 100%")),
+            },
+            None => s
+                .parse()
+                .map(Self::BytesFree)
+                .map_err(|_| anyhow!("expected a percentage (e.g. `90%`) or bytes, found `{s}`")),
+        }
+    }
+}
+
+impl OverflowThreshold {
+    fn is_exceeded(self, used: u32, size: u32) -> bool {
+        match self {
+            Self::Percent(percent) => used as f64 / size as f64 * 100.0 > percent,
+            Self::BytesFree(bytes) => size.saturating_sub(used) < bytes,
+        }
+    }
+}
+
+/// The result of [`Canary::measure`]
+pub struct StackUsage {
+    /// The program used at least this many bytes of stack
+    pub min_bytes: u32,
+    /// A stack overflow is likely
+    pub overflow: bool,
+}
+
+impl StackUsage {
+    fn new(min_bytes: u32, overflow: bool) -> Self {
+        Self {
+            min_bytes,
+            overflow,
+        }
+    }
+}
+
 impl Canary {
     /// Decide if and where to place the stack canary.
     ///
@@ -66,7 +145,7 @@ impl Canary {
         elf: &Elf,
         target_info: &TargetInfo,
     ) -> anyhow::Result<Option<Self>> {
-        let canary = match Self::prepare(elf, &target_info.stack_info) {
+        let mut canary = match Self::prepare(elf, &target_info.stack_info)? {
             Some(canary) => canary,
             None => return Ok(None),
         };
@@ -74,7 +153,8 @@ impl Canary {
         let start = Instant::now();
 
         // paint stack
-        paint_subroutine::execute(core, canary.addr, canary.size)?;
+        let method = Method::for_core(target_info.core_type());
+        canary.method = paint(core, canary.addr, canary.size, CANARY_U32, method)?;
 
         let seconds = start.elapsed().as_secs_f64();
         canary.log_time("painting", seconds);
@@ -83,13 +163,19 @@ impl Canary {
     }
 
     /// Measure the stack usage.
-    ///
-    /// Returns `true` if a stack overflow is likely.
-    pub fn measure(self, core: &mut Core, elf: &Elf) -> anyhow::Result<bool> {
+    pub fn measure(
+        self,
+        core: &mut Core,
+        elf: &Elf,
+        threshold: OverflowThreshold,
+    ) -> anyhow::Result<StackUsage> {
         let start = Instant::now();
 
         // measure stack usage
-        let touched_address = measure_subroutine::execute(core, self.addr, self.size)?;
+        let touched_address = match self.method {
+            Method::Subroutine => measure_subroutine::execute(core, self.addr, self.size)?,
+            Method::Probe => measure_with_probe(core, self.addr, self.size)?,
+        };
 
         let seconds = start.elapsed().as_secs_f64();
         self.log_time("reading", seconds);
@@ -107,59 +193,113 @@ impl Canary {
 
         let used_kb = min_stack_usage as f64 / 1024.0;
         let pct = used_kb / self.size_kb * 100.0;
+        let free = self.size.saturating_sub(min_stack_usage);
         let msg = format!(
-            "program has used at least {used_kb:.2}/{:.2} KiB ({pct:.1}%) of stack space",
+            "program has used at least {used_kb:.2}/{:.2} KiB ({pct:.1}%) of stack space, \
+            leaving at most {free} bytes",
             self.size_kb
         );
 
         // stack touched?
-        //
-        // We consider >90% stack usage a potential stack overflow
-        if pct > 90.0 {
+        if threshold.is_exceeded(min_stack_usage, self.size) {
+            if let Some((msp, psp)) = self.stack_pointers_elsewhere(core)? {
+                log::info!("{}", msg);
+                log::warn!(
+                    "the stack pointers (MSP = {msp:#010X}, PSP = {psp:#010X}) are outside of \
+                    the painted stack region; assuming the program moved its stack on purpose \
+                    and that the canary was overwritten by other data, not by a stack overflow"
+                );
+                return Ok(StackUsage::new(min_stack_usage, false));
+            }
+
             log::warn!("{}", msg);
             if self.data_below_stack {
                 log::warn!("data segments might be corrupted due to stack overflow");
             }
-            Ok(true)
+            Ok(StackUsage::new(min_stack_usage, true))
         } else {
             log::info!("{}", msg);
-            Ok(false)
+            Ok(StackUsage::new(min_stack_usage, false))
+        }
+    }
+
+    /// The painted part of the stack
+    pub fn range(&self) -> Range<u32> {
+        self.addr..self.addr + self.size
+    }
+
+    /// The stack usage up to now, while the program is halted in the middle of its run (see
+    /// [`crate::snapshot`]).
+    ///
+    /// Reads the canary with the probe, so that no code runs on the target, and leaves it in
+    /// place for [`Canary::measure`].
+    pub fn used_so_far(&self, core: &mut Core, elf: &Elf) -> anyhow::Result<u32> {
+        let touched_address = measure_with_probe(core, self.addr, self.size)?;
+        Ok(touched_address.map_or(0, |address| {
+            elf.vector_table.initial_stack_pointer - address
+        }))
+    }
+
+    /// Returns `(MSP, PSP)` if neither stack pointer is in the painted region, or just below it.
+    ///
+    /// An overflowing stack pointer ends up below the region, while a program which moved its
+    /// stack, e.g. to another RAM region or to a process stack, leaves it somewhere else.
+    fn stack_pointers_elsewhere(&self, core: &mut Core) -> anyhow::Result<Option<(u32, u32)>> {
+        let msp = core.read_core_reg::<u32>(MSP)?;
+        let psp = core.read_core_reg::<u32>(PSP)?;
+
+        // allow the stack pointer to have overflowed by up to the size of the stack
+        let stack_or_overflow = self.addr.saturating_sub(self.size)..=self.addr + self.size;
+        match stack_or_overflow.contains(&msp) || stack_or_overflow.contains(&psp) {
+            true => Ok(None),
+            false => Ok(Some((msp, psp))),
         }
     }
 
     /// Prepare, but not place the canary.
     ///
     /// If this succeeds, we have all the information we need in order to place the canary.
-    fn prepare(elf: &Elf, stack_info: &Option<StackInfo>) -> Option<Self> {
+    fn prepare(elf: &Elf, stack_info: &Option<StackInfo>) -> anyhow::Result<Option<Self>> {
         let stack_info = match stack_info {
             Some(stack_info) => stack_info,
             None => {
                 log::debug!("couldn't find valid stack range, not placing stack canary");
-                return None;
+                return Ok(None);
             }
         };
 
         if elf.program_uses_heap() {
             log::debug!("heap in use, not placing stack canary");
-            return None;
+            return Ok(None);
         }
 
         let stack_addr = *stack_info.range.start();
         let stack_size = *stack_info.range.end() - stack_addr;
 
         log::debug!(
-            "{stack_size} bytes of stack available ({stack_addr:#010X} ..= {:#010X})",
+            "{stack_size} bytes of stack available in RAM region{} ({stack_addr:#010X} ..= {:#010X})",
+            stack_info
+                .region_name()
+                .map(|name| format!(" `{name}`"))
+                .unwrap_or_default(),
             stack_info.range.end(),
         );
 
-        Self::assert_subroutines(stack_addr, stack_size)?;
+        if Self::assert_subroutines(stack_addr, stack_size).is_none() {
+            warnings::warn(
+                Warning::CanaryNotPlaced,
+                "subroutines do not fit in stack; not placing stack canary",
+            )?;
+            return Ok(None);
+        }
 
-        Some(Canary {
+        Ok(Some(Canary {
             addr: stack_addr,
             data_below_stack: stack_info.data_below_stack,
+            method: Method::Subroutine,
             size: stack_size,
             size_kb: stack_size as f64 / 1024.0,
-        })
+        }))
     }
 
     fn log_time(&self, action: &str, seconds: f64) {
@@ -185,7 +325,6 @@ impl Canary {
             "measure subroutine needs to be 4-byte-aligned"
         );
         if (stack_size < paint_subroutine::size()) || (stack_size < measure_subroutine::size()) {
-            log::warn!("subroutines do not fit in stack; not placing stack canary");
             None
         } else {
             Some(())
@@ -193,6 +332,102 @@ impl Canary {
     }
 }
 
+/// Fill the word-aligned `range` of RAM with `pattern`, like the stack gets painted.
+///
+/// Whole words are written, which also initializes the ECC of RAM that requires it.
+/// Ranges too small to hold the paint subroutine are written through the probe.
+///
+/// Expects the [`Core`] to be halted and leaves it halted.
+pub fn fill(
+    core: &mut Core,
+    range: Range<u32>,
+    pattern: u32,
+    core_type: CoreType,
+) -> anyhow::Result<()> {
+    assert!(
+        range.start % 4 == 0 && range.end % 4 == 0,
+        "range needs to be 4-byte-aligned"
+    );
+
+    let size = range.end - range.start;
+    let method = match size <= paint_subroutine::size() {
+        true => Method::Probe,
+        false => Method::for_core(core_type),
+    };
+
+    // painting goes up to and including `low_addr + size`
+    paint(core, range.start, size - 4, pattern, method)?;
+    Ok(())
+}
+
+/// Paint the word-aligned `range` of RAM with the canary value, like the stack (see
+/// [`crate::ram_map`]).
+pub fn paint_region(core: &mut Core, range: Range<u32>, core_type: CoreType) -> anyhow::Result<()> {
+    fill(core, range, CANARY_U32, core_type)
+}
+
+/// For each byte of the word-aligned `range`, whether it isn't the canary value anymore; reads
+/// the memory with the probe, so that no code runs on the target.
+pub fn touched_bytes(core: &mut Core, range: Range<u32>) -> Result<Vec<bool>, probe_rs::Error> {
+    let mut words = vec![0; (range.end - range.start) as usize / 4];
+    core.read_32(range.start.into(), &mut words)?;
+    Ok(words
+        .into_iter()
+        .flat_map(u32::to_le_bytes)
+        .map(|byte| byte != CANARY_U8)
+        .collect())
+}
+
+/// Paint the memory from `low_addr` up to and including `low_addr + size` with `pattern`.
+///
+/// Falls back to the probe if the subroutine can't run, e.g. because the RAM is not executable.
+/// Returns the method which painted the memory.
+fn paint(
+    core: &mut Core,
+    low_addr: u32,
+    size: u32,
+    pattern: u32,
+    method: Method,
+) -> anyhow::Result<Method> {
+    if method == Method::Subroutine {
+        match paint_subroutine::execute(core, low_addr, size, pattern) {
+            Ok(()) => return Ok(Method::Subroutine),
+            Err(e) => {
+                log::debug!("paint subroutine failed: {e}");
+                log::info!(
+                    "can't run code from RAM; painting with the probe instead, which is slower"
+                );
+                // the failed subroutine may have left the core in an exception handler
+                core.reset_and_halt(TIMEOUT)?;
+            }
+        }
+    }
+
+    let words = vec![pattern; size as usize / 4 + 1];
+    core.write_32(low_addr.into(), &words)?;
+    Ok(Method::Probe)
+}
+
+/// Search the memory from `low_addr` to `low_addr + size` for the lowest byte which isn't
+/// [`CANARY_U8`] anymore, reading it with the probe.
+fn measure_with_probe(
+    core: &mut Core,
+    low_addr: u32,
+    size: u32,
+) -> Result<Option<u32>, probe_rs::Error> {
+    let mut words = vec![0; size as usize / 4];
+    core.read_32(low_addr.into(), &mut words)?;
+
+    let touched = words.into_iter().enumerate().find_map(|(index, word)| {
+        let offset = word
+            .to_le_bytes()
+            .into_iter()
+            .position(|b| b != CANARY_U8)?;
+        Some(low_addr + index as u32 * 4 + offset as u32)
+    });
+    Ok(touched)
+}
+
 /// Paint-stack subroutine.
 ///
 /// # Rust
@@ -200,7 +435,7 @@ impl Canary {
 /// Corresponds to following rust code:
 ///
 /// ```rust
-/// unsafe fn paint(low_addr: u32, high_addr: u32, pattern: u32) {
+/// unsafe fn paint(mut low_addr: u32, high_addr: u32, pattern: u32) {
 ///     while low_addr <= high_addr {
 ///         (low_addr as *mut u32).write(pattern);
 ///         low_addr += 4;
@@ -226,7 +461,7 @@ impl Canary {
 mod paint_subroutine {
     use super::*;
 
-    /// Write the carnary value to the stack.
+    /// Write `pattern` to the stack.
     ///
     /// # Safety
     ///
@@ -239,20 +474,30 @@ mod paint_subroutine {
     /// We place the subroutine inside the memory we want to paint. The subroutine
     /// paints the whole memory, except of itself. After the subroutine finishes
     /// executing we overwrite the subroutine using the probe.
-    pub fn execute(core: &mut Core, low_addr: u32, stack_size: u32) -> Result<(), probe_rs::Error> {
-        super::execute_subroutine(core, low_addr, stack_size, self::SUBROUTINE)?;
-        self::overwrite_subroutine(core, low_addr)?;
+    pub fn execute(
+        core: &mut Core,
+        low_addr: u32,
+        stack_size: u32,
+        pattern: u32,
+    ) -> Result<(), probe_rs::Error> {
+        super::execute_subroutine(core, low_addr, stack_size, pattern, self::SUBROUTINE)?;
+        self::overwrite_subroutine(core, low_addr, pattern)?;
         Ok(())
     }
 
     /// Overwrite the subroutine with the canary value.
     ///
     /// Happens after the subroutine finishes.
-    fn overwrite_subroutine(core: &mut Core, low_addr: u32) -> Result<(), probe_rs::Error> {
-        core.write_8(low_addr as u64, &[CANARY_U8; self::SUBROUTINE.len()])
+    fn overwrite_subroutine(
+        core: &mut Core,
+        low_addr: u32,
+        pattern: u32,
+    ) -> Result<(), probe_rs::Error> {
+        let words = [pattern; self::SUBROUTINE.len() / 4];
+        core.write_32(low_addr as u64, &words)
     }
 
-    const SUBROUTINE: [u8; 12] = [
+    pub const SUBROUTINE: [u8; 12] = [
         0x88, 0x42, // cmp      r0, r1
         0x01, 0xd8, // bhi.n    #6 <paint+0x8>
         0x04, 0xc0, // stmia    r0!, {r2}
@@ -345,8 +590,9 @@ mod measure_subroutine {
             return Ok(Some(addr)); // return early, if we find a touched value
         }
 
-        super::execute_subroutine(core, low_addr, stack_size, self::SUBROUTINE)?;
-        self::get_result(core)
+        let r0 =
+            super::execute_subroutine(core, low_addr, stack_size, CANARY_U32, self::SUBROUTINE)?;
+        self::get_result(core, r0)
     }
 
     /// Searches though memory byte by byte using the SWD/JTAG probe.
@@ -361,12 +607,12 @@ mod measure_subroutine {
         }
     }
 
-    /// Read out result from register r0 and process it to get lowest touched byte.
+    /// Process the result from register r0 to get lowest touched byte.
     ///
     /// Happens after the subroutine finishes.
-    fn get_result(core: &mut Core) -> Result<Option<u32>, probe_rs::Error> {
+    fn get_result(core: &mut Core, r0: u32) -> Result<Option<u32>, probe_rs::Error> {
         // get the address of the lowest touched 4-byte-word
-        let word_addr = match core.read_core_reg(RegisterId(0))? {
+        let word_addr = match r0 {
             0 => return Ok(None),
             n => n,
         };
@@ -382,7 +628,7 @@ mod measure_subroutine {
         Ok(Some(word_addr + offset as u32))
     }
 
-    const SUBROUTINE: [u8; 20] = [
+    pub const SUBROUTINE: [u8; 20] = [
         0x88, 0x42, // cmp      r0, r1
         0x04, 0xd2, // bcs.n    #0xc <measure+0xe>
         0x03, 0x68, // ldr      r3, [r0, #0]
@@ -405,8 +651,8 @@ mod measure_subroutine {
 /// # How?
 ///
 /// We place the parameters in the registers (see table below), place the subroutine
-/// in memory, set the program counter to the beginning of the subroutine, execute
-/// the subroutine and reset the program counter afterwards.
+/// in memory and run it with [`routine::execute`], which restores the registers afterwards.
+/// Returns the value of `r0`.
 ///
 /// ## Register-parameter-mapping
 ///
@@ -419,30 +665,70 @@ fn execute_subroutine<const N: usize>(
     core: &mut Core,
     low_addr: u32,
     stack_size: u32,
+    pattern: u32,
     subroutine: [u8; N],
-) -> Result<(), probe_rs::Error> {
+) -> Result<u32, probe_rs::Error> {
     let subroutine_size = N as u32;
     let high_addr = low_addr + stack_size;
 
-    // set the registers
     // NOTE: add `subroutine_size` to `low_addr`, to avoid the subroutine overwriting itself
-    core.write_core_reg(RegisterId(0), low_addr + subroutine_size)?;
-    core.write_core_reg(RegisterId(1), high_addr)?;
-    core.write_core_reg(RegisterId(2), CANARY_U32)?;
+    let args = [
+        (RegisterId(0), low_addr + subroutine_size),
+        (RegisterId(1), high_addr),
+        (RegisterId(2), pattern),
+    ];
+    let registers = routine::execute(core, low_addr, &subroutine, &args)?;
+    Ok(registers.get(RegisterId(0)))
+}
 
-    // write subroutine to stack
-    core.write_8(low_addr as u64, &subroutine)?;
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
 
-    // store current PC and set PC to beginning of subroutine
-    let previous_pc = core.read_core_reg(PC)?;
-    core.write_core_reg(PC, low_addr)?;
+    use super::*;
+
+    #[rstest]
+    #[case::paint(&paint_subroutine::SUBROUTINE)]
+    #[case::measure(&measure_subroutine::SUBROUTINE)]
+    fn subroutine_runs_on_armv6m(#[case] subroutine: &[u8]) {
+        // 32-bit Thumb-2 instructions start with a halfword of 0b11101, 0b11110 or 0b11111;
+        // ARMv6-M only has a handful of those, none of which the subroutines need
+        for halfword in subroutine.chunks_exact(2) {
+            let halfword = u16::from_le_bytes([halfword[0], halfword[1]]);
+            assert!(
+                halfword >> 11 < 0b11101,
+                "{halfword:#06X} is a 32-bit instruction"
+            );
+        }
+    }
 
-    // execute the subroutine and wait for it to finish
-    core.run()?;
-    core.wait_for_core_halted(TIMEOUT)?;
+    #[rstest]
+    #[case::cortex_m0(CoreType::Armv6m, Method::Subroutine)]
+    #[case::cortex_m33(CoreType::Armv8m, Method::Subroutine)]
+    #[case::riscv(CoreType::Riscv, Method::Probe)]
+    fn method_depends_on_core(#[case] core_type: CoreType, #[case] expected: Method) {
+        assert_eq!(Method::for_core(core_type), expected);
+    }
 
-    // reset PC to where it was before
-    core.write_core_reg::<u32>(PC, previous_pc)?;
+    #[rstest]
+    #[case::below_percent("90%", 900, false)]
+    #[case::above_percent("90%", 901, true)]
+    #[case::enough_bytes_free("128", 872, false)]
+    #[case::too_few_bytes_free("128", 873, true)]
+    fn applies_overflow_threshold(
+        #[case] threshold: &str,
+        #[case] used: u32,
+        #[case] expected: bool,
+    ) {
+        let threshold = threshold.parse::<OverflowThreshold>().unwrap();
+        assert_eq!(threshold.is_exceeded(used, 1000), expected);
+    }
 
-    Ok(())
+    #[rstest]
+    #[case::above_100_percent("101%")]
+    #[case::negative("-1")]
+    #[case::unit("1KiB")]
+    fn rejects_invalid_overflow_threshold(#[case] threshold: &str) {
+        assert!(threshold.parse::<OverflowThreshold>().is_err());
+    }
 }
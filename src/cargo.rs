@@ -0,0 +1,269 @@
+//! The `cargo probe-run` subcommand: build a program like `cargo run` does, then run it
+
+use std::{
+    env,
+    ffi::OsString,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use clap::{Args, Parser};
+use serde::Deserialize;
+
+use crate::cli;
+
+#[derive(Parser)]
+#[command(bin_name = "cargo")]
+enum Cargo {
+    /// Build a program and run it on the device, like `cargo run` with `probe-run` as the runner.
+    ProbeRun(CargoArgs),
+}
+
+#[derive(Args, Debug, Default, PartialEq, Eq)]
+struct CargoArgs {
+    /// Activate all available features.
+    #[arg(long)]
+    all_features: bool,
+
+    /// Build and run the given binary.
+    #[arg(long, value_name = "NAME")]
+    bin: Option<String>,
+
+    /// Build and run the given example.
+    #[arg(long, value_name = "NAME", conflicts_with = "bin")]
+    example: Option<String>,
+
+    /// Space or comma separated list of features to activate (repeatable).
+    #[arg(long, short = 'F')]
+    features: Vec<String>,
+
+    /// Path to the `Cargo.toml` of the package.
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+
+    /// Do not activate the `default` feature.
+    #[arg(long)]
+    no_default_features: bool,
+
+    /// The package to build.
+    #[arg(long, short)]
+    package: Option<String>,
+
+    /// Build with the given profile.
+    #[arg(long, value_name = "PROFILE-NAME")]
+    profile: Option<String>,
+
+    /// Build with the `release` profile.
+    #[arg(long, short, conflicts_with = "profile")]
+    release: bool,
+
+    /// Build for the given target triple.
+    #[arg(long, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Options for `probe-run`, e.g. `-- --chip nRF52840_xxAA`.
+    #[arg(last = true, value_name = "PROBE-RUN-OPTIONS")]
+    probe_run_args: Vec<OsString>,
+}
+
+impl CargoArgs {
+    /// The arguments of the `cargo build` invocation.
+    fn build_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "build".into(),
+            "--message-format=json-render-diagnostics".into(),
+        ];
+
+        let options = [
+            ("--bin", self.bin.as_ref().map(OsString::from)),
+            ("--example", self.example.as_ref().map(OsString::from)),
+            (
+                "--manifest-path",
+                self.manifest_path.as_ref().map(OsString::from),
+            ),
+            ("--package", self.package.as_ref().map(OsString::from)),
+            ("--profile", self.profile.as_ref().map(OsString::from)),
+            ("--target", self.target.as_ref().map(OsString::from)),
+        ];
+        for (flag, value) in options {
+            if let Some(value) = value {
+                args.extend([flag.into(), value]);
+            }
+        }
+        for features in &self.features {
+            args.extend(["--features".into(), features.into()]);
+        }
+
+        let flags = [
+            ("--all-features", self.all_features),
+            ("--no-default-features", self.no_default_features),
+            ("--release", self.release),
+        ];
+        for (flag, enabled) in flags {
+            if enabled {
+                args.push(flag.into());
+            }
+        }
+
+        args
+    }
+}
+
+/// The messages of `cargo build --message-format=json`; only the ones we need
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message {
+    CompilerArtifact {
+        target: ArtifactTarget,
+        executable: Option<PathBuf>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ArtifactTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+pub fn handle_arguments() -> anyhow::Result<i32> {
+    let Cargo::ProbeRun(args) = Cargo::parse();
+    let elf = build(&args)?;
+
+    let probe_run_args = [OsString::from("probe-run")]
+        .into_iter()
+        .chain(args.probe_run_args)
+        .chain([elf.into()]);
+    cli::run(cli::Opts::parse_from(probe_run_args))
+}
+
+/// Build the program with cargo and return the path of its ELF.
+fn build(args: &CargoArgs) -> anyhow::Result<PathBuf> {
+    // `CARGO` is set if we were invoked as `cargo probe-run`
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut child = Command::new(cargo)
+        .args(args.build_args())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("failed to run `cargo build`")?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let executables = executables(BufReader::new(stdout))?;
+
+    if !child.wait()?.success() {
+        bail!("`cargo build` failed");
+    }
+    select_executable(executables)
+}
+
+/// The names and paths of the binaries and examples among the artifacts reported by cargo.
+fn executables(messages: impl BufRead) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut executables = vec![];
+    for line in messages.lines() {
+        let line = line?;
+        // cargo passes through everything build scripts print, so not every line is a message
+        let Ok(Message::CompilerArtifact { target, executable }) = serde_json::from_str(&line)
+        else {
+            continue;
+        };
+
+        let is_program = target
+            .kind
+            .iter()
+            .any(|kind| kind == "bin" || kind == "example");
+        if let (true, Some(executable)) = (is_program, executable) {
+            executables.push((target.name, executable));
+        }
+    }
+    Ok(executables)
+}
+
+fn select_executable(mut executables: Vec<(String, PathBuf)>) -> anyhow::Result<PathBuf> {
+    match executables.len() {
+        0 => Err(anyhow!("`cargo build` produced no binary to run")),
+        1 => Ok(executables.remove(0).1),
+        _ => {
+            let names = executables
+                .iter()
+                .map(|(name, _)| format!("`{name}`"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!("`cargo build` produced several binaries ({names}); pick one with `--bin` or `--example`")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cargo_invocation() {
+        let Cargo::ProbeRun(args) = Cargo::try_parse_from([
+            "cargo-probe-run",
+            "probe-run",
+            "--example",
+            "blinky",
+            "--release",
+            "--",
+            "--chip",
+            "nRF52840_xxAA",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            args,
+            CargoArgs {
+                example: Some("blinky".into()),
+                release: true,
+                probe_run_args: vec!["--chip".into(), "nRF52840_xxAA".into()],
+                ..CargoArgs::default()
+            }
+        );
+        assert_eq!(
+            args.build_args(),
+            [
+                "build",
+                "--message-format=json-render-diagnostics",
+                "--example",
+                "blinky",
+                "--release"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_executables_in_messages() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"name":"cortex-m","kind":["lib"]},"executable":null}
+{"reason":"compiler-artifact","target":{"name":"build-script-build","kind":["custom-build"]},"executable":null}
+warning: printed by a build script
+{"reason":"compiler-artifact","target":{"name":"blinky","kind":["example"]},"executable":"/app/target/thumbv7em-none-eabihf/release/examples/blinky"}
+{"reason":"build-finished","success":true}
+"#;
+
+        let executables = executables(messages.as_bytes()).unwrap();
+
+        assert_eq!(
+            executables,
+            [(
+                "blinky".to_string(),
+                PathBuf::from("/app/target/thumbv7em-none-eabihf/release/examples/blinky")
+            )]
+        );
+    }
+
+    #[test]
+    fn rejects_several_executables() {
+        let executables = vec![
+            ("a".to_string(), PathBuf::from("a")),
+            ("b".to_string(), PathBuf::from("b")),
+        ];
+
+        let error = select_executable(executables).unwrap_err();
+
+        assert!(error.to_string().contains("`a`, `b`"));
+    }
+}
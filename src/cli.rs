@@ -1,63 +1,299 @@
-use std::path::PathBuf;
+use std::{
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-use clap::{ArgAction, Parser};
+use anyhow::{anyhow, bail, Context as _};
+use clap::{ArgAction, Args, CommandFactory as _, Parser, Subcommand, ValueEnum};
 use defmt_decoder::DEFMT_VERSIONS;
 use git_version::git_version;
 use probe_rs::Probe;
+use regex::Regex;
 
-use crate::probe;
+use crate::{
+    backtrace, canary, debug_session, doctor,
+    elf::{self, Elf},
+    elf_source, erase, gdb_remote, history, inject, inspect, list_chips, metrics, monitor,
+    output_route, path_map, poke, probe, project_config, ram_init, routine, run, schema,
+    suggest_chip, test_manifest, trace, warnings,
+};
 
 /// Successfull termination of process.
-const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_SUCCESS: i32 = 0;
+/// Unsuccessful termination of process.
+pub const EXIT_FAILURE: i32 = 1;
 
 /// A Cargo runner for microcontrollers.
-#[derive(Parser)]
-#[command()]
+#[derive(Clone, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Opts {
+    /// Also show this many defmt log lines after each line `--grep` matches.
+    #[arg(
+        short = 'A',
+        long,
+        value_name = "NUM",
+        default_value = "0",
+        global = true
+    )]
+    pub after_context: usize,
+
+    /// Access the core through this access port instead of the one of the chip description, e.g.
+    /// for chips whose cores sit behind several APs (ARM only).
+    #[arg(long, value_name = "INDEX", global = true)]
+    pub ap: Option<u8>,
+
+    /// Also show this many defmt log lines before each line `--grep` matches.
+    #[arg(
+        short = 'B',
+        long,
+        value_name = "NUM",
+        default_value = "0",
+        global = true
+    )]
+    pub before_context: usize,
+
+    /// Wait `<ms>` milliseconds after resetting the target, before accessing its RAM, e.g. for
+    /// boards whose external RAM or crystal needs time to start up.
+    #[arg(long, value_name = "MS", default_value = "0", global = true)]
+    pub before_run_delay: u64,
+
+    /// Ring the terminal bell when flashing finished, the program faulted or the session ended.
+    #[arg(long, global = true)]
+    pub bell: bool,
+
     /// Disable or enable backtrace (auto in case of panic or stack overflow).
     #[arg(long, default_value = "auto")]
     pub backtrace: String,
 
     /// Configure the number of lines to print before a backtrace gets cut off.
-    #[arg(long, default_value = "50")]
+    #[arg(long, default_value = "50", global = true)]
     pub backtrace_limit: u32,
 
+    /// End the backtrace at the first frame of the function `<symbol>` (e.g. `main`) or of the
+    /// crate `<crate>` (e.g. an RTOS scheduler), hiding the runtime frames below (repeatable).
+    #[arg(long, value_name = "SYMBOL|CRATE", global = true)]
+    pub backtrace_stop_at: Vec<String>,
+
+    /// Set a breakpoint on the panic handler to catch panics regardless of its implementation.
+    #[arg(long)]
+    pub catch_panics: bool,
+
     /// The chip to program.
-    #[arg(long, required = true, conflicts_with_all = HELPER_CMDS, env = "PROBE_RUN_CHIP")]
+    ///
+    /// Can also be embedded in the ELF (see `.probe-run` section in the README), or else is taken
+    /// from the runner in `.cargo/config.toml` or from `Embed.toml`. `auto` picks the chip whose
+    /// memory map fits the program, if there is exactly one (see `suggest-chip`).
+    #[arg(long, env = "PROBE_RUN_CHIP", global = true)]
     chip: Option<String>,
 
     /// Path to chip description file, in YAML format.
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub chip_description_path: Option<PathBuf>,
 
-    /// Connect to device when NRST is pressed.
+    /// Remove the write protection of STM32 flash sectors which the program is flashed to, by
+    /// reprogramming the option bytes.
     #[arg(long)]
+    pub clear_wrp: bool,
+
+    /// Print how flash size, stack usage and run time changed since the last run of the same ELF
+    /// (see `.probe-run/history.json`).
+    #[arg(long)]
+    pub compare_last: bool,
+
+    /// Connect to device when NRST is pressed.
+    #[arg(long, global = true)]
     pub connect_under_reset: bool,
 
+    /// Debug this core of multi-core chips, by its index in the chip description, e.g. `1` for
+    /// the Cortex-M4 of an STM32H745.
+    #[arg(long, value_name = "INDEX", default_value = "0", global = true)]
+    pub core: usize,
+
+    /// Record every operation on the probe, with addresses, sizes and timings, into a
+    /// gzip-compressed log at this path, to attach to bug reports.
+    #[arg(long, value_name = "PATH", global = true)]
+    pub debug_session: Option<PathBuf>,
+
+    /// Decode defmt frames on a separate thread, so that RTT polling is not slowed down by decoding.
+    #[arg(long, global = true)]
+    pub decode_thread: bool,
+
+    /// Decode the defmt stream with this encoding instead of the one the ELF's defmt table names,
+    /// e.g. when the program on the target was built with other `defmt` features.
+    #[arg(long, value_enum, value_name = "ENCODING", global = true)]
+    pub defmt_encoding: Option<DefmtEncoding>,
+
+    /// Turn a warning into an error, given by its code (e.g. `W003`) or `all` (repeatable).
+    #[arg(long, value_name = "CODE", global = true)]
+    pub deny: Vec<warnings::Deny>,
+
     /// Disable use of double buffering while downloading flash.
     #[arg(long)]
     pub disable_double_buffering: bool,
 
-    /// Path to an ELF firmware file.
+    /// Print what flashing would erase and program, then exit without touching the chip.
+    #[arg(long, conflicts_with = "no_flash")]
+    pub dry_run: bool,
+
+    /// Path to an ELF firmware file; `-` reads it from stdin, and an `http(s)://` URL downloads it.
     #[arg(required = true, conflicts_with_all = HELPER_CMDS)]
     elf: Option<PathBuf>,
 
-    /// Mass-erase all nonvolatile memory before downloading flash.
+    /// End the run successfully once the function `<symbol>` returns, e.g. `main` of a program which
+    /// loops forever afterwards; the stack usage is measured then.
+    #[arg(long, value_name = "SYMBOL")]
+    pub end_symbol: Option<String>,
+
+    /// The function to run up to before the program starts, e.g. to set the RTT channel's mode
+    /// (default: `main`); for programs whose entry point has another name.
+    #[arg(long, value_name = "SYMBOL", global = true)]
+    pub entry_symbol: Option<String>,
+
+    /// Mass-erase all nonvolatile memory before downloading flash, after confirmation; skipped if
+    /// flashing erases all of the flash anyway.
     #[arg(long)]
     pub erase_all: bool,
 
+    /// Also erase a flash range while flashing, given as `<start>..<end>` or by the name of an
+    /// ELF section or a flash region of the chip, e.g. `settings` (repeatable).
+    #[arg(long, value_name = "RANGE", conflicts_with_all = ["erase_all", "no_flash"])]
+    pub erase_sectors: Vec<erase::FlashSpec>,
+
+    /// Run a position-independent Thumb routine, loaded into RAM at `<address>`, after the reset
+    /// and before the program starts, e.g. `unlock.bin@0x20000000:r0=0x1234`; it ends with `bkpt`
+    /// and its registers are printed (repeatable).
+    #[arg(long, value_name = "BIN@ADDRESS[:REG=VALUE,..]")]
+    pub exec_routine: Vec<routine::Routine>,
+
+    /// Exit with `<code>` when the program ends with `<outcome>`: `abort`, `breakpoint`, `ctrlc`,
+    /// `end-symbol`, `hang`, `hardfault`, `ok`, `overflow`, `panic`, `run-until` or `timeout`,
+    /// e.g. `overflow=3,panic=4,ctrlc=130`.
+    #[arg(
+        long,
+        value_name = "OUTCOME=CODE",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub exit_code_map: Vec<backtrace::ExitCodeMapping>,
+
+    /// Sample which exception or interrupt the program handles every `--sample-interval` and
+    /// print how often each one showed up when the program stops.
+    #[arg(long, global = true)]
+    pub exception_stats: bool,
+
+    /// Explain the warning with the given code (e.g. `W003`) and exit.
+    #[arg(long, value_name = "CODE", conflicts_with = "chip")]
+    explain: Option<warnings::Warning>,
+
+    /// Exit with an error if `flash`, `stack` or `duration` grew by more than the limit since the
+    /// last run, e.g. `stack=+10%` or `flash=+1024` (repeatable; implies `--compare-last`).
+    #[arg(long, value_name = "METRIC=+LIMIT")]
+    pub fail_on_regression: Vec<history::Limit>,
+
+    /// Only list the chip families whose name starts with `<NAME>` (with `--list-chips`).
+    #[arg(long, value_name = "NAME", requires = "list_chips")]
+    family: Option<String>,
+
+    /// Flash memory the chip description doesn't know, e.g. external QSPI flash, with the flash
+    /// algorithm in `<FILE>`, in the YAML format of a chip description's `flash_algorithms`
+    /// (repeatable).
+    #[arg(long, value_name = "FILE", global = true)]
+    pub flash_loader: Vec<PathBuf>,
+
+    /// Flash the program through another probe than `--probe`, e.g. on a rig whose probe for
+    /// running and logging can't flash the chip; given like `--probe`. The program is still run,
+    /// monitored and inspected through `--probe`.
+    #[arg(long, value_name = "PROBE", conflicts_with_all = ["no_flash", "dry_run"])]
+    pub flash_probe: Option<String>,
+
+    /// Flash the program even if it doesn't fit the chip's memory or core.
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// Always colorize the output, even if it isn't a terminal.
+    #[arg(long, global = true)]
+    pub force_color: bool,
+
+    /// Only show the defmt log lines which match this regex (repeatable), as printed by the
+    /// default log format: timestamp, level, message and location. Host logs are always shown.
+    #[arg(long, value_name = "REGEX", global = true)]
+    pub grep: Vec<Regex>,
+
+    /// Halt the program when it neither sent RTT data nor halted for `<SECS>` seconds, and print
+    /// where it hangs (see `--on-hang`).
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    pub halt_on_silence: Option<u64>,
+
+    /// Print a status line to stderr when the target was silent for `<SECS>` seconds (with
+    /// `--json-format lines`, a JSON event to stdout instead).
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    pub heartbeat: Option<u64>,
+
+    /// Bytes per row of `--rtt-decoder hexdump`.
+    #[arg(long, default_value = "16", value_parser = clap::value_parser!(u16).range(1..), global = true)]
+    pub hexdump_width: u16,
+
+    /// Open the source locations linked by `--hyperlinks` with this URL instead of a `file://`
+    /// URL, e.g. `vscode://file{path}:{line}:{column}`.
+    #[arg(
+        long,
+        env = "PROBE_RUN_HYPERLINK_URL",
+        value_name = "TEMPLATE",
+        global = true
+    )]
+    pub hyperlink_url: Option<String>,
+
+    /// Make the paths in backtraces and defmt log locations clickable in terminals which support
+    /// OSC 8 hyperlinks.
+    #[arg(long, value_enum, default_value = "auto", global = true)]
+    pub hyperlinks: Hyperlinks,
+
+    /// Whenever the program calls the function `<symbol>`, make it fault (`fault`), overwrite a
+    /// register (e.g. `r0=0`) or overwrite memory (`<symbol-or-addr>=<value>`), then let it
+    /// continue (repeatable).
+    #[arg(long, value_name = "ACTION@SYMBOL")]
+    pub inject: Vec<inject::Injection>,
+
+    /// Record the last branches in the Micro Trace Buffer and list them after a fault. The buffer is
+    /// a symbol, e.g. a `static` array, or `<start>..<end>` in the MTB's SRAM; its size must be a
+    /// power of two, and it must be aligned to its size.
+    #[arg(long, value_name = "BUFFER")]
+    pub itrace: Option<trace::BufferSpec>,
+
     /// Output logs a structured json.
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub json: bool,
 
+    /// The shape of the `--json` output.
+    #[arg(
+        long,
+        value_enum,
+        default_value = "schema",
+        requires = "json",
+        global = true
+    )]
+    pub json_format: JsonFormat,
+
     /// List supported chips and exit.
-    #[arg(long)]
+    #[arg(long, conflicts_with = "chip")]
     list_chips: bool,
 
     /// Lists all the connected probes and exit.
-    #[arg(long)]
+    #[arg(long, conflicts_with = "chip")]
     list_probes: bool,
 
+    /// Lock the probe, so that other `probe-run`s sharing the machine's probes can't open it, and
+    /// wait up to `<SECS>` seconds for a busy probe. If several probes match `--probe`, use the
+    /// first free one.
+    #[arg(long, value_name = "SECS", global = true)]
+    pub lock_timeout: Option<u64>,
+
+    /// Render defmt messages as described by a TOML annotation file, e.g. with units, in hex or
+    /// with the names of enum values.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub log_annotations: Option<PathBuf>,
+
     /// Applies the given format to the log output.
     ///
     /// The arguments between curly braces are placeholders for log metadata.
@@ -73,17 +309,41 @@ pub struct Opts {
     /// For example, with the format "{t} [{L}] Location<{f}:{l}> {s}"
     /// a log would look like this:
     /// "23124 [INFO ] Location<main.rs:23> Hello, world!"
-    #[arg(long, verbatim_doc_comment)]
+    #[arg(long, verbatim_doc_comment, global = true)]
     pub log_format: Option<String>,
 
     /// Applies the given format to the host log output. (see --log-format)
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub host_log_format: Option<String>,
 
+    /// Where to write probe-run's own logs, separators and backtraces: `stderr` or a file.
+    #[arg(long, value_name = "stderr|PATH", default_value = "stderr", value_parser = output_route::parse_host, conflicts_with = "pty", global = true)]
+    pub host_output: output_route::Destination,
+
+    /// The longest wait between two polls of the probe while the program is quiet, in
+    /// milliseconds; polling speeds up again as soon as logs arrive. `0` polls continuously.
+    #[arg(long, value_name = "MS", default_value = "20", global = true)]
+    pub max_poll_interval: u64,
+
     /// Whether to measure the program's stack consumption.
     #[arg(long)]
     pub measure_stack: bool,
 
+    /// Serve metrics of the session, like the bytes received and the outcomes of runs, in the
+    /// Prometheus text format at `http://<ADDR>/metrics`, e.g. `127.0.0.1:9090`.
+    #[arg(long, value_name = "ADDR", global = true)]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Show a desktop notification when flashing finished, the program faulted or the session ended.
+    #[cfg(feature = "notify")]
+    #[arg(long, global = true)]
+    pub notify: bool,
+
+    /// Don't apply the chip quirks probe-run ships with, e.g. freezing the watchdogs of STM32s
+    /// while the core is halted (see `--quirks`).
+    #[arg(long, global = true)]
+    pub no_builtin_quirks: bool,
+
     /// Skip writing the application binary to flash.
     #[arg(
         long,
@@ -92,73 +352,510 @@ pub struct Opts {
     )]
     pub no_flash: bool,
 
-    /// The probe to use (eg. `VID:PID`, `VID:PID:Serial`, or just `Serial`).
-    #[arg(long, env = "PROBE_RUN_PROBE")]
+    /// Don't reset the target when the program ends; after Ctrl-C, the program keeps running.
+    #[arg(long)]
+    pub no_reset: bool,
+
+    /// Don't read and print why the device last reset, from the chip's reset reason register.
+    #[arg(long, global = true)]
+    pub no_reset_reason: bool,
+
+    /// What to do once `--halt-on-silence` found the program hung: print its backtrace and resume
+    /// it, or end the run.
+    #[arg(
+        long,
+        value_enum,
+        default_value = "exit",
+        requires = "halt_on_silence",
+        global = true
+    )]
+    pub on_hang: OnHang,
+
+    /// What to do with RTT data when the output queue is full: wait for the output to catch up,
+    /// which can stall the program if its channel blocks when full, or drop the data.
+    #[arg(long, value_enum, default_value = "block", global = true)]
+    pub output_overflow: OutputOverflow,
+
+    /// Print the RTT data on a separate thread, with a queue of up to this many reads in between,
+    /// so that a slow consumer of the output doesn't hold up reading the RTT channel.
+    #[arg(long, value_name = "READS", value_parser = clap::value_parser!(u32).range(1..), global = true)]
+    pub output_queue: Option<u32>,
+
+    /// Substitute the path prefix `<from>` with `<to>` in locations, eg. for firmware built in a container (repeatable).
+    #[arg(long, value_name = "FROM=TO", global = true)]
+    pub path_map: Vec<path_map::PathMap>,
+
+    /// Read and print `<symbol-or-addr>` before `main` starts (repeatable).
+    #[arg(long, value_name = "SYMBOL-OR-ADDR")]
+    pub peek: Vec<poke::Location>,
+
+    /// Write `<value>` to `<symbol-or-addr>` before `main` starts (repeatable).
+    #[arg(long, value_name = "SYMBOL-OR-ADDR=VALUE")]
+    pub poke: Vec<poke::Poke>,
+
+    /// What to do after the program faulted, before `probe-run` exits.
+    #[arg(long, value_enum)]
+    pub post_mortem: Option<PostMortem>,
+
+    /// The probe to use (eg. `VID:PID`, `VID:PID:Serial`, `jlink`, `CMSIS-DAP:*`, or just `Serial`).
+    ///
+    /// Serial numbers and probe identifiers may contain globs; `cmsis-dap`, `daplink`, `jlink`,
+    /// `rpi-debugprobe` and `stlink` are accepted as shorthands for common probes.
+    #[arg(long, env = "PROBE_RUN_PROBE", global = true)]
     pub probe: Option<String>,
 
-    /// Whether to shorten paths (e.g. to crates.io dependencies) in backtraces and defmt logs
+    /// The probe to use, as index into the list of probes (see `--list-probes`) matching `--probe`.
+    #[arg(long, global = true)]
+    pub probe_index: Option<usize>,
+
+    /// Behave as if attached to a terminal: force colors and print all output to stdout, so that
+    /// host and target output keep their order when piped into another tool.
+    #[arg(long, global = true)]
+    pub pty: bool,
+
+    /// Apply the chip quirks in this TOML file, besides the built-in ones: register writes after
+    /// probe-run reset the chip (`post_reset`) and before the program starts (`pre_run`).
+    #[arg(long, value_name = "FILE", global = true)]
+    pub quirks: Option<PathBuf>,
+
+    /// Paint the RAM the program doesn't occupy before the run, and write a JSON map of which
+    /// parts of it the program wrote to this file after the run.
+    #[arg(long, value_name = "FILE")]
+    pub ram_map: Option<PathBuf>,
+
+    /// Also store the raw, undecoded bytes of RTT up channel 0 in rotating, time-stamped files in
+    /// `<dir>`, to decode them again later (e.g. with `defmt-print`).
+    #[arg(long, value_name = "DIR", global = true)]
+    pub raw_capture: Option<PathBuf>,
+
+    /// Unlock a chip protected against debug access (e.g. nRF APPROTECT) by mass-erasing it.
     #[arg(long)]
+    pub recover: bool,
+
+    /// Exit with an error if the ELF contains no RTT control block.
+    #[arg(long, conflicts_with = "rtt_scan_ram", global = true)]
+    pub require_rtt: bool,
+
+    /// How long to wait for the program to set up its RTT control block, retrying with a growing
+    /// delay, e.g. for programs which set up RTT after a long clock or radio init.
+    #[arg(long, value_name = "SECS", default_value = "5", global = true)]
+    pub rtt_attach_timeout: u64,
+
+    /// How to set the mode of the RTT up channel before the program starts.
+    #[arg(long, value_enum, default_value = "force", global = true)]
+    pub rtt_blocking: RttBlocking,
+
+    /// How to print the data of RTT up channel 0.
+    #[arg(long, value_enum, default_value = "auto", global = true)]
+    pub rtt_decoder: RttDecoder,
+
+    /// Scan all of the RAM for the RTT control block if it isn't found at the `_SEGGER_RTT`
+    /// symbol, instead of only the 64 KiB around it.
+    #[arg(long, global = true)]
+    pub rtt_scan_ram: bool,
+
+    /// End the run successfully once the program calls the function `<symbol>`, e.g. to measure a boot path.
+    #[arg(long, value_name = "SYMBOL")]
+    pub run_until: Option<String>,
+
+    /// How often to halt the program for a sample of `--exception-stats`, in milliseconds.
+    #[arg(long, value_name = "MS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    pub sample_interval: u64,
+
+    /// Only list the chips whose name matches the glob `<PATTERN>`, e.g. `'stm32f4*'`, or contains
+    /// it if it has no wildcards (with `--list-chips`).
+    #[arg(long, value_name = "PATTERN", requires = "list_chips")]
+    search: Option<String>,
+
+    /// Wait `<ms>` milliseconds after starting the program, before attaching to RTT.
+    #[arg(long, value_name = "MS", default_value = "0", global = true)]
+    pub settle_delay: u64,
+
+    /// Whether to shorten paths (e.g. to crates.io dependencies) in backtraces and defmt logs
+    #[arg(long, global = true)]
     pub shorten_paths: bool,
 
     /// The probe clock frequency in kHz
-    #[arg(long, env = "PROBE_RUN_SPEED")]
+    #[arg(long, env = "PROBE_RUN_SPEED", global = true)]
     pub speed: Option<u32>,
 
+    /// When the stack usage counts as a potential overflow: more than `<PCT>%` of the stack used,
+    /// or fewer than `<BYTES>` left.
+    #[arg(long, value_name = "PCT%|BYTES", default_value = "90%")]
+    pub stack_overflow_threshold: canary::OverflowThreshold,
+
+    /// Fail the run if the defmt timestamps go backwards or stay the same for many logs, instead of
+    /// warning about it (W016).
+    #[arg(long, global = true)]
+    pub strict_timestamps: bool,
+
+    /// Let the program exit with `svc #0xEE` (exit status in `r0`) or abort with `svc #0xEF`.
+    #[arg(long)]
+    pub svc_exit: bool,
+
+    /// Set the lowest level the program logs, for programs which filter their logs at runtime (see
+    /// the README).
+    #[arg(long, value_name = "LEVEL", global = true)]
+    pub target_log_level: Option<TargetLogLevel>,
+
+    /// Where to write the program's output, i.e. its logs and RTT data: `stdout` or a file.
+    #[arg(long, value_name = "stdout|PATH", default_value = "stdout", value_parser = output_route::parse_target, conflicts_with = "pty", global = true)]
+    pub target_output: output_route::Destination,
+
+    /// The color theme: `default`, `no-dim`, `colorblind` or the path to a theme file.
+    #[arg(
+        long,
+        env = "PROBE_RUN_THEME",
+        default_value = "default",
+        global = true
+    )]
+    pub theme: String,
+
+    /// End the run after this many seconds, like Ctrl-C does, if the program hasn't halted by
+    /// then; the outcome is `timeout`.
+    #[arg(long, value_name = "SECS", global = true)]
+    pub timeout: Option<u64>,
+
+    /// Answer confirmation prompts, like the ones of `--erase-all`, `--recover` and `--clear-wrp`,
+    /// with yes.
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
+    /// Read the logs from the serial port `<PORT>`, e.g. the virtual COM port of the probe, instead
+    /// of from RTT; the program is still flashed and inspected through the probe.
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["require_rtt", "rtt_scan_ram"], global = true)]
+    pub vcp: Option<String>,
+
+    /// The baud rate of `--vcp`.
+    #[arg(
+        long,
+        value_name = "BAUD",
+        default_value = "115200",
+        requires = "vcp",
+        global = true
+    )]
+    pub vcp_baud: u32,
+
     /// Enable more verbose output.
-    #[arg(short, long, action = ArgAction::Count)]
+    #[arg(short, long, action = ArgAction::Count, global = true)]
     pub verbose: u8,
 
     /// Verifies the written program.
     #[arg(long)]
     pub verify: bool,
 
+    /// Check that all RAM is readable before running the program, e.g. that ECC RAM is initialized.
+    #[arg(long)]
+    pub verify_ram_init: bool,
+
+    /// After flashing, compare the vector table on the target, and the stack pointer and reset
+    /// handler the core booted with, against the ELF's (W017).
+    #[arg(long)]
+    pub verify_vector_table: bool,
+
+    /// Access the target through a GDB server which owns the probe, e.g. `gdb:localhost:3333` for
+    /// `probe-rs gdb` or OpenOCD, instead of through the probe; only `monitor` and `backtrace`
+    /// support it.
+    #[arg(long, value_name = "gdb:HOST:PORT", global = true)]
+    pub via: Option<gdb_remote::Via>,
+
+    /// Keep checking VTOR while the program runs, and move the HardFault breakpoint when the
+    /// program relocates its vector table.
+    #[arg(long)]
+    pub vtor_follow: bool,
+
     /// Prints version information
-    #[arg(short = 'V', long)]
+    #[arg(short = 'V', long, conflicts_with = "chip")]
     version: bool,
 
-    /// Arguments passed after the ELF file path are discarded
+    /// Zero-fill a RAM region, given by name or as `<start>..<end>`, before running the program
+    /// (repeatable).
+    #[arg(long, value_name = "REGION")]
+    pub zero_ram: Vec<ram_init::RamSpec>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Arguments passed after the ELF file path are written into the program's `__probe_run_args`
+    /// buffer, if it has one, and discarded otherwise
     #[arg(allow_hyphen_values = true, hide = true, trailing_var_arg = true)]
-    _rest: Vec<String>,
+    pub target_args: Vec<String>,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Print the backtrace and fault registers of the program on the device, e.g. one which
+    /// crashed long ago, without flashing or resetting it; the device is left as it was.
+    Backtrace {
+        /// Path to the ELF file of the program on the device.
+        elf: PathBuf,
+    },
+    /// Check offline that all functions in the ELF have unwind info and exit.
+    CheckUnwind {
+        /// Path to an ELF firmware file.
+        elf: PathBuf,
+    },
+    /// Check the probe, the chip (with `--chip`) and the ELF, and exit.
+    Doctor {
+        /// Path to an ELF firmware file.
+        elf: Option<PathBuf>,
+    },
+    /// Write the defmt format strings of the ELF, with their index, level and argument types, as
+    /// JSON and exit.
+    ExportSchema {
+        /// Path to an ELF firmware file.
+        elf: PathBuf,
+
+        /// Write the schema to this file instead of stdout.
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Stream the logs of the program running on the device, without flashing or resetting it.
+    Monitor(MonitorArgs),
+    /// List the chips whose memory map fits the program in the ELF and exit.
+    SuggestChip {
+        /// Path to an ELF firmware file.
+        elf: PathBuf,
+    },
+    /// Run the programs listed in a test manifest one after another, and compare their outcomes
+    /// with the expected ones.
+    Test {
+        /// Path to the test manifest, in TOML or JSON.
+        manifest: PathBuf,
+    },
+}
+
+#[derive(Args, Clone)]
+pub struct MonitorArgs {
+    /// Print a backtrace when the device halts.
+    #[arg(long)]
+    pub backtrace: bool,
+
+    /// Path to the ELF file of the program running on the device.
+    pub elf: PathBuf,
+
+    /// Reset the device and run the program from the start, instead of attaching to it as is.
+    #[arg(long)]
+    pub reset: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum JsonFormat {
+    /// defmt's versioned JSON schema, preceded by the schema version
+    Schema,
+    /// One flat object per defmt frame (NDJSON), with the message already formatted
+    Lines,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RttBlocking {
+    /// Block the program while the channel is full, so that no logs get lost
+    Force,
+    /// Keep the mode the program set
+    Keep,
+    /// Never block the program; logs which don't fit into the channel get lost
+    Never,
+}
+
+/// The encodings of `--defmt-encoding`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DefmtEncoding {
+    /// Frames as they are, back to back
+    Raw,
+    /// Frames compressed with rzCOBS and separated by zeros
+    Rzcobs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RttDecoder {
+    /// defmt if the channel is named "defmt", the raw bytes otherwise, with SEGGER's virtual
+    /// terminals prefixed
+    Auto,
+    /// The raw bytes, e.g. for text
+    Raw,
+    /// Offset, hex bytes and ASCII, like `hexdump -C`, for binary data
+    Hexdump,
+}
+
+/// The values `--target-log-level` writes into the program's `__DEFMT_LOG_LEVEL_RUNTIME`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TargetLogLevel {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+    /// No logs at all
+    Off = 5,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Hyperlinks {
+    /// If stdout and stderr are colorized terminals, and the output isn't JSON
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnHang {
+    /// Print where the program hangs and let it run on
+    Resume,
+    /// Halt the program and end the run like `--timeout` does
+    Exit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputOverflow {
+    /// Stop reading the RTT channel until the output queue has room again
+    Block,
+    /// Drop the data which doesn't fit into the output queue, and mark the gap in the output
+    Drop,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PostMortem {
+    /// Inspect the halted target in an interactive prompt
+    Repl,
 }
 
 /// Helper commands, which will not execute probe-run normally.
-const HELPER_CMDS: [&str; 3] = ["list_chips", "list_probes", "version"];
+const HELPER_CMDS: [&str; 4] = ["explain", "list_chips", "list_probes", "version"];
+
+#[deprecated = "⚠️  As of 11.10.2023 `probe-run` is in maintainance mode. We \
+recommend everyone to switch to `probe-rs`. Read following article on the why \
+and on how to migrate: https://ferrous-systems.com/blog/probe-run-deprecation/"]
+pub fn deprecated() {
+    eprintln!(
+        "⚠️  As of 11.10.2023 `probe-run` is in maintainance mode. We recommend \
+        everyone to switch to `probe-rs`. Read following article on the why and \
+        on how to migrate: https://ferrous-systems.com/blog/probe-run-deprecation/\n"
+    );
+}
 
 pub fn handle_arguments() -> anyhow::Result<i32> {
-    let opts = Opts::parse();
+    run(Opts::parse())
+}
+
+/// Run probe-run with the already parsed `opts`.
+pub fn run(opts: Opts) -> anyhow::Result<i32> {
+    if let Some(path) = &opts.debug_session {
+        debug_session::start(path)?;
+    }
+    let outcome = run_command(opts);
+    debug_session::finish(&outcome)?;
+    outcome
+}
+
+fn run_command(mut opts: Opts) -> anyhow::Result<i32> {
+    run::configure_terminal_colorization(&opts)?;
+    warnings::set_denied(opts.deny.clone());
+    if let Some(addr) = opts.metrics_listen {
+        metrics::serve(addr)?;
+    }
 
     if opts.measure_stack {
         log::warn!("use of deprecated option `--measure-stack`: Has no effect and will vanish on next breaking release")
     }
 
-    if opts.version {
-        print_version();
+    if opts.via.is_some()
+        && !matches!(
+            opts.command,
+            Some(Command::Backtrace { .. } | Command::Monitor(_))
+        )
+    {
+        bail!("`--via` only works with the `monitor` and `backtrace` subcommands");
+    }
+
+    if let Some(Command::Backtrace { elf }) = &opts.command {
+        let elf = elf.clone();
+        apply_embedded_options(&mut opts, &elf)?;
+        let chip = required_chip(&opts, &elf)?;
+        inspect::run(&elf, &chip, &opts)
+    } else if let Some(Command::CheckUnwind { elf }) = &opts.command {
+        check_unwind(elf)
+    } else if let Some(Command::Doctor { elf }) = &opts.command {
+        let elf = elf.clone();
+        if let Some(elf) = &elf {
+            apply_embedded_options(&mut opts, elf)?;
+        }
+        let chip = match (&elf, opts.chip.as_deref()) {
+            (Some(elf), Some(_)) => Some(required_chip(&opts, elf)?),
+            (_, chip) => chip.map(str::to_string),
+        };
+        match doctor::run(elf.as_deref(), chip.as_deref(), &opts)? {
+            true => Ok(EXIT_SUCCESS),
+            false => Ok(EXIT_FAILURE),
+        }
+    } else if let Some(Command::ExportSchema { elf, out }) = &opts.command {
+        schema::export(elf, out.as_deref())?;
+        Ok(EXIT_SUCCESS)
+    } else if let Some(Command::Monitor(args)) = &opts.command {
+        let args = args.clone();
+        apply_embedded_options(&mut opts, &args.elf)?;
+        // the monitor never flashes; this also keeps `--no-flash` related warnings accurate
+        opts.no_flash = true;
+        let chip = required_chip(&opts, &args.elf)?;
+        monitor::run(&args.elf, &chip, &opts, &args)
+    } else if let Some(Command::SuggestChip { elf }) = &opts.command {
+        match suggest_chip::print(elf)? {
+            true => Ok(EXIT_SUCCESS),
+            false => Ok(EXIT_FAILURE),
+        }
+    } else if let Some(Command::Test { manifest }) = &opts.command {
+        let manifest = test_manifest::load(manifest)?;
+        apply_embedded_options(&mut opts, &manifest.tests[0].elf)?;
+        let chip = required_chip(&opts, &manifest.tests[0].elf)?;
+        test_manifest::run(&manifest, &chip, &opts)
+    } else if let Some(warning) = opts.explain {
+        warnings::explain(warning);
+        Ok(EXIT_SUCCESS)
+    } else if opts.version {
+        print_version(opts.json)?;
         Ok(EXIT_SUCCESS)
     } else if opts.list_probes {
         probe::print(&Probe::list_all());
         Ok(EXIT_SUCCESS)
     } else if opts.list_chips {
-        print_chips();
-        Ok(EXIT_SUCCESS)
-    } else if let (Some(elf), Some(chip)) = (opts.elf.as_deref(), opts.chip.as_deref()) {
-        crate::run_target_program(elf, chip, &opts)
+        let filter = list_chips::Filter::new(opts.family.as_deref(), opts.search.as_deref())?;
+        match list_chips::print(&filter, opts.json)? {
+            true => Ok(EXIT_SUCCESS),
+            false => Ok(EXIT_FAILURE),
+        }
+    } else if let Some(elf) = opts.elf.clone() {
+        // kept until the run ends, which removes the temporary file
+        let fetched = elf_source::fetch(&elf)?;
+        let elf = fetched
+            .as_ref()
+            .map_or(elf, |fetched| fetched.path().to_path_buf());
+        apply_embedded_options(&mut opts, &elf)?;
+        let chip = required_chip(&opts, &elf)?;
+        run::run_target_program(&elf, &chip, &opts)
     } else {
         unreachable!("due to `StructOpt` constraints")
     }
 }
 
-fn print_chips() {
-    let registry = probe_rs::config::families().expect("Could not retrieve chip family registry");
-    for chip_family in registry {
-        println!("{}\n    Variants:", chip_family.name);
-        for variant in chip_family.variants.iter() {
-            println!("        {}", variant.name);
-        }
+fn required_chip(opts: &Opts, elf_path: &Path) -> anyhow::Result<String> {
+    match opts.chip.as_deref() {
+        Some(chip) if chip.eq_ignore_ascii_case("auto") => suggest_chip::auto(elf_path),
+        Some(chip) => Ok(chip.to_string()),
+        None => match project_config::chip(&env::current_dir()?) {
+            Some((chip, path)) => {
+                log::info!("using chip `{chip}` from `{}`", path.display());
+                Ok(chip)
+            }
+            None => Err(anyhow!(
+                "no chip specified; pass `--chip`, set `PROBE_RUN_CHIP`, embed `chip = <name>` \
+                in the ELF's `.probe-run` section, or configure it in `.cargo/config.toml` or \
+                `Embed.toml` (or try `--chip auto`)"
+            )),
+        },
     }
 }
 
-/// The string reported by the `--version` flag
-fn print_version() {
+/// The string reported by the `--version` flag; a JSON document with `--json`
+fn print_version(json: bool) -> anyhow::Result<()> {
     /// Version from `Cargo.toml` e.g. `"0.1.4"`
     const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -174,10 +871,112 @@ fn print_version() {
     // Extract the "abbreviated object name"
     let hash = extract_git_hash(GIT_DESCRIBE);
 
-    println!(
-        "{VERSION} {hash}\nsupported defmt versions: {}",
-        DEFMT_VERSIONS.join(", ")
-    );
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&version_json(VERSION, hash))?
+        );
+    } else {
+        println!(
+            "{VERSION} {hash}\nsupported defmt versions: {}",
+            DEFMT_VERSIONS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Version of the `probe-rs` dependency, from `Cargo.lock` (see `build.rs`)
+const PROBE_RS_VERSION: &str = env!("PROBE_RS_VERSION");
+
+/// Increment when fields of the `--version --json` document change or get removed
+const VERSION_JSON_SCHEMA: u32 = 1;
+
+/// The `--version --json` document, for tools which wrap `probe-run`
+fn version_json(version: &str, git_hash: &str) -> serde_json::Value {
+    let command = Opts::command();
+    let flags = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| arg.get_long())
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "schema": VERSION_JSON_SCHEMA,
+        "version": version,
+        "git_hash": git_hash,
+        "defmt_versions": DEFMT_VERSIONS,
+        "probe_rs_version": PROBE_RS_VERSION,
+        "features": {
+            "ftdi": cfg!(feature = "ftdi"),
+            "notify": cfg!(feature = "notify"),
+        },
+        "flags": flags,
+    })
+}
+
+/// Use the options embedded in the ELF for everything not set on the command line or through
+/// environment variables. Flags can only be switched on.
+fn apply_embedded_options(opts: &mut Opts, elf_path: &Path) -> anyhow::Result<()> {
+    // a missing ELF is reported later, with a helpful message
+    let Ok(elf_bytes) = fs::read(elf_path) else {
+        return Ok(());
+    };
+
+    for (key, value) in elf::extract_embedded_options(&elf_bytes)? {
+        let parse_flag = || -> anyhow::Result<bool> {
+            value
+                .parse()
+                .with_context(|| format!("embedded option `{key}` must be `true` or `false`"))
+        };
+
+        match key.as_str() {
+            "chip" => {
+                opts.chip.get_or_insert(value);
+            }
+            "speed" => {
+                if opts.speed.is_none() {
+                    opts.speed = Some(parse_u32(&value)?);
+                }
+            }
+            "log-format" => {
+                opts.log_format.get_or_insert(value);
+            }
+            "host-log-format" => {
+                opts.host_log_format.get_or_insert(value);
+            }
+            "connect-under-reset" => opts.connect_under_reset |= parse_flag()?,
+            "verify" => opts.verify |= parse_flag()?,
+            "verify-vector-table" => opts.verify_vector_table |= parse_flag()?,
+            "require-rtt" if !opts.rtt_scan_ram => opts.require_rtt |= parse_flag()?,
+            "rtt-scan-ram" if !opts.require_rtt => opts.rtt_scan_ram |= parse_flag()?,
+            // conflicts with the command line, which takes precedence
+            "require-rtt" | "rtt-scan-ram" => {}
+            // NOTE the logger is not initialized yet
+            _ => eprintln!("warning: ignoring unknown option `{key}` embedded in the ELF"),
+        }
+    }
+
+    Ok(())
+}
+
+fn check_unwind(elf_path: &Path) -> anyhow::Result<i32> {
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = Elf::parse_offline(&elf_bytes, elf_path)?;
+
+    let report = backtrace::check::check(&elf)?;
+    backtrace::check::print(&report)?;
+
+    match report.is_ok() {
+        true => Ok(EXIT_SUCCESS),
+        false => Ok(EXIT_FAILURE),
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal number
+pub fn parse_u32(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
 }
 
 /// Extract git hash from a `git describe` statement
@@ -191,6 +990,12 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn arguments_are_consistent() {
+        // also checks the subcommands, which inherit the global arguments
+        Opts::command().debug_assert();
+    }
+
     #[rstest]
     #[case::normal("v0.2.3-12-g25c50d2", "g25c50d2")]
     #[case::modified("v0.2.3-12-g25c50d2-modified", "g25c50d2")]
@@ -199,4 +1004,24 @@ mod tests {
         let hash = extract_git_hash(description);
         assert_eq!(hash, expected)
     }
+
+    #[rstest]
+    #[case::decimal("1024", 1024)]
+    #[case::hex("0x20000000", 0x2000_0000)]
+    #[case::upper_hex("0XFF", 0xFF)]
+    fn should_parse_u32(#[case] input: &str, #[case] expected: u32) {
+        assert_eq!(parse_u32(input), Ok(expected))
+    }
+
+    #[test]
+    fn version_json_lists_flags() {
+        let json = version_json("0.3.11", "g25c50d2");
+
+        assert_eq!(json["version"], "0.3.11");
+        assert_eq!(json["probe_rs_version"], env!("PROBE_RS_VERSION"));
+        let flags = json["flags"].as_array().unwrap();
+        assert!(flags.contains(&"chip".into()));
+        // hidden arguments aren't part of the interface
+        assert!(!flags.contains(&"target-args".into()));
+    }
 }
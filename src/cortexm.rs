@@ -24,10 +24,30 @@ pub fn clear_thumb_bit(addr: u32) -> u32 {
     addr & !THUMB_BIT
 }
 
+/// The name of the exception with the number `number`, as in the IPSR; `0` is thread mode.
+pub fn exception_name(number: u16) -> String {
+    match number {
+        0 => "Thread".to_string(),
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        7 => "SecureFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMonitor".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        16.. => format!("IRQ{}", number - 16),
+        _ => format!("reserved exception {number}"),
+    }
+}
+
 /// Checks if PC is the HardFault handler
 // XXX may want to relax this to cover the whole PC range of the `HardFault` handler
-pub fn is_hard_fault(pc: u32, vector_table: &VectorTable) -> bool {
-    subroutine_eq(pc, vector_table.hard_fault)
+pub fn is_hard_fault(pc: u32, hard_fault_handler: u32) -> bool {
+    subroutine_eq(pc, hard_fault_handler)
 }
 
 pub fn is_thumb_bit_set(addr: u32) -> bool {
@@ -46,8 +66,31 @@ pub fn subroutine_eq(addr1: u32, addr2: u32) -> bool {
 /// The contents of the vector table
 #[derive(Debug)]
 pub struct VectorTable {
+    /// Where the ELF places the vector table
+    pub address: u32,
     // entry 0
     pub initial_stack_pointer: u32,
+    // entry 1: Reset handler
+    pub reset: u32,
     // entry 3: HardFault handler
     pub hard_fault: u32,
+    // entry 11: SVCall handler
+    pub svcall: Option<u32>,
+    /// All entries, indexed by exception number
+    pub entries: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::thread(0, "Thread")]
+    #[case::hard_fault(3, "HardFault")]
+    #[case::interrupt(18, "IRQ2")]
+    fn names_exception(#[case] number: u16, #[case] expected: &str) {
+        assert_eq!(exception_name(number), expected);
+    }
 }
@@ -0,0 +1,336 @@
+//! `--debug-session <path>`: record every interaction with the probe into a gzip-compressed log,
+//! to attach to bug reports
+//!
+//! Each line of the log is a JSON object with the microseconds since the start of the session,
+//! `t`, and its `kind`:
+//! - `start` and `end`: the version and arguments of probe-run, and how the session ended
+//! - `call`: a call into probe-rs, e.g. `set_hw_breakpoint` or `reset_and_halt`, with its
+//!   arguments and duration (`us`)
+//! - `event`: a log message of probe-rs, at any level
+//! - `attach`, `flash`, `read`, `write` and `rtt_read`: operations of probe-run on the target,
+//!   with addresses and sizes
+//!
+//! The recording functions do nothing unless [`start`] was called.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use anyhow::Context as _;
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tracing_core::{
+    field::{Field, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+
+static SESSION: OnceLock<Session> = OnceLock::new();
+
+/// A line of the log
+#[derive(Serialize)]
+struct Entry<'a> {
+    t: u64,
+    kind: &'a str,
+    #[serde(flatten)]
+    fields: Value,
+}
+
+struct Session {
+    started: Instant,
+    path: PathBuf,
+    /// `None` once the session was finished
+    log: Mutex<Option<GzEncoder<BufWriter<File>>>>,
+}
+
+impl Session {
+    fn elapsed_us(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.started).as_micros() as u64
+    }
+
+    fn write(&self, t: u64, kind: &str, fields: Value) {
+        let entry = Entry { t, kind, fields };
+        let mut log = self.log.lock().unwrap();
+        if let Some(writer) = log.as_mut() {
+            let written = serde_json::to_writer(&mut *writer, &entry)
+                .map_err(std::io::Error::from)
+                .and_then(|()| writer.write_all(b"\n"));
+            if let Err(e) = written {
+                // don't fail the run because of the log; stop recording instead
+                eprintln!("failed to write the debug session log: {e}");
+                *log = None;
+            }
+        }
+    }
+}
+
+/// Start recording into a new gzip file at `path`.
+pub fn start(path: &Path) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| {
+        format!(
+            "failed to create the debug session log `{}`",
+            path.display()
+        )
+    })?;
+    let session = Session {
+        started: Instant::now(),
+        path: path.to_path_buf(),
+        log: Mutex::new(Some(GzEncoder::new(
+            BufWriter::new(file),
+            Compression::default(),
+        ))),
+    };
+    if SESSION.set(session).is_err() {
+        anyhow::bail!("the debug session was started already");
+    }
+
+    // probe-rs reports what it does with `tracing`
+    tracing_core::dispatcher::set_global_default(tracing_core::Dispatch::new(Tracer::new()))
+        .context("failed to capture the log of probe-rs")?;
+
+    record("start", || {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "args": std::env::args().collect::<Vec<_>>(),
+        })
+    });
+    Ok(())
+}
+
+/// Record that probe-run did `kind`; `fields` describe it, e.g. the address and size of a read.
+pub fn record(kind: &str, fields: impl FnOnce() -> Value) {
+    if let Some(session) = SESSION.get() {
+        session.write(session.elapsed_us(Instant::now()), kind, fields());
+    }
+}
+
+/// Record how the session ended, and complete the log file.
+pub fn finish(outcome: &anyhow::Result<i32>) -> anyhow::Result<()> {
+    let Some(session) = SESSION.get() else {
+        return Ok(());
+    };
+    record("end", || match outcome {
+        Ok(exit_code) => json!({ "exit_code": exit_code }),
+        Err(e) => json!({ "error": format!("{e:#}") }),
+    });
+
+    let path = &session.path;
+    if let Some(writer) = session.log.lock().unwrap().take() {
+        writer
+            .finish()
+            .and_then(|mut file| file.flush())
+            .with_context(|| {
+                format!("failed to write the debug session log `{}`", path.display())
+            })?;
+    }
+    log::info!(
+        "wrote the debug session to `{}`; attach it to your bug report",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Records the spans and events of probe-rs, and forwards the events to the logger like the
+/// `log` feature of `tracing` does without a subscriber.
+struct Tracer {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, Call>>,
+}
+
+struct Call {
+    metadata: &'static Metadata<'static>,
+    fields: Map<String, Value>,
+    entered: Option<Instant>,
+}
+
+impl Tracer {
+    fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Subscriber for Tracer {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let call = Call {
+            metadata: attributes.metadata(),
+            fields: fields.0,
+            entered: None,
+        };
+        self.spans.lock().unwrap().insert(id, call);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        if let Some(call) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = Fields(std::mem::take(&mut call.fields));
+            values.record(&mut fields);
+            call.fields = fields.0;
+        }
+    }
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = match fields.0.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => String::new(),
+        };
+
+        let level = log_level(metadata.level());
+        let log_metadata = log::Metadata::builder()
+            .level(level)
+            .target(metadata.target())
+            .build();
+        if level <= log::max_level() && log::logger().enabled(&log_metadata) {
+            log::logger().log(
+                &log::Record::builder()
+                    .metadata(log_metadata)
+                    .module_path(metadata.module_path())
+                    .file(metadata.file())
+                    .line(metadata.line())
+                    .args(format_args!("{message}"))
+                    .build(),
+            );
+        }
+
+        record("event", || {
+            json!({
+                "level": metadata.level().as_str(),
+                "target": metadata.target(),
+                "message": message,
+                "fields": fields.0,
+            })
+        });
+    }
+
+    fn enter(&self, span: &span::Id) {
+        if let Some(call) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            call.entered = Some(Instant::now());
+        }
+    }
+
+    fn exit(&self, span: &span::Id) {
+        let Some(session) = SESSION.get() else {
+            return;
+        };
+        // don't hold the lock while writing, which may log
+        let (entered, fields) = {
+            let spans = self.spans.lock().unwrap();
+            let Some(call) = spans.get(&span.into_u64()) else {
+                return;
+            };
+            let Some(entered) = call.entered else {
+                return;
+            };
+            let fields = json!({
+                "name": call.metadata.name(),
+                "target": call.metadata.target(),
+                "args": call.fields,
+                "us": entered.elapsed().as_micros() as u64,
+            });
+            (entered, fields)
+        };
+        session.write(session.elapsed_us(entered), "call", fields);
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        self.spans.lock().unwrap().remove(&span.into_u64());
+        true
+    }
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+/// The fields of a span or event, as JSON
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn writes_compressed_json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("probe-run-debug-session-{}", std::process::id()));
+        let session = Session {
+            started: Instant::now(),
+            path: path.clone(),
+            log: Mutex::new(Some(GzEncoder::new(
+                BufWriter::new(File::create(&path).unwrap()),
+                Compression::default(),
+            ))),
+        };
+        session.write(12, "read", json!({ "address": "0x20000000", "size": 4 }));
+        session.write(34, "rtt_read", json!({ "size": 16 }));
+        let writer = session.log.lock().unwrap().take().unwrap();
+        writer.finish().unwrap().flush().unwrap();
+
+        let mut text = String::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            text,
+            "{\"t\":12,\"kind\":\"read\",\"address\":\"0x20000000\",\"size\":4}\n\
+            {\"t\":34,\"kind\":\"rtt_read\",\"size\":16}\n"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! The encoding of the defmt stream: the one the ELF's defmt table names, or `--defmt-encoding`
+//!
+//! The table records the encoding the `defmt` crate was built with, which is the right one unless
+//! the program on the target was built differently, e.g. with `--no-flash` or a custom logger. A
+//! stream in another encoding shows up as malformed frames right from the start, which
+//! [`MismatchCheck`] turns into an error that names both encodings.
+
+use anyhow::bail;
+use defmt_decoder::{DecodeError, Encoding, Frame, StreamDecoder, Table};
+
+use crate::cli;
+
+/// How many frames in a row may be malformed before the first good one, until the stream is
+/// taken to be in another encoding
+const MALFORMED_LIMIT: usize = 3;
+
+/// The encoding to decode the stream of `table` with
+pub fn effective(table: &Table, opts: &cli::Opts) -> Encoding {
+    match opts.defmt_encoding {
+        Some(cli::DefmtEncoding::Raw) => Encoding::Raw,
+        Some(cli::DefmtEncoding::Rzcobs) => Encoding::Rzcobs,
+        None => table.encoding(),
+    }
+}
+
+/// Log the encoding the stream gets decoded with.
+pub fn announce(table: &Table, opts: &cli::Opts) {
+    let encoding = effective(table, opts);
+    if encoding == table.encoding() {
+        log::debug!("defmt encoding: {}", name(encoding));
+    } else {
+        log::info!(
+            "defmt encoding: {} (`--defmt-encoding`; the ELF says {})",
+            name(encoding),
+            name(table.encoding())
+        );
+    }
+}
+
+pub fn name(encoding: Encoding) -> String {
+    format!("{encoding:?}").to_lowercase()
+}
+
+/// A stream decoder for `encoding`, which may differ from the one of `table`
+pub fn decoder<'a>(table: &'a Table, encoding: Encoding) -> Box<dyn StreamDecoder + 'a> {
+    if encoding == table.encoding() {
+        return table.new_stream_decoder();
+    }
+    match encoding {
+        Encoding::Raw => Box::new(Raw {
+            table,
+            data: vec![],
+        }),
+        _ => Box::new(Rzcobs { table, raw: vec![] }),
+    }
+}
+
+/// Turns malformed frames into errors: right away if `encoding` can't recover from them, and
+/// with a hint at `--defmt-encoding` if the stream doesn't start with a good frame.
+pub struct MismatchCheck {
+    encoding: Encoding,
+    table_encoding: Encoding,
+    decoded_any: bool,
+    malformed: usize,
+}
+
+impl MismatchCheck {
+    pub fn new(encoding: Encoding, table_encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            table_encoding,
+            decoded_any: false,
+            malformed: 0,
+        }
+    }
+
+    pub fn decoded(&mut self) {
+        self.decoded_any = true;
+    }
+
+    /// Note a malformed frame; errors if decoding can't go on.
+    pub fn malformed(&mut self) -> anyhow::Result<()> {
+        self.malformed += 1;
+        let can_recover = self.encoding.can_recover();
+        if self.decoded_any {
+            return match can_recover {
+                true => Ok(()),
+                false => Err(DecodeError::Malformed.into()),
+            };
+        }
+        if can_recover && self.malformed < MALFORMED_LIMIT {
+            return Ok(());
+        }
+
+        let other = match self.encoding {
+            Encoding::Raw => "rzcobs",
+            _ => "raw",
+        };
+        let source = match self.encoding == self.table_encoding {
+            true => "the ELF's defmt table",
+            false => "`--defmt-encoding`",
+        };
+        bail!(
+            "the defmt stream doesn't look {}-encoded, as {source} says: it starts with {} \
+            malformed frame(s)\nif the program on the target was built with another defmt \
+            encoding than the ELF, try `--defmt-encoding {other}`",
+            name(self.encoding),
+            self.malformed
+        )
+    }
+}
+
+/// Like defmt-decoder's decoder of the raw encoding, which is private
+struct Raw<'a> {
+    table: &'a Table,
+    data: Vec<u8>,
+}
+
+impl StreamDecoder for Raw<'_> {
+    fn received(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    fn decode(&mut self) -> Result<Frame<'_>, DecodeError> {
+        let (frame, consumed) = self.table.decode(&self.data)?;
+        self.data.drain(..consumed);
+        Ok(frame)
+    }
+}
+
+/// Like defmt-decoder's decoder of the rzCOBS encoding, which is private: frames end with a zero
+struct Rzcobs<'a> {
+    table: &'a Table,
+    raw: Vec<u8>,
+}
+
+impl StreamDecoder for Rzcobs<'_> {
+    fn received(&mut self, mut data: &[u8]) {
+        // a frame never starts with a zero
+        if self.raw.is_empty() {
+            while let [0, rest @ ..] = data {
+                data = rest;
+            }
+        }
+        self.raw.extend_from_slice(data);
+    }
+
+    fn decode(&mut self) -> Result<Frame<'_>, DecodeError> {
+        let zero = self
+            .raw
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let frame = rzcobs_decode(&self.raw[..zero]);
+        // drop the frame and its zeros even if it is malformed, to go on with the next one
+        let end = self.raw[zero..]
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(self.raw.len(), |nonzero| zero + nonzero);
+        self.raw.drain(..end);
+
+        match self.table.decode(&frame?) {
+            Ok((frame, _)) => Ok(frame),
+            Err(_) => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+/// Decode one rzCOBS frame, without its zero; it is decoded from the end.
+fn rzcobs_decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = vec![];
+    let mut data = data.iter().rev().copied();
+    let mut next = || data.next().ok_or(DecodeError::Malformed);
+    while let Ok(byte) = next() {
+        match byte {
+            0 => return Err(DecodeError::Malformed),
+            // a bitmap of the next 7 bytes, where a set bit is a zero
+            0x01..=0x7f => {
+                for bit in (0..7).rev() {
+                    match byte & (1 << bit) {
+                        0 => decoded.push(next()?),
+                        _ => decoded.push(0),
+                    }
+                }
+            }
+            // a zero after 7 or more non-zero bytes
+            0x80..=0xfe => {
+                decoded.push(0);
+                for _ in 0..(byte & 0x7f) + 7 {
+                    decoded.push(next()?);
+                }
+            }
+            // 134 non-zero bytes
+            0xff => {
+                for _ in 0..134 {
+                    decoded.push(next()?);
+                }
+            }
+        }
+    }
+    decoded.reverse();
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::empty(&[], &[])]
+    #[case::zeros(&[0x7f], &[0, 0, 0, 0, 0, 0, 0])]
+    #[case::bitmap(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01], &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06])]
+    #[case::run(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x80], &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x00])]
+    fn decodes_rzcobs(#[case] encoded: &[u8], #[case] expected: &[u8]) {
+        assert_eq!(rzcobs_decode(encoded).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::truncated_bitmap(&[0x01, 0x00])]
+    #[case::truncated_run(&[0x01, 0x80])]
+    fn rejects_malformed_rzcobs(#[case] encoded: &[u8]) {
+        assert!(rzcobs_decode(encoded).is_err());
+    }
+
+    #[test]
+    fn reports_mismatch_before_first_frame() {
+        let mut check = MismatchCheck::new(Encoding::Rzcobs, Encoding::Rzcobs);
+        assert!(check.malformed().is_ok());
+        assert!(check.malformed().is_ok());
+        let error = check.malformed().unwrap_err().to_string();
+        assert!(error.contains("`--defmt-encoding raw`"), "{error}");
+
+        // a raw stream can't recover
+        let mut check = MismatchCheck::new(Encoding::Raw, Encoding::Rzcobs);
+        let error = check.malformed().unwrap_err().to_string();
+        assert!(error.contains("`--defmt-encoding rzcobs`"), "{error}");
+    }
+
+    #[test]
+    fn skips_malformed_frames_after_a_good_one() {
+        let mut check = MismatchCheck::new(Encoding::Rzcobs, Encoding::Rzcobs);
+        check.decoded();
+        for _ in 0..MALFORMED_LIMIT * 2 {
+            assert!(check.malformed().is_ok());
+        }
+    }
+}
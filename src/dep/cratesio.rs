@@ -1,6 +1,9 @@
-use std::path::{self, Component, Path as StdPath, PathBuf};
+use std::{
+    fs,
+    path::{self, Component, Path as StdPath, PathBuf},
+};
 
-use colored::Colorize as _;
+use crate::theme;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Path<'p> {
@@ -34,11 +37,11 @@ impl<'p> Path<'p> {
         }
         registry_prefix.push(src);
 
-        let github = super::get_component_normal(components.next()?)?.to_str()?;
-        if !github.starts_with("github.com-") {
+        let registry = super::get_component_normal(components.next()?)?.to_str()?;
+        if !registry.starts_with("github.com-") && !registry.starts_with("index.crates.io-") {
             return None;
         }
-        registry_prefix.push(github);
+        registry_prefix.push(registry);
 
         let crate_name_version = super::get_component_normal(components.next()?)?.to_str()?;
 
@@ -49,6 +52,25 @@ impl<'p> Path<'p> {
         })
     }
 
+    /// The crate name, without the version, e.g. `cortex-m-rt`
+    pub fn crate_name(&self) -> &'p str {
+        match self.crate_name_version.rsplit_once('-') {
+            Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => name,
+            _ => self.crate_name_version,
+        }
+    }
+
+    /// The same file in the registry cache of `cargo_home`, e.g. if the program was built on
+    /// another machine. Cargo versions differ in the name of the registry directory, so all of
+    /// them are searched.
+    pub fn local_source(&self, cargo_home: &StdPath) -> Option<PathBuf> {
+        fs::read_dir(cargo_home.join("registry").join("src"))
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().join(self.crate_name_version).join(self.path))
+            .find(|path| path.is_file())
+    }
+
     pub fn format_short(&self) -> String {
         format!(
             "[{}]{}{}",
@@ -59,10 +81,13 @@ impl<'p> Path<'p> {
     }
 
     pub fn format_highlight(&self) -> String {
+        let theme = theme::current();
         format!(
             "{}{sep}{}{sep}{}",
-            self.registry_prefix.display().to_string().dimmed(),
-            self.crate_name_version.bold(),
+            theme
+                .path_prefix
+                .paint(&self.registry_prefix.display().to_string()),
+            theme.crate_name.paint(self.crate_name_version),
             self.path.display(),
             sep = path::MAIN_SEPARATOR,
         )
@@ -99,6 +124,7 @@ mod tests {
         };
 
         assert_eq!(expected, path);
+        assert_eq!(path.crate_name(), "cortex-m-rt");
 
         let expected = PathBuf::from("[cortex-m-rt-0.6.13]")
             .join("src")
@@ -107,4 +133,32 @@ mod tests {
 
         assert_eq!(expected.to_string_lossy(), formatted_str);
     }
+
+    #[test]
+    fn finds_local_source_in_other_registry() {
+        let cargo_home =
+            std::env::temp_dir().join(format!("probe-run-cargo-{}", std::process::id()));
+        let local = cargo_home
+            .join("registry")
+            .join("src")
+            .join("index.crates.io-6f17d22bba15001f")
+            .join("cortex-m-rt-0.6.13")
+            .join("src");
+        fs::create_dir_all(&local).unwrap();
+        fs::write(local.join("lib.rs"), "").unwrap();
+
+        let input = PathBuf::from("/home/ci/.cargo/registry/src/github.com-1ecc6299db9ec823")
+            .join("cortex-m-rt-0.6.13")
+            .join("src");
+        let found = Path::from_std_path(&input.join("lib.rs"))
+            .unwrap()
+            .local_source(&cargo_home);
+        let missing = Path::from_std_path(&input.join("main.rs"))
+            .unwrap()
+            .local_source(&cargo_home);
+        fs::remove_dir_all(&cargo_home).unwrap();
+
+        assert_eq!(found, Some(local.join("lib.rs")));
+        assert_eq!(missing, None);
+    }
 }
@@ -1,8 +1,11 @@
 //! Dependency path parsing
 
 use std::{
+    env,
     ffi::OsStr,
-    path::{Component, Path as StdPath},
+    path::{Component, Path as StdPath, PathBuf},
+    process::Command,
+    sync::OnceLock,
 };
 
 mod cratesio;
@@ -33,6 +36,35 @@ impl<'p> Path<'p> {
         }
     }
 
+    /// The crate the path belongs to, e.g. `cortex-m-rt` or `core`; `None` for local paths
+    pub fn crate_name(&self) -> Option<&'p str> {
+        match self {
+            Path::Cratesio(cratesio) => Some(cratesio.crate_name()),
+            Path::RustStd(rust_std) => rust_std.crate_name(),
+            Path::Rustc(rustc) => rustc.crate_name(),
+            Path::Verbatim(_) => None,
+        }
+    }
+
+    /// Where the file lives on this machine, if the path recorded in the debug info doesn't
+    /// exist here: `/rustc/<hash>` paths of the standard library, and dependencies built on
+    /// another machine.
+    pub fn local_source(&self, sources: &Sources) -> Option<PathBuf> {
+        match self {
+            Path::Cratesio(cratesio) => cratesio.local_source(sources.cargo_home.as_deref()?),
+            Path::Rustc(rustc) => rustc.local_source(sources.rust_src.as_ref()?),
+            Path::RustStd(_) | Path::Verbatim(_) => None,
+        }
+    }
+
+    /// Shortened (see `--shorten-paths`) or with the less relevant parts dimmed
+    pub fn format(&self, shorten: bool) -> String {
+        match shorten {
+            true => self.format_short(),
+            false => self.format_highlight(),
+        }
+    }
+
     pub fn format_short(&self) -> String {
         match self {
             Path::Cratesio(cratesio) => cratesio.format_short(),
@@ -52,6 +84,63 @@ impl<'p> Path<'p> {
     }
 }
 
+/// The places on this machine which hold the sources of dependencies
+pub struct Sources {
+    cargo_home: Option<PathBuf>,
+    rust_src: Option<RustSrc>,
+}
+
+/// The `rust-src` component of the current toolchain
+pub struct RustSrc {
+    commit_hash: String,
+    /// Corresponds to the root of the rust-lang/rust repository
+    dir: PathBuf,
+}
+
+static SOURCES: OnceLock<Sources> = OnceLock::new();
+
+impl Sources {
+    /// Looks up `$CARGO_HOME` and asks `rustc` for its sysroot, once.
+    pub fn get() -> &'static Self {
+        SOURCES.get_or_init(|| Self {
+            cargo_home: cargo_home(),
+            rust_src: rust_src(),
+        })
+    }
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".cargo"))
+    })
+}
+
+fn rust_src() -> Option<RustSrc> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let run = |args: &[&str]| -> Option<String> {
+        let output = Command::new(&rustc).args(args).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    };
+
+    let commit_hash = run(&["-vV"])?
+        .lines()
+        .find_map(|line| line.strip_prefix("commit-hash: "))?
+        .to_string();
+    let sysroot = run(&["--print", "sysroot"])?;
+    let dir = PathBuf::from(sysroot.trim())
+        .join("lib")
+        .join("rustlib")
+        .join("src")
+        .join("rust");
+    log::debug!("rust-src of commit {commit_hash}: {}", dir.display());
+    Some(RustSrc { commit_hash, dir })
+}
+
 fn get_component_normal(component: Component) -> Option<&OsStr> {
     if let Component::Normal(string) = component {
         Some(string)
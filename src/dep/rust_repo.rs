@@ -1,6 +1,6 @@
-use std::path::{self, Path as StdPath};
+use std::path::{self, Path as StdPath, PathBuf};
 
-use colored::Colorize as _;
+use crate::theme;
 
 /// Representation of a rust-lang/rust repo path
 #[derive(Debug, Eq, PartialEq)]
@@ -18,6 +18,23 @@ impl<'p> Path<'p> {
         }
     }
 
+    pub fn crate_name(&self) -> Option<&'p str> {
+        match self {
+            Path::One52(path) => Some(path.crate_name),
+            Path::Verbatim(_) => None,
+        }
+    }
+
+    /// The path relative to the root of the rust-lang/rust repository
+    pub fn to_path_buf(&self) -> PathBuf {
+        match self {
+            Path::One52(path) => StdPath::new(path.library)
+                .join(path.crate_name)
+                .join(path.path),
+            Path::Verbatim(path) => path.to_path_buf(),
+        }
+    }
+
     pub fn format(&self) -> String {
         match self {
             Path::One52(path) => path.format(),
@@ -63,7 +80,7 @@ impl<'p> One52Path<'p> {
         format!(
             "{}{sep}{}{sep}{}",
             self.library,
-            self.crate_name.bold(),
+            theme::current().crate_name.paint(self.crate_name),
             self.path.display(),
             sep = path::MAIN_SEPARATOR
         )
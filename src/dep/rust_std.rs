@@ -1,6 +1,6 @@
 use std::path::{self, Component, Path as StdPath, PathBuf};
 
-use colored::Colorize;
+use crate::theme;
 
 use self::toolchain::Toolchain;
 
@@ -59,6 +59,10 @@ impl<'p> Path<'p> {
         })
     }
 
+    pub fn crate_name(&self) -> Option<&'p str> {
+        self.rust_repo_path.crate_name()
+    }
+
     pub fn format_short(&self) -> String {
         format!(
             "[{}]{}{}",
@@ -69,11 +73,16 @@ impl<'p> Path<'p> {
     }
 
     pub fn format_highlight(&self) -> String {
+        let theme = theme::current();
         format!(
             "{}{sep}{}{sep}{}{sep}{}",
-            self.rustup_prefix.display().to_string().dimmed(),
+            theme
+                .path_prefix
+                .paint(&self.rustup_prefix.display().to_string()),
             self.toolchain.format_highlight(),
-            self.rust_std_prefix.display().to_string().dimmed(),
+            theme
+                .path_prefix
+                .paint(&self.rust_std_prefix.display().to_string()),
             self.rust_repo_path.format_highlight(),
             sep = path::MAIN_SEPARATOR
         )
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use colored::Colorize;
+use crate::theme;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Toolchain<'p> {
@@ -89,11 +89,12 @@ impl<'p> One52<'p> {
     }
 
     fn format_highlight(&self) -> String {
+        let theme = theme::current();
         format!(
             "{}{}{}",
-            self.format_short().bold(),
-            "-".dimmed(),
-            self.host.dimmed()
+            theme.crate_name.paint(&self.format_short()),
+            theme.path_prefix.paint("-"),
+            theme.path_prefix.paint(self.host)
         )
     }
 
@@ -1,6 +1,6 @@
 use std::path::{self, Component, Path as StdPath, PathBuf};
 
-use colored::Colorize;
+use crate::theme;
 
 use super::rust_repo;
 
@@ -43,6 +43,21 @@ impl<'p> Path<'p> {
         })
     }
 
+    pub fn crate_name(&self) -> Option<&'p str> {
+        self.rust_repo_path.crate_name()
+    }
+
+    /// The same file in the `rust-src` component of the toolchain `rust_src`, if the toolchain
+    /// was built from the same commit; other commits may have different line numbers.
+    pub fn local_source(&self, rust_src: &super::RustSrc) -> Option<PathBuf> {
+        let hash = self.rustc_prefix.file_name()?;
+        if hash != rust_src.commit_hash.as_str() {
+            return None;
+        }
+        let path = rust_src.dir.join(self.rust_repo_path.to_path_buf());
+        path.is_file().then_some(path)
+    }
+
     pub fn format_short(&self) -> String {
         format!(
             "[rust]{}{}",
@@ -54,7 +69,9 @@ impl<'p> Path<'p> {
     pub fn format_highlight(&self) -> String {
         format!(
             "{}{}{}",
-            self.rustc_prefix.display().to_string().dimmed(),
+            theme::current()
+                .path_prefix
+                .paint(&self.rustc_prefix.display().to_string()),
             path::MAIN_SEPARATOR,
             self.rust_repo_path.format_highlight()
         )
@@ -104,4 +121,30 @@ mod tests {
 
         assert_eq!(expected.to_string_lossy(), formatted_str);
     }
+
+    #[test]
+    fn finds_local_source_of_same_commit() {
+        let sysroot =
+            std::env::temp_dir().join(format!("probe-run-sysroot-{}", std::process::id()));
+        let local = sysroot.join("library").join("core").join("src");
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(local.join("panicking.rs"), "").unwrap();
+
+        let input = PathBuf::from("/rustc/9bc8c42bb2f19e745a63f3445f1ac248fb015e53")
+            .join("library")
+            .join("core")
+            .join("src")
+            .join("panicking.rs");
+        let path = Path::from_std_path(&input).unwrap();
+        let rust_src = |commit_hash: &str| crate::dep::RustSrc {
+            commit_hash: commit_hash.to_string(),
+            dir: sysroot.clone(),
+        };
+        let same_commit = path.local_source(&rust_src("9bc8c42bb2f19e745a63f3445f1ac248fb015e53"));
+        let other_commit = path.local_source(&rust_src("0000000000000000000000000000000000000000"));
+        std::fs::remove_dir_all(&sysroot).unwrap();
+
+        assert_eq!(same_commit, Some(local.join("panicking.rs")));
+        assert_eq!(other_commit, None);
+    }
 }
@@ -0,0 +1,192 @@
+//! `probe-run doctor`: check the probe, the chip and the ELF up front, instead of failing
+//! somewhere in the middle of a run
+
+use std::{fmt::Display, fs, path::Path};
+
+use anyhow::anyhow;
+use colored::Colorize as _;
+use probe_rs::{architecture::arm::DpAddress, CoreType, Permissions, Probe, Session};
+
+use crate::{backtrace, cli::Opts, elf::Elf, probe, target_info};
+
+/// Address of the Debug Port Identification Register
+const DPIDR: u8 = 0x0;
+
+/// Where udev looks for rules
+#[cfg(target_os = "linux")]
+const UDEV_RULES_DIRS: [&str; 3] = [
+    "/etc/udev/rules.d",
+    "/lib/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+];
+
+/// The outcome of the checks so far
+struct Checklist {
+    all_passed: bool,
+}
+
+impl Checklist {
+    /// Print the result of the check `name`; returns the value if it passed.
+    fn check<T>(&mut self, name: &str, result: anyhow::Result<(T, String)>) -> Option<T> {
+        match result {
+            Ok((value, detail)) => {
+                println!("{} {name}: {detail}", "✓".green());
+                Some(value)
+            }
+            Err(e) => {
+                println!("{} {name}: {}", "✗".red(), format!("{e:#}").red());
+                self.all_passed = false;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str, reason: impl Display) {
+        println!("{} {name}: skipped, {reason}", "-".dimmed());
+    }
+}
+
+/// Run all checks which the options allow; returns `true` if all of them passed.
+pub fn run(elf_path: Option<&Path>, chip: Option<&str>, opts: &Opts) -> anyhow::Result<bool> {
+    let mut checklist = Checklist { all_passed: true };
+
+    let probe = checklist.check("probe", open_probe(opts));
+    #[cfg(target_os = "linux")]
+    for probe in &Probe::list_all() {
+        checklist.check("udev rules", udev_rules(probe));
+    }
+
+    match (probe, chip) {
+        (Some(probe), Some(chip)) => {
+            checklist.check("chip", attach(probe, chip, opts));
+        }
+        (None, _) => checklist.skip("chip", "no probe"),
+        (_, None) => checklist.skip("chip", "no chip given (`--chip`)"),
+    }
+
+    match elf_path {
+        Some(elf_path) => check_elf(&mut checklist, elf_path)?,
+        None => checklist.skip("ELF", "no ELF given"),
+    }
+
+    Ok(checklist.all_passed)
+}
+
+/// Open the probe; `probe::open` explains permission problems.
+fn open_probe(opts: &Opts) -> anyhow::Result<(Probe, String)> {
+    let probe = probe::open(opts)?;
+    let name = probe.get_name();
+    Ok((probe, name))
+}
+
+/// Look for a udev rule which mentions the probe's vendor ID.
+#[cfg(target_os = "linux")]
+fn udev_rules(probe: &probe_rs::DebugProbeInfo) -> anyhow::Result<((), String)> {
+    let vendor_id = format!("{:04x}", probe.vendor_id);
+    let rule = UDEV_RULES_DIRS
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| {
+            fs::read_to_string(path)
+                .map(|rules| rules.to_ascii_lowercase().contains(&vendor_id))
+                .unwrap_or(false)
+        });
+
+    match rule {
+        Some(rule) => Ok(((), format!("{} ({vendor_id})", rule.display()))),
+        None => Err(anyhow!(
+            "no rule mentions the vendor ID {vendor_id} of {}; install this one as `{}`: {}",
+            probe.identifier,
+            probe::UDEV_RULE_PATH,
+            probe::udev_rule(probe, false)
+        )),
+    }
+}
+
+/// Attach to the chip and read its IDCODE.
+fn attach(probe: Probe, chip: &str, opts: &Opts) -> anyhow::Result<((), String)> {
+    let mut target = probe_rs::config::get_target_by_name(chip)?;
+    target_info::select_core(&mut target, opts.core, opts.ap)?;
+    let core_type = target.cores[opts.core].core_type;
+    let mut sess = match opts.connect_under_reset {
+        false => probe.attach(target, Permissions::new()),
+        true => probe.attach_under_reset(target, Permissions::new()),
+    }?;
+
+    let detail = match core_type {
+        CoreType::Riscv => format!("attached to {chip}"),
+        _ => format!("{chip}, IDCODE {:#010X}", read_idcode(&mut sess)?),
+    };
+    Ok(((), detail))
+}
+
+fn read_idcode(sess: &mut Session) -> anyhow::Result<u32> {
+    Ok(sess
+        .get_arm_interface()?
+        .read_raw_dp_register(DpAddress::Default, DPIDR)?)
+}
+
+fn check_elf(checklist: &mut Checklist, elf_path: &Path) -> anyhow::Result<()> {
+    let Some(elf_bytes) = checklist.check(
+        "ELF",
+        fs::read(elf_path)
+            .map(|bytes| (bytes, elf_path.display().to_string()))
+            .map_err(|e| anyhow!(e)),
+    ) else {
+        return Ok(());
+    };
+
+    // the ELF doesn't parse if the defmt version is unsupported, so check it first
+    let defmt = defmt_decoder::Table::parse(&elf_bytes).map(|table| {
+        let detail = match table {
+            Some(_) => "version supported".to_string(),
+            None => "not used".to_string(),
+        };
+        ((), detail)
+    });
+    if checklist.check("defmt", defmt).is_none() {
+        return Ok(());
+    }
+
+    let Some(elf) = checklist.check(
+        "sections",
+        Elf::parse_offline(&elf_bytes, elf_path).map(|elf| {
+            let detail = "`.vector_table`, `.text`, `.debug_frame` and `main` found".to_string();
+            (elf, detail)
+        }),
+    ) else {
+        return Ok(());
+    };
+    let vector_table = &elf.vector_table;
+    checklist.check(
+        "vector table",
+        Ok((
+            (),
+            format!(
+                "initial stack pointer {:#010X}, reset handler {:#010X}",
+                vector_table.initial_stack_pointer, vector_table.reset
+            ),
+        )),
+    );
+
+    let report = backtrace::check::check(&elf)?;
+    let unwind = match report.is_ok() {
+        true => Ok((
+            (),
+            format!(
+                "{} FDEs in `.debug_frame` cover all {} functions",
+                report.num_fdes, report.num_functions
+            ),
+        )),
+        false => Err(anyhow!(
+            "{} functions lack unwind info and {} FDEs are stray; see `probe-run check-unwind`",
+            report.uncovered_functions.len(),
+            report.stray_fdes.len()
+        )),
+    };
+    checklist.check("unwind info", unwind);
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Access to the DWARF debug info of the ELF, for lookups `addr2line` doesn't cover
+
+use std::borrow::Cow;
+
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian};
+use object::{Object as _, ObjectSection as _};
+
+use crate::elf::Elf;
+
+pub type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// The (decompressed) debug sections; [`Sections::borrow`] them to read them
+pub struct Sections<'a>(gimli::Dwarf<Cow<'a, [u8]>>, RunTimeEndian);
+
+impl<'a> Sections<'a> {
+    pub fn load(elf: &'a Elf) -> anyhow::Result<Self> {
+        let endian = match elf.is_little_endian() {
+            true => RunTimeEndian::Little,
+            false => RunTimeEndian::Big,
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> anyhow::Result<Cow<[u8]>> {
+            Ok(match elf.section_by_name(id.name()) {
+                Some(section) => section.uncompressed_data()?,
+                None => Cow::Borrowed(&[]),
+            })
+        })?;
+        Ok(Self(dwarf, endian))
+    }
+
+    pub fn borrow(&self) -> gimli::Dwarf<Reader> {
+        let endian = self.1;
+        self.0.borrow(|section| EndianSlice::new(section, endian))
+    }
+}
+
+/// The string value of an attribute, like `DW_AT_name`
+pub fn attr_string(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    value: AttributeValue<Reader>,
+) -> anyhow::Result<String> {
+    Ok(dwarf
+        .attr_string(unit, value)?
+        .to_string_lossy()
+        .into_owned())
+}
@@ -0,0 +1,189 @@
+//! Locations of the defmt log statements the defmt location map lacks, looked up in the DWARF
+//! debug info: the declaration of the log statement's interned string, or else the code address
+//! of the scope around it, resolved like `addr2line` does (see warning W005)
+
+use std::{collections::HashMap, path::PathBuf};
+
+use defmt_decoder::Location;
+use gimli::AttributeValue;
+
+use crate::{
+    dwarf::{self, attr_string, Reader},
+    elf::Elf,
+};
+
+/// The name of the statics the defmt macros intern log messages in
+const LOG_STATEMENT: &str = "DEFMT_LOG_STATEMENT";
+
+pub struct DwarfLocations<'a> {
+    elf: &'a Elf<'a>,
+    /// By frame index; `None` until the first lookup, which scans the debug info once
+    locations: Option<HashMap<u64, Location>>,
+}
+
+impl<'a> DwarfLocations<'a> {
+    pub fn new(elf: &'a Elf<'a>) -> Self {
+        Self {
+            elf,
+            locations: None,
+        }
+    }
+
+    /// The location of the log statement with the frame index `index`.
+    pub fn get(&mut self, index: u64) -> Option<&Location> {
+        let elf = self.elf;
+        self.locations
+            .get_or_insert_with(|| match scan(elf) {
+                Ok(locations) => {
+                    log::debug!(
+                        "found {} defmt locations in the DWARF info",
+                        locations.len()
+                    );
+                    locations
+                }
+                Err(e) => {
+                    log::debug!("failed to look up defmt locations in the DWARF info: {e:?}");
+                    HashMap::new()
+                }
+            })
+            .get(&index)
+    }
+}
+
+/// Find the locations of the log statements the defmt location map lacks.
+fn scan(elf: &Elf) -> anyhow::Result<HashMap<u64, Location>> {
+    let known = elf.defmt_locations.as_ref();
+    let missing = elf
+        .defmt_symbols()
+        .into_iter()
+        .filter(|(index, _)| known.map_or(true, |known| !known.contains_key(&(*index as u64))))
+        .map(|(index, raw)| (raw, index as u64))
+        .collect::<HashMap<_, _>>();
+    if missing.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let sections = dwarf::Sections::load(elf)?;
+    let dwarf = sections.borrow();
+    let addr2line = addr2line::Context::new(&**elf)?;
+
+    let mut locations = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        // the namespaces and the code address of the scopes around the current entry, by depth
+        let mut scopes: Vec<(Option<String>, Option<u64>)> = vec![];
+        let mut depth = 0;
+        let mut entries = unit.entries();
+        while let Some((delta_depth, entry)) = entries.next_dfs()? {
+            depth += delta_depth;
+            scopes.truncate(depth.max(0) as usize);
+
+            let namespace = match entry.tag() {
+                gimli::DW_TAG_namespace => entry
+                    .attr_value(gimli::DW_AT_name)?
+                    .map(|name| attr_string(&dwarf, &unit, name))
+                    .transpose()?,
+                _ => None,
+            };
+            let address = match entry.tag() {
+                gimli::DW_TAG_subprogram
+                | gimli::DW_TAG_lexical_block
+                | gimli::DW_TAG_inlined_subroutine => dwarf
+                    .die_ranges(&unit, entry)?
+                    .next()?
+                    .map(|range| range.begin),
+                _ => None,
+            };
+
+            if entry.tag() == gimli::DW_TAG_variable && is_log_statement(&dwarf, &unit, entry)? {
+                let index = match entry.attr_value(gimli::DW_AT_linkage_name)? {
+                    Some(name) => missing.get(&*attr_string(&dwarf, &unit, name)?),
+                    None => None,
+                };
+                if let Some(&index) = index {
+                    let module = scopes
+                        .iter()
+                        .filter_map(|(namespace, _)| namespace.as_deref())
+                        .collect::<Vec<_>>()
+                        .join("::");
+                    let scope_address = scopes.iter().rev().find_map(|(_, address)| *address);
+                    let location = declaration(&dwarf, &unit, entry)?.or_else(|| {
+                        let location = addr2line.find_location(scope_address?).ok()??;
+                        Some((PathBuf::from(location.file?), u64::from(location.line?)))
+                    });
+                    if let Some((file, line)) = location {
+                        locations.insert(index, Location { file, line, module });
+                    }
+                }
+            }
+
+            scopes.push((namespace, address));
+        }
+    }
+    Ok(locations)
+}
+
+fn is_log_statement(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    entry: &gimli::DebuggingInformationEntry<Reader>,
+) -> anyhow::Result<bool> {
+    Ok(match entry.attr_value(gimli::DW_AT_name)? {
+        Some(name) => attr_string(dwarf, unit, name)? == LOG_STATEMENT,
+        None => false,
+    })
+}
+
+/// The file and line the variable `entry` is declared at
+fn declaration(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    entry: &gimli::DebuggingInformationEntry<Reader>,
+) -> anyhow::Result<Option<(PathBuf, u64)>> {
+    let (Some(AttributeValue::FileIndex(file)), Some(line)) = (
+        entry.attr_value(gimli::DW_AT_decl_file)?,
+        entry.attr_value(gimli::DW_AT_decl_line)?,
+    ) else {
+        return Ok(None);
+    };
+    let (Some(line), Some(program)) = (line.udata_value(), &unit.line_program) else {
+        return Ok(None);
+    };
+    let header = program.header();
+    let Some(file) = header.file(file) else {
+        return Ok(None);
+    };
+
+    let mut path = PathBuf::new();
+    if let Some(comp_dir) = &unit.comp_dir {
+        path.push(&*comp_dir.to_string_lossy());
+    }
+    if let Some(directory) = file.directory(header) {
+        path.push(attr_string(dwarf, unit, directory)?);
+    }
+    path.push(attr_string(dwarf, unit, file.path_name())?);
+    Ok(Some((path, line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn finds_the_locations_defmt_lacks() {
+        let path = Path::new("tests/test_elfs/levels-with-timestamp");
+        let bytes = fs::read(path).unwrap();
+        let mut elf = Elf::parse_offline(&bytes, path).unwrap();
+        let expected = elf.defmt_locations.take().unwrap();
+
+        let mut fallback = DwarfLocations::new(&elf);
+        for (index, location) in &expected {
+            let found = fallback.get(*index).unwrap();
+            assert_eq!((&found.file, found.line), (&location.file, location.line));
+            assert_eq!(found.module, location.module);
+        }
+    }
+}
@@ -9,13 +9,20 @@ use std::{
 use anyhow::{anyhow, bail};
 use defmt_decoder::{Locations, Table};
 use object::{
-    read::File as ObjectFile, Object as _, ObjectSection as _, ObjectSymbol as _, SymbolSection,
+    read::File as ObjectFile, Object as _, ObjectSection as _, ObjectSymbol as _, SectionKind,
+    SymbolKind, SymbolSection,
 };
 
-use crate::cortexm;
+use crate::{
+    backtrace::SymbolCache,
+    cortexm,
+    warnings::{self, Warning},
+};
 
 pub struct Elf<'file> {
     elf: ObjectFile<'file>,
+    /// Function symbols, sorted by address
+    functions: Vec<(Range<u32>, &'file str)>,
     symbols: Symbols,
 
     pub debug_frame: DebugFrame<'file>,
@@ -23,6 +30,8 @@ pub struct Elf<'file> {
     pub defmt_table: Option<Table>,
     pub elf_path: &'file Path,
     pub live_functions: HashSet<&'file str>,
+    /// Memoized symbolication of backtrace addresses
+    pub symbol_cache: SymbolCache,
     pub vector_table: cortexm::VectorTable,
 }
 
@@ -31,10 +40,27 @@ impl<'file> Elf<'file> {
         elf_bytes: &'file [u8],
         elf_path: &'file Path,
         reset_fn_address: u32,
+    ) -> Result<Self, anyhow::Error> {
+        Self::parse_with_reset_fn(elf_bytes, elf_path, Some(reset_fn_address))
+    }
+
+    /// Parse the ELF without a target, taking the reset handler address from its vector table.
+    pub fn parse_offline(
+        elf_bytes: &'file [u8],
+        elf_path: &'file Path,
+    ) -> Result<Self, anyhow::Error> {
+        Self::parse_with_reset_fn(elf_bytes, elf_path, None)
+    }
+
+    fn parse_with_reset_fn(
+        elf_bytes: &'file [u8],
+        elf_path: &'file Path,
+        reset_fn_address: Option<u32>,
     ) -> Result<Self, anyhow::Error> {
         let elf = ObjectFile::parse(elf_bytes)?;
 
         let live_functions = extract_live_functions(&elf)?;
+        let functions = extract_functions(&elf);
 
         let (defmt_table, defmt_locations) = extract_defmt_info(elf_bytes)?;
         let vector_table = extract_vector_table(&elf)?;
@@ -42,22 +68,39 @@ impl<'file> Elf<'file> {
 
         let debug_frame = extract_debug_frame(&elf)?;
 
+        let reset_fn_address = reset_fn_address.unwrap_or(vector_table.reset);
         let symbols = extract_symbols(&elf, reset_fn_address)?;
 
         Ok(Self {
             elf,
+            functions,
             symbols,
             debug_frame,
             defmt_locations,
             defmt_table,
             elf_path,
             live_functions,
+            symbol_cache: SymbolCache::default(),
             vector_table,
         })
     }
 
-    pub fn main_fn_address(&self) -> u32 {
-        self.symbols.main_fn_address
+    /// The address of the function the program's setup runs up to: `entry_symbol`, or `main`.
+    ///
+    /// Returns `None` if the ELF has no `main`, e.g. a mixed C and Rust program with another entry
+    /// point; the program then starts at the reset vector.
+    pub fn entry_fn_address(&self, entry_symbol: Option<&str>) -> anyhow::Result<Option<u32>> {
+        match entry_symbol {
+            Some(name) => match self.find_symbol(name) {
+                Some(range) => Ok(Some(range.start)),
+                None => bail!("entry symbol `{name}` (`--entry-symbol`) not found in the ELF"),
+            },
+            None => Ok(self.symbols.main_fn_address),
+        }
+    }
+
+    pub fn panic_fn_address(&self) -> Option<u32> {
+        self.symbols.panic_fn_address
     }
 
     pub fn program_uses_heap(&self) -> bool {
@@ -71,6 +114,55 @@ impl<'file> Elf<'file> {
     pub fn rtt_buffer_address(&self) -> Option<u32> {
         self.symbols.rtt_buffer_address
     }
+
+    /// Look up the address range of a symbol by its raw or demangled (hash-less) name.
+    pub fn find_symbol(&self, name: &str) -> Option<Range<u32>> {
+        let symbol = self.elf.symbols().find(|symbol| match symbol.name() {
+            Ok(raw_name) => {
+                raw_name == name || format!("{:#}", rustc_demangle::demangle(raw_name)) == name
+            }
+            Err(_) => false,
+        })?;
+
+        let address = cortexm::clear_thumb_bit(symbol.address().try_into().ok()?);
+        let size: u32 = symbol.size().try_into().ok()?;
+        Some(address..address + size)
+    }
+
+    /// Look up the (raw) name of the function symbol that contains `pc`.
+    ///
+    /// Unlike the DWARF lookup, this also works for code without debug info, like linker veneers
+    /// and assembly.
+    pub fn function_name(&self, pc: u32) -> Option<&'file str> {
+        let index = self
+            .functions
+            .partition_point(|(range, _)| range.start <= pc)
+            .checked_sub(1)?;
+        let (range, name) = &self.functions[index];
+        range.contains(&pc).then_some(*name)
+    }
+
+    /// If `pc` is in a linker-generated veneer, return the name of the function it branches to.
+    pub fn veneer_target(&self, pc: u32) -> Option<&'file str> {
+        self.function_name(pc).and_then(veneer_target)
+    }
+
+    /// The raw, JSON-encoded symbols of the `.defmt` section by their index in the defmt table,
+    /// which is their address; sorted by index. Markers like `_defmt_version_` are left out.
+    pub fn defmt_symbols(&self) -> Vec<(usize, &'file str)> {
+        let Some(section) = self.elf.section_by_name(".defmt") else {
+            return vec![];
+        };
+        let mut symbols = self
+            .elf
+            .symbols()
+            .filter(|symbol| symbol.section_index() == Some(section.index()))
+            .filter_map(|symbol| Some((symbol.address() as usize, symbol.name().ok()?)))
+            .filter(|(_, name)| name.starts_with('{'))
+            .collect::<Vec<_>>();
+        symbols.sort_unstable();
+        symbols
+    }
 }
 
 impl<'elf> Deref for Elf<'elf> {
@@ -92,10 +184,22 @@ fn extract_live_functions<'file>(elf: &ObjectFile<'file>) -> anyhow::Result<Hash
             )
         })?;
 
+    // functions which run from RAM are not in `.text` but in an executable section nonetheless
+    let is_executable = |symbol: &object::Symbol| match symbol.section() {
+        SymbolSection::Section(index) => {
+            index == text
+                || (symbol.kind() == SymbolKind::Text
+                    && elf
+                        .section_by_index(index)
+                        .map_or(false, |section| section.kind() == SectionKind::Text))
+        }
+        _ => false,
+    };
+
     let live_functions = elf
         .symbols()
         .filter_map(|symbol| {
-            if symbol.section() == SymbolSection::Section(text) {
+            if is_executable(&symbol) {
                 Some(symbol.name())
             } else {
                 None
@@ -106,6 +210,48 @@ fn extract_live_functions<'file>(elf: &ObjectFile<'file>) -> anyhow::Result<Hash
     Ok(live_functions)
 }
 
+fn extract_functions<'file>(elf: &ObjectFile<'file>) -> Vec<(Range<u32>, &'file str)> {
+    let mut functions = elf
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.is_definition())
+        .filter_map(|symbol| {
+            let start = cortexm::clear_thumb_bit(symbol.address().try_into().ok()?);
+            let size: u32 = symbol.size().try_into().ok()?;
+            Some((start..start + size, symbol.name().ok()?))
+        })
+        .collect::<Vec<_>>();
+    functions.sort_by_key(|(range, _)| range.start);
+
+    // linkers emit veneers without a size; they end where the next function starts
+    for index in 0..functions.len() {
+        if functions[index].0.is_empty() {
+            let start = functions[index].0.start;
+            if let Some(next) = functions[index + 1..]
+                .iter()
+                .map(|(next, _)| next.start)
+                .find(|&next| next > start)
+            {
+                functions[index].0.end = next;
+            }
+        }
+    }
+
+    functions
+}
+
+/// Recognize the veneers (or "thunks") the linker inserts for branches which are out of range,
+/// e.g. from flash to RAM, by their name, and return the name of the branch target.
+///
+/// GNU ld names them `__<target>_veneer`, LLD e.g. `__Thumbv7ABSLongThunk_<target>`.
+pub fn veneer_target(name: &str) -> Option<&str> {
+    let name = name.strip_prefix("__")?;
+    if let Some(target) = name.strip_suffix("_veneer") {
+        return Some(target);
+    }
+    let (kind, target) = name.split_once('_')?;
+    (kind.ends_with("Thunk") && !target.is_empty()).then_some(target)
+}
+
 fn extract_defmt_info(elf_bytes: &[u8]) -> anyhow::Result<(Option<Table>, Option<Locations>)> {
     let defmt_table = match env::var("PROBE_RUN_IGNORE_VERSION").as_deref() {
         Ok("true") | Ok("1") => defmt_decoder::Table::parse_ignore_version(elf_bytes)?,
@@ -118,14 +264,25 @@ fn extract_defmt_info(elf_bytes: &[u8]) -> anyhow::Result<(Option<Table>, Option
         let locations = table.get_locations(elf_bytes)?;
 
         if !table.is_empty() && locations.is_empty() {
-            log::warn!("insufficient DWARF info; compile your program with `debug = 2` to enable location info");
-        } else if table
-            .indices()
-            .all(|idx| locations.contains_key(&(idx as u64)))
-        {
-            defmt_locations = Some(locations);
+            warnings::warn(
+                Warning::InsufficientDwarf,
+                "insufficient DWARF info; compile your program with `debug = 2` to enable location info",
+            )?;
         } else {
-            log::warn!("(BUG) location info is incomplete; it will be omitted from the output");
+            let num_missing = table
+                .indices()
+                .filter(|idx| !locations.contains_key(&(*idx as u64)))
+                .count();
+            if num_missing != 0 {
+                warnings::warn(
+                    Warning::IncompleteLocations,
+                    format_args!(
+                        "(BUG) location info is incomplete for {num_missing} log statements; \
+                        looking them up in the DWARF line info instead"
+                    ),
+                )?;
+            }
+            defmt_locations = Some(locations);
         }
     }
 
@@ -145,16 +302,23 @@ fn extract_vector_table(elf: &ObjectFile) -> anyhow::Result<cortexm::VectorTable
     }
 
     let bytes = section.data()?;
-    let mut words = bytes
+    let entries = bytes
         .chunks_exact(4)
-        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()));
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let mut words = entries.iter().copied();
 
-    if let (Some(initial_stack_pointer), Some(_reset), Some(_third), Some(hard_fault)) =
+    if let (Some(initial_stack_pointer), Some(reset), Some(_third), Some(hard_fault)) =
         (words.next(), words.next(), words.next(), words.next())
     {
         Ok(cortexm::VectorTable {
+            address: start as u32,
             initial_stack_pointer,
+            reset,
             hard_fault,
+            // entries 4..=10 are skipped
+            svcall: words.nth(7),
+            entries,
         })
     } else {
         Err(anyhow!(
@@ -179,14 +343,24 @@ fn extract_debug_frame<'file>(elf: &ObjectFile<'file>) -> anyhow::Result<DebugFr
 }
 
 struct Symbols {
-    main_fn_address: u32,
+    main_fn_address: Option<u32>,
+    panic_fn_address: Option<u32>,
     program_uses_heap: bool,
     reset_fn_range: Range<u32>,
     rtt_buffer_address: Option<u32>,
 }
 
+/// Whether `name` is the mangled name of `core::panicking::panic_fmt`, in the legacy or the `v0`
+/// mangling scheme
+fn is_panic_fmt(name: &str) -> bool {
+    // demangle only the candidates; the ELF has thousands of symbols
+    name.contains("panic_fmt")
+        && format!("{:#}", rustc_demangle::demangle(name)) == "core::panicking::panic_fmt"
+}
+
 fn extract_symbols(elf: &ObjectFile, reset_fn_address: u32) -> anyhow::Result<Symbols> {
-    let mut main_fn_address = None;
+    let mut main_fns = Vec::new();
+    let mut panic_fn_address = None;
     let mut program_uses_heap = false;
     let mut reset_symbols = Vec::new();
     let mut rtt_buffer_address = None;
@@ -199,8 +373,14 @@ fn extract_symbols(elf: &ObjectFile, reset_fn_address: u32) -> anyhow::Result<Sy
 
         let address = symbol.address().try_into().expect("expected 32-bit ELF");
         match name {
-            "main" => main_fn_address = Some(cortexm::clear_thumb_bit(address)),
+            "main" => main_fns.push((cortexm::clear_thumb_bit(address), symbol.is_global())),
             "_SEGGER_RTT" => rtt_buffer_address = Some(address),
+            // the `#[panic_handler]`; every panic ends up here, whatever the handler does
+            "rust_begin_unwind" => panic_fn_address = Some(cortexm::clear_thumb_bit(address)),
+            // fall back to `core::panicking::panic_fmt` if the handler got inlined
+            _ if panic_fn_address.is_none() && is_panic_fmt(name) => {
+                panic_fn_address = Some(cortexm::clear_thumb_bit(address))
+            }
             "__rust_alloc" | "__rg_alloc" | "__rdl_alloc" | "malloc" if !program_uses_heap => {
                 log::debug!("symbol `{}` indicates heap is in use", name);
                 program_uses_heap = true;
@@ -214,7 +394,7 @@ fn extract_symbols(elf: &ObjectFile, reset_fn_address: u32) -> anyhow::Result<Sy
         }
     }
 
-    let main_fn_address = main_fn_address.ok_or(anyhow!("`main` symbol not found"))?;
+    let main_fn_address = pick_main_fn(&main_fns);
     let reset_fn_range = {
         if reset_symbols.len() == 1 {
             let reset = reset_symbols.remove(0);
@@ -230,8 +410,133 @@ fn extract_symbols(elf: &ObjectFile, reset_fn_address: u32) -> anyhow::Result<Sy
 
     Ok(Symbols {
         main_fn_address,
+        panic_fn_address,
         program_uses_heap,
         reset_fn_range,
         rtt_buffer_address,
     })
 }
+
+/// Pick the program's `main` out of all `(address, is_global)` symbols of that name.
+///
+/// Mixed C and Rust programs may have several, e.g. a `static` C function; the global one is
+/// the entry point.
+fn pick_main_fn(main_fns: &[(u32, bool)]) -> Option<u32> {
+    let mut addresses = main_fns
+        .iter()
+        .filter(|(_, is_global)| *is_global)
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        addresses = main_fns.iter().map(|(address, _)| *address).collect();
+    }
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    match addresses[..] {
+        [] => {
+            log::debug!("`main` symbol not found");
+            None
+        }
+        [address] => Some(address),
+        [address, ..] => {
+            log::warn!(
+                "found {} `main` symbols; using the one at {address:#010X}; pass another with `--entry-symbol`",
+                addresses.len()
+            );
+            Some(address)
+        }
+    }
+}
+
+/// Name of the section with run options embedded by the firmware (see [`extract_embedded_options`])
+const EMBEDDED_OPTIONS_SECTION: &str = ".probe-run";
+
+/// Extract the run options embedded in the `.probe-run` section, as `(key, value)` pairs.
+///
+/// The section contains one `key = value` pair per line; empty lines, `#` comments and NUL
+/// padding are ignored.
+pub fn extract_embedded_options(elf_bytes: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    let elf = ObjectFile::parse(elf_bytes)?;
+    let Some(section) = elf.section_by_name(EMBEDDED_OPTIONS_SECTION) else {
+        return Ok(vec![]);
+    };
+
+    let text = std::str::from_utf8(section.data()?)
+        .map_err(|_| anyhow!("section `{EMBEDDED_OPTIONS_SECTION}` is not valid UTF-8"))?;
+    parse_embedded_options(text)
+}
+
+fn parse_embedded_options(text: &str) -> anyhow::Result<Vec<(String, String)>> {
+    text.lines()
+        .map(|line| line.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                anyhow!("expected `key = value` in section `{EMBEDDED_OPTIONS_SECTION}`, found `{line}`")
+            })?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn parses_embedded_options() {
+        let text =
+            "# run options\nchip = nRF52840_xxAA\n\nspeed=4000\nlog-format = {L} {s}\n\0\0\0";
+
+        let options = parse_embedded_options(text).unwrap();
+
+        assert_eq!(
+            options,
+            [
+                ("chip".to_string(), "nRF52840_xxAA".to_string()),
+                ("speed".to_string(), "4000".to_string()),
+                ("log-format".to_string(), "{L} {s}".to_string()),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::legacy("_ZN4core9panicking9panic_fmt17h1a2b3c4d5e6f7a8bE", true)]
+    #[case::v0("_RNvNtCsaD1GcQfzGCY_4core9panicking9panic_fmt", true)]
+    #[case::other_crate("_ZN3app9panicking9panic_fmt17h1a2b3c4d5e6f7a8bE", false)]
+    #[case::closure("_RNCNvNtCsaD1GcQfzGCY_4core9panicking9panic_fmt0B5_", false)]
+    fn recognizes_panic_fmt(#[case] name: &str, #[case] expected: bool) {
+        assert_eq!(is_panic_fmt(name), expected);
+    }
+
+    #[test]
+    fn rejects_line_without_value() {
+        assert!(parse_embedded_options("chip").is_err());
+    }
+
+    #[rstest]
+    #[case::single(&[(0x100, true)], Some(0x100))]
+    #[case::global_wins(&[(0x200, false), (0x100, true)], Some(0x100))]
+    #[case::only_local(&[(0x200, false)], Some(0x200))]
+    #[case::same_address(&[(0x100, true), (0x100, true)], Some(0x100))]
+    #[case::missing(&[], None)]
+    fn picks_main_fn(#[case] main_fns: &[(u32, bool)], #[case] expected: Option<u32>) {
+        assert_eq!(pick_main_fn(main_fns), expected);
+    }
+
+    #[rstest]
+    #[case::gnu_ld("__ram_function_veneer", Some("ram_function"))]
+    #[case::lld("__Thumbv7ABSLongThunk_ram_function", Some("ram_function"))]
+    #[case::lld_v6m(
+        "__Thumbv6MPILongThunk__ZN3app3foo17h0123456789abcdefE",
+        Some("_ZN3app3foo17h0123456789abcdefE")
+    )]
+    #[case::function("__cortex_m_rt_main", None)]
+    #[case::no_prefix("ram_function_veneer", None)]
+    fn recognizes_veneers(#[case] name: &str, #[case] expected: Option<&str>) {
+        assert_eq!(veneer_target(name), expected);
+    }
+}
@@ -0,0 +1,199 @@
+//! `-` and `http(s)://` URLs as the ELF argument: the ELF is read from stdin or downloaded with
+//! `curl`, into a temporary file which is removed when probe-run exits
+//!
+//! The rest of probe-run only sees the temporary file. Source locations come from the debug info,
+//! so backtraces still point into the sources; only the history of `--compare-last` is keyed by
+//! the temporary path and doesn't find earlier runs.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher as _, Hasher as _},
+    io::{self, Read as _},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context as _};
+
+/// The first bytes of every ELF file
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// How often to pick another name if the temporary directory exists already
+const CREATE_DIR_ATTEMPTS: usize = 16;
+
+/// An ELF read from stdin or downloaded, stored in a temporary directory
+pub struct Fetched {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl Fetched {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Fetched {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Read the ELF `arg` names from stdin (`-`) or download it (a URL); returns `None` for paths.
+pub fn fetch(arg: &Path) -> anyhow::Result<Option<Fetched>> {
+    let arg_str = arg.to_string_lossy();
+    let source = if arg_str == "-" {
+        Source::Stdin
+    } else if arg_str.starts_with("http://") || arg_str.starts_with("https://") {
+        Source::Url(&arg_str)
+    } else {
+        return Ok(None);
+    };
+
+    let dir = create_temp_dir()?;
+    // removes the directory again on errors
+    let fetched = Fetched {
+        path: dir.join(source.file_name()),
+        dir,
+    };
+
+    match source {
+        Source::Stdin => {
+            let mut bytes = vec![];
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .context("failed to read the ELF from stdin")?;
+            fs::write(&fetched.path, bytes)?;
+        }
+        Source::Url(url) => download(url, &fetched.path)?,
+    }
+
+    let mut magic = [0; ELF_MAGIC.len()];
+    let is_elf = fs::File::open(&fetched.path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_or(false, |()| magic == ELF_MAGIC);
+    if !is_elf {
+        bail!("{} is not an ELF file", source.describe());
+    }
+    log::debug!(
+        "stored the ELF from {} at `{}`",
+        source.describe(),
+        fetched.path.display()
+    );
+    Ok(Some(fetched))
+}
+
+/// Create a new directory with an unpredictable name in the temporary directory; an existing
+/// directory, which someone else may control, is never reused.
+fn create_temp_dir() -> anyhow::Result<PathBuf> {
+    let temp_dir = std::env::temp_dir();
+    for _ in 0..CREATE_DIR_ATTEMPTS {
+        // `RandomState` is seeded with random keys, so this needs no extra dependency
+        let suffix = RandomState::new().build_hasher().finish();
+        let dir = temp_dir.join(format!("probe-run-{}-{suffix:016x}", std::process::id()));
+        match create_private_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to create the directory `{}`", dir.display()))
+            }
+        }
+    }
+    bail!(
+        "failed to create a new directory in `{}`: all names tried exist",
+        temp_dir.display()
+    )
+}
+
+/// Create `dir`, which must not exist yet; on unix only the current user may access it.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+enum Source<'a> {
+    Stdin,
+    Url(&'a str),
+}
+
+impl Source<'_> {
+    /// The name of the temporary file: the last segment of the URL, like `curl -O` names it
+    fn file_name(&self) -> String {
+        let name = match self {
+            Source::Stdin => None,
+            Source::Url(url) => url
+                .split(['?', '#'])
+                .next()
+                .and_then(|url| url.split_once("://"))
+                .and_then(|(_, rest)| rest.split_once('/'))
+                .and_then(|(_host, path)| path.rsplit('/').next())
+                .filter(|name| !name.is_empty()),
+        };
+        name.unwrap_or("firmware.elf").to_string()
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::Stdin => "stdin".to_string(),
+            Source::Url(url) => format!("`{url}`"),
+        }
+    }
+}
+
+fn download(url: &str, path: &Path) -> anyhow::Result<()> {
+    let status = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(path)
+        .arg(url)
+        .status()
+        .context("failed to run `curl`, which downloads ELFs given by URL; is it installed?")?;
+    if !status.success() {
+        bail!("failed to download `{url}`: `curl` exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::file("https://ci.example.com/artifacts/hello", "hello")]
+    #[case::query("https://ci.example.com/hello.elf?token=abc", "hello.elf")]
+    #[case::trailing_slash("https://ci.example.com/artifacts/", "firmware.elf")]
+    #[case::host_only("http://localhost:8000", "firmware.elf")]
+    #[case::domain_only("https://example.com", "firmware.elf")]
+    fn names_downloaded_file(#[case] url: &str, #[case] expected: &str) {
+        assert_eq!(Source::Url(url).file_name(), expected);
+    }
+
+    #[test]
+    fn creates_fresh_temp_dirs() {
+        let first = create_temp_dir().unwrap();
+        let second = create_temp_dir().unwrap();
+        assert_ne!(first, second);
+        assert!(first.is_dir() && second.is_dir());
+        // an existing directory is an error, not reused
+        assert!(create_private_dir(&first).is_err());
+        fs::remove_dir(first).unwrap();
+        fs::remove_dir(second).unwrap();
+    }
+
+    #[test]
+    fn leaves_paths_alone() {
+        assert!(fetch(Path::new("target/thumbv7em-none-eabihf/debug/hello"))
+            .unwrap()
+            .is_none());
+    }
+}
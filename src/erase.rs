@@ -0,0 +1,192 @@
+//! `--erase-sectors`: erase chosen flash ranges along with flashing the program, instead of all
+//! or nothing
+//!
+//! The ranges are handed to the flash loader as erased bytes, so that it erases their sectors and
+//! leaves them blank, in the same pass that programs the ELF.
+
+use std::{ops::Range, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use object::{Object as _, ObjectSection as _};
+use probe_rs::config::MemoryRegion;
+
+use crate::{cli, flash_plan};
+
+/// A flash range given as `<start>..<end>`, or by the name of an ELF section (e.g. `.settings`)
+/// or of a flash region of the chip (e.g. `UICR`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlashSpec {
+    Name(String),
+    Range(Range<u64>),
+}
+
+impl FromStr for FlashSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("..") {
+            Some((start, end)) => {
+                let range = u64::from(cli::parse_u32(start)?)..u64::from(cli::parse_u32(end)?);
+                if range.is_empty() {
+                    bail!("`{s}` is an empty address range");
+                }
+                Ok(FlashSpec::Range(range))
+            }
+            None if s.is_empty() => bail!("expected an address range or a region name"),
+            None => Ok(FlashSpec::Name(s.to_string())),
+        }
+    }
+}
+
+impl FlashSpec {
+    fn resolve(&self, elf_bytes: &[u8], memory_map: &[MemoryRegion]) -> anyhow::Result<Range<u64>> {
+        let range = match self {
+            FlashSpec::Range(range) => range.clone(),
+            FlashSpec::Name(name) => match elf_section(elf_bytes, name)? {
+                Some(range) => range,
+                None => flash_regions(memory_map)
+                    .find(|(region_name, _)| region_name.eq_ignore_ascii_case(name))
+                    .map(|(_, range)| range)
+                    .ok_or_else(|| {
+                        anyhow!("no ELF section or flash region named `{name}` to erase")
+                    })?,
+            },
+        };
+
+        if !is_flash(&range, memory_map) {
+            bail!("{range:#010X?} (`--erase-sectors`) is not in the flash memory of the chip");
+        }
+        Ok(range)
+    }
+}
+
+/// Resolve `specs` to address ranges in flash.
+pub fn resolve(
+    specs: &[FlashSpec],
+    elf_bytes: &[u8],
+    target: &probe_rs::Target,
+) -> anyhow::Result<Vec<Range<u64>>> {
+    specs
+        .iter()
+        .map(|spec| spec.resolve(elf_bytes, &target.memory_map))
+        .collect()
+}
+
+/// Stage erased bytes for the parts of `ranges` which the program doesn't occupy; the flash
+/// loader then erases the sectors of `ranges` and leaves them blank.
+pub fn stage(
+    loader: &mut probe_rs::flashing::FlashLoader,
+    target: &probe_rs::Target,
+    ranges: &[Range<u64>],
+    segments: &[Range<u64>],
+) -> anyhow::Result<()> {
+    for range in subtract(ranges, segments) {
+        log::info!("erasing flash {range:#010X?}");
+        let erased_byte_value = flash_plan::erased_byte_value(target, range.start);
+        loader.add_data(
+            range.start,
+            &vec![erased_byte_value; (range.end - range.start) as usize],
+        )?;
+    }
+    Ok(())
+}
+
+/// The address range of the ELF section `name`, with or without its leading dot
+fn elf_section(elf_bytes: &[u8], name: &str) -> anyhow::Result<Option<Range<u64>>> {
+    let elf = object::File::parse(elf_bytes)?;
+    let section = elf.sections().find(|section| {
+        section.name().map_or(false, |section_name| {
+            section_name == name || section_name.strip_prefix('.') == Some(name)
+        })
+    });
+    Ok(section
+        .map(|section| section.address()..section.address() + section.size())
+        .filter(|range| !range.is_empty()))
+}
+
+/// The flash regions in the memory map, with their names (`flash` if unnamed)
+fn flash_regions(memory_map: &[MemoryRegion]) -> impl Iterator<Item = (String, Range<u64>)> + '_ {
+    memory_map.iter().filter_map(|region| match region {
+        MemoryRegion::Nvm(nvm) => Some((
+            nvm.name.clone().unwrap_or_else(|| "flash".to_string()),
+            nvm.range.clone(),
+        )),
+        _ => None,
+    })
+}
+
+/// Returns `true` if flash regions, which may be adjacent, cover all of `range`.
+fn is_flash(range: &Range<u64>, memory_map: &[MemoryRegion]) -> bool {
+    let mut address = range.start;
+    while address < range.end {
+        match flash_regions(memory_map).find(|(_, region)| region.contains(&address)) {
+            Some((_, region)) => address = region.end,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The parts of `ranges`, which may overlap each other, not covered by `segments`
+pub fn subtract(ranges: &[Range<u64>], segments: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    let mut remaining: Vec<Range<u64>> = vec![];
+    for range in sorted {
+        match remaining.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => remaining.push(range),
+        }
+    }
+    for segment in segments {
+        remaining = remaining
+            .into_iter()
+            .flat_map(|range| {
+                [
+                    range.start..range.end.min(segment.start),
+                    range.start.max(segment.end)..range.end,
+                ]
+            })
+            .filter(|range| !range.is_empty())
+            .collect();
+    }
+    remaining
+}
+
+#[cfg(test)]
+// the slices in the tests are lists of ranges
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::range("0xF8000..0x100000", FlashSpec::Range(0xF8000..0x10_0000))]
+    #[case::name("settings", FlashSpec::Name("settings".to_string()))]
+    fn parses_spec(#[case] input: &str, #[case] expected: FlashSpec) {
+        assert_eq!(input.parse::<FlashSpec>().unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_empty_range() {
+        assert!("0x1000..0x1000".parse::<FlashSpec>().is_err());
+    }
+
+    #[rstest]
+    #[case::disjoint(&[0x2000..0x3000], &[0x1000..0x1800])]
+    #[case::overlapping_start(&[0x0800..0x1200], &[0x1200..0x1800])]
+    #[case::inside(&[0x1200..0x1400], &[0x1000..0x1200, 0x1400..0x1800])]
+    #[case::covering(&[0x0000..0x4000], &[])]
+    fn subtracts_segments(#[case] segments: &[Range<u64>], #[case] expected: &[Range<u64>]) {
+        assert_eq!(subtract(&[0x1000..0x1800], segments), expected);
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            subtract(&[0x1400..0x2000, 0x1000..0x1800], &[]),
+            [0x1000..0x2000]
+        );
+    }
+}
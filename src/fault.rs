@@ -0,0 +1,164 @@
+//! The fault status registers of the System Control Block, which tell why the core faulted, and
+//! the exception it is handling (see the `backtrace` subcommand)
+
+use std::io::{self, Write as _};
+
+use probe_rs::CoreType;
+
+use crate::{
+    cli::{JsonFormat, Opts},
+    cortexm,
+    registers::XPSR,
+    transport::Transport,
+};
+
+/// Configurable Fault Status Register: MemManage, BusFault and UsageFault status
+const CFSR: u32 = 0xE000_ED28;
+/// HardFault Status Register
+const HFSR: u32 = 0xE000_ED2C;
+/// MemManage Fault Address Register
+const MMFAR: u32 = 0xE000_ED34;
+/// BusFault Address Register
+const BFAR: u32 = 0xE000_ED38;
+
+const IPSR_MASK: u32 = 0x1FF;
+
+/// Bit numbers and names of the CFSR flags
+const CFSR_FLAGS: &[(u32, &str)] = &[
+    (0, "IACCVIOL"),
+    (1, "DACCVIOL"),
+    (3, "MUNSTKERR"),
+    (4, "MSTKERR"),
+    (5, "MLSPERR"),
+    (7, "MMARVALID"),
+    (8, "IBUSERR"),
+    (9, "PRECISERR"),
+    (10, "IMPRECISERR"),
+    (11, "UNSTKERR"),
+    (12, "STKERR"),
+    (13, "LSPERR"),
+    (15, "BFARVALID"),
+    (16, "UNDEFINSTR"),
+    (17, "INVSTATE"),
+    (18, "INVPC"),
+    (19, "NOCP"),
+    (20, "STKOF"),
+    (24, "UNALIGNED"),
+    (25, "DIVBYZERO"),
+];
+const MMARVALID: u32 = 1 << 7;
+const BFARVALID: u32 = 1 << 15;
+
+/// Bit numbers and names of the HFSR flags
+const HFSR_FLAGS: &[(u32, &str)] = &[(1, "VECTTBL"), (30, "FORCED"), (31, "DEBUGEVT")];
+
+pub struct FaultRegisters {
+    /// The number of the active exception, from the IPSR
+    exception: u32,
+    /// CFSR and HFSR; ARMv6-M has neither
+    status: Option<(u32, u32)>,
+    /// MMFAR, if CFSR marks it valid
+    mmfar: Option<u32>,
+    /// BFAR, if CFSR marks it valid
+    bfar: Option<u32>,
+}
+
+impl FaultRegisters {
+    /// Read the registers of the halted `core`.
+    pub fn read(core: &mut dyn Transport, core_type: CoreType) -> anyhow::Result<Self> {
+        let exception = core.read_core_reg(XPSR)? & IPSR_MASK;
+        if core_type == CoreType::Armv6m {
+            return Ok(Self {
+                exception,
+                status: None,
+                mmfar: None,
+                bfar: None,
+            });
+        }
+
+        let cfsr = core.read_word_32(CFSR.into())?;
+        let hfsr = core.read_word_32(HFSR.into())?;
+        let mmfar = match cfsr & MMARVALID {
+            0 => None,
+            _ => Some(core.read_word_32(MMFAR.into())?),
+        };
+        let bfar = match cfsr & BFARVALID {
+            0 => None,
+            _ => Some(core.read_word_32(BFAR.into())?),
+        };
+        Ok(Self {
+            exception,
+            status: Some((cfsr, hfsr)),
+            mmfar,
+            bfar,
+        })
+    }
+
+    pub fn print(&self, opts: &Opts) -> io::Result<()> {
+        let exception = cortexm::exception_name(self.exception as u16);
+        match (opts.json, opts.json_format) {
+            (true, JsonFormat::Lines) => {
+                let (cfsr, hfsr) = self.status.unzip();
+                let event = serde_json::json!({
+                    "event": "fault_registers",
+                    "exception": exception,
+                    "cfsr": cfsr,
+                    "cfsr_flags": cfsr.map(|cfsr| decode(cfsr, CFSR_FLAGS)),
+                    "hfsr": hfsr,
+                    "hfsr_flags": hfsr.map(|hfsr| decode(hfsr, HFSR_FLAGS)),
+                    "mmfar": self.mmfar,
+                    "bfar": self.bfar,
+                });
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer(&mut stdout, &event)?;
+                writeln!(stdout)?;
+                stdout.flush()
+            }
+            _ => {
+                log::info!("the core is in {exception}");
+                if let Some((cfsr, hfsr)) = self.status {
+                    log::info!("HFSR = {hfsr:#010X} ({})", flags(hfsr, HFSR_FLAGS));
+                    log::info!("CFSR = {cfsr:#010X} ({})", flags(cfsr, CFSR_FLAGS));
+                }
+                if let Some(mmfar) = self.mmfar {
+                    log::info!("MMFAR = {mmfar:#010X}");
+                }
+                if let Some(bfar) = self.bfar {
+                    log::info!("BFAR = {bfar:#010X}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The names of the flags set in `value`
+fn decode(value: u32, flags: &[(u32, &'static str)]) -> Vec<&'static str> {
+    flags
+        .iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn flags(value: u32, flags: &[(u32, &'static str)]) -> String {
+    match decode(value, flags) {
+        names if names.is_empty() => "no flags set".to_string(),
+        names => names.join(", "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::precise_bus_fault(0x0000_8200, &["PRECISERR", "BFARVALID"])]
+    #[case::divide_by_zero(0x0200_0000, &["DIVBYZERO"])]
+    #[case::none(0, &[])]
+    fn decodes_cfsr(#[case] cfsr: u32, #[case] expected: &[&str]) {
+        assert_eq!(decode(cfsr, CFSR_FLAGS), expected);
+    }
+}
@@ -0,0 +1,226 @@
+//! `--flash-loader`: flash memory the chip description doesn't know, e.g. external QSPI flash,
+//! with a flash algorithm supplied at runtime
+
+use std::{fs, ops::Range, path::Path};
+
+use anyhow::{bail, Context as _};
+use probe_rs::config::{MemoryRegion, NvmRegion, RawFlashAlgorithm};
+
+/// The stack probe-rs gives flash algorithms which don't ask for a size
+const DEFAULT_STACK_SIZE: u64 = 512;
+
+/// Read the flash algorithm at `path`, in the format of the `flash_algorithms` of a chip
+/// description.
+pub fn load(path: &Path) -> anyhow::Result<RawFlashAlgorithm> {
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "elf")
+    {
+        bail!(
+            "`--flash-loader` takes a flash algorithm in YAML; convert `{}` with `target-gen elf`",
+            path.display()
+        );
+    }
+    let yaml =
+        fs::read_to_string(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    serde_yaml::from_str(&yaml).with_context(|| {
+        format!(
+            "failed to parse the flash algorithm in `{}`",
+            path.display()
+        )
+    })
+}
+
+/// Add `algorithm` to `target`, along with a flash region for the memory it programs.
+pub fn register(
+    target: &mut probe_rs::Target,
+    mut algorithm: RawFlashAlgorithm,
+) -> anyhow::Result<()> {
+    check_ram(&algorithm, &target.memory_map)?;
+
+    let core_names = target
+        .cores
+        .iter()
+        .map(|core| core.name.clone())
+        .collect::<Vec<_>>();
+    if algorithm.cores.is_empty() {
+        algorithm.cores = core_names.clone();
+    }
+
+    let flash = algorithm.flash_properties.address_range.clone();
+    let covered = target.memory_map.iter().any(|region| match region {
+        MemoryRegion::Nvm(region) => {
+            region.range.start <= flash.start && flash.end <= region.range.end
+        }
+        _ => false,
+    });
+    if !covered {
+        if let Some(overlapped) = target
+            .memory_map
+            .iter()
+            .find(|region| overlaps(region_range(region), &flash))
+        {
+            bail!(
+                "the flash of `--flash-loader` algorithm `{}` ({:#010x}..{:#010x}) overlaps the \
+                chip's memory region {:#010x?}",
+                algorithm.name,
+                flash.start,
+                flash.end,
+                region_range(overlapped)
+            );
+        }
+        target.memory_map.push(MemoryRegion::Nvm(NvmRegion {
+            name: Some(algorithm.name.clone()),
+            range: flash.clone(),
+            is_boot_memory: false,
+            cores: core_names,
+        }));
+    }
+
+    // probe-rs picks the default algorithm if several cover a region
+    algorithm.default = true;
+    for other in &mut target.flash_algorithms {
+        let other_flash = &other.flash_properties.address_range;
+        if overlaps(other_flash, &flash) {
+            other.default = false;
+        }
+    }
+    log::debug!(
+        "registered flash algorithm `{}` for {:#010x}..{:#010x}",
+        algorithm.name,
+        flash.start,
+        flash.end
+    );
+    target.flash_algorithms.push(algorithm);
+    Ok(())
+}
+
+/// Check that the chip has RAM for the code, stack and a page buffer of `algorithm`, at its load
+/// address if it has one.
+fn check_ram(algorithm: &RawFlashAlgorithm, memory_map: &[MemoryRegion]) -> anyhow::Result<()> {
+    let needed = algorithm.instructions.len() as u64
+        + algorithm.stack_size.map_or(DEFAULT_STACK_SIZE, u64::from)
+        + u64::from(algorithm.flash_properties.page_size);
+    let mut ram = memory_map.iter().filter_map(|region| match region {
+        MemoryRegion::Ram(region) => Some(&region.range),
+        _ => None,
+    });
+
+    let fits = match algorithm.load_address {
+        Some(start) => ram.any(|range| range.start <= start && start + needed <= range.end),
+        None => ram.any(|range| range.end - range.start >= needed),
+    };
+    if !fits {
+        match algorithm.load_address {
+            Some(start) => bail!(
+                "`--flash-loader` algorithm `{}` needs {needed} bytes of RAM at {start:#010x}, \
+                which the chip doesn't have",
+                algorithm.name
+            ),
+            None => bail!(
+                "`--flash-loader` algorithm `{}` needs {needed} bytes of RAM, more than any RAM \
+                region of the chip has",
+                algorithm.name
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn region_range(region: &MemoryRegion) -> &Range<u64> {
+    match region {
+        MemoryRegion::Ram(region) => &region.range,
+        MemoryRegion::Generic(region) => &region.range,
+        MemoryRegion::Nvm(region) => &region.range,
+    }
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::config::{FlashProperties, RamRegion};
+    use rstest::rstest;
+
+    use super::*;
+
+    fn memory_map() -> Vec<MemoryRegion> {
+        vec![
+            MemoryRegion::Nvm(NvmRegion {
+                name: Some("FLASH".into()),
+                range: 0x0800_0000..0x0810_0000,
+                is_boot_memory: true,
+                cores: vec!["main".into()],
+            }),
+            MemoryRegion::Ram(RamRegion {
+                name: Some("SRAM".into()),
+                range: 0x2000_0000..0x2000_2000,
+                is_boot_memory: false,
+                cores: vec!["main".into()],
+            }),
+        ]
+    }
+
+    fn algorithm(load_address: Option<u64>, instructions: usize) -> RawFlashAlgorithm {
+        RawFlashAlgorithm {
+            name: "qspi".into(),
+            instructions: vec![0; instructions],
+            load_address,
+            flash_properties: FlashProperties {
+                address_range: 0x9000_0000..0x9100_0000,
+                page_size: 0x100,
+                ..FlashProperties::default()
+            },
+            ..RawFlashAlgorithm::default()
+        }
+    }
+
+    #[rstest]
+    #[case::pic(None, 0x1000, true)]
+    #[case::pic_too_large(None, 0x2000, false)]
+    #[case::load_address(Some(0x2000_1000), 0x800, true)]
+    #[case::load_address_past_end(Some(0x2000_1c00), 0x800, false)]
+    #[case::load_address_outside_ram(Some(0x1000_0000), 0x800, false)]
+    fn checks_ram(
+        #[case] load_address: Option<u64>,
+        #[case] instructions: usize,
+        #[case] fits: bool,
+    ) {
+        let algorithm = algorithm(load_address, instructions);
+        assert_eq!(check_ram(&algorithm, &memory_map()).is_ok(), fits);
+    }
+
+    #[test]
+    fn parses_chip_description_format() {
+        let algorithm: RawFlashAlgorithm = serde_yaml::from_str(
+            "
+            name: qspi
+            description: W25Q128 on QSPI
+            instructions: AAAAAA==
+            pc_init: 0x1
+            pc_uninit: 0x41
+            pc_program_page: 0x81
+            pc_erase_sector: 0xc1
+            data_section_offset: 0x100
+            flash_properties:
+              address_range:
+                start: 0x90000000
+                end: 0x91000000
+              page_size: 0x100
+              erased_byte_value: 0xff
+              program_page_timeout: 100
+              erase_sector_timeout: 1000
+              sectors:
+                - size: 0x1000
+                  address: 0x0
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            algorithm.flash_properties.address_range,
+            0x9000_0000..0x9100_0000
+        );
+    }
+}
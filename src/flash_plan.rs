@@ -0,0 +1,399 @@
+//! What flashing will do, worked out from the ELF and the chip description alone
+
+use std::{ops::Range, time::Duration};
+
+use object::{
+    elf::{FileHeader32, PT_LOAD},
+    read::elf::{FileHeader as _, ProgramHeader as _},
+    Endianness,
+};
+use probe_rs::config::{FlashProperties, MemoryRegion, NvmRegion, RawFlashAlgorithm};
+
+pub struct Plan {
+    chip: String,
+    /// `None` without `--erase-all`
+    erase_all: Option<MassErase>,
+    regions: Vec<RegionPlan>,
+}
+
+/// What `--erase-all` erases
+struct MassErase {
+    num_bytes: u64,
+    estimate: Duration,
+    /// Flashing erases all sectors of the flash anyway
+    redundant: bool,
+}
+
+struct RegionPlan {
+    name: String,
+    algorithm: String,
+    layout: Layout,
+    estimate: Duration,
+}
+
+/// Sectors to erase and pages to program, for one flash algorithm
+#[derive(Debug, PartialEq, Eq)]
+struct Layout {
+    sectors: Vec<Range<u64>>,
+    page_size: u64,
+    num_pages: usize,
+    num_bytes: u64,
+}
+
+/// Work out which flash sectors and pages the program in `elf_bytes` is written to, and which
+/// the `erase_ranges` of `--erase-sectors` are blanked in.
+pub fn plan(
+    elf_bytes: &[u8],
+    target: &probe_rs::Target,
+    erase_all: bool,
+    erase_ranges: &[Range<u64>],
+) -> anyhow::Result<Plan> {
+    let mut segments = loadable_segments(elf_bytes)?;
+    segments.extend_from_slice(erase_ranges);
+
+    let mut regions = vec![];
+    let mut mass_erase = MassErase {
+        num_bytes: 0,
+        estimate: Duration::ZERO,
+        redundant: true,
+    };
+    for region in &target.memory_map {
+        let MemoryRegion::Nvm(region) = region else {
+            continue;
+        };
+
+        let segments = segments
+            .iter()
+            .filter_map(|segment| intersect(segment, &region.range))
+            .collect::<Vec<_>>();
+        if segments.is_empty() && !erase_all {
+            continue;
+        }
+
+        let Some(algorithm) = flash_algorithm(region, &target.flash_algorithms) else {
+            log::debug!("no flash algorithm for region {:#010X?}", region.range);
+            continue;
+        };
+        let properties = &algorithm.flash_properties;
+        let layout = layout(properties, &segments);
+        if erase_all {
+            let (all_sectors, estimate) = mass_erase_estimate(properties, &region.range);
+            mass_erase.num_bytes += region.range.end - region.range.start;
+            mass_erase.estimate += estimate;
+            mass_erase.redundant &= layout.sectors == all_sectors;
+        }
+        if segments.is_empty() {
+            continue;
+        }
+
+        let estimate = Duration::from_millis(
+            layout.sectors.len() as u64 * u64::from(properties.erase_sector_timeout)
+                + layout.num_pages as u64 * u64::from(properties.program_page_timeout),
+        );
+
+        regions.push(RegionPlan {
+            name: region.name.clone().unwrap_or_else(|| "flash".to_string()),
+            algorithm: algorithm.name.clone(),
+            layout,
+            estimate,
+        });
+    }
+
+    Ok(Plan {
+        chip: target.name.clone(),
+        erase_all: erase_all.then_some(mass_erase),
+        regions,
+    })
+}
+
+impl Plan {
+    /// The size of the flash `--erase-all` erases and how long it takes at most, unless flashing
+    /// erases all of it anyway.
+    pub fn mass_erase(&self) -> Option<(u64, Duration)> {
+        self.erase_all
+            .as_ref()
+            .filter(|mass_erase| !mass_erase.redundant)
+            .map(|mass_erase| (mass_erase.num_bytes, mass_erase.estimate))
+    }
+
+    /// Log a one-line summary.
+    pub fn log(&self) {
+        if self
+            .erase_all
+            .as_ref()
+            .map_or(false, |mass_erase| mass_erase.redundant)
+        {
+            log::info!("skipping `--erase-all`: flashing erases all of the flash anyway");
+        }
+        let num_sectors = self
+            .regions
+            .iter()
+            .map(|r| r.layout.sectors.len())
+            .sum::<usize>();
+        let num_bytes = self.regions.iter().map(|r| r.layout.num_bytes).sum::<u64>();
+        let estimate = self.regions.iter().map(|r| r.estimate).sum::<Duration>();
+        log::info!(
+            "flash plan: erase {num_sectors} sectors, program {:.02} KiB (at most ~{:.1}s)",
+            num_bytes as f64 / 1024.0,
+            estimate.as_secs_f64()
+        );
+    }
+
+    /// Returns `true` if flashing erases any part of `range`.
+    pub fn erases(&self, range: &Range<u64>) -> bool {
+        self.mass_erase().is_some()
+            || self.regions.iter().any(|region| {
+                region
+                    .layout
+                    .sectors
+                    .iter()
+                    .any(|sector| intersect(sector, range).is_some())
+            })
+    }
+
+    /// Print the plan in detail.
+    pub fn print(&self) {
+        println!("flash plan for {}:", self.chip);
+        match &self.erase_all {
+            Some(mass_erase) if mass_erase.redundant => {
+                println!("  skip `--erase-all`: flashing erases all of the flash anyway")
+            }
+            Some(mass_erase) => println!(
+                "  mass-erase all nonvolatile memory ({:.02} KiB, at most ~{:.1}s)",
+                mass_erase.num_bytes as f64 / 1024.0,
+                mass_erase.estimate.as_secs_f64()
+            ),
+            None => {}
+        }
+        if self.regions.is_empty() {
+            println!("  nothing to flash");
+        }
+
+        for region in &self.regions {
+            let layout = &region.layout;
+            let erase_size = layout.sectors.iter().map(|s| s.end - s.start).sum::<u64>();
+            println!(
+                "  region `{}` (flash algorithm `{}`)",
+                region.name, region.algorithm
+            );
+            println!(
+                "    erase {} sectors ({:.02} KiB):",
+                layout.sectors.len(),
+                erase_size as f64 / 1024.0
+            );
+            for range in merge_adjacent(&layout.sectors) {
+                println!("      {:#010X}..{:#010X}", range.start, range.end);
+            }
+            println!(
+                "    program {:.02} KiB in {} pages of {} bytes",
+                layout.num_bytes as f64 / 1024.0,
+                layout.num_pages,
+                layout.page_size
+            );
+            println!(
+                "    estimated time: at most ~{:.1}s",
+                region.estimate.as_secs_f64()
+            );
+        }
+    }
+}
+
+/// The physical address ranges of the `PT_LOAD` segments with data, as the flash loader sees them.
+pub fn loadable_segments(elf_bytes: &[u8]) -> anyhow::Result<Vec<Range<u64>>> {
+    let header = FileHeader32::<Endianness>::parse(elf_bytes)?;
+    let endian = header.endian()?;
+
+    let segments = header
+        .program_headers(endian, elf_bytes)?
+        .iter()
+        .filter(|segment| segment.p_type(endian) == PT_LOAD && segment.p_filesz(endian) != 0)
+        .map(|segment| {
+            let start = u64::from(segment.p_paddr(endian));
+            start..start + u64::from(segment.p_filesz(endian))
+        })
+        .collect();
+    Ok(segments)
+}
+
+/// The value of erased bytes at `address`; `0xFF` for most flash.
+pub fn erased_byte_value(target: &probe_rs::Target, address: u64) -> u8 {
+    target
+        .memory_map
+        .iter()
+        .find_map(|region| match region {
+            MemoryRegion::Nvm(region) if region.range.contains(&address) => {
+                flash_algorithm(region, &target.flash_algorithms)
+            }
+            _ => None,
+        })
+        .map_or(0xFF, |algorithm| {
+            algorithm.flash_properties.erased_byte_value
+        })
+}
+
+/// Pick the flash algorithm for `region` the way the flash loader does.
+fn flash_algorithm<'a>(
+    region: &NvmRegion,
+    algorithms: &'a [RawFlashAlgorithm],
+) -> Option<&'a RawFlashAlgorithm> {
+    let candidates = algorithms
+        .iter()
+        .filter(|algorithm| {
+            let range = &algorithm.flash_properties.address_range;
+            range.start <= region.range.start && region.range.end <= range.end
+        })
+        .collect::<Vec<_>>();
+
+    match candidates[..] {
+        [algorithm] => Some(algorithm),
+        _ => candidates.into_iter().find(|algorithm| algorithm.default),
+    }
+}
+
+fn layout(properties: &FlashProperties, segments: &[Range<u64>]) -> Layout {
+    let page_size = u64::from(properties.page_size);
+    let mut sectors: Vec<Range<u64>> = vec![];
+    let mut pages: Vec<u64> = vec![];
+
+    for segment in segments {
+        let mut address = segment.start;
+        while address < segment.end {
+            let Some(sector) = sector(properties, address) else {
+                break;
+            };
+            if !sectors.contains(&sector) {
+                sectors.push(sector.clone());
+            }
+            address = sector.end.min(segment.end);
+        }
+
+        let mut page = segment.start - segment.start % page_size;
+        while page < segment.end {
+            if !pages.contains(&page) {
+                pages.push(page);
+            }
+            page += page_size;
+        }
+    }
+    sectors.sort_by_key(|sector| sector.start);
+
+    Layout {
+        sectors,
+        page_size,
+        num_pages: pages.len(),
+        num_bytes: pages.len() as u64 * page_size,
+    }
+}
+
+/// All sectors of the flash `range`, and how long erasing them takes at most
+fn mass_erase_estimate(
+    properties: &FlashProperties,
+    range: &Range<u64>,
+) -> (Vec<Range<u64>>, Duration) {
+    let sectors = layout(properties, &[range.clone()]).sectors;
+    let estimate =
+        Duration::from_millis(sectors.len() as u64 * u64::from(properties.erase_sector_timeout));
+    (sectors, estimate)
+}
+
+/// The address range of the sector which contains `address`.
+fn sector(properties: &FlashProperties, address: u64) -> Option<Range<u64>> {
+    let flash = &properties.address_range;
+    if !flash.contains(&address) {
+        return None;
+    }
+
+    let offset = address - flash.start;
+    let description = properties
+        .sectors
+        .iter()
+        .rfind(|description| description.address <= offset)?;
+    let index = (offset - description.address) / description.size;
+    let start = flash.start + description.address + index * description.size;
+    Some(start..start + description.size)
+}
+
+fn intersect(a: &Range<u64>, b: &Range<u64>) -> Option<Range<u64>> {
+    let range = a.start.max(b.start)..a.end.min(b.end);
+    (!range.is_empty()).then_some(range)
+}
+
+fn merge_adjacent(ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut merged: Vec<Range<u64>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => merged.push(range.clone()),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::config::SectorDescription;
+
+    use super::*;
+
+    /// 16 KiB sectors followed by 64 KiB sectors, like the first STM32F4 flash bank
+    fn properties() -> FlashProperties {
+        FlashProperties {
+            address_range: 0x0800_0000..0x0810_0000,
+            page_size: 0x400,
+            sectors: vec![
+                SectorDescription {
+                    size: 0x4000,
+                    address: 0,
+                },
+                SectorDescription {
+                    size: 0x1_0000,
+                    address: 0x1_0000,
+                },
+            ],
+            ..FlashProperties::default()
+        }
+    }
+
+    #[test]
+    fn layout_spans_sectors_of_different_sizes() {
+        let segments = [0x0800_0000..0x0800_0100, 0x0800_bf00..0x0801_0100];
+
+        let layout = layout(&properties(), &segments);
+
+        assert_eq!(
+            layout,
+            Layout {
+                sectors: vec![
+                    0x0800_0000..0x0800_4000,
+                    0x0800_8000..0x0800_c000,
+                    0x0800_c000..0x0801_0000,
+                    0x0801_0000..0x0802_0000,
+                ],
+                page_size: 0x400,
+                num_pages: 19,
+                num_bytes: 19 * 0x400,
+            }
+        );
+    }
+
+    #[test]
+    fn estimates_mass_erase() {
+        let properties = FlashProperties {
+            erase_sector_timeout: 1000,
+            ..properties()
+        };
+
+        let (sectors, estimate) = mass_erase_estimate(&properties, &(0x0800_0000..0x0810_0000));
+
+        // 4 sectors of 16 KiB, then 15 of 64 KiB
+        assert_eq!(sectors.len(), 19);
+        assert_eq!(estimate, Duration::from_secs(19));
+    }
+
+    #[test]
+    fn merges_adjacent_sectors() {
+        let sectors = [0..0x4000, 0x4000..0x8000, 0x1_0000..0x2_0000];
+
+        assert_eq!(merge_adjacent(&sectors), [0..0x8000, 0x1_0000..0x2_0000]);
+    }
+}
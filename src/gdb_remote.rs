@@ -0,0 +1,348 @@
+//! `--via gdb:<host>:<port>`: access the target through a GDB server which owns the probe, e.g.
+//! `probe-rs gdb` or OpenOCD, with the GDB remote serial protocol
+//!
+//! In all-stop mode, the server only accesses the target while the core is halted; so reading
+//! the RTT channel halts the core briefly on every poll.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read as _, Write as _},
+    net::TcpStream,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::RegisterId;
+
+use crate::{registers, transport::Transport};
+
+/// How long to wait for a reply of the server
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most bytes read or written with a single packet
+const MAX_CHUNK: usize = 1024;
+/// Signal of the stop reply to an interrupt (`SIGINT`)
+const SIGNAL_INTERRUPT: u8 = 2;
+/// The GDB register number of xPSR in the ARM M-profile register set; `r0` to `r15` keep theirs
+const GDB_XPSR: u16 = 25;
+
+/// The value of `--via`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Via {
+    /// `gdb:<host>:<port>`
+    Gdb(String),
+}
+
+impl FromStr for Via {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("gdb", addr)) if addr.contains(':') => Ok(Via::Gdb(addr.to_string())),
+            _ => bail!("expected `gdb:<host>:<port>`, e.g. `gdb:localhost:3333`"),
+        }
+    }
+}
+
+/// Why the core halted, from a stop reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The core halted because we interrupted it
+    Interrupted,
+    /// The core halted by itself, e.g. on a breakpoint, with the given signal
+    Halted(u8),
+    /// The server ended the debug session
+    Exited,
+}
+
+impl Stop {
+    fn parse(reply: &str) -> anyhow::Result<Self> {
+        let signal = |hex: &str| {
+            u8::from_str_radix(hex.get(..2).unwrap_or(hex), 16)
+                .map_err(|_| anyhow!("malformed stop reply `{reply}`"))
+        };
+        Ok(match reply.split_at(reply.len().min(1)) {
+            ("S" | "T", rest) => match signal(rest)? {
+                SIGNAL_INTERRUPT => Stop::Interrupted,
+                signal => Stop::Halted(signal),
+            },
+            ("W" | "X", _) => Stop::Exited,
+            _ => bail!("unexpected reply `{reply}` instead of a stop reply"),
+        })
+    }
+}
+
+pub struct Client {
+    stream: TcpStream,
+    running: bool,
+}
+
+impl Client {
+    pub fn connect(addr: &str) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .with_context(|| format!("failed to connect to the GDB server at `{addr}`"))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        log::debug!("connected to the GDB server at `{addr}`");
+        Ok(Self {
+            stream,
+            running: false,
+        })
+    }
+
+    /// Why the core is halted; servers halt it when a client connects.
+    pub fn halt_reason(&mut self) -> anyhow::Result<Stop> {
+        Stop::parse(&self.request("?")?)
+    }
+
+    /// Let the core run until it halts or gets [`Client::interrupt`]ed.
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        self.send("c")?;
+        self.running = true;
+        Ok(())
+    }
+
+    /// Halt the running core; returns `Stop::Halted` if it halted by itself in the meantime.
+    pub fn interrupt(&mut self) -> anyhow::Result<Stop> {
+        if let Some(stop) = self.poll_stop()? {
+            return Ok(stop);
+        }
+        self.stream.write_all(&[0x03])?;
+        let stop = Stop::parse(&self.receive()?)?;
+        self.running = false;
+        Ok(stop)
+    }
+
+    /// End the session; the server lets the core run.
+    pub fn detach(mut self) -> anyhow::Result<()> {
+        if self.running {
+            self.interrupt()?;
+        }
+        self.request("D")?;
+        Ok(())
+    }
+
+    /// The stop reply, if the running core halted by itself.
+    fn poll_stop(&mut self) -> anyhow::Result<Option<Stop>> {
+        if !self.running {
+            return Ok(None);
+        }
+        self.stream.set_nonblocking(true)?;
+        let pending = self.stream.peek(&mut [0]);
+        self.stream.set_nonblocking(false)?;
+        match pending {
+            Ok(0) => bail!("the GDB server closed the connection"),
+            Ok(_) => {
+                self.running = false;
+                Ok(Some(Stop::parse(&self.receive()?)?))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Send `packet` and return the reply; fails on error replies (`Exx`).
+    fn request(&mut self, packet: &str) -> anyhow::Result<String> {
+        if self.running {
+            bail!("bug? accessed the target through the GDB server while the core runs");
+        }
+        self.send(packet)?;
+        let reply = self.receive()?;
+        match reply.strip_prefix('E') {
+            Some(code) if code.len() == 2 => {
+                bail!("the GDB server replied with error {code} to `{packet}`")
+            }
+            _ => Ok(reply),
+        }
+    }
+
+    fn send(&mut self, packet: &str) -> anyhow::Result<()> {
+        let framed = frame(packet);
+        loop {
+            self.stream.write_all(framed.as_bytes())?;
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => log::debug!("the GDB server asked to retransmit `{packet}`"),
+                byte => bail!("expected an acknowledgement from the GDB server, got {byte:#04x}"),
+            }
+        }
+    }
+
+    fn receive(&mut self) -> anyhow::Result<String> {
+        while self.read_byte()? != b'$' {}
+        let mut data = vec![];
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        let checksum = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+        if checksum != Some(sum(&data)) {
+            self.stream.write_all(b"-")?;
+            bail!("packet from the GDB server has a bad checksum");
+        }
+        self.stream.write_all(b"+")?;
+        Ok(String::from_utf8_lossy(&expand(&data)).into_owned())
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl Transport for Client {
+    fn read_core_reg(&mut self, reg: RegisterId) -> anyhow::Result<u32> {
+        let number = match reg {
+            registers::XPSR => GDB_XPSR,
+            RegisterId(number @ 0..=15) => number,
+            _ => bail!("register {reg:?} can't be read through a GDB server"),
+        };
+        let bytes = decode_hex(&self.request(&format!("p{number:x}"))?)?;
+        let bytes = bytes
+            .get(..4)
+            .ok_or_else(|| anyhow!("GDB server sent a short register value"))?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn read_8(&mut self, address: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        for (index, chunk) in data.chunks_mut(MAX_CHUNK).enumerate() {
+            let address = address + (index * MAX_CHUNK) as u64;
+            let bytes = decode_hex(&self.request(&format!("m{address:x},{:x}", chunk.len()))?)?;
+            if bytes.len() != chunk.len() {
+                bail!(
+                    "GDB server could only read {} of {} bytes at {address:#010x}",
+                    bytes.len(),
+                    chunk.len()
+                );
+            }
+            chunk.copy_from_slice(&bytes);
+        }
+        Ok(())
+    }
+
+    fn write_8(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()> {
+        for (index, chunk) in data.chunks(MAX_CHUNK).enumerate() {
+            let address = address + (index * MAX_CHUNK) as u64;
+            let mut packet = format!("M{address:x},{:x}:", chunk.len());
+            for byte in chunk {
+                write!(packet, "{byte:02x}").unwrap();
+            }
+            self.request(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+/// `$<packet>#<checksum>`
+fn frame(packet: &str) -> String {
+    format!("${packet}#{:02x}", sum(packet.as_bytes()))
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Undo the run-length encoding of a packet: `x*<n>` repeats `x` another `n - 29` times.
+fn expand(data: &[u8]) -> Vec<u8> {
+    let mut expanded = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match (byte, expanded.last().copied()) {
+            (b'*', Some(previous)) => {
+                let count = bytes.next().map_or(0, |count| count.saturating_sub(29));
+                expanded.extend(std::iter::repeat(previous).take(count.into()));
+            }
+            _ => expanded.push(byte),
+        }
+    }
+    expanded
+}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let malformed = || anyhow!("malformed hex data `{hex}` from the GDB server");
+    // on bytes, because a lossily decoded reply may contain multi-byte characters
+    let digit = |byte: u8| (byte as char).to_digit(16).ok_or_else(malformed);
+    let bytes = hex.as_bytes();
+    if bytes.len() % 2 != 0 {
+        return Err(malformed());
+    }
+    bytes
+        .chunks(2)
+        .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn frames_packet() {
+        assert_eq!(frame("m20000000,4"), "$m20000000,4#4f");
+    }
+
+    #[test]
+    fn expands_run_length_encoding() {
+        // `0* ` is `0` repeated 3 more times (` ` is 32)
+        assert_eq!(expand(b"0* 1"), b"00001");
+    }
+
+    #[rstest]
+    #[case::interrupted("T02thread:01;", Stop::Interrupted)]
+    #[case::breakpoint("S05", Stop::Halted(5))]
+    #[case::exited("W00", Stop::Exited)]
+    fn parses_stop_reply(#[case] reply: &str, #[case] expected: Stop) {
+        assert_eq!(Stop::parse(reply).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::gdb("gdb:localhost:3333", Some(Via::Gdb("localhost:3333".into())))]
+    #[case::no_port("gdb:localhost", None)]
+    #[case::unknown("openocd:localhost:4444", None)]
+    fn parses_via(#[case] input: &str, #[case] expected: Option<Via>) {
+        assert_eq!(input.parse::<Via>().ok(), expected);
+    }
+
+    #[rstest]
+    #[case::bytes("00ff7A", Some(vec![0x00, 0xff, 0x7a]))]
+    #[case::empty("", Some(vec![]))]
+    #[case::odd("0ff", None)]
+    #[case::not_hex("zz", None)]
+    #[case::multi_byte_char("0\u{e9}0", None)]
+    #[case::replacement_char("\u{fffd}", None)]
+    fn decodes_hex(#[case] hex: &str, #[case] expected: Option<Vec<u8>>) {
+        assert_eq!(decode_hex(hex).ok(), expected);
+    }
+
+    #[test]
+    fn reads_memory() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 16];
+            let n = stream.read(&mut request).unwrap();
+            assert_eq!(&request[..n], frame("m20000000,4").as_bytes());
+            stream.write_all(b"+").unwrap();
+            stream.write_all(frame("efbeadde").as_bytes()).unwrap();
+            let mut ack = [0];
+            stream.read_exact(&mut ack).unwrap();
+            assert_eq!(&ack, b"+");
+        });
+
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.read_word_32(0x2000_0000).unwrap(), 0xdead_beef);
+        server.join().unwrap();
+    }
+}
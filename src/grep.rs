@@ -0,0 +1,169 @@
+//! `--grep <regex>`: show only the defmt log lines which match, with `-A` / `-B` lines of context,
+//! like piping the output through `grep` without losing its colors
+
+use std::collections::VecDeque;
+
+use defmt_decoder::Frame;
+use defmt_parser::Level;
+use regex::Regex;
+
+use crate::{cli, location_cache::FrameLocation, log_annotations::Annotations};
+
+/// A decoded defmt frame, kept to be printed as context of a later match
+#[derive(Debug, PartialEq, Eq)]
+pub struct Line {
+    pub index: u64,
+    pub timestamp: Option<String>,
+    pub level: Option<Level>,
+    pub message: String,
+}
+
+impl Line {
+    pub fn new(frame: &Frame, annotations: Option<&Annotations>) -> Self {
+        Self {
+            index: frame.index(),
+            timestamp: frame.display_timestamp().map(|ts| ts.to_string()),
+            level: frame.level(),
+            message: annotations
+                .and_then(|annotations| annotations.message(frame))
+                .unwrap_or_else(|| frame.display_message().to_string()),
+        }
+    }
+
+    /// The text the patterns are matched against: the line as the default log format shows it,
+    /// e.g. `0.000100 INFO  hello └─ app::main @ src/main.rs:12`
+    fn text(&self, location: Option<&FrameLocation>) -> String {
+        let mut text = String::new();
+        if let Some(timestamp) = &self.timestamp {
+            text.push_str(timestamp);
+            text.push(' ');
+        }
+        if let Some(level) = self.level {
+            text.push_str(&format!("{:<5} ", level.as_str().to_uppercase()));
+        }
+        text.push_str(&self.message);
+        if let Some(location) = location {
+            let FrameLocation {
+                display_path,
+                line,
+                module,
+                ..
+            } = location;
+            text.push_str(&format!(" └─ {module} @ {display_path}:{line}"));
+        }
+        text
+    }
+}
+
+/// What to print for a line passed to [`Grep::push`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Shown<T> {
+    Line(T),
+    /// Lines were left out between the shown ones, like `grep` prints `--`
+    Separator,
+}
+
+pub struct Grep<T = Line> {
+    patterns: Vec<Regex>,
+    before: usize,
+    after: usize,
+    /// The last lines which didn't match, for the context before the next match
+    held: VecDeque<T>,
+    /// How many lines after the last match are still shown
+    after_left: usize,
+    /// Whether any line was shown yet
+    shown_any: bool,
+    /// Whether lines were left out since the last shown line
+    skipped: bool,
+}
+
+impl Grep {
+    pub fn new(opts: &cli::Opts) -> Option<Self> {
+        (!opts.grep.is_empty())
+            .then(|| Self::with_context(opts.grep.clone(), opts.before_context, opts.after_context))
+    }
+
+    /// Pass the next `line`; returns what to print now, in order.
+    pub fn push_line(&mut self, line: Line, location: Option<&FrameLocation>) -> Vec<Shown<Line>> {
+        let text = line.text(location);
+        self.push(&text, line)
+    }
+}
+
+impl<T> Grep<T> {
+    fn with_context(patterns: Vec<Regex>, before: usize, after: usize) -> Self {
+        Self {
+            patterns,
+            before,
+            after,
+            held: VecDeque::new(),
+            after_left: 0,
+            shown_any: false,
+            skipped: false,
+        }
+    }
+
+    /// Pass the next `line`, whose text is `text`; returns what to print now, in order.
+    fn push(&mut self, text: &str, line: T) -> Vec<Shown<T>> {
+        let mut shown = vec![];
+        if self.patterns.iter().any(|pattern| pattern.is_match(text)) {
+            if self.skipped && self.shown_any {
+                shown.push(Shown::Separator);
+            }
+            shown.extend(self.held.drain(..).map(Shown::Line));
+            shown.push(Shown::Line(line));
+            self.after_left = self.after;
+        } else if self.after_left > 0 {
+            self.after_left -= 1;
+            shown.push(Shown::Line(line));
+        } else {
+            self.held.push_back(line);
+            if self.held.len() > self.before {
+                self.held.pop_front();
+                self.skipped = true;
+            }
+            return shown;
+        }
+        self.shown_any = true;
+        self.skipped = false;
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Show `lines` through a grep for `x`; `|` stands for a separator
+    fn grep(lines: &str, before: usize, after: usize) -> String {
+        let mut grep = Grep::with_context(vec![Regex::new("x").unwrap()], before, after);
+        let mut output = String::new();
+        for line in lines.chars() {
+            for shown in grep.push(&line.to_string(), line) {
+                output.push(match shown {
+                    Shown::Line(line) => line,
+                    Shown::Separator => '|',
+                });
+            }
+        }
+        output
+    }
+
+    #[rstest]
+    #[case::no_context("abxcdxe", 0, 0, "x|x")]
+    #[case::before("abxcdxe", 1, 0, "bx|dx")]
+    #[case::after("abxcdxe", 0, 1, "xc|xe")]
+    #[case::overlapping("abxcdxe", 2, 2, "abxcdxe")]
+    #[case::adjacent_matches("axxb", 0, 0, "xx")]
+    #[case::no_match("abc", 1, 1, "")]
+    fn shows_matches_with_context(
+        #[case] lines: &str,
+        #[case] before: usize,
+        #[case] after: usize,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(grep(lines, before, after), expected);
+    }
+}
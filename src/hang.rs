@@ -0,0 +1,82 @@
+//! `--halt-on-silence`: halt a program which neither sent RTT data nor halted for a while, and
+//! show where it hangs (see `--on-hang`)
+
+use std::time::{Duration, Instant};
+
+pub struct HangDetector {
+    timeout: Duration,
+    /// The last output of the target, or when it was last found hung
+    last: Instant,
+}
+
+impl HangDetector {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last: Instant::now(),
+        }
+    }
+
+    /// Note that the target sent data, which shows that it isn't hung.
+    pub fn output(&mut self) {
+        self.last = Instant::now();
+    }
+
+    /// Returns how long the running target was silent, once that's the whole timeout; the
+    /// detector then starts over.
+    pub fn hung(&mut self, core_halted: bool) -> Option<Duration> {
+        self.hung_at(Instant::now(), core_halted)
+    }
+
+    fn hung_at(&mut self, now: Instant, core_halted: bool) -> Option<Duration> {
+        // a halted core is handled by the caller, e.g. as the end of the program
+        if core_halted {
+            self.last = now;
+            return None;
+        }
+        let silence = now.saturating_duration_since(self.last);
+        if silence < self.timeout {
+            return None;
+        }
+        self.last = now;
+        Some(silence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_silence_of_running_core() {
+        let mut detector = HangDetector::new(Duration::from_secs(10));
+        let start = detector.last;
+        assert_eq!(
+            detector.hung_at(start + Duration::from_secs(9), false),
+            None
+        );
+        assert_eq!(
+            detector.hung_at(start + Duration::from_secs(10), false),
+            Some(Duration::from_secs(10))
+        );
+        // starts over after a hang was found
+        assert_eq!(
+            detector.hung_at(start + Duration::from_secs(11), false),
+            None
+        );
+    }
+
+    #[test]
+    fn halted_core_is_not_hung() {
+        let mut detector = HangDetector::new(Duration::from_secs(10));
+        let start = detector.last;
+        assert_eq!(
+            detector.hung_at(start + Duration::from_secs(20), true),
+            None
+        );
+        assert_eq!(
+            detector.hung_at(start + Duration::from_secs(25), false),
+            None
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! A periodic status line while the target is silent, so that CI doesn't mistake a quiet program
+//! for a hang (see `--heartbeat`)
+
+use std::{
+    io::{self, Write as _},
+    time::{Duration, Instant},
+};
+
+use crate::cli::{JsonFormat, Opts};
+
+pub struct Heartbeat {
+    interval: Duration,
+    start: Instant,
+    /// The last output of the target, or the last heartbeat
+    last: Instant,
+    num_rtt_bytes: u64,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            start: now,
+            last: now,
+            num_rtt_bytes: 0,
+        }
+    }
+
+    /// Note that the target sent `num_bytes` over RTT, which postpones the next heartbeat.
+    pub fn output(&mut self, num_bytes: usize) {
+        self.num_rtt_bytes += num_bytes as u64;
+        self.last = Instant::now();
+    }
+
+    /// Print the status if the target was silent for the whole interval.
+    pub fn beat(&mut self, core_halted: bool, opts: &Opts) -> io::Result<()> {
+        if self.last.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last = Instant::now();
+
+        let elapsed = self.start.elapsed().as_secs();
+        let core = match core_halted {
+            true => "halted",
+            false => "running",
+        };
+        match (opts.json, opts.json_format) {
+            (false, _) => {
+                eprintln!("{}", status_line(elapsed, core, self.num_rtt_bytes));
+                Ok(())
+            }
+            (true, JsonFormat::Lines) => {
+                let event = serde_json::json!({
+                    "event": "heartbeat",
+                    "elapsed_secs": elapsed,
+                    "core": core,
+                    "rtt_bytes": self.num_rtt_bytes,
+                });
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer(&mut stdout, &event)?;
+                writeln!(stdout)?;
+                stdout.flush()
+            }
+            // defmt's versioned schema has no place for other events
+            (true, JsonFormat::Schema) => Ok(()),
+        }
+    }
+}
+
+fn status_line(elapsed_secs: u64, core: &str, num_rtt_bytes: u64) -> String {
+    format!("still running, {elapsed_secs} s elapsed, core {core}, {num_rtt_bytes} bytes RTT")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_status_line() {
+        assert_eq!(
+            status_line(120, "running", 0),
+            "still running, 120 s elapsed, core running, 0 bytes RTT"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! `--rtt-decoder hexdump`: print binary RTT data like `hexdump -C`, with offset, hex bytes and
+//! an ASCII gutter
+
+use std::fmt::Write as _;
+
+/// Bytes per group; groups are separated by an extra space
+const GROUP_SIZE: usize = 8;
+
+pub struct Hexdump {
+    width: usize,
+    /// Offset of the first byte in `pending` from the start of the stream
+    offset: u64,
+    /// The bytes of the current, incomplete row
+    pending: Vec<u8>,
+}
+
+impl Hexdump {
+    /// `width` is the number of bytes per row.
+    pub fn new(width: usize) -> Self {
+        Self {
+            width,
+            offset: 0,
+            pending: Vec::with_capacity(width),
+        }
+    }
+
+    /// Feed `bytes` into the dump and return the rows they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut rows = vec![];
+        for &byte in bytes {
+            self.pending.push(byte);
+            if self.pending.len() == self.width {
+                rows.push(self.take_row());
+            }
+        }
+        rows
+    }
+
+    /// Return the incomplete row, if any, e.g. when the stream ends.
+    pub fn flush(&mut self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| self.take_row())
+    }
+
+    fn take_row(&mut self) -> String {
+        let row = format_row(self.offset, &self.pending, self.width);
+        self.offset += self.pending.len() as u64;
+        self.pending.clear();
+        row
+    }
+}
+
+fn format_row(offset: u64, bytes: &[u8], width: usize) -> String {
+    let mut row = format!("{offset:08x} ");
+    for index in 0..width {
+        if index % GROUP_SIZE == 0 {
+            row.push(' ');
+        }
+        match bytes.get(index) {
+            Some(byte) => write!(row, "{byte:02x} ").unwrap(),
+            // pad incomplete rows, so that the ASCII gutter stays aligned
+            None => row.push_str("   "),
+        }
+    }
+
+    let ascii = bytes
+        .iter()
+        .map(|&byte| match byte {
+            0x20..=0x7e => byte as char,
+            _ => '.',
+        })
+        .collect::<String>();
+    write!(row, " |{ascii}|").unwrap();
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_full_row() {
+        assert_eq!(
+            format_row(0x10, b"Hello, world!\n\x00\xff", 16),
+            "00000010  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|"
+        );
+    }
+
+    #[test]
+    fn pads_incomplete_row() {
+        assert_eq!(
+            format_row(0, b"abc", 8),
+            "00000000  61 62 63                 |abc|"
+        );
+    }
+
+    #[test]
+    fn continues_offset_across_reads() {
+        let mut hexdump = Hexdump::new(4);
+
+        assert_eq!(hexdump.push(b"ab"), Vec::<String>::new());
+        let rows = hexdump.push(b"cdefghi");
+        let last = hexdump.flush();
+
+        assert_eq!(
+            rows,
+            [
+                "00000000  61 62 63 64  |abcd|",
+                "00000004  65 66 67 68  |efgh|"
+            ]
+        );
+        assert_eq!(last.as_deref(), Some("00000008  69           |i|"));
+    }
+}
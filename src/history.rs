@@ -0,0 +1,277 @@
+//! The resource usage of earlier runs, stored per project in `.probe-run/history.json`, and
+//! `--compare-last`, which reports how the current run differs from the previous one
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Opts;
+
+/// File (relative to the project directory) that stores the history.
+const HISTORY_PATH: &str = ".probe-run/history.json";
+
+/// How many runs the history keeps, over all ELFs
+const MAX_RUNS: usize = 100;
+
+/// The resources one run of a program consumed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub elf: PathBuf,
+    pub chip: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub flash_bytes: u64,
+    /// Lower bound of the stack usage; `None` if the stack wasn't measured
+    pub stack_bytes: Option<u64>,
+    pub duration_ms: u64,
+}
+
+impl Run {
+    pub fn new(
+        elf: &Path,
+        chip: &str,
+        flash_bytes: u64,
+        stack_bytes: Option<u64>,
+        duration: Duration,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        Self {
+            elf: elf.to_owned(),
+            chip: chip.to_string(),
+            timestamp,
+            flash_bytes,
+            stack_bytes,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    fn get(&self, metric: Metric) -> Option<u64> {
+        match metric {
+            Metric::Flash => Some(self.flash_bytes),
+            Metric::Stack => self.stack_bytes,
+            Metric::Duration => Some(self.duration_ms),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Metric {
+    Flash,
+    Stack,
+    Duration,
+}
+
+const METRICS: [Metric; 3] = [Metric::Flash, Metric::Stack, Metric::Duration];
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Flash => "flash",
+            Metric::Stack => "stack",
+            Metric::Duration => "duration",
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Metric::Flash | Metric::Stack => "bytes",
+            Metric::Duration => "ms",
+        }
+    }
+}
+
+/// How much a metric may grow compared to the last run (see `--fail-on-regression`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limit {
+    metric: Metric,
+    threshold: Threshold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Threshold {
+    Percent(f64),
+    /// In the unit of the metric
+    Absolute(u64),
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, threshold) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `<metric>=+<limit>`, e.g. `stack=+10%`"))?;
+        let metric = METRICS
+            .into_iter()
+            .find(|metric| metric.name() == name)
+            .ok_or_else(|| {
+                anyhow!("unknown metric `{name}`; expected `flash`, `stack` or `duration`")
+            })?;
+
+        let threshold = threshold.strip_prefix('+').unwrap_or(threshold);
+        let threshold = match threshold.strip_suffix('%') {
+            Some(percent) => match percent.parse::<f64>() {
+                Ok(percent) if percent >= 0.0 => Threshold::Percent(percent),
+                _ => bail!("`{threshold}` is not a valid percentage"),
+            },
+            None => Threshold::Absolute(
+                threshold
+                    .parse()
+                    .map_err(|_| anyhow!("`{threshold}` is not a valid limit"))?,
+            ),
+        };
+
+        Ok(Self { metric, threshold })
+    }
+}
+
+impl Limit {
+    /// Returns `true` if the metric grew by more than the threshold.
+    fn is_exceeded(&self, last: u64, current: u64) -> bool {
+        let growth = current.saturating_sub(last);
+        match self.threshold {
+            Threshold::Absolute(limit) => growth > limit,
+            // a metric which was 0 has no meaningful relative growth
+            Threshold::Percent(_) if last == 0 => false,
+            Threshold::Percent(limit) => growth as f64 / last as f64 * 100.0 > limit,
+        }
+    }
+}
+
+/// Compare `run` to the last run of the same ELF, if requested, and add it to the history.
+///
+/// Returns `true` if a metric regressed beyond its `--fail-on-regression` limit.
+pub fn update(run: Run, opts: &Opts) -> anyhow::Result<bool> {
+    let mut runs = load();
+
+    let compare = opts.compare_last || !opts.fail_on_regression.is_empty();
+    let last = runs.iter().rev().find(|last| last.elf == run.elf);
+    let regressed = match (compare, last) {
+        (true, Some(last)) => compare_runs(last, &run, &opts.fail_on_regression),
+        (true, None) => {
+            log::info!("no earlier run of this program to compare with");
+            false
+        }
+        (false, _) => false,
+    };
+
+    runs.push(run);
+    let excess = runs.len().saturating_sub(MAX_RUNS);
+    runs.drain(..excess);
+    if let Err(e) = store(&runs) {
+        log::warn!("failed to store the run history in `{HISTORY_PATH}`: {e}");
+    }
+
+    Ok(regressed)
+}
+
+/// Log the changes from `last` to `current`.
+///
+/// Returns `true` if a metric exceeded its limit.
+fn compare_runs(last: &Run, current: &Run, limits: &[Limit]) -> bool {
+    let mut regressed = false;
+    for metric in METRICS {
+        let (Some(last_value), Some(value)) = (last.get(metric), current.get(metric)) else {
+            continue;
+        };
+
+        let line = format!(
+            "{} {value} {} ({}, compared to the last run)",
+            metric.name(),
+            metric.unit(),
+            format_delta(last_value, value)
+        );
+        let exceeded = limits
+            .iter()
+            .filter(|limit| limit.metric == metric)
+            .any(|limit| limit.is_exceeded(last_value, value));
+        if exceeded {
+            log::error!("{line} exceeds the limit of `--fail-on-regression`");
+            regressed = true;
+        } else {
+            log::info!("{line}");
+        }
+    }
+    regressed
+}
+
+fn format_delta(last: u64, current: u64) -> String {
+    let delta = current as i64 - last as i64;
+    match last {
+        0 => format!("{delta:+}"),
+        _ => format!("{delta:+}, {:+.1}%", delta as f64 / last as f64 * 100.0),
+    }
+}
+
+fn load() -> Vec<Run> {
+    let Ok(json) = fs::read_to_string(HISTORY_PATH) else {
+        return vec![];
+    };
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        log::warn!("ignoring the run history in `{HISTORY_PATH}`, which is corrupted: {e}");
+        vec![]
+    })
+}
+
+fn store(runs: &[Run]) -> anyhow::Result<()> {
+    let path = Path::new(HISTORY_PATH);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(runs)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::percent("stack=+10%", Metric::Stack, Threshold::Percent(10.0))]
+    #[case::absolute("flash=+1024", Metric::Flash, Threshold::Absolute(1024))]
+    #[case::without_plus("duration=500", Metric::Duration, Threshold::Absolute(500))]
+    fn parses_limit(#[case] input: &str, #[case] metric: Metric, #[case] threshold: Threshold) {
+        assert_eq!(input.parse::<Limit>().unwrap(), Limit { metric, threshold });
+    }
+
+    #[rstest]
+    #[case::unknown_metric("heap=+10%")]
+    #[case::no_threshold("stack")]
+    #[case::negative("stack=-10%")]
+    fn rejects_invalid_limit(#[case] input: &str) {
+        assert!(input.parse::<Limit>().is_err());
+    }
+
+    #[rstest]
+    #[case::within_percent("stack=+10%", 1000, 1100, false)]
+    #[case::above_percent("stack=+10%", 1000, 1101, true)]
+    #[case::shrunk("stack=+10%", 1000, 500, false)]
+    #[case::from_zero("stack=+10%", 0, 500, false)]
+    #[case::above_absolute("flash=+100", 1000, 1101, true)]
+    fn checks_limit(
+        #[case] limit: &str,
+        #[case] last: u64,
+        #[case] current: u64,
+        #[case] expected: bool,
+    ) {
+        let limit = limit.parse::<Limit>().unwrap();
+        assert_eq!(limit.is_exceeded(last, current), expected);
+    }
+
+    #[test]
+    fn formats_delta() {
+        assert_eq!(format_delta(1000, 1100), "+100, +10.0%");
+        assert_eq!(format_delta(1000, 900), "-100, -10.0%");
+        assert_eq!(format_delta(0, 10), "+10");
+    }
+}
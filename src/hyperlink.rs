@@ -0,0 +1,131 @@
+//! Clickable source locations: OSC 8 hyperlinks around the paths in backtraces and defmt log
+//! locations (see `--hyperlinks`)
+
+use std::{
+    fmt::Write as _,
+    io::{self, IsTerminal as _},
+    path::Path,
+    sync::OnceLock,
+};
+
+use crate::cli::{Hyperlinks, Opts};
+
+static LINKS: OnceLock<Option<Links>> = OnceLock::new();
+
+pub struct Links {
+    /// The URL template of `--hyperlink-url`; `file://` URLs if `None`
+    url: Option<String>,
+}
+
+/// Decide whether to emit hyperlinks; call once, before the first location gets formatted.
+pub fn init(opts: &Opts) {
+    let enabled = match opts.hyperlinks {
+        Hyperlinks::Always => true,
+        Hyperlinks::Never => false,
+        // JSON consumers get paths, not terminal escape sequences
+        Hyperlinks::Auto => {
+            !opts.json
+                && io::stdout().is_terminal()
+                && io::stderr().is_terminal()
+                && colored::control::SHOULD_COLORIZE.should_colorize()
+        }
+    };
+    let links = enabled.then(|| Links {
+        url: opts.hyperlink_url.clone(),
+    });
+    // already initialized by an earlier call
+    let _ = LINKS.set(links);
+}
+
+/// Make `text` link to `path` at `line`, if hyperlinks are enabled.
+///
+/// Relative paths are relative to `current_dir`.
+pub fn wrap(
+    text: String,
+    path: &Path,
+    current_dir: &Path,
+    line: u32,
+    column: Option<u32>,
+) -> String {
+    match LINKS.get() {
+        Some(Some(links)) => links.wrap(&text, &current_dir.join(path), line, column),
+        _ => text,
+    }
+}
+
+impl Links {
+    fn wrap(&self, text: &str, path: &Path, line: u32, column: Option<u32>) -> String {
+        format!(
+            "\x1b]8;;{}\x1b\\{text}\x1b]8;;\x1b\\",
+            self.url(path, line, column)
+        )
+    }
+
+    fn url(&self, path: &Path, line: u32, column: Option<u32>) -> String {
+        let path = encode_path(path);
+        match &self.url {
+            Some(template) => template
+                .replace("{path}", &path)
+                .replace("{line}", &line.to_string())
+                .replace("{column}", &column.unwrap_or(1).to_string()),
+            // `file://` URLs have no way to point at a line
+            None => format!("file://{path}"),
+        }
+    }
+}
+
+/// Percent-encode `path` for use in a URL, with `/` separators and a leading `/`.
+fn encode_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut encoded = String::with_capacity(path.len() + 1);
+    if !path.starts_with('/') {
+        // Windows paths like `C:/…`
+        encoded.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                encoded.push(byte as char)
+            }
+            _ => write!(encoded, "%{byte:02X}").unwrap(),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::file(None, "file:///home/user/app/src/main.rs")]
+    #[case::vscode(
+        Some("vscode://file{path}:{line}:{column}"),
+        "vscode://file/home/user/app/src/main.rs:12:1"
+    )]
+    fn formats_url(#[case] template: Option<&str>, #[case] expected: &str) {
+        let links = Links {
+            url: template.map(String::from),
+        };
+        let url = links.url(Path::new("/home/user/app/src/main.rs"), 12, None);
+        assert_eq!(url, expected);
+    }
+
+    #[rstest]
+    #[case::space("/home/my app/main.rs", "/home/my%20app/main.rs")]
+    #[case::windows(r"C:\app\main.rs", "/C:/app/main.rs")]
+    fn encodes_path(#[case] path: &str, #[case] expected: &str) {
+        assert_eq!(encode_path(Path::new(path)), expected);
+    }
+
+    #[test]
+    fn wraps_text_in_osc8() {
+        let links = Links { url: None };
+        assert_eq!(
+            links.wrap("src/main.rs:3", Path::new("/app/src/main.rs"), 3, None),
+            "\x1b]8;;file:///app/src/main.rs\x1b\\src/main.rs:3\x1b]8;;\x1b\\"
+        );
+    }
+}
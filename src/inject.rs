@@ -0,0 +1,126 @@
+//! `--inject <action>@<symbol>`: change the program's state whenever it calls a function, to
+//! exercise its error paths from the host
+//!
+//! The actions are
+//! - `fault`: the function faults right away, as if it branched to a non-executable address
+//! - `<register>=<value>`, e.g. `r0=0`: overwrite a core register
+//! - `<symbol-or-addr>=<value>`: overwrite memory, like `--poke`
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, RegisterId};
+
+use crate::{cli, elf::Elf, poke, registers};
+
+/// Where `fault` makes the program branch to: the system region, which is never executable, so
+/// that fetching the instruction raises a fault
+const FAULT_ADDRESS: u32 = 0xEFFF_FFFE;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Injection {
+    action: Action,
+    symbol: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Action {
+    Fault,
+    Register(RegisterId, u32),
+    Memory(poke::Poke),
+}
+
+impl FromStr for Injection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, symbol) = s
+            .rsplit_once('@')
+            .filter(|(action, symbol)| !action.is_empty() && !symbol.is_empty())
+            .ok_or_else(|| anyhow!("expected `<action>@<symbol>`, e.g. `fault@read_sensor`"))?;
+        let action = match action.split_once('=') {
+            None if action == "fault" => Action::Fault,
+            None => bail!("unknown action `{action}`; expected `fault` or `<target>=<value>`"),
+            Some((target, value)) => match register(target) {
+                Some(register) => Action::Register(register, cli::parse_u32(value)?),
+                None => Action::Memory(action.parse()?),
+            },
+        };
+        Ok(Injection {
+            action,
+            symbol: symbol.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Injection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.action {
+            Action::Fault => write!(f, "a fault"),
+            Action::Register(RegisterId(number), value) => write!(f, "r{number} = {value:#x}"),
+            Action::Memory(poke) => write!(f, "`{}` = {:#x}", poke.location, poke.value),
+        }?;
+        write!(f, " at `{}`", self.symbol)
+    }
+}
+
+impl Injection {
+    /// The address of the function to inject at, for its breakpoint
+    pub fn address(&self, elf: &Elf) -> anyhow::Result<u32> {
+        let symbol = &self.symbol;
+        Ok(elf
+            .find_symbol(symbol)
+            .ok_or_else(|| anyhow!("`--inject` symbol `{symbol}` not found"))?
+            .start)
+    }
+
+    /// Inject into the program, which is halted at the beginning of the function.
+    pub fn apply(&self, core: &mut Core, elf: &Elf) -> anyhow::Result<()> {
+        match &self.action {
+            Action::Fault => core.write_core_reg(registers::PC, FAULT_ADDRESS)?,
+            Action::Register(register, value) => core.write_core_reg(*register, *value)?,
+            Action::Memory(poke) => poke::apply(core, elf, std::slice::from_ref(poke), &[])?,
+        }
+        log::info!("injected {self}");
+        Ok(())
+    }
+}
+
+/// The register called `name`: `r0` to `r12`, `sp`, `lr` or `pc`
+fn register(name: &str) -> Option<RegisterId> {
+    let number = match name {
+        "sp" => 13,
+        "lr" => 14,
+        "pc" => 15,
+        _ => name.strip_prefix('r')?.parse().ok().filter(|n| *n <= 12)?,
+    };
+    Some(RegisterId(number))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::fault("fault@app::read_sensor", Action::Fault)]
+    #[case::register("r0=0xff@read_sensor", Action::Register(RegisterId(0), 0xff))]
+    #[case::named_register("lr=0@read_sensor", Action::Register(RegisterId(14), 0))]
+    #[case::memory(
+        "ERRORS=3@read_sensor",
+        Action::Memory("ERRORS=3".parse().unwrap())
+    )]
+    fn parses_injection(#[case] input: &str, #[case] action: Action) {
+        let injection = input.parse::<Injection>().unwrap();
+        assert_eq!(injection.action, action);
+    }
+
+    #[rstest]
+    #[case::no_symbol("fault")]
+    #[case::empty_symbol("fault@")]
+    #[case::unknown_action("crash@read_sensor")]
+    fn rejects_malformed_injection(#[case] input: &str) {
+        assert!(input.parse::<Injection>().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! `probe-run backtrace`: print the backtrace of the program on the device and leave it as it was
+
+use std::{env, fs, path::Path};
+
+use anyhow::bail;
+
+use crate::{
+    backtrace, cli,
+    elf::Elf,
+    fault::FaultRegisters,
+    gdb_remote,
+    run::{attach_to_probe, init_logger, lookup_probe_target, TIMEOUT},
+    target_info::TargetInfo,
+};
+
+/// Attach to the device as is, print the backtrace of its program and the fault registers, and
+/// leave the device as it was: a running program resumes, a halted one stays halted.
+pub fn run(elf_path: &Path, chip_name: &str, opts: &cli::Opts) -> anyhow::Result<i32> {
+    if opts.connect_under_reset {
+        bail!("`backtrace` attaches without resetting the device; drop `--connect-under-reset`");
+    }
+    if let Some(gdb_remote::Via::Gdb(server)) = &opts.via {
+        return backtrace_via_gdb(elf_path, chip_name, opts, server);
+    }
+    let probe_target = lookup_probe_target(elf_path, chip_name, opts)?;
+    let mut sess = attach_to_probe(probe_target.clone(), opts)?;
+    let memory_map = sess.target().memory_map.clone();
+    let core = &mut sess.core(opts.core)?;
+
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = &Elf::parse_offline(&elf_bytes, elf_path)?;
+    let stack_start = elf.vector_table.initial_stack_pointer;
+    let target_info = TargetInfo::new(elf, memory_map, probe_target, opts.core, stack_start)?;
+    init_logger(elf, opts)?;
+
+    let was_halted = core.core_halted()?;
+    if !was_halted {
+        core.halt(TIMEOUT)?;
+    }
+    let result = (|| {
+        FaultRegisters::read(core, target_info.core_type())?.print(opts)?;
+        let mut settings = backtrace::Settings::new(env::current_dir()?, false, opts, false);
+        settings.backtrace = backtrace::BacktraceOptions::Always;
+        backtrace::print(core, elf, &target_info, &mut settings)
+    })();
+    // resume even if printing failed, as the device must be left as it was
+    if !was_halted {
+        core.run()?;
+    }
+    let outcome = result?;
+
+    match was_halted {
+        true => log::info!("detached from the device; the core stays halted"),
+        false => log::info!("detached from the device; the program keeps running"),
+    }
+    outcome.log();
+    Ok(outcome.exit_code(&opts.exit_code_map))
+}
+
+/// `backtrace` with `--via`: print the backtrace and the fault registers through the GDB server
+/// at `server`, which halts the core while a client is connected.
+fn backtrace_via_gdb(
+    elf_path: &Path,
+    chip_name: &str,
+    opts: &cli::Opts,
+    server: &str,
+) -> anyhow::Result<i32> {
+    let probe_target = lookup_probe_target(elf_path, chip_name, opts)?;
+    let memory_map = probe_target.memory_map.clone();
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = &Elf::parse_offline(&elf_bytes, elf_path)?;
+    let stack_start = elf.vector_table.initial_stack_pointer;
+    let target_info = TargetInfo::new(elf, memory_map, probe_target, opts.core, stack_start)?;
+    init_logger(elf, opts)?;
+
+    let mut client = gdb_remote::Client::connect(server)?;
+    client.halt_reason()?;
+    let result = (|| {
+        FaultRegisters::read(&mut client, target_info.core_type())?.print(opts)?;
+        let mut settings = backtrace::Settings::new(env::current_dir()?, false, opts, false);
+        settings.backtrace = backtrace::BacktraceOptions::Always;
+        backtrace::print(&mut client, elf, &target_info, &mut settings)
+    })();
+    // detaching lets the program run on
+    client.detach()?;
+    let outcome = result?;
+
+    log::info!("detached from the GDB server");
+    outcome.log();
+    Ok(outcome.exit_code(&opts.exit_code_map))
+}
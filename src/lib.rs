@@ -0,0 +1,80 @@
+//! The implementation of the `probe-run` and `cargo-probe-run` binaries; not a stable API
+
+mod arm_attributes;
+mod backtrace;
+mod canary;
+mod cargo;
+mod cli;
+mod cortexm;
+mod debug_session;
+mod defmt_encoding;
+mod dep;
+mod doctor;
+mod dwarf;
+mod dwarf_locations;
+mod elf;
+mod elf_source;
+mod erase;
+mod fault;
+mod flash_loader;
+mod flash_plan;
+mod gdb_remote;
+mod grep;
+mod hang;
+mod heartbeat;
+mod hexdump;
+mod history;
+mod hyperlink;
+mod inject;
+mod inspect;
+mod list_chips;
+mod location_cache;
+mod log_annotations;
+mod metrics;
+mod monitor;
+mod notify;
+mod output_queue;
+mod output_route;
+mod path_map;
+mod poke;
+mod poll;
+mod probe;
+mod probe_lock;
+mod project_config;
+mod quirks;
+mod ram_init;
+mod ram_map;
+mod raw_capture;
+mod registers;
+mod remap;
+mod repl;
+mod reset_reason;
+mod routine;
+mod rtt_locate;
+mod rtt_memory;
+mod rtt_overrun;
+mod rtt_terminal;
+mod run;
+mod sampling;
+mod schema;
+mod snapshot;
+mod stacked;
+mod suggest_chip;
+mod svc;
+mod target_args;
+mod target_info;
+mod test_manifest;
+mod theme;
+mod timestamp_check;
+mod trace;
+mod transport;
+mod vcp;
+mod vector_table;
+mod vtor;
+mod warnings;
+mod write_protection;
+
+pub use crate::{cargo::handle_arguments as handle_cargo_arguments, cli::handle_arguments};
+// the deprecation warning is meant for the binaries, which call it
+#[allow(deprecated)]
+pub use crate::cli::deprecated;
@@ -0,0 +1,155 @@
+//! `--list-chips`: the chips of the registry, optionally filtered by family and by a glob on the
+//! chip name, as text or as JSON for IDE chip pickers
+
+use anyhow::anyhow;
+use glob::{MatchOptions, Pattern};
+use probe_rs::config::{Chip, ChipFamily, MemoryRegion};
+
+/// Chip names are matched case-insensitively, like `--chip` is.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// Which chips to list (see `--family` and `--search`)
+pub struct Filter {
+    /// Families whose name starts with this, ignoring case
+    family: Option<String>,
+    /// Chips whose name matches this glob
+    search: Option<Pattern>,
+}
+
+impl Filter {
+    /// `search` is a glob; without wildcards, it matches chip names containing it.
+    pub fn new(family: Option<&str>, search: Option<&str>) -> anyhow::Result<Self> {
+        let search = search
+            .map(|search| {
+                let pattern = match search.contains(['*', '?', '[']) {
+                    true => search.to_string(),
+                    false => format!("*{search}*"),
+                };
+                Pattern::new(&pattern).map_err(|e| anyhow!("invalid `--search` pattern: {e}"))
+            })
+            .transpose()?;
+        Ok(Self {
+            family: family.map(str::to_lowercase),
+            search,
+        })
+    }
+
+    fn includes_family(&self, family: &ChipFamily) -> bool {
+        self.family.as_ref().map_or(true, |prefix| {
+            family.name.to_lowercase().starts_with(prefix)
+        })
+    }
+
+    fn includes_chip(&self, chip: &Chip) -> bool {
+        self.search.as_ref().map_or(true, |pattern| {
+            pattern.matches_with(&chip.name, MATCH_OPTIONS)
+        })
+    }
+}
+
+/// The families with the chips `filter` includes, leaving out families without any
+fn list(families: Vec<ChipFamily>, filter: &Filter) -> Vec<(String, Vec<Chip>)> {
+    families
+        .into_iter()
+        .filter(|family| filter.includes_family(family))
+        .filter_map(|family| {
+            let chips = family
+                .variants
+                .into_iter()
+                .filter(|chip| filter.includes_chip(chip))
+                .collect::<Vec<_>>();
+            (!chips.is_empty()).then_some((family.name, chips))
+        })
+        .collect()
+}
+
+/// Print the chips `filter` includes, as JSON with `json`.
+///
+/// Returns `false` if there are none.
+pub fn print(filter: &Filter, json: bool) -> anyhow::Result<bool> {
+    let families = probe_rs::config::families()?;
+    let listing = list(families, filter);
+
+    if json {
+        let listing = listing
+            .iter()
+            .map(|(family, chips)| {
+                serde_json::json!({
+                    "family": family,
+                    "chips": chips.iter().map(chip_json).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+    } else {
+        for (family, chips) in &listing {
+            println!("{family}\n    Variants:");
+            for chip in chips {
+                println!("        {}", chip.name);
+            }
+        }
+        if listing.is_empty() {
+            println!("no chip in the registry matches");
+        }
+    }
+    Ok(!listing.is_empty())
+}
+
+fn chip_json(chip: &Chip) -> serde_json::Value {
+    let size = |is_kind: fn(&MemoryRegion) -> bool| {
+        chip.memory_map
+            .iter()
+            .filter(|region| is_kind(region))
+            .map(|region| {
+                let range = match region {
+                    MemoryRegion::Ram(ram) => &ram.range,
+                    MemoryRegion::Nvm(nvm) => &nvm.range,
+                    MemoryRegion::Generic(generic) => &generic.range,
+                };
+                range.end - range.start
+            })
+            .sum::<u64>()
+    };
+    serde_json::json!({
+        "name": chip.name,
+        "cores": chip.cores.iter().map(|core| core.core_type).collect::<Vec<_>>(),
+        "flash_bytes": size(|region| matches!(region, MemoryRegion::Nvm(_))),
+        "ram_bytes": size(|region| matches!(region, MemoryRegion::Ram(_))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn names(listing: &[(String, Vec<Chip>)]) -> Vec<&str> {
+        listing
+            .iter()
+            .flat_map(|(_, chips)| chips.iter().map(|chip| chip.name.as_str()))
+            .collect()
+    }
+
+    #[rstest]
+    #[case::family(Some("nrf52"), None, "nRF52840_xxAA", true)]
+    #[case::other_family(Some("stm32"), None, "nRF52840_xxAA", false)]
+    #[case::glob(None, Some("stm32f4*"), "STM32F401RETx", true)]
+    #[case::glob_mismatch(None, Some("stm32f4*"), "STM32F103C8", false)]
+    #[case::substring(None, Some("52840"), "nRF52840_xxAA", true)]
+    fn filters_chips(
+        #[case] family: Option<&str>,
+        #[case] search: Option<&str>,
+        #[case] chip: &str,
+        #[case] expected: bool,
+    ) {
+        let filter = Filter::new(family, search).unwrap();
+        let families = probe_rs::config::families().unwrap();
+        let listing = list(families, &filter);
+        assert_eq!(names(&listing).contains(&chip), expected);
+    }
+}
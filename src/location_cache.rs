@@ -0,0 +1,178 @@
+//! The locations of defmt frames, formatted the first time a log statement's index appears
+//! instead of for every frame
+
+use std::{collections::HashMap, path::Path};
+
+use defmt_decoder::{Location, Locations};
+
+use crate::{
+    cli::Opts,
+    dep,
+    dwarf_locations::DwarfLocations,
+    elf::Elf,
+    hyperlink,
+    path_map::{self, PathMap},
+};
+
+/// The location of a log statement, formatted for output
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrameLocation {
+    /// Relative to the current directory, or a shortened or highlighted dependency path
+    pub display_path: String,
+    /// Relative to the current directory, or absolute (see `--json-format lines`)
+    pub json_path: String,
+    pub line: u32,
+    pub module: String,
+}
+
+pub struct LocationCache<'a> {
+    locations: Option<&'a Locations>,
+    /// For the log statements `locations` lacks
+    fallback: Option<DwarfLocations<'a>>,
+    current_dir: &'a Path,
+    path_map: &'a [PathMap],
+    shorten_paths: bool,
+    /// By frame index; `None` if the ELF has no location for the index
+    cache: HashMap<u64, Option<FrameLocation>>,
+}
+
+impl<'a> LocationCache<'a> {
+    pub fn new(elf: &'a Elf<'a>, current_dir: &'a Path, opts: &'a Opts) -> Self {
+        Self {
+            locations: elf.defmt_locations.as_ref(),
+            fallback: Some(DwarfLocations::new(elf)),
+            current_dir,
+            path_map: &opts.path_map,
+            shorten_paths: opts.shorten_paths,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The location of the log statement with the frame index `index`.
+    pub fn get(&mut self, index: u64) -> Option<&FrameLocation> {
+        let Self {
+            locations,
+            fallback,
+            current_dir,
+            path_map,
+            shorten_paths,
+            cache,
+        } = self;
+        cache
+            .entry(index)
+            .or_insert_with(|| {
+                let location = match (*locations).and_then(|locations| locations.get(&index)) {
+                    Some(location) => location,
+                    None => fallback.as_mut()?.get(index)?,
+                };
+                Some(format(location, current_dir, path_map, *shorten_paths))
+            })
+            .as_ref()
+    }
+}
+
+fn format(
+    location: &Location,
+    current_dir: &Path,
+    path_map: &[PathMap],
+    shorten_paths: bool,
+) -> FrameLocation {
+    let fullpath = path_map::remap(path_map, &location.file);
+    let (display_path, json_path) = match fullpath.strip_prefix(current_dir) {
+        Ok(relpath) => {
+            let relpath = relpath.display().to_string();
+            (relpath.clone(), relpath)
+        }
+        Err(_) => (
+            dep::Path::from_std_path(&fullpath).format(shorten_paths),
+            fullpath.display().to_string(),
+        ),
+    };
+    let line = location.line as u32;
+
+    FrameLocation {
+        display_path: hyperlink::wrap(display_path, &fullpath, current_dir, line, None),
+        json_path,
+        line,
+        module: location.module.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Instant};
+
+    use super::*;
+
+    fn locations(num: u64) -> Locations {
+        (0..num)
+            .map(|index| {
+                let location = Location {
+                    file: PathBuf::from(format!("/tmp/app/src/module{index}.rs")),
+                    line: index,
+                    module: format!("app::module{index}"),
+                };
+                (index, location)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn formats_location_once() {
+        let locations = locations(2);
+        let current_dir = Path::new("/tmp/app");
+        let mut cache = LocationCache {
+            locations: Some(&locations),
+            fallback: None,
+            current_dir,
+            path_map: &[],
+            shorten_paths: false,
+            cache: HashMap::new(),
+        };
+
+        let expected = FrameLocation {
+            display_path: "src/module1.rs".to_string(),
+            json_path: "src/module1.rs".to_string(),
+            line: 1,
+            module: "app::module1".to_string(),
+        };
+        assert_eq!(cache.get(1), Some(&expected));
+        assert_eq!(cache.get(1), Some(&expected));
+        assert_eq!(cache.get(7), None);
+        assert_eq!(cache.cache.len(), 2);
+    }
+
+    /// Compare formatting the location of every frame with the cache; run with
+    /// `cargo test --lib location_cache -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark() {
+        const NUM_FRAMES: u64 = 1_000_000;
+        let locations = locations(10_000);
+        let current_dir = Path::new("/home/user/project");
+
+        let start = Instant::now();
+        for frame in 0..NUM_FRAMES {
+            let location = locations.get(&(frame % 100)).unwrap();
+            std::hint::black_box(format(location, current_dir, &[], false));
+        }
+        let uncached = start.elapsed();
+
+        let mut cache = LocationCache {
+            locations: Some(&locations),
+            fallback: None,
+            current_dir,
+            path_map: &[],
+            shorten_paths: false,
+            cache: HashMap::new(),
+        };
+        let start = Instant::now();
+        for frame in 0..NUM_FRAMES {
+            std::hint::black_box(cache.get(frame % 100));
+        }
+        let cached = start.elapsed();
+
+        println!("{NUM_FRAMES} frames: {uncached:?} uncached, {cached:?} cached");
+        assert!(cached < uncached);
+    }
+}
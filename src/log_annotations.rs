@@ -0,0 +1,361 @@
+//! `--log-annotations`: render defmt messages more readably, e.g. with units, in hex or with the
+//! names of raw enum values, without re-flashing the program
+//!
+//! The annotation file is TOML; each annotation picks a format string by its text or index:
+//!
+//! ``` toml
+//! [[annotation]]
+//! format = "battery: {=u16}"
+//! args = [{ unit = "mV" }]
+//!
+//! [[annotation]]
+//! index = 7
+//! render = "state: {0} (status {1})"
+//! args = [{ names = { 0 = "idle", 1 = "running" } }, { hex = true }]
+//! ```
+//!
+//! The arguments are taken from the message as defmt rendered it, so they are matched against the
+//! literal parts of the format string.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context as _};
+use defmt_decoder::Frame;
+use defmt_parser::{Fragment, Level, ParserMode};
+use log::Record;
+use serde::Deserialize;
+
+use crate::elf::Elf;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    annotation: Vec<Annotation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Annotation {
+    /// The format string to annotate, as written in the program
+    format: Option<String>,
+    /// The index of the format string to annotate (see `export-schema`)
+    index: Option<u64>,
+    /// Replaces the message; `{N}` stands for argument `N`
+    render: Option<String>,
+    /// How to show the arguments, by index
+    #[serde(default)]
+    args: Vec<ArgStyle>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArgStyle {
+    /// Show an unsigned integer in hex
+    #[serde(default)]
+    hex: bool,
+    /// Appended to the value, separated by a space
+    unit: Option<String>,
+    /// Names for values, e.g. of an enum sent as an integer
+    #[serde(default)]
+    names: HashMap<String, String>,
+}
+
+impl ArgStyle {
+    fn apply(&self, value: &str) -> String {
+        let mut styled = match self.names.get(value) {
+            Some(name) => name.clone(),
+            None if self.hex => match value.parse::<u64>() {
+                Ok(int) => format!("{int:#x}"),
+                Err(_) => value.to_string(),
+            },
+            None => value.to_string(),
+        };
+        if let Some(unit) = &self.unit {
+            styled.push(' ');
+            styled.push_str(unit);
+        }
+        styled
+    }
+}
+
+/// The annotations of a program, by the index of the format string
+pub struct Annotations {
+    by_index: HashMap<u64, Rendering>,
+}
+
+struct Rendering {
+    fragments: Vec<Fragment<'static>>,
+    render: Option<String>,
+    args: Vec<ArgStyle>,
+}
+
+impl Annotations {
+    /// Load the annotation file at `path` for the format strings of `elf`.
+    pub fn load(path: &Path, elf: &Elf) -> anyhow::Result<Self> {
+        let toml = fs::read_to_string(path)
+            .with_context(|| format!("failed to read `{}`", path.display()))?;
+        let file: File = toml::from_str(&toml)
+            .with_context(|| format!("failed to parse `{}`", path.display()))?;
+        let formats = format_strings(elf);
+
+        let mut by_index = HashMap::new();
+        for annotation in file.annotation {
+            let indices = match (&annotation.format, annotation.index) {
+                (Some(format), None) => formats
+                    .iter()
+                    .filter(|(_, candidate)| candidate == format)
+                    .map(|(index, _)| *index)
+                    .collect::<Vec<_>>(),
+                (None, Some(index)) => vec![index],
+                _ => bail!(
+                    "each annotation in `{}` needs either `format` or `index`",
+                    path.display()
+                ),
+            };
+            if indices.is_empty() {
+                log::warn!(
+                    "the program has no format string {:?} to annotate",
+                    annotation.format.unwrap_or_default()
+                );
+                continue;
+            }
+
+            for index in indices {
+                let Some((_, format)) = formats.iter().find(|(candidate, _)| *candidate == index)
+                else {
+                    bail!("the program has no format string with index {index} to annotate");
+                };
+                let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+                    .with_context(|| format!("failed to parse the format string {format:?}"))?
+                    .into_iter()
+                    .map(into_owned)
+                    .collect();
+                let rendering = Rendering {
+                    fragments,
+                    render: annotation.render.clone(),
+                    args: annotation.args.clone(),
+                };
+                by_index.insert(index, rendering);
+            }
+        }
+        log::debug!("annotating {} format strings", by_index.len());
+        Ok(Self { by_index })
+    }
+
+    /// The annotated message of `frame`, or `None` if it has no annotation.
+    pub fn message(&self, frame: &Frame) -> Option<String> {
+        let rendering = self.by_index.get(&frame.index())?;
+        rendering.apply(&frame.display_message().to_string())
+    }
+}
+
+impl Rendering {
+    /// Re-render `message`; `None` if it doesn't match the format string.
+    fn apply(&self, message: &str) -> Option<String> {
+        let mut values = vec![];
+        if !match_fragments(&self.fragments, message, &mut values) {
+            return None;
+        }
+        let style = |index: usize, value: &str| match self.args.get(index) {
+            Some(style) => style.apply(value),
+            None => value.to_string(),
+        };
+
+        let mut rendered = String::new();
+        match &self.render {
+            Some(template) => {
+                rendered = template.clone();
+                for (index, value) in values.iter().rev() {
+                    rendered = rendered.replace(&format!("{{{index}}}"), &style(*index, value));
+                }
+            }
+            None => {
+                let mut values = values.iter();
+                for fragment in &self.fragments {
+                    match fragment {
+                        Fragment::Literal(literal) => rendered.push_str(literal),
+                        Fragment::Parameter(_) => {
+                            let (index, value) = values.next()?;
+                            rendered.push_str(&style(*index, value));
+                        }
+                    }
+                }
+            }
+        }
+        Some(rendered)
+    }
+}
+
+/// Match `message` against `fragments`, collecting the text of each parameter with its argument
+/// index into `values`; a parameter takes as little text as possible.
+fn match_fragments<'m>(
+    fragments: &[Fragment],
+    message: &'m str,
+    values: &mut Vec<(usize, &'m str)>,
+) -> bool {
+    let Some((fragment, rest)) = fragments.split_first() else {
+        return message.is_empty();
+    };
+    match fragment {
+        Fragment::Literal(literal) => match message.strip_prefix(&**literal) {
+            Some(message) => match_fragments(rest, message, values),
+            None => false,
+        },
+        Fragment::Parameter(parameter) => {
+            for (end, _) in message.char_indices().skip(1).chain([(message.len(), ' ')]) {
+                values.push((parameter.index, &message[..end]));
+                if match_fragments(rest, &message[end..], values) {
+                    return true;
+                }
+                values.pop();
+            }
+            false
+        }
+    }
+}
+
+fn into_owned(fragment: Fragment) -> Fragment<'static> {
+    match fragment {
+        Fragment::Literal(literal) => Fragment::Literal(literal.into_owned().into()),
+        Fragment::Parameter(parameter) => Fragment::Parameter(parameter),
+    }
+}
+
+/// The format strings of the defmt symbols of `elf`, with their index
+fn format_strings(elf: &Elf) -> Vec<(u64, String)> {
+    #[derive(Deserialize)]
+    struct Symbol {
+        data: String,
+    }
+
+    elf.defmt_symbols()
+        .into_iter()
+        .filter_map(|(index, raw)| {
+            let symbol = serde_json::from_str::<Symbol>(raw).ok()?;
+            Some((index as u64, symbol.data))
+        })
+        .collect()
+}
+
+/// Log `frame` with `message` instead of its own, like `defmt_decoder::log::log_defmt` does.
+pub fn log_frame(
+    frame: &Frame,
+    message: &str,
+    file: Option<&str>,
+    line: Option<u32>,
+    module_path: Option<&str>,
+) {
+    let timestamp = frame.display_timestamp().map(|ts| ts.to_string());
+    log_message(
+        frame.level(),
+        timestamp.as_deref(),
+        message,
+        file,
+        line,
+        module_path,
+    );
+}
+
+/// Log a defmt frame from its parts, e.g. of a frame `--grep` held back.
+///
+/// The logger of the pinned `defmt-decoder` recognizes defmt frames by the `defmt@` target, which
+/// carries their level and timestamp.
+pub fn log_message(
+    level: Option<Level>,
+    timestamp: Option<&str>,
+    message: &str,
+    file: Option<&str>,
+    line: Option<u32>,
+    module_path: Option<&str>,
+) {
+    let level = level.map(|level| level.as_str().to_uppercase());
+    let payload = serde_json::json!({
+        "level": level,
+        "timestamp": timestamp.unwrap_or_default(),
+    });
+    let target = format!("defmt@{payload}");
+    log::logger().log(
+        &Record::builder()
+            .args(format_args!("{message}"))
+            .target(&target)
+            .module_path(module_path)
+            .file(file)
+            .line(line)
+            .build(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn rendering(format: &str, render: Option<&str>, args: Vec<ArgStyle>) -> Rendering {
+        Rendering {
+            fragments: defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+                .unwrap()
+                .into_iter()
+                .map(into_owned)
+                .collect(),
+            render: render.map(str::to_string),
+            args,
+        }
+    }
+
+    #[test]
+    fn adds_unit() {
+        let unit = ArgStyle {
+            unit: Some("mV".into()),
+            ..ArgStyle::default()
+        };
+        let rendering = rendering("battery: {=u16}, ok", None, vec![unit]);
+        assert_eq!(
+            rendering.apply("battery: 3300, ok").as_deref(),
+            Some("battery: 3300 mV, ok")
+        );
+    }
+
+    #[test]
+    fn renders_template() {
+        let names = ArgStyle {
+            names: HashMap::from([("1".into(), "running".into())]),
+            ..ArgStyle::default()
+        };
+        let hex = ArgStyle {
+            hex: true,
+            ..ArgStyle::default()
+        };
+        let rendering = rendering(
+            "{=u8} {=u32}",
+            Some("state: {0} (status {1})"),
+            vec![names, hex],
+        );
+        assert_eq!(
+            rendering.apply("1 255").as_deref(),
+            Some("state: running (status 0xff)")
+        );
+    }
+
+    #[rstest]
+    #[case::literal_mismatch("voltage: 3300")]
+    #[case::missing_suffix("battery: 3300")]
+    fn ignores_other_messages(#[case] message: &str) {
+        let rendering = rendering("battery: {=u16}, ok", None, vec![]);
+        assert_eq!(rendering.apply(message), None);
+    }
+
+    #[test]
+    fn parses_file() {
+        let file: File = toml::from_str(
+            r#"
+            [[annotation]]
+            format = "state: {=u8}"
+            args = [{ names = { 0 = "idle" }, unit = "s" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.annotation[0].args[0].names["0"], "idle");
+    }
+}
@@ -0,0 +1,261 @@
+//! `--metrics-listen`: serve counters and gauges of the session in the Prometheus text format,
+//! e.g. to track the health of a device in a soak test with Grafana
+//!
+//! The recording functions do nothing unless [`serve`] was called. The gauges of the last run
+//! are only set when a run ends, so they can only be scraped while the process lingers after it,
+//! e.g. between the programs of `probe-run test` or after `monitor` printed the backtrace.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead as _, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+
+use crate::backtrace::Outcome;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+struct Metrics {
+    started: Instant,
+    bytes_received: AtomicU64,
+    frames_decoded: AtomicU64,
+    frames_malformed: AtomicU64,
+    rtt_overruns: AtomicU64,
+    reconnects: AtomicU64,
+    /// How often each outcome ended a run, in order of first occurrence
+    outcomes: Mutex<Vec<(&'static str, u64)>>,
+    /// Bytes; `u64::MAX` until the first measurement
+    stack_usage: AtomicU64,
+    /// Milliseconds; `u64::MAX` until the first run ended
+    run_duration: AtomicU64,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes_received: AtomicU64::new(0),
+            frames_decoded: AtomicU64::new(0),
+            frames_malformed: AtomicU64::new(0),
+            rtt_overruns: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            outcomes: Mutex::new(vec![]),
+            stack_usage: AtomicU64::new(u64::MAX),
+            run_duration: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{name}{labels} {value}");
+            }
+        };
+        let counter =
+            |value: &AtomicU64| vec![(String::new(), value.load(Ordering::Relaxed) as f64)];
+        let gauge = |value: &AtomicU64, scale: f64| match value.load(Ordering::Relaxed) {
+            u64::MAX => vec![],
+            value => vec![(String::new(), value as f64 / scale)],
+        };
+
+        metric(
+            "probe_run_uptime_seconds",
+            "gauge",
+            "Seconds since probe-run started serving metrics.",
+            &[(String::new(), self.started.elapsed().as_secs_f64())],
+        );
+        metric(
+            "probe_run_bytes_received_total",
+            "counter",
+            "Bytes of log data received from the target.",
+            &counter(&self.bytes_received),
+        );
+        metric(
+            "probe_run_frames_decoded_total",
+            "counter",
+            "defmt frames decoded.",
+            &counter(&self.frames_decoded),
+        );
+        metric(
+            "probe_run_frames_malformed_total",
+            "counter",
+            "Malformed defmt frames.",
+            &counter(&self.frames_malformed),
+        );
+        metric(
+            "probe_run_rtt_overruns_total",
+            "counter",
+            "Times the program dropped logs because its RTT buffer was full.",
+            &counter(&self.rtt_overruns),
+        );
+        metric(
+            "probe_run_reconnects_total",
+            "counter",
+            "Times probe-run attached to the probe again, e.g. after unlocking the chip.",
+            &counter(&self.reconnects),
+        );
+        let outcomes = self
+            .outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(outcome, count)| (format!("{{outcome=\"{outcome}\"}}"), *count as f64))
+            .collect::<Vec<_>>();
+        metric(
+            "probe_run_outcomes_total",
+            "counter",
+            "Runs which ended with the outcome, e.g. `hardfault` or `overflow`.",
+            &outcomes,
+        );
+        metric(
+            "probe_run_stack_usage_bytes",
+            "gauge",
+            "Stack usage of the last run, as measured by the stack canary.",
+            &gauge(&self.stack_usage, 1.0),
+        );
+        metric(
+            "probe_run_run_duration_seconds",
+            "gauge",
+            "Duration of the last run.",
+            &gauge(&self.run_duration, 1000.0),
+        );
+        text
+    }
+}
+
+/// Serve the metrics at `http://<addr>/metrics` on a background thread.
+pub fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("failed to listen on `{addr}` for `--metrics-listen`"))?;
+    let metrics = METRICS.get_or_init(Metrics::new);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = respond(stream, metrics) {
+                log::debug!("failed to serve metrics: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn add(counter: fn(&Metrics) -> &AtomicU64, value: u64) {
+    if let Some(metrics) = METRICS.get() {
+        counter(metrics).fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+pub fn bytes_received(num_bytes: usize) {
+    add(|metrics| &metrics.bytes_received, num_bytes as u64);
+}
+
+pub fn frame_decoded() {
+    add(|metrics| &metrics.frames_decoded, 1);
+}
+
+pub fn frame_malformed() {
+    add(|metrics| &metrics.frames_malformed, 1);
+}
+
+pub fn rtt_overrun() {
+    add(|metrics| &metrics.rtt_overruns, 1);
+}
+
+pub fn reconnected() {
+    add(|metrics| &metrics.reconnects, 1);
+}
+
+pub fn stack_usage(bytes: u32) {
+    if let Some(metrics) = METRICS.get() {
+        metrics
+            .stack_usage
+            .store(u64::from(bytes), Ordering::Relaxed);
+    }
+}
+
+pub fn run_ended(outcome: Outcome, duration: Option<Duration>) {
+    let Some(metrics) = METRICS.get() else {
+        return;
+    };
+    let name = outcome.name().unwrap_or("exit");
+    let mut outcomes = metrics.outcomes.lock().unwrap_or_else(|e| e.into_inner());
+    match outcomes.iter_mut().find(|(outcome, _)| *outcome == name) {
+        Some((_, count)) => *count += 1,
+        None => outcomes.push((name, 1)),
+    }
+    if let Some(duration) = duration {
+        metrics
+            .run_duration
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::new();
+        metrics.bytes_received.store(42, Ordering::Relaxed);
+        metrics.reconnects.store(1, Ordering::Relaxed);
+        metrics.outcomes.lock().unwrap().push(("hardfault", 2));
+        metrics.run_duration.store(1500, Ordering::Relaxed);
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "# TYPE probe_run_bytes_received_total counter\nprobe_run_bytes_received_total 42\n"
+        ));
+        assert!(text.contains("probe_run_reconnects_total 1\n"));
+        assert!(text.contains("probe_run_outcomes_total{outcome=\"hardfault\"} 2\n"));
+        assert!(text.contains("probe_run_run_duration_seconds 1.5\n"));
+        // not measured yet
+        assert!(!text.contains("\nprobe_run_stack_usage_bytes "));
+    }
+
+    #[test]
+    fn serves_over_http() {
+        use std::io::Read as _;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        respond(stream, &Metrics::new()).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("probe_run_uptime_seconds "));
+    }
+}
@@ -0,0 +1,219 @@
+//! `probe-run monitor`: stream the logs of the program running on the device, without flashing it
+
+use std::{
+    env, fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use signal_hook::consts::signal;
+
+use crate::{
+    backtrace, cli, debug_session, defmt_encoding,
+    elf::Elf,
+    gdb_remote, metrics, notify,
+    reset_reason::ResetReason,
+    rtt_memory,
+    run::{
+        abort_logging, attach_to_probe, delay, detach_from_program, init_logger, load_annotations,
+        lookup_probe_target, print_logs, print_separator, start_program, Sink, Stop,
+        MIN_READ_BUF_SIZE, TIMEOUT,
+    },
+    target_info::TargetInfo,
+    vcp::Vcp,
+};
+
+/// Stream the logs of the program running on the device, without flashing it and, unless
+/// `--reset` is passed, without resetting or halting it.
+pub fn run(
+    elf_path: &Path,
+    chip_name: &str,
+    opts: &cli::Opts,
+    args: &cli::MonitorArgs,
+) -> anyhow::Result<i32> {
+    if let Some(gdb_remote::Via::Gdb(server)) = &opts.via {
+        return monitor_via_gdb(elf_path, chip_name, opts, args, server);
+    }
+    let probe_target = lookup_probe_target(elf_path, chip_name, opts)?;
+    let mut sess = attach_to_probe(probe_target.clone(), opts)?;
+    let reset_reason = ResetReason::read(&mut sess, opts);
+    let memory_map = sess.target().memory_map.clone();
+    let core = &mut sess.core(opts.core)?;
+
+    // the core keeps running, so take the vector table from the ELF instead of the registers
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = &Elf::parse_offline(&elf_bytes, elf_path)?;
+    let stack_start = elf.vector_table.initial_stack_pointer;
+    let mut target_info = TargetInfo::new(elf, memory_map, probe_target, opts.core, stack_start)?;
+
+    init_logger(elf, opts)?;
+    if let Some(reset_reason) = reset_reason {
+        reset_reason.print(opts)?;
+    }
+
+    let vcp = match &opts.vcp {
+        Some(port) => Some(Vcp::open(port, opts.vcp_baud)?),
+        None => None,
+    };
+    let mut setup = match args.reset {
+        true => {
+            core.reset_and_halt(TIMEOUT)?;
+            delay(opts.before_run_delay, "after reset");
+            Some(start_program(core, elf, target_info.remap.as_ref(), opts)?)
+        }
+        false => None,
+    };
+
+    let current_dir = env::current_dir()?;
+    let stop = print_logs(core, elf, &target_info, None, vcp, opts, setup.as_mut()) // blocks until exception or Ctrl-C
+        .map_err(|e| abort_logging(core, setup.as_ref(), e))?;
+    let detached = stop != Stop::Halted;
+    print_separator()?;
+
+    if let Some(setup) = setup {
+        target_info.hard_fault_handler = setup.hard_fault;
+        target_info.run_until = setup.run_until;
+        target_info.end_symbol_return = setup.end_symbol_return;
+        detach_from_program(core, setup, false)?;
+    }
+    if detached {
+        log::info!("detached from the device; the program keeps running");
+        return Ok(cli::EXIT_SUCCESS);
+    }
+
+    // the device halted by itself, e.g. on a breakpoint
+    let mut backtrace_settings = backtrace::Settings::new(current_dir, false, opts, false);
+    backtrace_settings.backtrace = match args.backtrace {
+        true => backtrace::BacktraceOptions::Always,
+        false => backtrace::BacktraceOptions::Never,
+    };
+    let outcome = backtrace::print(core, elf, &target_info, &mut backtrace_settings)?;
+
+    outcome.log();
+    notify::send(opts, &outcome.to_string());
+    metrics::run_ended(outcome, None);
+    Ok(outcome.exit_code(&opts.exit_code_map))
+}
+
+/// `monitor` with `--via`: stream the logs through the GDB server at `server`, and print the
+/// backtrace if the device halts.
+fn monitor_via_gdb(
+    elf_path: &Path,
+    chip_name: &str,
+    opts: &cli::Opts,
+    args: &cli::MonitorArgs,
+    server: &str,
+) -> anyhow::Result<i32> {
+    if args.reset {
+        bail!(
+            "`monitor --reset` needs exclusive access to the probe; it can't be used with `--via`"
+        );
+    }
+    let probe_target = lookup_probe_target(elf_path, chip_name, opts)?;
+    let memory_map = probe_target.memory_map.clone();
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = &Elf::parse_offline(&elf_bytes, elf_path)?;
+    let stack_start = elf.vector_table.initial_stack_pointer;
+    let target_info = TargetInfo::new(elf, memory_map, probe_target, opts.core, stack_start)?;
+    init_logger(elf, opts)?;
+
+    let mut client = gdb_remote::Client::connect(server)?;
+    client.halt_reason()?;
+    let current_dir = env::current_dir()?;
+    let halted = print_logs_via_gdb(&mut client, elf, &current_dir, opts)?;
+    print_separator()?;
+    if !halted {
+        client.detach()?;
+        log::info!("detached from the device; the program keeps running");
+        return Ok(cli::EXIT_SUCCESS);
+    }
+
+    let mut backtrace_settings = backtrace::Settings::new(current_dir, false, opts, false);
+    backtrace_settings.backtrace = match args.backtrace {
+        true => backtrace::BacktraceOptions::Always,
+        false => backtrace::BacktraceOptions::Never,
+    };
+    let outcome = backtrace::print(&mut client, elf, &target_info, &mut backtrace_settings)?;
+
+    outcome.log();
+    notify::send(opts, &outcome.to_string());
+    metrics::run_ended(outcome, None);
+    Ok(outcome.exit_code(&opts.exit_code_map))
+}
+
+/// Read the RTT channel by halting the core periodically, until the core halts by itself
+/// (returns `true`) or Ctrl-C is pressed.
+fn print_logs_via_gdb(
+    client: &mut gdb_remote::Client,
+    elf: &Elf,
+    current_dir: &Path,
+    opts: &cli::Opts,
+) -> anyhow::Result<bool> {
+    let exit = Arc::new(AtomicBool::new(false));
+    let sig_id = signal_hook::flag::register(signal::SIGINT, exit.clone())?;
+
+    let channel = match elf.rtt_buffer_address() {
+        Some(address) => Some(rtt_memory::UpChannel::attach(client, address)?),
+        None if opts.require_rtt => bail!(
+            "RTT control block (`_SEGGER_RTT` symbol) not found in the ELF, but `--require-rtt` was set"
+        ),
+        None => {
+            eprintln!("RTT logs not available; blocking until the device halts..");
+            None
+        }
+    };
+    let use_defmt = opts.rtt_decoder == cli::RttDecoder::Auto
+        && channel
+            .as_ref()
+            .map_or(false, |channel| channel.name() == Some("defmt"));
+    if use_defmt && elf.defmt_table.is_none() {
+        bail!("\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data");
+    }
+    let defmt_table = elf.defmt_table.as_ref().filter(|_| use_defmt);
+    if let Some(table) = defmt_table {
+        defmt_encoding::announce(table, opts);
+    }
+    let annotations = load_annotations(defmt_table, elf, opts)?;
+    let mut sink = Sink::new(defmt_table, annotations.as_ref(), elf, current_dir, opts);
+
+    print_separator()?;
+    let poll_interval = Duration::from_millis(opts.max_poll_interval);
+    let mut read_buf = vec![0; MIN_READ_BUF_SIZE];
+    let halted = loop {
+        client.resume()?;
+        thread::sleep(poll_interval);
+        let stop = client.interrupt()?;
+
+        if let Some(channel) = &channel {
+            loop {
+                let num_bytes_read = channel.read(client, &mut read_buf)?;
+                if num_bytes_read == 0 {
+                    break;
+                }
+                debug_session::record(
+                    "rtt_read",
+                    || serde_json::json!({ "size": num_bytes_read, "overrun": false }),
+                );
+                metrics::bytes_received(num_bytes_read);
+                sink.received(&read_buf[..num_bytes_read], false, opts)?;
+            }
+        }
+
+        match stop {
+            gdb_remote::Stop::Interrupted if exit.load(Ordering::Relaxed) => break false,
+            gdb_remote::Stop::Interrupted => {}
+            gdb_remote::Stop::Halted(_) => break true,
+            gdb_remote::Stop::Exited => bail!("the GDB server ended the debug session"),
+        }
+    };
+    sink.finish();
+
+    signal_hook::low_level::unregister(sig_id);
+    Ok(halted)
+}
@@ -0,0 +1,64 @@
+//! `--notify` and `--bell`: tell the user about the progress of long-running sessions
+
+use crate::cli;
+
+/// Notify the user about `message`, as configured through `opts`.
+///
+/// Failing to notify is not an error; the message is logged in any case.
+pub fn send(opts: &cli::Opts, message: &str) {
+    if opts.bell {
+        eprint!("\x07");
+    }
+
+    #[cfg(feature = "notify")]
+    if opts.notify {
+        if let Err(e) = desktop::show(message) {
+            log::warn!("failed to show desktop notification: {e}");
+        }
+    }
+
+    #[cfg(not(feature = "notify"))]
+    let _ = message;
+}
+
+#[cfg(feature = "notify")]
+mod desktop {
+    use std::process::Command;
+
+    use anyhow::bail;
+
+    const TITLE: &str = "probe-run";
+
+    #[cfg(target_os = "macos")]
+    fn command(message: &str) -> Command {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {} with title {}",
+            quote(message),
+            quote(TITLE)
+        ));
+        command
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn command(message: &str) -> Command {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", TITLE, TITLE, message]);
+        command
+    }
+
+    #[cfg(not(unix))]
+    pub fn show(_message: &str) -> anyhow::Result<()> {
+        bail!("desktop notifications are not supported on this platform")
+    }
+
+    #[cfg(unix)]
+    pub fn show(message: &str) -> anyhow::Result<()> {
+        let status = command(message).status()?;
+        if !status.success() {
+            bail!("notification command failed with {status}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+//! `--output-queue`: hand the RTT data to a separate thread for decoding and printing, so that a
+//! slow consumer of the output, e.g. a pipe into a busy program, doesn't hold up reading the RTT
+//! channel -- which stalls the program if its channel blocks when full
+//!
+//! The queue is bounded; `--output-overflow` decides whether a full queue holds up reading, or
+//! drops the data.
+
+use std::{
+    mem,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+};
+
+use crate::cli::OutputOverflow;
+
+/// The bytes read in one poll, and whether data was lost before them
+pub type Chunk = (Vec<u8>, bool);
+
+pub struct Queue {
+    sender: SyncSender<Chunk>,
+    overflow: OutputOverflow,
+    dropped_bytes: u64,
+    /// Data was dropped since the last chunk which was queued
+    gap: bool,
+}
+
+/// Create a queue of up to `capacity` chunks, and the receiving end for the output thread.
+pub fn bounded(capacity: usize, overflow: OutputOverflow) -> (Queue, Receiver<Chunk>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let queue = Queue {
+        sender,
+        overflow,
+        dropped_bytes: 0,
+        gap: false,
+    };
+    (queue, receiver)
+}
+
+impl Queue {
+    /// Queue `bytes`; `lost` marks that data was lost before them. Returns `false` if the output
+    /// thread hung up, which it only does if it failed.
+    pub fn send(&mut self, bytes: &[u8], lost: bool) -> bool {
+        let chunk = (bytes.to_vec(), lost || mem::take(&mut self.gap));
+        match self.overflow {
+            OutputOverflow::Block => self.sender.send(chunk).is_ok(),
+            OutputOverflow::Drop => match self.sender.try_send(chunk) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped_bytes += bytes.len() as u64;
+                    self.gap = true;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        }
+    }
+
+    /// The number of bytes dropped because the queue was full
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
+}
+
+/// The loop of the output thread: pass every queued chunk to `received`, until the queue hangs up
+/// or `received` fails.
+pub fn drain<E>(
+    receiver: Receiver<Chunk>,
+    mut received: impl FnMut(&[u8], bool) -> Result<(), E>,
+) -> Result<(), E> {
+    for (bytes, lost) in receiver {
+        received(&bytes, lost)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use defmt_decoder::{DecodeError, Table};
+
+    use super::*;
+
+    /// A stream of `num_frames` raw-encoded frames of `tests/test_elfs/hello-raw`, and its table
+    fn hello_frames(num_frames: usize) -> (Table, Vec<u8>) {
+        let elf = std::fs::read("tests/test_elfs/hello-raw").unwrap();
+        let table = Table::parse(&elf).unwrap().unwrap();
+        let index = table.indices().next().unwrap() as u16;
+        let stream = index.to_le_bytes().repeat(num_frames);
+        (table, stream)
+    }
+
+    /// Decode the chunks passed to the returned closure into `messages`, like the output sink.
+    fn decode_into<'a>(
+        table: &'a Table,
+        messages: &'a mut Vec<String>,
+    ) -> impl FnMut(&[u8], bool) -> Result<(), DecodeError> + 'a {
+        let mut decoder = table.new_stream_decoder();
+        move |bytes, _lost| {
+            decoder.received(bytes);
+            loop {
+                match decoder.decode() {
+                    Ok(frame) => messages.push(frame.display_message().to_string()),
+                    Err(DecodeError::UnexpectedEof) => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Decode `stream` in chunks of `chunk_size` bytes, inline or on an output thread.
+    fn decode(table: &Table, stream: &[u8], chunk_size: usize, threaded: bool) -> Vec<String> {
+        let mut messages = vec![];
+        if !threaded {
+            let mut received = decode_into(table, &mut messages);
+            for chunk in stream.chunks(chunk_size) {
+                received(chunk, false).unwrap();
+            }
+            drop(received);
+            return messages;
+        }
+
+        thread::scope(|scope| {
+            let (mut queue, receiver) = bounded(16, OutputOverflow::Block);
+            // like the sink, the decoder is created on the output thread
+            let handle = scope.spawn(move || {
+                drain(receiver, decode_into(table, &mut messages)).unwrap();
+                messages
+            });
+            for chunk in stream.chunks(chunk_size) {
+                assert!(queue.send(chunk, false));
+            }
+            drop(queue);
+            handle.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn drops_when_full_and_marks_the_gap() {
+        let (mut queue, receiver) = bounded(1, OutputOverflow::Drop);
+
+        assert!(queue.send(b"first", false));
+        assert!(queue.send(b"dropped", false));
+        assert_eq!(receiver.recv().unwrap(), (b"first".to_vec(), false));
+        assert!(queue.send(b"next", false));
+
+        assert_eq!(receiver.recv().unwrap(), (b"next".to_vec(), true));
+        assert_eq!(queue.dropped_bytes(), 7);
+    }
+
+    #[test]
+    fn reports_hang_up() {
+        let (mut queue, receiver) = bounded(1, OutputOverflow::Block);
+        drop(receiver);
+        assert!(!queue.send(b"bytes", false));
+    }
+
+    #[test]
+    fn output_thread_decodes_like_inline() {
+        let (table, stream) = hello_frames(1000);
+        // odd chunks split frames
+        let inline = decode(&table, &stream, 333, false);
+        assert_eq!(inline.len(), 1000);
+        assert_eq!(inline[0], "Hello, world!");
+        assert_eq!(decode(&table, &stream, 333, true), inline);
+    }
+
+    /// Measure the throughput of decoding inline and on the output thread; run with
+    /// `cargo test --release --lib output_queue -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark() {
+        const NUM_FRAMES: usize = 4_000_000;
+        // the size of a typical RTT buffer, read in one poll
+        const CHUNK_SIZE: usize = 1024;
+        let (table, stream) = hello_frames(NUM_FRAMES);
+        let mib = stream.len() as f64 / (1024.0 * 1024.0);
+
+        for threaded in [false, true] {
+            let start = Instant::now();
+            let messages = decode(&table, &stream, CHUNK_SIZE, threaded);
+            let elapsed = start.elapsed();
+            assert_eq!(messages.len(), NUM_FRAMES);
+            println!(
+                "threaded: {threaded}: {mib:.1} MiB in {elapsed:?}, {:.1} MiB/s",
+                mib / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
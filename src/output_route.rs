@@ -0,0 +1,97 @@
+//! `--host-output` and `--target-output`: write probe-run's own logs and the program's output into
+//! separate files, e.g. to archive the device logs of a CI run without the host's
+//!
+//! The logger prints defmt frames to stdout and host logs to stderr, and everything else follows
+//! that split: RTT bytes and `--json-format lines` events go to stdout, separators, backtraces and
+//! status lines to stderr. So the whole stream gets redirected, before anything is printed.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _};
+
+use crate::cli;
+
+/// Where `--host-output` or `--target-output` goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// stderr for the host output, stdout for the target output
+    Standard,
+    File(PathBuf),
+}
+
+/// Parse `--host-output`: `stderr` or a path
+pub fn parse_host(s: &str) -> anyhow::Result<Destination> {
+    parse(s, "stderr", "stdout")
+}
+
+/// Parse `--target-output`: `stdout` or a path
+pub fn parse_target(s: &str) -> anyhow::Result<Destination> {
+    parse(s, "stdout", "stderr")
+}
+
+fn parse(s: &str, standard: &str, other: &str) -> anyhow::Result<Destination> {
+    match s {
+        _ if s == standard => Ok(Destination::Standard),
+        _ if s == other => bail!("`{other}` isn't supported here; use `{standard}` or a path"),
+        "" => bail!("expected `{standard}` or a path"),
+        _ => Ok(Destination::File(s.into())),
+    }
+}
+
+/// Redirect stdout and stderr into the files of `--target-output` and `--host-output`.
+pub fn apply(opts: &cli::Opts) -> anyhow::Result<()> {
+    if let Destination::File(path) = &opts.target_output {
+        redirect(&io::stdout(), path).context("failed to redirect the target output")?;
+    }
+    if let Destination::File(path) = &opts.host_output {
+        redirect(&io::stderr(), path).context("failed to redirect the host output")?;
+    }
+    Ok(())
+}
+
+/// Point the file descriptor of `stream` to a new file at `path`.
+#[cfg(unix)]
+fn redirect(stream: &impl std::os::unix::io::AsRawFd, path: &Path) -> anyhow::Result<()> {
+    use std::{fs::File, os::unix::io::AsRawFd as _};
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create `{}`", path.display()))?;
+    // SAFETY: both file descriptors are valid; `dup2` keeps the file open after `file` is dropped
+    match unsafe { libc::dup2(file.as_raw_fd(), stream.as_raw_fd()) } {
+        -1 => Err(io::Error::last_os_error().into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn redirect<T>(_stream: &T, path: &Path) -> anyhow::Result<()> {
+    bail!(
+        "can't write output to `{}` on this platform; redirect it in the shell instead",
+        path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::standard("stderr", Some(Destination::Standard))]
+    #[case::file("logs/host.log", Some(Destination::File("logs/host.log".into())))]
+    #[case::other_stream("stdout", None)]
+    #[case::empty("", None)]
+    fn parses_host_output(#[case] input: &str, #[case] expected: Option<Destination>) {
+        assert_eq!(parse_host(input).ok(), expected);
+    }
+
+    #[test]
+    fn parses_target_output() {
+        assert_eq!(parse_target("stdout").unwrap(), Destination::Standard);
+        assert!(parse_target("stderr").is_err());
+    }
+}
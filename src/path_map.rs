@@ -0,0 +1,66 @@
+//! `--path-map`: substitute path prefixes of the build machine with local ones
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+
+/// A `<from>=<to>` path prefix substitution
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathMap {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl FromStr for PathMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(PathMap {
+                from: from.into(),
+                to: to.into(),
+            }),
+            _ => Err(anyhow!("expected `<from>=<to>`")),
+        }
+    }
+}
+
+/// Applies the first of the `maps` whose `from` is a prefix of `path`
+pub fn remap<'p>(maps: &[PathMap], path: &'p Path) -> Cow<'p, Path> {
+    maps.iter()
+        .find_map(|map| {
+            path.strip_prefix(&map.from)
+                .ok()
+                .map(|rest| map.to.join(rest))
+        })
+        .map_or(Cow::Borrowed(path), Cow::Owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::mapped("/build/src/main.rs", "/home/user/app/src/main.rs")]
+    #[case::first_match_wins("/build/vendor/lib.rs", "/home/user/app/vendor/lib.rs")]
+    #[case::second_map("/cargo/registry/src/lib.rs", "/home/user/.cargo/registry/src/lib.rs")]
+    #[case::partial_component("/builder/src/main.rs", "/builder/src/main.rs")]
+    #[case::unmapped(
+        "/rustc/abc/library/core/src/panic.rs",
+        "/rustc/abc/library/core/src/panic.rs"
+    )]
+    fn should_remap(#[case] path: &str, #[case] expected: &str) {
+        let maps = [
+            "/build=/home/user/app".parse().unwrap(),
+            "/build/vendor=/elsewhere".parse().unwrap(),
+            "/cargo=/home/user/.cargo".parse().unwrap(),
+        ];
+        assert_eq!(remap(&maps, Path::new(path)), Path::new(expected));
+    }
+}
@@ -0,0 +1,158 @@
+//! `--poke` and `--peek`: access target memory before the program's `main` runs
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, MemoryInterface as _};
+
+use crate::{cli, elf::Elf};
+
+/// A memory location given either as a symbol name or as an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    Address(u32),
+    Symbol(String),
+}
+
+impl FromStr for Location {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            bail!("expected a symbol name or an address");
+        }
+
+        match s.starts_with(|c: char| c.is_ascii_digit()) {
+            true => Ok(Location::Address(cli::parse_u32(s)?)),
+            false => Ok(Location::Symbol(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Address(address) => write!(f, "{address:#010x}"),
+            Location::Symbol(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A `<location>=<value>` pair passed to `--poke`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Poke {
+    pub location: Location,
+    pub value: u32,
+}
+
+impl FromStr for Poke {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (location, value) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("expected `<symbol-or-addr>=<value>`"))?;
+        Ok(Poke {
+            location: location.parse()?,
+            value: cli::parse_u32(value)?,
+        })
+    }
+}
+
+/// The `u8` of programs which filter their logs at runtime, set by `--target-log-level`
+pub const LOG_LEVEL_SYMBOL: &str = "__DEFMT_LOG_LEVEL_RUNTIME";
+
+/// The poke which sets the runtime log level of the program to `level`
+pub fn log_level(elf: &Elf, level: cli::TargetLogLevel) -> anyhow::Result<Poke> {
+    if elf.find_symbol(LOG_LEVEL_SYMBOL).is_none() {
+        bail!(
+            "`--target-log-level` needs the program to filter its logs at runtime, \
+            but it has no `{LOG_LEVEL_SYMBOL}` symbol"
+        );
+    }
+    Ok(Poke {
+        location: Location::Symbol(LOG_LEVEL_SYMBOL.to_string()),
+        value: level as u32,
+    })
+}
+
+/// Writes all `pokes` to, then reads all `peeks` from the target memory
+pub fn apply(core: &mut Core, elf: &Elf, pokes: &[Poke], peeks: &[Location]) -> anyhow::Result<()> {
+    for poke in pokes {
+        let (address, width) = resolve(elf, &poke.location)?;
+        if width < 4 && poke.value >> (8 * width) != 0 {
+            bail!(
+                "value {:#x} does not fit into `{}` ({width} bytes)",
+                poke.value,
+                poke.location
+            );
+        }
+
+        let bytes = &poke.value.to_le_bytes()[..width as usize];
+        core.write_8(address.into(), bytes)?;
+        log::debug!("poked {:#x} into `{}`", poke.value, poke.location);
+    }
+
+    for location in peeks {
+        let (address, width) = resolve(elf, location)?;
+        let mut bytes = [0; 4];
+        core.read_8(address.into(), &mut bytes[..width as usize])?;
+        log::info!("{location} = {:#x}", u32::from_le_bytes(bytes));
+    }
+
+    Ok(())
+}
+
+/// Returns the address and width in bytes of `location`
+///
+/// Symbols are accessed with their own size, addresses as 32-bit words.
+fn resolve(elf: &Elf, location: &Location) -> anyhow::Result<(u32, u32)> {
+    match location {
+        Location::Address(address) => Ok((*address, 4)),
+        Location::Symbol(name) => {
+            let range = elf
+                .find_symbol(name)
+                .ok_or_else(|| anyhow!("symbol `{name}` not found"))?;
+            match range.end - range.start {
+                width @ (1 | 2 | 4) => Ok((range.start, width)),
+                0 => Ok((range.start, 4)),
+                width => bail!(
+                    "symbol `{name}` is {width} bytes large; only 1, 2 and 4 bytes are supported"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::symbol("SEED=42", Location::Symbol("SEED".into()), 42)]
+    #[case::address("0x20000000=0xff", Location::Address(0x2000_0000), 0xff)]
+    #[case::path("app::FLAG=1", Location::Symbol("app::FLAG".into()), 1)]
+    fn should_parse_poke(#[case] input: &str, #[case] location: Location, #[case] value: u32) {
+        assert_eq!(input.parse::<Poke>().unwrap(), Poke { location, value })
+    }
+
+    #[rstest]
+    #[case::missing_value("SEED")]
+    #[case::bad_value("SEED=x")]
+    #[case::empty_location("=1")]
+    fn should_reject_malformed_poke(#[case] input: &str) {
+        assert!(input.parse::<Poke>().is_err())
+    }
+
+    #[test]
+    fn log_level_needs_symbol() {
+        let path = std::path::Path::new("tests/test_elfs/hello-rzcobs");
+        let bytes = std::fs::read(path).unwrap();
+        let elf = Elf::parse_offline(&bytes, path).unwrap();
+
+        let error = log_level(&elf, cli::TargetLogLevel::Warn).unwrap_err();
+        assert!(error.to_string().contains(LOG_LEVEL_SYMBOL));
+    }
+}
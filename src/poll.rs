@@ -0,0 +1,91 @@
+//! Adaptive polling of the probe: back-to-back while the program sends logs, backing off
+//! exponentially while it is quiet, so that idle runs don't flood the probe's USB link with RTT
+//! reads and halt queries (see `--max-poll-interval`)
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The first delay after a busy poll
+const MIN_DELAY: Duration = Duration::from_millis(1);
+
+pub struct Backoff {
+    delay: Duration,
+    max: Duration,
+    num_polls: u64,
+    num_busy_polls: u64,
+    started: Instant,
+}
+
+impl Backoff {
+    /// `max` of zero polls continuously.
+    pub fn new(max: Duration) -> Self {
+        Self {
+            delay: Duration::ZERO,
+            max,
+            num_polls: 0,
+            num_busy_polls: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Wait before the next poll; not at all if the last one was `busy`, e.g. read logs or found
+    /// the core halted.
+    pub fn wait(&mut self, busy: bool) {
+        self.num_polls += 1;
+        if busy {
+            self.num_busy_polls += 1;
+        }
+        self.delay = next_delay(self.delay, busy, self.max);
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+    }
+
+    pub fn log_stats(&self) {
+        let secs = self.started.elapsed().as_secs_f64();
+        log::debug!(
+            "polled the probe {} times in {secs:.1} s ({:.0} per second), {} of them busy",
+            self.num_polls,
+            self.num_polls as f64 / secs.max(f64::EPSILON),
+            self.num_busy_polls
+        );
+    }
+}
+
+fn next_delay(delay: Duration, busy: bool, max: Duration) -> Duration {
+    if busy || max.is_zero() {
+        return Duration::ZERO;
+    }
+    (delay * 2).clamp(MIN_DELAY.min(max), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn backs_off_while_idle() {
+        let mut delay = Duration::ZERO;
+        let delays = (0..7)
+            .map(|_| {
+                delay = next_delay(delay, false, MAX);
+                delay.as_millis()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 20, 20]);
+    }
+
+    #[test]
+    fn busy_poll_resets_delay() {
+        assert_eq!(next_delay(MAX, true, MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_max_polls_continuously() {
+        assert_eq!(next_delay(MAX, false, Duration::ZERO), Duration::ZERO);
+    }
+}
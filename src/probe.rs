@@ -1,14 +1,29 @@
-use std::str::FromStr;
+use std::{
+    env,
+    error::Error as _,
+    fs,
+    io::{self, BufRead as _, IsTerminal as _, Write as _},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail};
-use probe_rs::{DebugProbeInfo, Probe};
+use glob::Pattern;
+use probe_rs::{DebugProbeError, DebugProbeInfo, DebugProbeType, Probe};
 
-use crate::cli;
+use crate::{cli, probe_lock, theme};
 
 const NO_PROBE_FOUND_ERR: &str = "no probe was found.\n
 Common reasons for this are faulty cables or missing permissions.
 For detailed instructions, visit: https://github.com/knurling-rs/probe-run#troubleshooting";
 
+/// File (relative to the project directory) that stores the last interactively selected probe.
+const LAST_PROBE_PATH: &str = ".probe-run/last-probe";
+
+/// Where to install the udev rule for a probe
+pub const UDEV_RULE_PATH: &str = "/etc/udev/rules.d/69-probe-rs.rules";
+
 pub fn open(opts: &cli::Opts) -> Result<Probe, anyhow::Error> {
     let all_probes = Probe::list_all();
     let filtered_probes = if let Some(probe_opt) = opts.probe.as_deref() {
@@ -24,12 +39,17 @@ pub fn open(opts: &cli::Opts) -> Result<Probe, anyhow::Error> {
 
     log::debug!("found {} probes", filtered_probes.len());
 
-    if filtered_probes.len() > 1 {
-        print(&filtered_probes);
-        bail!("more than one probe found; use --probe to specify which one to use");
-    }
-
-    let mut probe = filtered_probes[0].open()?;
+    let probe = match opts.lock_timeout {
+        Some(timeout) => {
+            let candidates = match opts.probe_index {
+                Some(_) => vec![select(&filtered_probes, opts.probe_index)?],
+                None => filtered_probes.iter().collect(),
+            };
+            probe_lock::acquire(&candidates, Duration::from_secs(timeout))?
+        }
+        None => select(&filtered_probes, opts.probe_index)?,
+    };
+    let mut probe = probe.open().map_err(|e| open_error(e, probe))?;
     log::debug!("opened probe");
 
     if let Some(speed) = opts.speed {
@@ -47,35 +67,322 @@ pub fn print(probes: &[DebugProbeInfo]) {
             .enumerate()
             .for_each(|(num, link)| println!("[{num}]: {link:?}"));
     } else {
-        println!("Error: {NO_PROBE_FOUND_ERR}");
+        println!(
+            "{} {NO_PROBE_FOUND_ERR}",
+            theme::current().error.paint("Error:")
+        );
     }
 }
 
-fn filter(probes: &[DebugProbeInfo], selector: &ProbeFilter) -> Vec<DebugProbeInfo> {
-    probes
-        .iter()
-        .filter(|probe| {
-            if let Some((vid, pid)) = selector.vid_pid {
-                if probe.vendor_id != vid || probe.product_id != pid {
-                    return false;
-                }
+/// Why the operating system doesn't let probe-run open a probe it found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AccessProblem {
+    /// Linux: no udev rule gives the user access to the USB device
+    MissingUdevRule,
+    /// Linux: ModemManager took the probe's serial port for a modem
+    ClaimedByModemManager,
+    /// Windows: the USB interface of the probe has no WinUSB driver
+    MissingWinUsbDriver,
+}
+
+impl AccessProblem {
+    /// Classify the messages of the error chain of opening a probe, on `os` (like
+    /// [`env::consts::OS`]).
+    fn classify(
+        messages: &str,
+        os: &str,
+        modem_manager_running: impl FnOnce() -> bool,
+    ) -> Option<Self> {
+        let messages = messages.to_ascii_lowercase();
+        let mentions = |patterns: &[&str]| patterns.iter().any(|p| messages.contains(p));
+        match os {
+            "linux" if mentions(&["busy"]) && modem_manager_running() => {
+                Some(Self::ClaimedByModemManager)
+            }
+            "linux"
+                if mentions(&[
+                    "access denied",
+                    "permission denied",
+                    "insufficient permissions",
+                ]) =>
+            {
+                Some(Self::MissingUdevRule)
+            }
+            "windows" if mentions(&["not supported", "entity not found"]) => {
+                Some(Self::MissingWinUsbDriver)
             }
+            _ => None,
+        }
+    }
+
+    /// Step-by-step instructions to fix the problem for `probe`
+    fn remediation(self, probe: &DebugProbeInfo) -> String {
+        let usb_id = format!("{:04x}:{:04x}", probe.vendor_id, probe.product_id);
+        match self {
+            Self::MissingUdevRule => format!(
+                "the probe {} ({usb_id}) was found, but you lack the permission to open it\n\
+                1. install this udev rule as `{UDEV_RULE_PATH}`:\n\n\
+                \x20   {}\n\n\
+                2. reload the rules: `sudo udevadm control --reload && sudo udevadm trigger`\n\
+                3. unplug the probe and plug it back in\n\
+                4. if it still fails, add yourself to the `plugdev` group with \
+                `sudo usermod -aG plugdev $USER`, then log out and back in",
+                probe.identifier,
+                udev_rule(probe, false),
+            ),
+            Self::ClaimedByModemManager => format!(
+                "the probe {} ({usb_id}) is busy; ModemManager is running and probably claimed \
+                its serial port, taking it for a modem\n\
+                1. make ModemManager ignore the probe with this udev rule in `{UDEV_RULE_PATH}`:\n\n\
+                \x20   {}\n\n\
+                2. reload the rules: `sudo udevadm control --reload && sudo udevadm trigger`\n\
+                3. unplug the probe and plug it back in\n\
+                or, if you don't use a modem, stop ModemManager: \
+                `sudo systemctl disable --now ModemManager`",
+                probe.identifier,
+                udev_rule(probe, true),
+            ),
+            Self::MissingWinUsbDriver => format!(
+                "the probe {} ({usb_id}) was found, but Windows has no WinUSB driver for it\n\
+                1. download Zadig from https://zadig.akeo.ie and run it\n\
+                2. in `Options`, check `List All Devices`\n\
+                3. select the probe (USB ID {}), or its debug interface if it has several\n\
+                4. select `WinUSB` as the driver and click `Replace Driver`\n\
+                5. unplug the probe and plug it back in\n\
+                (for a J-Link, switch to WinUSB in SEGGER's J-Link Configurator instead)",
+                probe.identifier,
+                usb_id.to_ascii_uppercase().replace(':', " "),
+            ),
+        }
+    }
+}
+
+/// The udev rule which gives users access to `probe`, and optionally hides it from ModemManager
+pub fn udev_rule(probe: &DebugProbeInfo, ignore_modem_manager: bool) -> String {
+    let mut rule = format!(
+        "ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"660\", \
+        GROUP=\"plugdev\", TAG+=\"uaccess\"",
+        probe.vendor_id, probe.product_id
+    );
+    if ignore_modem_manager {
+        rule.push_str(", ENV{ID_MM_DEVICE_IGNORE}=\"1\"");
+    }
+    rule
+}
+
+/// Explain why `probe` couldn't be opened, if the cause is known.
+fn open_error(error: DebugProbeError, probe: &DebugProbeInfo) -> anyhow::Error {
+    let mut messages = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        messages = format!("{messages}: {cause}");
+        source = cause.source();
+    }
+    log::debug!("failed to open the probe: {messages}");
+
+    let problem = AccessProblem::classify(&messages, env::consts::OS, modem_manager_running);
+    let error = anyhow::Error::new(error);
+    match problem {
+        Some(problem) => error.context(problem.remediation(probe)),
+        None => error.context(format!("failed to open the probe {}", probe.identifier)),
+    }
+}
+
+/// Returns `true` if a process called `ModemManager` runs.
+fn modem_manager_running() -> bool {
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+    processes.filter_map(Result::ok).any(|process| {
+        fs::read_to_string(process.path().join("comm"))
+            .map_or(false, |name| name.trim() == "ModemManager")
+    })
+}
 
-            if let Some(serial) = &selector.serial {
-                if probe.serial_number.as_deref() != Some(serial) {
-                    return false;
-                }
+/// Pick one of the `probes`.
+///
+/// In order of precedence: the probe at `probe_index`, the only probe, or the probe chosen
+/// interactively (TTY only), where Enter confirms the probe remembered from the last choice.
+fn select(
+    probes: &[DebugProbeInfo],
+    probe_index: Option<usize>,
+) -> anyhow::Result<&DebugProbeInfo> {
+    let interactive = io::stdin().is_terminal();
+    let remembered = match interactive {
+        true => load_last_selection(probes),
+        false => None,
+    };
+    let index = match choose(probes.len(), probe_index, remembered, interactive)? {
+        Choice::Probe(index) => index,
+        Choice::Ask { remembered } => {
+            let index = ask_for_index(probes, remembered)?;
+            if let Err(e) = store_last_selection(&probes[index]) {
+                log::debug!("failed to remember the selected probe: {e}");
             }
+            index
+        }
+        Choice::Ambiguous => {
+            print(probes);
+            bail!(
+                "more than one probe found; use --probe or --probe-index to specify which one to use"
+            );
+        }
+    };
+    Ok(&probes[index])
+}
+
+/// How [`select`] picks one of the probes
+#[derive(Debug, PartialEq, Eq)]
+enum Choice {
+    Probe(usize),
+    /// Ask on the terminal; Enter picks the `remembered` probe, if any
+    Ask {
+        remembered: Option<usize>,
+    },
+    /// Several probes, and no terminal to ask which one
+    Ambiguous,
+}
+
+fn choose(
+    num_probes: usize,
+    probe_index: Option<usize>,
+    remembered: Option<usize>,
+    interactive: bool,
+) -> anyhow::Result<Choice> {
+    match probe_index {
+        Some(index) if index < num_probes => Ok(Choice::Probe(index)),
+        Some(index) => bail!("`--probe-index {index}` is out of range; {num_probes} probes found"),
+        None if num_probes == 1 => Ok(Choice::Probe(0)),
+        // a remembered probe is only a suggestion: CI must not flash whichever board was used last
+        None if interactive => Ok(Choice::Ask { remembered }),
+        None => Ok(Choice::Ambiguous),
+    }
+}
+
+fn ask_for_index(probes: &[DebugProbeInfo], remembered: Option<usize>) -> anyhow::Result<usize> {
+    print(probes);
+
+    let mut stdin = io::stdin().lock();
+    loop {
+        match remembered {
+            Some(index) => eprint!(
+                "select a probe [0-{}] (Enter: {index}, selected last time): ",
+                probes.len() - 1
+            ),
+            None => eprint!("select a probe [0-{}]: ", probes.len() - 1),
+        }
+        io::stderr().flush()?;
 
-            true
-        })
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            bail!("no probe selected");
+        }
+        match (line.trim(), remembered) {
+            ("", Some(index)) => return Ok(index),
+            (line, _) => match line.parse::<usize>() {
+                Ok(index) if index < probes.len() => return Ok(index),
+                _ => eprintln!("invalid selection `{line}`"),
+            },
+        }
+    }
+}
+
+/// Returns the index of the remembered probe, if exactly one of the `probes` matches it.
+fn load_last_selection(probes: &[DebugProbeInfo]) -> Option<usize> {
+    let selector = fs::read_to_string(LAST_PROBE_PATH)
+        .ok()?
+        .trim()
+        .parse::<ProbeFilter>()
+        .ok()?;
+    find_remembered(&selector, probes)
+}
+
+fn find_remembered(selector: &ProbeFilter, probes: &[DebugProbeInfo]) -> Option<usize> {
+    let mut matching = probes
+        .iter()
+        .enumerate()
+        .filter(|(_, probe)| selector.matches(probe));
+    match (matching.next(), matching.next()) {
+        (Some((index, _)), None) => Some(index),
+        _ => None,
+    }
+}
+
+fn store_last_selection(probe: &DebugProbeInfo) -> io::Result<()> {
+    let path = Path::new(LAST_PROBE_PATH);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut selector = format!("{:04x}:{:04x}", probe.vendor_id, probe.product_id);
+    if let Some(serial) = &probe.serial_number {
+        selector = format!("{selector}:{}", Pattern::escape(serial));
+    }
+    fs::write(path, selector)
+}
+
+fn filter(probes: &[DebugProbeInfo], selector: &ProbeFilter) -> Vec<DebugProbeInfo> {
+    probes
+        .iter()
+        .filter(|probe| selector.matches(probe))
         .cloned()
         .collect()
 }
 
+/// Selects probes by USB ID, probe alias or identifier, and serial number (glob).
+///
+/// Accepted forms: `Serial`, `Alias`, `VID:PID`, `VID:PID:Serial` and `Name:Serial`, where `Name`
+/// is an alias (see [`alias`]) or a glob matched against the probe identifier.
 struct ProbeFilter {
-    vid_pid: Option<(u16, u16)>,
-    serial: Option<String>,
+    device: Option<Device>,
+    serial: Option<Pattern>,
+}
+
+enum Device {
+    VidPid(u16, u16),
+    Type(DebugProbeType),
+    Identifier(Pattern),
+}
+
+impl Device {
+    fn matches(&self, probe: &DebugProbeInfo) -> bool {
+        match self {
+            Device::VidPid(vid, pid) => probe.vendor_id == *vid && probe.product_id == *pid,
+            Device::Type(probe_type) => probe.probe_type == *probe_type,
+            Device::Identifier(pattern) => pattern.matches(&probe.identifier),
+        }
+    }
+}
+
+/// Shorthands for common probes
+fn alias(name: &str) -> Option<Device> {
+    let device = match name.to_ascii_lowercase().as_str() {
+        "cmsis-dap" => Device::Type(DebugProbeType::CmsisDap),
+        "daplink" => Device::VidPid(0x0d28, 0x0204),
+        "jlink" | "j-link" => Device::Type(DebugProbeType::JLink),
+        "rpi-debugprobe" => Device::VidPid(0x2e8a, 0x000c),
+        "stlink" | "st-link" => Device::Type(DebugProbeType::StLink),
+        _ => return None,
+    };
+    Some(device)
+}
+
+impl ProbeFilter {
+    fn matches(&self, probe: &DebugProbeInfo) -> bool {
+        if let Some(device) = &self.device {
+            if !device.matches(probe) {
+                return false;
+            }
+        }
+
+        if let Some(serial) = &self.serial {
+            if !serial.matches(probe.serial_number.as_deref().unwrap_or_default()) {
+                return false;
+            }
+        }
+
+        true
+    }
 }
 
 impl FromStr for ProbeFilter {
@@ -84,19 +391,188 @@ impl FromStr for ProbeFilter {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = s.split(':').collect::<Vec<_>>();
         match *parts {
-            [serial] => Ok(Self {
-                vid_pid: None,
-                serial: Some(serial.to_string()),
-            }),
-            [vid, pid] => Ok(Self {
-                vid_pid: Some((u16::from_str_radix(vid, 16)?, u16::from_str_radix(pid, 16)?)),
+            [name] => match alias(name) {
+                Some(device) => Ok(Self {
+                    device: Some(device),
+                    serial: None,
+                }),
+                None => Ok(Self {
+                    device: None,
+                    serial: Some(Pattern::new(name)?),
+                }),
+            },
+            [vid, pid] if is_usb_id(vid) && is_usb_id(pid) => Ok(Self {
+                device: Some(Device::VidPid(
+                    u16::from_str_radix(vid, 16)?,
+                    u16::from_str_radix(pid, 16)?,
+                )),
                 serial: None,
             }),
+            [name, serial] => Ok(Self {
+                device: Some(match alias(name) {
+                    Some(device) => device,
+                    None => Device::Identifier(Pattern::new(name)?),
+                }),
+                serial: Some(Pattern::new(serial)?),
+            }),
             [vid, pid, serial] => Ok(Self {
-                vid_pid: Some((u16::from_str_radix(vid, 16)?, u16::from_str_radix(pid, 16)?)),
-                serial: Some(serial.to_string()),
+                device: Some(Device::VidPid(
+                    u16::from_str_radix(vid, 16)?,
+                    u16::from_str_radix(pid, 16)?,
+                )),
+                serial: Some(Pattern::new(serial)?),
             }),
             _ => Err(anyhow!("invalid probe filter")),
         }
     }
 }
+
+/// Returns `true` if `s` looks like a hexadecimal USB vendor or product ID.
+fn is_usb_id(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 4 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn probe(identifier: &str, vid_pid: (u16, u16), serial: Option<&str>) -> DebugProbeInfo {
+        let probe_type = match vid_pid.0 {
+            0x1366 => DebugProbeType::JLink,
+            0x0483 => DebugProbeType::StLink,
+            _ => DebugProbeType::CmsisDap,
+        };
+        DebugProbeInfo::new(
+            identifier,
+            vid_pid.0,
+            vid_pid.1,
+            serial.map(str::to_string),
+            probe_type,
+            None,
+        )
+    }
+
+    #[rstest]
+    #[case::serial("123456", true)]
+    #[case::vid_pid("0d28:0204", true)]
+    #[case::vid_pid_serial("0d28:0204:123456", true)]
+    #[case::vid_pid_other_serial("0d28:0204:654321", false)]
+    #[case::alias("daplink", true)]
+    #[case::alias_any_serial("DAPLink:*", true)]
+    #[case::alias_serial_glob("cmsis-dap:123*", true)]
+    #[case::other_alias("jlink", false)]
+    #[case::identifier_glob("*CMSIS-DAP:12345?", true)]
+    #[case::other_identifier("J-Link*:*", false)]
+    fn should_match_probe(#[case] selector: &str, #[case] expected: bool) {
+        let probe = probe("DAPLink CMSIS-DAP", (0x0d28, 0x0204), Some("123456"));
+        let filter = selector.parse::<ProbeFilter>().unwrap();
+        assert_eq!(filter.matches(&probe), expected);
+    }
+
+    #[rstest]
+    #[case::index(3, Some(1), None, false, Choice::Probe(1))]
+    #[case::single(1, None, None, false, Choice::Probe(0))]
+    #[case::ask(3, None, None, true, Choice::Ask { remembered: None })]
+    #[case::remembered(3, None, Some(2), true, Choice::Ask { remembered: Some(2) })]
+    #[case::ambiguous(3, None, None, false, Choice::Ambiguous)]
+    #[case::remembered_without_terminal(3, None, Some(2), false, Choice::Ambiguous)]
+    fn chooses_probe(
+        #[case] num_probes: usize,
+        #[case] probe_index: Option<usize>,
+        #[case] remembered: Option<usize>,
+        #[case] interactive: bool,
+        #[case] expected: Choice,
+    ) {
+        assert_eq!(
+            choose(num_probes, probe_index, remembered, interactive).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_probe_index_out_of_range() {
+        assert!(choose(2, Some(2), None, true).is_err());
+    }
+
+    #[test]
+    fn finds_remembered_probe() {
+        let probes = [
+            probe("J-Link", (0x1366, 0x0101), Some("000123")),
+            probe("DAPLink CMSIS-DAP", (0x0d28, 0x0204), Some("123456")),
+            probe("DAPLink CMSIS-DAP", (0x0d28, 0x0204), Some("654321")),
+        ];
+        let find = |selector: &str| find_remembered(&selector.parse().unwrap(), &probes);
+        assert_eq!(find("0d28:0204:654321"), Some(2));
+        // not unique
+        assert_eq!(find("0d28:0204"), None);
+        // disconnected
+        assert_eq!(find("0483:374b:0001"), None);
+    }
+
+    #[rstest]
+    #[case::linux_access(
+        "USB Communication Error: Access denied (insufficient permissions)",
+        "linux",
+        false,
+        Some(AccessProblem::MissingUdevRule)
+    )]
+    #[case::linux_hidraw(
+        "Failed to open a device with path '/dev/hidraw3': Permission denied",
+        "linux",
+        false,
+        Some(AccessProblem::MissingUdevRule)
+    )]
+    #[case::modem_manager(
+        "USB Communication Error: Resource busy",
+        "linux",
+        true,
+        Some(AccessProblem::ClaimedByModemManager)
+    )]
+    #[case::busy("USB Communication Error: Resource busy", "linux", false, None)]
+    #[case::windows_driver(
+        "USB Communication Error: Operation not supported or unimplemented on this platform",
+        "windows",
+        false,
+        Some(AccessProblem::MissingWinUsbDriver)
+    )]
+    #[case::windows_access(
+        "USB Communication Error: Access denied (insufficient permissions)",
+        "windows",
+        false,
+        None
+    )]
+    #[case::macos(
+        "USB Communication Error: Access denied (insufficient permissions)",
+        "macos",
+        false,
+        None
+    )]
+    fn classifies_access_problem(
+        #[case] messages: &str,
+        #[case] os: &str,
+        #[case] modem_manager_running: bool,
+        #[case] expected: Option<AccessProblem>,
+    ) {
+        let problem = AccessProblem::classify(messages, os, || modem_manager_running);
+        assert_eq!(problem, expected);
+    }
+
+    #[test]
+    fn udev_rule_matches_usb_id() {
+        let probe = probe("DAPLink CMSIS-DAP", (0x0d28, 0x0204), None);
+        assert_eq!(
+            udev_rule(&probe, true),
+            "ATTRS{idVendor}==\"0d28\", ATTRS{idProduct}==\"0204\", MODE=\"660\", \
+            GROUP=\"plugdev\", TAG+=\"uaccess\", ENV{ID_MM_DEVICE_IGNORE}=\"1\""
+        );
+    }
+
+    #[test]
+    fn wildcard_serial_matches_probe_without_serial() {
+        let probe = probe("J-Link", (0x1366, 0x0101), None);
+        let filter = "jlink:*".parse::<ProbeFilter>().unwrap();
+        assert!(filter.matches(&probe));
+    }
+}
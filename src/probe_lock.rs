@@ -0,0 +1,165 @@
+//! `--lock-timeout`: an advisory lock file per probe, so that several `probe-run`s sharing the
+//! probes of a machine (e.g. CI jobs of a device farm) wait for a free probe instead of failing to
+//! open a busy one
+//!
+//! The locks are held until `probe-run` exits; the operating system releases them even if it
+//! crashes.
+
+use std::{
+    env,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use probe_rs::DebugProbeInfo;
+
+/// How often to check whether a busy probe became free
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The locks this `probe-run` holds, by key
+static HELD: Mutex<Vec<(String, File)>> = Mutex::new(vec![]);
+
+/// Lock the first of the `probes` no other `probe-run` uses, waiting up to `timeout` for one to
+/// become free.
+pub fn acquire<'a>(
+    probes: &[&'a DebugProbeInfo],
+    timeout: Duration,
+) -> anyhow::Result<&'a DebugProbeInfo> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        for probe in probes {
+            if try_lock(probe)? {
+                log::debug!("locked probe {probe:?}");
+                return Ok(probe);
+            }
+        }
+
+        if start.elapsed() >= timeout {
+            match probes {
+                [probe] => bail!(
+                    "probe {probe:?} is in use by another `probe-run` \
+                    (waited {}s, see `--lock-timeout`)",
+                    timeout.as_secs()
+                ),
+                _ => bail!(
+                    "all {} matching probes are in use by other `probe-run`s \
+                    (waited {}s, see `--lock-timeout`)",
+                    probes.len(),
+                    timeout.as_secs()
+                ),
+            }
+        }
+        if !waiting {
+            eprintln!("probe is in use by another `probe-run`; waiting for it..");
+            waiting = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Lock `probe`, unless another `probe-run` holds its lock.
+fn try_lock(probe: &DebugProbeInfo) -> io::Result<bool> {
+    let key = key(probe);
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    if held.iter().any(|(held_key, _)| *held_key == key) {
+        return Ok(true);
+    }
+
+    match lock_file(&path(&key))? {
+        Some(file) => {
+            held.push((key, file));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Identifies a probe across processes: its USB IDs and serial number
+fn key(probe: &DebugProbeInfo) -> String {
+    let mut key = format!("{:04x}-{:04x}", probe.vendor_id, probe.product_id);
+    if let Some(serial) = &probe.serial_number {
+        key.push('-');
+        key.extend(serial.chars().map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        }));
+    }
+    key
+}
+
+fn path(key: &str) -> PathBuf {
+    env::temp_dir().join(format!("probe-run-{key}.lock"))
+}
+
+/// Take an exclusive lock on the file at `path`, creating it if necessary. Returns `None` if
+/// someone else holds the lock.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> io::Result<Option<File>> {
+    use std::os::fd::AsRawFd as _;
+
+    // the file may belong to another user, who ran `probe-run` first; locking doesn't need write
+    // access
+    let file = match File::options().append(true).create(true).open(path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(path)?,
+        file => file?,
+    };
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+        0 => Ok(Some(file)),
+        _ => match io::Error::last_os_error() {
+            e if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_file(path: &Path) -> io::Result<Option<File>> {
+    log::debug!(
+        "`--lock-timeout` is not supported on this platform; not locking `{}`",
+        path.display()
+    );
+    File::options()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::DebugProbeType;
+
+    use super::*;
+
+    #[test]
+    fn keys_by_ids_and_serial() {
+        let probe = DebugProbeInfo::new(
+            "J-Link",
+            0x1366,
+            0x1051,
+            Some("0010/5023.1".to_string()),
+            DebugProbeType::JLink,
+            None,
+        );
+        assert_eq!(key(&probe), "1366-1051-0010_5023_1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lock_is_exclusive() {
+        let path = env::temp_dir().join(format!("probe-run-test-{}.lock", std::process::id()));
+        let lock = lock_file(&path).unwrap();
+        assert!(lock.is_some());
+        assert!(lock_file(&path).unwrap().is_none());
+
+        drop(lock);
+        assert!(lock_file(&path).unwrap().is_some());
+        let _ = std::fs::remove_file(path);
+    }
+}
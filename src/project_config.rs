@@ -0,0 +1,144 @@
+//! Look up the chip in the configuration files of the project probe-run is run in, for when it is
+//! run by hand rather than as cargo's runner: the `--chip` of the runner in `.cargo/config.toml`,
+//! or the chip `cargo-embed` uses from `Embed.toml`
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// Cargo configuration files, by precedence
+const CARGO_CONFIGS: [&str; 2] = [".cargo/config.toml", ".cargo/config"];
+const EMBED_CONFIG: &str = "Embed.toml";
+
+#[derive(Deserialize)]
+struct CargoConfig {
+    #[serde(default)]
+    target: BTreeMap<String, CargoTarget>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    runner: Option<Runner>,
+}
+
+/// A runner is a command line, or a list of its words.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Runner {
+    Line(String),
+    Words(Vec<String>),
+}
+
+/// `Embed.toml` is a map of profiles, e.g. `[default.general]`.
+#[derive(Deserialize)]
+struct EmbedProfile {
+    general: Option<EmbedGeneral>,
+}
+
+#[derive(Deserialize)]
+struct EmbedGeneral {
+    chip: Option<String>,
+}
+
+/// The chip configured for the project around `dir`, and the file it is configured in
+///
+/// Directories closer to `dir` take precedence; within a directory, the cargo configuration takes
+/// precedence over `Embed.toml`.
+pub fn chip(dir: &Path) -> Option<(String, PathBuf)> {
+    dir.ancestors().find_map(|dir| {
+        let cargo_configs = CARGO_CONFIGS.iter().map(|name| (dir.join(name), true));
+        let embed_config = (dir.join(EMBED_CONFIG), false);
+        cargo_configs
+            .chain([embed_config])
+            .find_map(|(path, is_cargo)| {
+                let contents = fs::read_to_string(&path).ok()?;
+                let chip = match is_cargo {
+                    true => chip_from_cargo_config(&contents),
+                    false => chip_from_embed_config(&contents),
+                };
+                match chip {
+                    Ok(chip) => chip.map(|chip| (chip, path)),
+                    Err(e) => {
+                        log::debug!("failed to parse `{}`: {e}", path.display());
+                        None
+                    }
+                }
+            })
+    })
+}
+
+/// The `--chip` passed by the first runner which passes one
+fn chip_from_cargo_config(contents: &str) -> anyhow::Result<Option<String>> {
+    let config = toml::from_str::<CargoConfig>(contents)?;
+    Ok(config.target.values().find_map(|target| {
+        let words = match target.runner.as_ref()? {
+            Runner::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+            Runner::Words(words) => words.clone(),
+        };
+        chip_argument(&words)
+    }))
+}
+
+/// The chip of the `default` profile, or else of the first profile which has one
+fn chip_from_embed_config(contents: &str) -> anyhow::Result<Option<String>> {
+    let profiles = toml::from_str::<BTreeMap<String, EmbedProfile>>(contents)?;
+    let chip = |profile: &EmbedProfile| profile.general.as_ref()?.chip.clone();
+    Ok(profiles
+        .get("default")
+        .and_then(chip)
+        .or_else(|| profiles.values().find_map(chip)))
+}
+
+/// The value of `--chip <name>` or `--chip=<name>` in a command line
+fn chip_argument(words: &[String]) -> Option<String> {
+    words
+        .iter()
+        .enumerate()
+        .find_map(|(index, word)| match word.as_str() {
+            "--chip" => words.get(index + 1).cloned(),
+            _ => word.strip_prefix("--chip=").map(str::to_string),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::line(
+        "[target.'cfg(all(target_arch = \"arm\", target_os = \"none\"))']\nrunner = \"probe-run --chip nRF52840_xxAA\"",
+        Some("nRF52840_xxAA")
+    )]
+    #[case::words(
+        "[target.thumbv7em-none-eabihf]\nrunner = [\"probe-run\", \"--chip=STM32F401RE\"]",
+        Some("STM32F401RE")
+    )]
+    #[case::no_chip("[target.thumbv6m-none-eabi]\nrunner = \"probe-run\"", None)]
+    #[case::no_runner("[build]\ntarget = \"thumbv6m-none-eabi\"", None)]
+    fn finds_chip_in_cargo_config(#[case] contents: &str, #[case] expected: Option<&str>) {
+        assert_eq!(
+            chip_from_cargo_config(contents).unwrap().as_deref(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::default(
+        "[debug.general]\nchip = \"nrf51822\"\n[default.general]\nchip = \"nRF52840_xxAA\"",
+        Some("nRF52840_xxAA")
+    )]
+    #[case::other_profile("[debug.general]\nchip = \"nrf51822\"", Some("nrf51822"))]
+    #[case::no_chip("[default.rtt]\nenabled = true", None)]
+    fn finds_chip_in_embed_config(#[case] contents: &str, #[case] expected: Option<&str>) {
+        assert_eq!(
+            chip_from_embed_config(contents).unwrap().as_deref(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Chip family quirks: register writes at fixed points of a run, e.g. to keep a watchdog from
+//! resetting the chip while it is flashed or halted (see `--quirks` and `--no-builtin-quirks`)
+//!
+//! Quirks are TOML, a list of `[[quirk]]` tables like the built-in ones in [`BUILTIN`]:
+//! - `name`: shows up in the log when the quirk is applied
+//! - `chips`: the chips the quirk applies to, by (case-insensitive) target name prefix
+//! - `post_reset`: writes after probe-run reset the chip, before flashing and before the program
+//!   starts
+//! - `pre_run`: writes right before the program starts
+//!
+//! A write sets the register at `address` to `value`, or sets the bits of `set` and clears the
+//! bits of `clear`, keeping the others.
+
+use std::{fmt, fs, path::Path};
+
+use anyhow::{bail, Context as _};
+use probe_rs::{Core, MemoryInterface as _};
+use serde::Deserialize;
+
+use crate::cli;
+
+/// The quirks probe-run ships with, unless `--no-builtin-quirks`
+const BUILTIN: &str = r#"
+# debug access stops in the low-power modes, and the watchdogs keep counting while the core is
+# halted; DBGMCU_CR and DBGMCU_APB1_FZ
+[[quirk]]
+name = "stm32: debug in low-power modes, freeze watchdogs when halted"
+chips = ["stm32f2", "stm32f4", "stm32f7"]
+post_reset = [
+    { address = 0xE0042004, set = 0x7 },
+    { address = 0xE0042008, set = 0x1800 },
+]
+
+# DBGMCU_CR, which also freezes the watchdogs on the STM32F1
+[[quirk]]
+name = "stm32: debug in low-power modes, freeze watchdogs when halted"
+chips = ["stm32f1"]
+post_reset = [{ address = 0xE0042004, set = 0x307 }]
+
+# DBG_CR and DBG_APB_FZ1
+[[quirk]]
+name = "stm32: debug in low-power modes, freeze watchdogs when halted"
+chips = ["stm32g0", "stm32l0"]
+post_reset = [
+    { address = 0x40015804, set = 0x6 },
+    { address = 0x40015808, set = 0x1800 },
+]
+
+# a reset through the debug port doesn't reset the watchdog, so the watchdog of the program that
+# ran before resets the chip in the middle of flashing; WATCHDOG_CTRL.ENABLE
+[[quirk]]
+name = "rp2040: stop the watchdog of the previous program"
+chips = ["rp2040"]
+post_reset = [{ address = 0x40058000, clear = 0x40000000 }]
+"#;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    quirk: Vec<Quirk>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Quirk {
+    name: String,
+    chips: Vec<String>,
+    #[serde(default)]
+    post_reset: Vec<Write>,
+    #[serde(default)]
+    pre_run: Vec<Write>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Write {
+    address: u32,
+    value: Option<u32>,
+    #[serde(default)]
+    set: u32,
+    #[serde(default)]
+    clear: u32,
+}
+
+/// When during a run quirks are applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Point {
+    PostReset,
+    PreRun,
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Point::PostReset => "post_reset",
+            Point::PreRun => "pre_run",
+        })
+    }
+}
+
+/// The quirks of one chip
+pub struct Quirks(Vec<Quirk>);
+
+impl Quirks {
+    /// The built-in quirks and those of `--quirks` which apply to the chip `target_name`.
+    pub fn load(target_name: &str, opts: &cli::Opts) -> anyhow::Result<Self> {
+        let mut quirks = vec![];
+        if !opts.no_builtin_quirks {
+            quirks.extend(parse(BUILTIN).context("failed to parse the built-in quirks")?);
+        }
+        if let Some(path) = &opts.quirks {
+            quirks.extend(load_file(path)?);
+        }
+
+        let quirks = Self::for_chip(quirks, target_name);
+        for quirk in &quirks.0 {
+            log::debug!("chip quirk `{}` applies", quirk.name);
+        }
+        Ok(quirks)
+    }
+
+    fn for_chip(mut quirks: Vec<Quirk>, target_name: &str) -> Self {
+        let target_name = target_name.to_ascii_lowercase();
+        quirks.retain(|quirk| {
+            quirk
+                .chips
+                .iter()
+                .any(|prefix| target_name.starts_with(&prefix.to_ascii_lowercase()))
+        });
+        Self(quirks)
+    }
+
+    /// Returns `true` if any quirk writes at `point`.
+    pub fn any_at(&self, point: Point) -> bool {
+        self.0.iter().any(|quirk| !quirk.writes(point).is_empty())
+    }
+
+    /// Do the writes of all quirks for `point`; the core is halted.
+    pub fn apply(&self, core: &mut Core, point: Point) -> anyhow::Result<()> {
+        for quirk in &self.0 {
+            let writes = quirk.writes(point);
+            if writes.is_empty() {
+                continue;
+            }
+            log::debug!("applying chip quirk `{}` ({point})", quirk.name);
+            for write in writes {
+                write
+                    .apply(core)
+                    .with_context(|| format!("failed to apply chip quirk `{}`", quirk.name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Quirk {
+    fn writes(&self, point: Point) -> &[Write] {
+        match point {
+            Point::PostReset => &self.post_reset,
+            Point::PreRun => &self.pre_run,
+        }
+    }
+}
+
+impl Write {
+    fn apply(&self, core: &mut Core) -> anyhow::Result<()> {
+        let address = u64::from(self.address);
+        let value = match self.value {
+            Some(value) => value,
+            None => (core.read_word_32(address)? | self.set) & !self.clear,
+        };
+        core.write_word_32(address, value)?;
+        log::trace!("wrote {value:#010x} to {address:#010x}");
+        Ok(())
+    }
+}
+
+fn load_file(path: &Path) -> anyhow::Result<Vec<Quirk>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read the quirks `{}`", path.display()))?;
+    parse(&contents).with_context(|| format!("failed to parse the quirks `{}`", path.display()))
+}
+
+fn parse(contents: &str) -> anyhow::Result<Vec<Quirk>> {
+    let file = toml::from_str::<File>(contents)?;
+    for quirk in &file.quirk {
+        let writes = quirk.post_reset.iter().chain(&quirk.pre_run);
+        for write in writes {
+            if write.value.is_some() && (write.set != 0 || write.clear != 0) {
+                bail!(
+                    "quirk `{}` writes {:#010x} with both `value` and `set` or `clear`",
+                    quirk.name,
+                    write.address
+                );
+            }
+        }
+    }
+    Ok(file.quirk)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::stm32f4("STM32F401RETx", true)]
+    #[case::stm32f1("STM32F103C8", true)]
+    #[case::rp2040("RP2040", true)]
+    #[case::nrf52("nRF52840_xxAA", false)]
+    fn finds_builtin_quirks(#[case] target_name: &str, #[case] expected: bool) {
+        let quirks = Quirks::for_chip(parse(BUILTIN).unwrap(), target_name);
+        assert_eq!(quirks.any_at(Point::PostReset), expected);
+    }
+
+    #[test]
+    fn parses_quirks() {
+        let quirks = parse(
+            r#"
+            [[quirk]]
+            name = "kinetis: disable COP"
+            chips = ["MKL25Z"]
+            pre_run = [{ address = 0x40048100, value = 0 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            quirks[0].pre_run,
+            [Write {
+                address: 0x4004_8100,
+                value: Some(0),
+                set: 0,
+                clear: 0,
+            }]
+        );
+    }
+
+    #[rstest]
+    #[case::value_and_set("pre_run = [{ address = 0x0, value = 1, set = 1 }]")]
+    #[case::unknown_point("post_flash = [{ address = 0x0, value = 1 }]")]
+    #[case::unknown_field("pre_run = [{ address = 0x0, mask = 1 }]")]
+    fn rejects_invalid_quirks(#[case] writes: &str) {
+        let contents = format!("[[quirk]]\nname = \"test\"\nchips = [\"rp2040\"]\n{writes}");
+        assert!(parse(&contents).is_err());
+    }
+}
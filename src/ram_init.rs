@@ -0,0 +1,130 @@
+//! `--zero-ram` and `--verify-ram-init`: prepare and check the RAM before the program runs
+//!
+//! Some chips, like the STM32H7, have RAM with ECC which faults on reads of words that were
+//! never written. Zero-filling such RAM and verifying that all of it is readable helps to tell
+//! these faults apart from bugs in the program.
+
+use std::{ops::Range, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{config::MemoryRegion, Core, MemoryInterface as _};
+
+use crate::{canary, cli, target_info::TargetInfo};
+
+/// Size of the blocks in which the RAM is read during verification
+const BLOCK_SIZE: u32 = 1024;
+
+/// A RAM region given by its name in the memory map or as `<start>..<end>` (see `--zero-ram`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RamSpec {
+    Name(String),
+    Range(Range<u32>),
+}
+
+impl FromStr for RamSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("..") {
+            Some((start, end)) => {
+                let range = cli::parse_u32(start)?..cli::parse_u32(end)?;
+                if range.is_empty() || range.start % 4 != 0 || range.end % 4 != 0 {
+                    bail!("`{s}` is not a non-empty, 4-byte-aligned address range");
+                }
+                Ok(RamSpec::Range(range))
+            }
+            None => Ok(RamSpec::Name(s.to_string())),
+        }
+    }
+}
+
+impl RamSpec {
+    fn resolve(&self, memory_map: &[MemoryRegion]) -> anyhow::Result<Range<u32>> {
+        match self {
+            RamSpec::Range(range) => Ok(range.clone()),
+            RamSpec::Name(name) => ram_regions(memory_map)
+                .find(|(region_name, _)| region_name.eq_ignore_ascii_case(name))
+                .map(|(_, range)| range)
+                .ok_or_else(|| anyhow!("no RAM region named `{name}` in the memory map")),
+        }
+    }
+}
+
+/// Zero-fill all `specs` RAM regions.
+pub fn zero_fill(
+    core: &mut Core,
+    target_info: &TargetInfo,
+    specs: &[RamSpec],
+) -> anyhow::Result<()> {
+    for spec in specs {
+        let range = spec.resolve(&target_info.memory_map)?;
+        log::info!("zero-filling RAM {range:#010X?}");
+        canary::fill(core, range, 0, target_info.core_type())?;
+    }
+    Ok(())
+}
+
+/// Check that every RAM region of the memory map can be read.
+pub fn verify(core: &mut Core, memory_map: &[MemoryRegion]) -> anyhow::Result<()> {
+    let mut failures = vec![];
+    for (name, range) in ram_regions(memory_map) {
+        let mut buf = vec![0; BLOCK_SIZE as usize / 4];
+        let failed_block = range.clone().step_by(BLOCK_SIZE as usize).find(|&address| {
+            let len = (range.end - address).min(BLOCK_SIZE) as usize / 4;
+            core.read_32(address.into(), &mut buf[..len]).is_err()
+        });
+
+        match failed_block {
+            Some(address) => {
+                log::error!(
+                    "RAM region `{name}` {range:#010X?} is not readable at {address:#010X}"
+                );
+                failures.push(name);
+            }
+            None => log::debug!("RAM region `{name}` {range:#010X?} is readable"),
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "{} RAM region(s) could not be read; on chips with ECC RAM, initialize them with \
+            `--zero-ram <region>` before running the program",
+            failures.len()
+        );
+    }
+    log::info!("RAM is initialized and readable");
+    Ok(())
+}
+
+/// The RAM regions in the memory map, with their names (`RAM` if unnamed)
+pub fn ram_regions(memory_map: &[MemoryRegion]) -> impl Iterator<Item = (String, Range<u32>)> + '_ {
+    memory_map.iter().filter_map(|region| match region {
+        MemoryRegion::Ram(ram) => Some((
+            ram.name.clone().unwrap_or_else(|| "RAM".to_string()),
+            ram.range.start as u32..ram.range.end as u32,
+        )),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::name("SRAM1", RamSpec::Name("SRAM1".into()))]
+    #[case::range("0x24000000..0x24080000", RamSpec::Range(0x2400_0000..0x2408_0000))]
+    fn should_parse_ram_spec(#[case] input: &str, #[case] expected: RamSpec) {
+        assert_eq!(input.parse::<RamSpec>().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::unaligned("0x20000001..0x20000100")]
+    #[case::empty("0x20000000..0x20000000")]
+    #[case::not_a_number("0x20000000..end")]
+    fn should_reject_malformed_ram_spec(#[case] input: &str) {
+        assert!(input.parse::<RamSpec>().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! `--ram-map`: paint the RAM the program doesn't occupy with the stack canary's value before the
+//! run, and afterwards export which parts of it were written, e.g. to find buffers larger than
+//! expected or stray DMA writes
+//!
+//! The RAM of the ELF's sections (`.data`, `.bss`, `.uninit`, ...) is left alone; the stack is
+//! part of the map, since the canary already painted it.
+
+use std::{fs, ops::Range, path::Path, time::Instant};
+
+use anyhow::Context as _;
+use object::{elf::SHF_ALLOC, Object as _, ObjectSection as _, SectionFlags};
+use probe_rs::Core;
+
+use crate::{
+    canary::{self, Canary},
+    elf::Elf,
+    erase, ram_init,
+    target_info::TargetInfo,
+};
+
+/// Bumped on incompatible changes of the output
+const MAP_VERSION: u32 = 1;
+/// Bytes per entry of a region's `buckets`
+const BUCKET_SIZE: usize = 256;
+
+pub struct RamMap {
+    /// The name of the RAM region, and the part of it which is mapped
+    regions: Vec<(String, Range<u32>)>,
+}
+
+impl RamMap {
+    /// Paint the RAM which neither the sections of the ELF nor the stack canary occupy.
+    ///
+    /// Expects the core to be reset-halted, after the canary was installed.
+    pub fn paint(
+        core: &mut Core,
+        elf: &Elf,
+        target_info: &TargetInfo,
+        canary: Option<&Canary>,
+    ) -> anyhow::Result<Self> {
+        let sections = ram_sections(elf);
+        let regions = ram_init::ram_regions(&target_info.memory_map)
+            .flat_map(|(name, ram)| {
+                let ram = u64::from(ram.start)..u64::from(ram.end);
+                erase::subtract(&[ram], &sections)
+                    .into_iter()
+                    .filter_map(word_aligned)
+                    .map(move |range| (name.clone(), range))
+            })
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let canary = canary
+            .map(|canary| canary.range())
+            .map(|range| u64::from(range.start)..u64::from(range.end));
+        let mut painted = 0;
+        for (_, range) in &regions {
+            let range = u64::from(range.start)..u64::from(range.end);
+            for range in erase::subtract(&[range], canary.as_slice()) {
+                if let Some(range) = word_aligned(range) {
+                    painted += range.end - range.start;
+                    canary::paint_region(core, range, target_info.core_type())?;
+                }
+            }
+        }
+        log::debug!(
+            "painting {:.2} KiB of RAM for `--ram-map` took {:.3}s",
+            painted as f64 / 1024.0,
+            start.elapsed().as_secs_f64()
+        );
+
+        Ok(Self { regions })
+    }
+
+    /// Read which parts of the painted RAM were written, and write the map to `path` as JSON.
+    ///
+    /// Reads the memory with the probe only, so it must run before the stack canary's measuring
+    /// subroutine writes to the stack.
+    pub fn export(&self, core: &mut Core, path: &Path) -> anyhow::Result<()> {
+        let mut total_bytes = 0;
+        let mut written_bytes = 0;
+        let mut regions = vec![];
+        for (name, range) in &self.regions {
+            let touched = canary::touched_bytes(core, range.clone())?;
+            let buckets = bucket_counts(&touched);
+            let written = buckets.iter().sum::<usize>();
+            total_bytes += touched.len();
+            written_bytes += written;
+            regions.push(serde_json::json!({
+                "name": name,
+                "start": range.start,
+                "end": range.end,
+                "written_bytes": written,
+                "buckets": buckets,
+            }));
+        }
+
+        let map = serde_json::json!({
+            "version": MAP_VERSION,
+            "bucket_size": BUCKET_SIZE,
+            "regions": regions,
+        });
+        let json = serde_json::to_string_pretty(&map)?;
+        fs::write(path, json + "\n")
+            .with_context(|| format!("failed to write the RAM map to `{}`", path.display()))?;
+        log::info!(
+            "the program wrote {written_bytes} of {total_bytes} bytes of unoccupied RAM; \
+            wrote the RAM map to `{}`",
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// The address ranges of the sections of the ELF which take up memory
+fn ram_sections(elf: &Elf) -> Vec<Range<u64>> {
+    elf.sections()
+        .filter(|section| {
+            matches!(section.flags(), SectionFlags::Elf { sh_flags } if sh_flags & u64::from(SHF_ALLOC) != 0)
+        })
+        .map(|section| section.address()..section.address() + section.size())
+        .filter(|range| !range.is_empty())
+        .collect()
+}
+
+/// The whole words of `range`, if any
+fn word_aligned(range: Range<u64>) -> Option<Range<u32>> {
+    let start = u32::try_from(range.start.next_multiple_of(4)).ok()?;
+    let end = u32::try_from(range.end - range.end % 4).ok()?;
+    (start < end).then_some(start..end)
+}
+
+/// The number of touched bytes in each bucket
+fn bucket_counts(touched: &[bool]) -> Vec<usize> {
+    touched
+        .chunks(BUCKET_SIZE)
+        .map(|bucket| bucket.iter().filter(|touched| **touched).count())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::aligned(0x2000_0000..0x2000_0100, Some(0x2000_0000..0x2000_0100))]
+    #[case::unaligned(0x2000_0002..0x2000_0103, Some(0x2000_0004..0x2000_0100))]
+    #[case::too_small(0x2000_0001..0x2000_0003, None)]
+    fn aligns_to_words(#[case] range: Range<u64>, #[case] expected: Option<Range<u32>>) {
+        assert_eq!(word_aligned(range), expected);
+    }
+
+    #[test]
+    fn counts_touched_bytes_per_bucket() {
+        let mut touched = vec![false; BUCKET_SIZE * 2 + 4];
+        touched[1] = true;
+        touched[BUCKET_SIZE * 2..].fill(true);
+        assert_eq!(bucket_counts(&touched), [1, 0, 4]);
+    }
+}
@@ -0,0 +1,128 @@
+//! The raw, undecoded bytes of the RTT up channel, stored in rotating files while the logs get
+//! decoded live (see `--raw-capture`), so that they can be decoded again later, e.g. with a newer
+//! `defmt-print`
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context as _;
+
+/// Start a new file once the current one reached this size
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Delete the oldest file of the run once there are more than this many
+const MAX_FILES: usize = 8;
+
+pub struct RawCapture {
+    dir: PathBuf,
+    channel: String,
+    max_file_size: u64,
+    file: Option<File>,
+    /// Bytes written to `file`
+    file_size: u64,
+    /// The files of this run, oldest first
+    files: VecDeque<PathBuf>,
+    num_files: u32,
+}
+
+impl RawCapture {
+    /// Capture the bytes of the up channel `channel` into `dir`, which gets created if missing.
+    pub fn new(dir: &Path, channel: Option<&str>) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create raw capture directory {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            // the name ends up in file names, so only keep harmless characters
+            channel: channel
+                .unwrap_or("up0")
+                .chars()
+                .map(
+                    |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        true => c,
+                        false => '_',
+                    },
+                )
+                .collect(),
+            max_file_size: MAX_FILE_SIZE,
+            file: None,
+            file_size: 0,
+            files: VecDeque::new(),
+            num_files: 0,
+        })
+    }
+
+    /// Append `bytes` to the current file, starting a new one if it is full.
+    ///
+    /// Reads are never split across files, so a file may get a little larger than the limit.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.file.is_none() || self.file_size >= self.max_file_size {
+            self.rotate()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(bytes)?;
+            self.file_size += bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // the sequence number keeps files apart which were started within the same millisecond
+        let path = self.dir.join(format!(
+            "{}-{millis:013}-{:04}.bin",
+            self.channel, self.num_files
+        ));
+        self.num_files += 1;
+        self.file = Some(File::create(&path)?);
+        self.file_size = 0;
+        log::debug!("capturing raw RTT data into {}", path.display());
+
+        self.files.push_back(path);
+        if self.files.len() > MAX_FILES {
+            if let Some(oldest) = self.files.pop_front() {
+                fs::remove_file(oldest)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_files() {
+        let dir =
+            std::env::temp_dir().join(format!("probe-run-raw-capture-{}", std::process::id()));
+        let mut capture = RawCapture::new(&dir, Some("defmt")).unwrap();
+        capture.max_file_size = 4;
+
+        for chunk in 0..MAX_FILES as u8 + 2 {
+            capture.write(&[chunk; 4]).unwrap();
+        }
+
+        let mut files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        let contents = files
+            .iter()
+            .map(|file| fs::read(file).unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the two oldest files got deleted
+        assert_eq!(files.len(), MAX_FILES);
+        assert_eq!(contents.first(), Some(&vec![2; 4]));
+        assert_eq!(contents.last(), Some(&vec![MAX_FILES as u8 + 1; 4]));
+    }
+}
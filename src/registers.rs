@@ -1,20 +1,25 @@
 use std::collections::{btree_map, BTreeMap};
 
 use gimli::{read::CfaRule, EndianSlice, LittleEndian, Register, RegisterRule};
-use probe_rs::{Core, MemoryInterface, RegisterId};
+use probe_rs::RegisterId;
+
+use crate::transport::Transport;
 
 pub const LR: RegisterId = RegisterId(14);
 pub const PC: RegisterId = RegisterId(15);
 pub const SP: RegisterId = RegisterId(13);
+pub const XPSR: RegisterId = RegisterId(0b1_0000);
+pub const MSP: RegisterId = RegisterId(0b1_0001);
+pub const PSP: RegisterId = RegisterId(0b1_0010);
 
 /// Cache and track the state of CPU registers while the stack is being unwound.
-pub struct Registers<'c, 'probe> {
+pub struct Registers<'c> {
     cache: BTreeMap<u16, u32>,
-    pub core: &'c mut Core<'probe>,
+    pub core: &'c mut dyn Transport,
 }
 
-impl<'c, 'probe> Registers<'c, 'probe> {
-    pub fn new(lr: u32, sp: u32, core: &'c mut Core<'probe>) -> Self {
+impl<'c> Registers<'c> {
+    pub fn new(lr: u32, sp: u32, core: &'c mut dyn Transport) -> Self {
         let mut cache = BTreeMap::new();
         cache.insert(LR.0, lr);
         cache.insert(SP.0, sp);
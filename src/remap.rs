@@ -0,0 +1,122 @@
+//! Flash which the chip maps to address 0 at boot, e.g. on STM32s. The core may then execute the
+//! program through the alias, so that the PC differs from the addresses the ELF was linked at.
+
+use std::ops::Range;
+
+use probe_rs::config::MemoryRegion;
+
+/// Two views of the boot flash: where the ELF was linked and where the core may execute it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remap {
+    /// The addresses the core may execute at, instead of the linked ones
+    executed: Range<u32>,
+    /// Where the same memory starts in the ELF
+    linked: u32,
+}
+
+impl Remap {
+    /// Find the alias of the boot flash which contains `linked_address`, if the chip has one.
+    ///
+    /// The memory map doesn't describe aliases, so this assumes one at address 0 if the boot
+    /// flash starts elsewhere and no other region covers address 0.
+    pub fn find(memory_map: &[MemoryRegion], linked_address: u32) -> Option<Self> {
+        let boot_flash = memory_map.iter().find_map(|region| match region {
+            MemoryRegion::Nvm(region) if region.is_boot_memory && region.range.start != 0 => {
+                let start = u32::try_from(region.range.start).ok()?;
+                let end = u32::try_from(region.range.end).ok()?;
+                Some(start..end)
+            }
+            _ => None,
+        })?;
+        let alias = 0..boot_flash.end - boot_flash.start;
+
+        let alias_is_free = memory_map.iter().all(|region| {
+            let range = match region {
+                MemoryRegion::Nvm(region) => &region.range,
+                MemoryRegion::Ram(region) => &region.range,
+                MemoryRegion::Generic(region) => &region.range,
+            };
+            range.end <= u64::from(alias.start) || u64::from(alias.end) <= range.start
+        });
+        if !alias_is_free {
+            return None;
+        }
+
+        if boot_flash.contains(&linked_address) {
+            Some(Self {
+                executed: alias,
+                linked: boot_flash.start,
+            })
+        } else if alias.contains(&linked_address) {
+            Some(Self {
+                linked: alias.start,
+                executed: boot_flash,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The address at which the core may also execute the code linked at `linked_address`;
+    /// `None` if the address is outside of the remapped flash.
+    pub fn executed_address(&self, linked_address: u32) -> Option<u32> {
+        let offset = linked_address.checked_sub(self.linked)?;
+        (offset < self.executed.len() as u32).then_some(self.executed.start + offset)
+    }
+
+    /// The linked address of `address`, if the core executes it through the alias; other
+    /// addresses are returned as they are.
+    pub fn linked_address(&self, address: u32) -> u32 {
+        match self.executed.contains(&address) {
+            true => address - self.executed.start + self.linked,
+            false => address,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::config::{NvmRegion, RamRegion};
+
+    use super::*;
+
+    fn memory_map(flash_start: u64) -> Vec<MemoryRegion> {
+        vec![
+            MemoryRegion::Nvm(NvmRegion {
+                name: None,
+                range: flash_start..flash_start + 0x10_0000,
+                is_boot_memory: true,
+                cores: vec![],
+            }),
+            MemoryRegion::Ram(RamRegion {
+                name: None,
+                range: 0x2000_0000..0x2002_0000,
+                is_boot_memory: false,
+                cores: vec![],
+            }),
+        ]
+    }
+
+    #[test]
+    fn maps_flash_linked_program_to_alias() {
+        let remap = Remap::find(&memory_map(0x0800_0000), 0x0800_0400).unwrap();
+
+        assert_eq!(remap.executed_address(0x0800_0400), Some(0x0000_0400));
+        assert_eq!(remap.executed_address(0x2000_0100), None);
+        assert_eq!(remap.linked_address(0x0000_0400), 0x0800_0400);
+        assert_eq!(remap.linked_address(0x2000_0100), 0x2000_0100);
+    }
+
+    #[test]
+    fn maps_alias_linked_program_to_flash() {
+        let remap = Remap::find(&memory_map(0x0800_0000), 0x0000_0400).unwrap();
+
+        assert_eq!(remap.executed_address(0x0000_0400), Some(0x0800_0400));
+        assert_eq!(remap.linked_address(0x0800_0400), 0x0000_0400);
+    }
+
+    #[test]
+    fn finds_no_alias_if_flash_starts_at_zero() {
+        assert_eq!(Remap::find(&memory_map(0), 0x400), None);
+    }
+}
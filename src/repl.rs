@@ -0,0 +1,256 @@
+//! Interactive inspection of the halted target after a fault (see `--post-mortem repl`)
+
+use std::io::{self, BufRead as _, Write as _};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, MemoryInterface as _, RegisterId};
+
+use crate::{
+    backtrace::{self, BacktraceOptions},
+    cli,
+    elf::Elf,
+    registers::{LR, PC, SP, XPSR},
+    target_info::TargetInfo,
+    theme,
+};
+
+const HELP: &str = "commands:
+    read <addr> <len>   print `len` bytes of memory starting at `addr`
+    read-sym <name>     print the memory of the symbol `name`
+    regs                print the core registers
+    bt                  print the backtrace
+    stack <n>           print the top `n` words of the stack
+    help                print this message
+    quit                exit";
+
+/// Number of bytes per line of a memory dump
+const BYTES_PER_LINE: usize = 16;
+/// The most bytes `read` and `read-sym` print, and the most words `stack` prints
+const MAX_READ_LEN: u32 = 64 * 1024;
+const MAX_STACK_WORDS: u32 = MAX_READ_LEN / 4;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Empty,
+    Help,
+    Quit,
+    Read { addr: u32, len: u32 },
+    ReadSymbol(&'a str),
+    Registers,
+    Backtrace,
+    Stack(u32),
+}
+
+fn parse(line: &str) -> anyhow::Result<Command<'_>> {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    let command = match *args.as_slice() {
+        [] => Command::Empty,
+        ["help"] => Command::Help,
+        ["quit"] | ["exit"] => Command::Quit,
+        ["read", addr, len] => {
+            let addr = cli::parse_u32(addr)?;
+            let len = cli::parse_u32(len)?;
+            check_range(addr, len)?;
+            Command::Read { addr, len }
+        }
+        ["read-sym", name] => Command::ReadSymbol(name),
+        ["regs"] => Command::Registers,
+        ["bt"] => Command::Backtrace,
+        ["stack", n] => {
+            let n = cli::parse_u32(n)?;
+            if n > MAX_STACK_WORDS {
+                bail!("`stack` prints at most {MAX_STACK_WORDS} words, not {n}");
+            }
+            Command::Stack(n)
+        }
+        _ => bail!("unknown command `{}`; try `help`", line.trim()),
+    };
+    Ok(command)
+}
+
+/// Check that `len` bytes from `addr` can be read.
+fn check_range(addr: u32, len: u32) -> anyhow::Result<()> {
+    if len > MAX_READ_LEN {
+        bail!("at most {MAX_READ_LEN} bytes can be read at once, not {len}");
+    }
+    // the range may end right at the top of the address space
+    if len > 0 && addr.checked_add(len - 1).is_none() {
+        bail!("{len} bytes from {addr:#010x} go past the end of the address space");
+    }
+    Ok(())
+}
+
+pub fn run(
+    core: &mut Core,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    backtrace_settings: &mut backtrace::Settings,
+) -> anyhow::Result<()> {
+    eprintln!("entering post-mortem mode; type `help` for a list of commands");
+
+    let mut stdin = io::stdin().lock();
+    loop {
+        eprint!("(probe-run) ");
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(()); // EOF
+        }
+
+        let result = match parse(&line) {
+            Ok(Command::Empty) => Ok(()),
+            Ok(Command::Help) => {
+                eprintln!("{HELP}");
+                Ok(())
+            }
+            Ok(Command::Quit) => return Ok(()),
+            Ok(Command::Read { addr, len }) => dump(core, addr, len),
+            Ok(Command::ReadSymbol(name)) => read_symbol(core, elf, name),
+            Ok(Command::Registers) => print_registers(core),
+            Ok(Command::Backtrace) => print_backtrace(core, elf, target_info, backtrace_settings),
+            Ok(Command::Stack(n)) => print_stack(core, n),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            eprintln!("{} {e}", theme::current().error.paint("error:"));
+        }
+    }
+}
+
+fn read_symbol(core: &mut Core, elf: &Elf, name: &str) -> anyhow::Result<()> {
+    let range = elf
+        .find_symbol(name)
+        .ok_or_else(|| anyhow!("symbol `{name}` not found"))?;
+    if range.is_empty() {
+        bail!("symbol `{name}` at {:#010X} has no size", range.start);
+    }
+    let len = range.end - range.start;
+    check_range(range.start, len)?;
+    dump(core, range.start, len)
+}
+
+fn dump(core: &mut Core, addr: u32, len: u32) -> anyhow::Result<()> {
+    let mut bytes = vec![0; len as usize];
+    core.read_8(addr.into(), &mut bytes)?;
+    for line in format_dump(addr, &bytes) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Lines like `0x20000000: 68 65 6c 6c 6f 00 ..  hello.`
+fn format_dump(addr: u32, bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex = line
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                    true => byte as char,
+                    false => '.',
+                })
+                .collect::<String>();
+            let line_addr = addr.wrapping_add((i * BYTES_PER_LINE) as u32);
+            format!("{line_addr:#010x}: {hex:<47}  {ascii}")
+        })
+        .collect()
+}
+
+fn print_registers(core: &mut Core) -> anyhow::Result<()> {
+    for n in 0..=12 {
+        let value = core.read_core_reg::<u32>(RegisterId(n))?;
+        println!("r{n:<4} {value:#010x}");
+    }
+    for (name, reg) in [("sp", SP), ("lr", LR), ("pc", PC), ("xpsr", XPSR)] {
+        let value = core.read_core_reg::<u32>(reg)?;
+        println!("{name:<5} {value:#010x}");
+    }
+    Ok(())
+}
+
+fn print_backtrace(
+    core: &mut Core,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    settings: &mut backtrace::Settings,
+) -> anyhow::Result<()> {
+    settings.backtrace = BacktraceOptions::Always;
+    backtrace::print(core, elf, target_info, settings)?;
+    Ok(())
+}
+
+fn print_stack(core: &mut Core, n: u32) -> anyhow::Result<()> {
+    let sp = core.read_core_reg::<u32>(SP)?;
+    check_range(sp, 4 * n)?;
+
+    let mut words = vec![0; n as usize];
+    core.read_32(sp.into(), &mut words)?;
+    for line in format_stack(sp, &words) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+fn format_stack(sp: u32, words: &[u32]) -> Vec<String> {
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| format!("{:#010x}: {word:#010x}", sp.wrapping_add(4 * i as u32)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::empty("  \n", Command::Empty)]
+    #[case::read("read 0x20000000 16", Command::Read { addr: 0x2000_0000, len: 16 })]
+    #[case::read_up_to_end("read 0xfffffff0 16", Command::Read { addr: 0xffff_fff0, len: 16 })]
+    #[case::read_sym("read-sym COUNTER", Command::ReadSymbol("COUNTER"))]
+    #[case::stack("stack 8", Command::Stack(8))]
+    #[case::exit("exit", Command::Quit)]
+    fn parses_command(#[case] line: &str, #[case] expected: Command) {
+        assert_eq!(parse(line).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::too_long("read 0 0xffffffff")]
+    #[case::past_end("read 0xfffffff0 17")]
+    #[case::too_many_words("stack 0xffffffff")]
+    #[case::not_a_number("read 0x20000000 many")]
+    #[case::unknown("write 0x20000000 1")]
+    fn rejects_command(#[case] line: &str) {
+        assert!(parse(line).is_err());
+    }
+
+    #[test]
+    fn formats_dump() {
+        let bytes = b"hello, world!\0\x01\xffmore";
+        assert_eq!(
+            format_dump(0x2000_0000, bytes),
+            [
+                "0x20000000: 68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 00 01 ff  hello, world!...",
+                "0x20000010: 6d 6f 72 65                                      more",
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_stack_at_end_of_address_space() {
+        assert_eq!(
+            format_stack(0xffff_fff8, &[1, 2]),
+            ["0xfffffff8: 0x00000001", "0xfffffffc: 0x00000002"]
+        );
+    }
+}
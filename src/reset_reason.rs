@@ -0,0 +1,102 @@
+//! Why the device last reset, read from the chip's reset reason register on attach (see
+//! `--no-reset-reason`)
+
+use std::io::{self, Write as _};
+
+use probe_rs::MemoryInterface as _;
+
+use crate::{
+    cli::{JsonFormat, Opts},
+    target_info::{self, ResetReasonRegister},
+};
+
+pub struct ResetReason {
+    register: &'static ResetReasonRegister,
+    value: u32,
+}
+
+impl ResetReason {
+    /// Read the reset reason register, if the chip family is known.
+    ///
+    /// This happens before flashing, which resets the chip itself. The logger isn't set up yet
+    /// at that point, so the reason gets printed later (see [`ResetReason::print`]).
+    pub fn read(sess: &mut probe_rs::Session, opts: &Opts) -> Option<Self> {
+        if opts.no_reset_reason {
+            return None;
+        }
+        let register = target_info::reset_reason_register(&sess.target().name)?;
+        // the register is informational only; a chip which can't be read fails later anyway
+        let value = sess
+            .core(opts.core)
+            .ok()?
+            .read_word_32(register.address.into())
+            .ok()?;
+        Some(Self { register, value })
+    }
+
+    pub fn print(&self, opts: &Opts) -> io::Result<()> {
+        let causes = decode(self.register, self.value);
+        match (opts.json, opts.json_format) {
+            (true, JsonFormat::Lines) => {
+                let event = serde_json::json!({
+                    "event": "reset_reason",
+                    "register": self.register.name,
+                    "value": self.value,
+                    "causes": causes,
+                });
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer(&mut stdout, &event)?;
+                writeln!(stdout)?;
+                stdout.flush()
+            }
+            _ => {
+                log::info!(
+                    "device last reset due to: {} ({} = {:#010X})",
+                    causes.join(", "),
+                    self.register.name,
+                    self.value
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The causes whose flags are set in `value`.
+///
+/// The flags are sticky until the firmware clears them, so several causes may show up.
+fn decode(register: &ResetReasonRegister, value: u32) -> Vec<&'static str> {
+    let causes = register
+        .flags
+        .iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, cause)| *cause)
+        .collect::<Vec<_>>();
+    match (causes.is_empty(), register.no_flag) {
+        (true, Some(cause)) => vec![cause],
+        (true, None) => vec!["unknown"],
+        (false, _) => causes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::nrf_watchdog("nRF52840_xxAA", 0b10, &["watchdog"])]
+    #[case::nrf_no_flag("nRF52832_xxAA", 0, &["power-on or brownout reset"])]
+    #[case::stm32_pin_and_power_on("STM32F401RETx", 0x0C00_0000, &["pin reset", "power-on reset"])]
+    #[case::stm32_no_flag("STM32F103C8", 0, &["unknown"])]
+    fn decodes_causes(#[case] target: &str, #[case] value: u32, #[case] expected: &[&str]) {
+        let register = target_info::reset_reason_register(target).unwrap();
+        assert_eq!(decode(register, value), expected);
+    }
+
+    #[test]
+    fn unknown_family_has_no_register() {
+        assert!(target_info::reset_reason_register("esp32c3").is_none());
+    }
+}
@@ -0,0 +1,288 @@
+//! Run code in the RAM of the halted target: the subroutines of the stack canary, and the
+//! routines of `--exec-routine`, e.g. a vendor unlock sequence or board init which has to run
+//! before the program
+//!
+//! A routine is position-independent Thumb code which gets its arguments in `r0`..`r12` and ends
+//! with a `bkpt` instruction. All registers of the program and the RAM the routine was loaded
+//! into are restored afterwards, so that the program starts as if the routine never ran.
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::{config::MemoryRegion, Core, MemoryInterface as _, RegisterId};
+
+use crate::{
+    cli, cortexm, ram_init,
+    registers::{LR, MSP, PC, PSP, SP, XPSR},
+    run::TIMEOUT,
+};
+
+/// The Thumb state bit of the xPSR, without which the core faults on the first instruction
+const XPSR_THUMB: u32 = 1 << 24;
+
+/// The registers of the program which [`execute`] saves and restores; the stack pointers come
+/// first, so that restoring `sp` leaves the active one as it was.
+const PROGRAM_REGISTERS: [(RegisterId, &str); 19] = [
+    (MSP, "msp"),
+    (PSP, "psp"),
+    (RegisterId(0), "r0"),
+    (RegisterId(1), "r1"),
+    (RegisterId(2), "r2"),
+    (RegisterId(3), "r3"),
+    (RegisterId(4), "r4"),
+    (RegisterId(5), "r5"),
+    (RegisterId(6), "r6"),
+    (RegisterId(7), "r7"),
+    (RegisterId(8), "r8"),
+    (RegisterId(9), "r9"),
+    (RegisterId(10), "r10"),
+    (RegisterId(11), "r11"),
+    (RegisterId(12), "r12"),
+    (SP, "sp"),
+    (LR, "lr"),
+    (PC, "pc"),
+    (XPSR, "xpsr"),
+];
+
+/// The values of [`PROGRAM_REGISTERS`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers([u32; 19]);
+
+impl Registers {
+    fn read(core: &mut Core) -> Result<Self, probe_rs::Error> {
+        let mut values = [0; 19];
+        for ((register, _), value) in PROGRAM_REGISTERS.iter().zip(&mut values) {
+            *value = core.read_core_reg::<u32>(*register)?;
+        }
+        Ok(Self(values))
+    }
+
+    pub fn get(&self, register: RegisterId) -> u32 {
+        PROGRAM_REGISTERS
+            .iter()
+            .zip(self.0)
+            .find(|((id, _), _)| *id == register)
+            .map(|(_, value)| value)
+            .expect("not a program register")
+    }
+
+    /// The names of the registers whose value differs in `other`
+    fn differing(&self, other: &Registers) -> Vec<&'static str> {
+        PROGRAM_REGISTERS
+            .iter()
+            .zip(self.0.iter().zip(other.0))
+            .filter(|(_, (value, other))| **value != *other)
+            .map(|((_, name), _)| *name)
+            .collect()
+    }
+}
+
+/// Write `code` to `address`, set `args` and run it until it halts; then restore the registers
+/// of the program. Returns the registers at the halt.
+///
+/// All registers of the program are saved, not only the ones the code uses, and checked after
+/// restoring them: the unwinder and the post-mortem REPL rely on them, so an incomplete restore
+/// fails instead of producing a bogus backtrace.
+pub fn execute(
+    core: &mut Core,
+    address: u32,
+    code: &[u8],
+    args: &[(RegisterId, u32)],
+) -> Result<Registers, probe_rs::Error> {
+    let saved = Registers::read(core)?;
+
+    for &(register, value) in args {
+        core.write_core_reg(register, value)?;
+    }
+    core.write_8(address.into(), code)?;
+    core.write_core_reg(PC, cortexm::clear_thumb_bit(address))?;
+    core.write_core_reg(XPSR, saved.get(XPSR) | XPSR_THUMB)?;
+
+    // run the code and wait for it to finish
+    let result = core.run().and_then(|()| core.wait_for_core_halted(TIMEOUT));
+    let result = match result {
+        Ok(()) => Registers::read(core),
+        Err(e) => {
+            core.halt(TIMEOUT)?;
+            Err(e)
+        }
+    };
+
+    // restore the registers, even if the code did not finish
+    for ((register, _), value) in PROGRAM_REGISTERS.into_iter().zip(saved.0) {
+        core.write_core_reg(register, value)?;
+    }
+    let unrestored = saved.differing(&Registers::read(core)?);
+    if !unrestored.is_empty() {
+        return Err(probe_rs::Error::Other(anyhow!(
+            "failed to restore {} after running code on the target; the backtrace would be wrong",
+            unrestored.join(", ")
+        )));
+    }
+
+    result
+}
+
+/// A routine given with `--exec-routine <bin>@<address>[:<reg>=<value>,..]`, e.g.
+/// `unlock.bin@0x20000000:r0=0x1234,r1=1`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Routine {
+    path: PathBuf,
+    address: u32,
+    args: Vec<(u16, u32)>,
+}
+
+impl FromStr for Routine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((path, rest)) = s.rsplit_once('@') else {
+            bail!("`{s}` is not `<bin>@<address>[:<reg>=<value>,..]`");
+        };
+        let (address, args) = rest.split_once(':').unwrap_or((rest, ""));
+        let address =
+            cli::parse_u32(address).map_err(|_| anyhow!("`{address}` is not an address"))?;
+        if address % 2 != 0 {
+            bail!("the routine needs a 2-byte-aligned address, not {address:#010x}");
+        }
+        let args = args
+            .split(',')
+            .filter(|arg| !arg.is_empty())
+            .map(parse_arg)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            path: path.into(),
+            address,
+            args,
+        })
+    }
+}
+
+/// Parse an argument like `r3=0x10`
+fn parse_arg(arg: &str) -> anyhow::Result<(u16, u32)> {
+    let (register, value) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("`{arg}` is not `<reg>=<value>`"))?;
+    let register = register
+        .strip_prefix('r')
+        .and_then(|number| number.parse::<u16>().ok())
+        .filter(|number| *number <= 12)
+        .ok_or_else(|| anyhow!("routines take arguments in `r0`..`r12`, not `{register}`"))?;
+    let value = cli::parse_u32(value).map_err(|_| anyhow!("`{value}` is not a number"))?;
+    Ok((register, value))
+}
+
+impl Routine {
+    /// Load the routine into RAM, run it and print the registers it returns; the RAM it was
+    /// loaded into gets its contents back.
+    pub fn run(&self, core: &mut Core, memory_map: &[MemoryRegion]) -> anyhow::Result<()> {
+        let name = self.path.display();
+        let code =
+            fs::read(&self.path).with_context(|| format!("failed to read the routine `{name}`"))?;
+        if code.is_empty() {
+            bail!("the routine `{name}` is empty");
+        }
+        let range = self.address..self.address + code.len() as u32;
+        let in_ram = ram_init::ram_regions(memory_map)
+            .any(|(_, ram)| ram.start <= range.start && range.end <= ram.end);
+        if !in_ram {
+            bail!(
+                "the routine `{name}` doesn't fit into RAM at {:#010x}..{:#010x}",
+                range.start,
+                range.end
+            );
+        }
+
+        let mut saved_ram = vec![0; code.len()];
+        core.read_8(self.address.into(), &mut saved_ram)?;
+        let args = self
+            .args
+            .iter()
+            .map(|&(register, value)| (RegisterId(register), value))
+            .collect::<Vec<_>>();
+        log::debug!("running the routine `{name}` at {:#010x}", self.address);
+        let result = execute(core, self.address, &code, &args).and_then(|registers| {
+            // before the RAM gets its contents back
+            let mut instruction = [0; 2];
+            core.read_8(registers.get(PC).into(), &mut instruction)?;
+            Ok((registers, u16::from_le_bytes(instruction)))
+        });
+        core.write_8(self.address.into(), &saved_ram)?;
+        let (registers, instruction) =
+            result.with_context(|| format!("failed to run the routine `{name}`"))?;
+        if !is_bkpt(instruction) {
+            bail!(
+                "the routine `{name}` halted at {:#010x}, not at a `bkpt` instruction",
+                registers.get(PC)
+            );
+        }
+
+        let returned = (0..=12)
+            .map(|number| format!("r{number}={:#010x}", registers.get(RegisterId(number))))
+            .collect::<Vec<_>>()
+            .join(" ");
+        log::info!("routine `{name}` returned {returned}");
+        Ok(())
+    }
+}
+
+/// Returns `true` for the Thumb `bkpt #imm8` instruction
+fn is_bkpt(instruction: u16) -> bool {
+    instruction & 0xFF00 == 0xBE00
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn reports_differing_registers() {
+        let saved = Registers([0x2000_1000; 19]);
+        let mut restored = saved;
+        assert!(saved.differing(&restored).is_empty());
+
+        restored.0[15] = 0x2000_0f00;
+        restored.0[17] = 0x2000_0000;
+        assert_eq!(saved.differing(&restored), ["sp", "pc"]);
+    }
+
+    #[rstest]
+    #[case::address_only("unlock.bin@0x20000000", "unlock.bin", 0x2000_0000, &[])]
+    #[case::args(
+        "unlock.bin@0x20000000:r0=0x1234,r12=1",
+        "unlock.bin",
+        0x2000_0000,
+        &[(0, 0x1234), (12, 1)]
+    )]
+    #[case::windows_path(r"C:\fw\init.bin@536870912", r"C:\fw\init.bin", 0x2000_0000, &[])]
+    fn parses_routine(
+        #[case] input: &str,
+        #[case] path: &str,
+        #[case] address: u32,
+        #[case] args: &[(u16, u32)],
+    ) {
+        let routine = input.parse::<Routine>().unwrap();
+        assert_eq!(routine.path, PathBuf::from(path));
+        assert_eq!(routine.address, address);
+        assert_eq!(routine.args, args);
+    }
+
+    #[rstest]
+    #[case::no_address("unlock.bin")]
+    #[case::unaligned("unlock.bin@0x20000001")]
+    #[case::sp("unlock.bin@0x20000000:sp=0")]
+    #[case::r13("unlock.bin@0x20000000:r13=0")]
+    #[case::no_value("unlock.bin@0x20000000:r0")]
+    fn rejects_invalid_routine(#[case] input: &str) {
+        assert!(input.parse::<Routine>().is_err());
+    }
+
+    #[rstest]
+    #[case::bkpt(0xBEAB, true)]
+    #[case::nop(0xBF00, false)]
+    fn recognizes_bkpt(#[case] instruction: u16, #[case] expected: bool) {
+        assert_eq!(is_bkpt(instruction), expected);
+    }
+}
@@ -0,0 +1,91 @@
+//! Find the RTT control block when it isn't at the address of `_SEGGER_RTT`, e.g. because a
+//! bootloader started the program with a different RAM layout than the ELF's
+
+use std::ops::Range;
+
+use probe_rs::config::MemoryRegion;
+
+use crate::transport::Transport;
+
+/// The magic string an initialized RTT control block starts with
+const RTT_ID: [u8; 16] = *b"SEGGER RTT\0\0\0\0\0\0";
+/// How far from `_SEGGER_RTT` to look for a moved control block, in either direction
+const SCAN_LIMIT: u32 = 0x1_0000;
+
+/// Whether the RTT control block's magic string is at `address`.
+///
+/// An unreadable `address` has no control block either.
+pub fn has_control_block(core: &mut dyn Transport, address: u32) -> bool {
+    let mut id = [0; RTT_ID.len()];
+    match core.read_8(address.into(), &mut id) {
+        Ok(()) => id == RTT_ID,
+        Err(e) => {
+            log::trace!("couldn't read the RTT control block at {address:#010X}: {e}");
+            false
+        }
+    }
+}
+
+/// The part of the RAM region containing `expected` that is at most [`SCAN_LIMIT`] bytes away
+/// from it, or `None` if `expected` isn't in RAM.
+pub fn scan_range(memory_map: &[MemoryRegion], expected: u32) -> Option<Range<u32>> {
+    memory_map.iter().find_map(|region| match region {
+        MemoryRegion::Ram(ram) if ram.range.contains(&u64::from(expected)) => {
+            let start = expected
+                .saturating_sub(SCAN_LIMIT)
+                .max(ram.range.start as u32);
+            let end = u64::from(expected.saturating_add(SCAN_LIMIT)).min(ram.range.end);
+            Some(start..end as u32)
+        }
+        _ => None,
+    })
+}
+
+/// How far the control block moved from `_SEGGER_RTT`, e.g. `+0x100`
+pub fn offset(expected: u32, found: u32) -> String {
+    match found.checked_sub(expected) {
+        Some(offset) => format!("+{offset:#X}"),
+        None => format!("-{:#X}", expected - found),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::config::{NvmRegion, RamRegion};
+    use rstest::rstest;
+
+    use super::*;
+
+    fn memory_map() -> Vec<MemoryRegion> {
+        vec![
+            MemoryRegion::Nvm(NvmRegion {
+                name: None,
+                range: 0x0000_0000..0x0010_0000,
+                is_boot_memory: true,
+                cores: vec![],
+            }),
+            MemoryRegion::Ram(RamRegion {
+                name: None,
+                range: 0x2000_0000..0x2004_0000,
+                is_boot_memory: false,
+                cores: vec![],
+            }),
+        ]
+    }
+
+    #[rstest]
+    #[case::middle(0x2002_0000, Some(0x2001_0000..0x2003_0000))]
+    #[case::start(0x2000_0100, Some(0x2000_0000..0x2001_0100))]
+    #[case::end(0x2003_ff00, Some(0x2002_ff00..0x2004_0000))]
+    #[case::not_in_ram(0x0000_1000, None)]
+    fn bounds_scan_to_ram_region(#[case] expected: u32, #[case] range: Option<Range<u32>>) {
+        assert_eq!(scan_range(&memory_map(), expected), range);
+    }
+
+    #[rstest]
+    #[case::up(0x2000_0000, 0x2000_0100, "+0x100")]
+    #[case::down(0x2000_0100, 0x2000_0000, "-0x100")]
+    fn formats_offset(#[case] expected: u32, #[case] found: u32, #[case] formatted: &str) {
+        assert_eq!(offset(expected, found), formatted);
+    }
+}
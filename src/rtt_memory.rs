@@ -0,0 +1,167 @@
+//! Read an RTT up channel with plain memory accesses, for transports which `probe_rs::rtt` can't
+//! use (see `--via`)
+
+use anyhow::bail;
+
+use crate::{rtt_locate, transport::Transport};
+
+/// Offset of the up channel descriptors in the control block: the magic string, then the
+/// number of up and of down channels
+const UP_CHANNELS_OFFSET: u32 = 24;
+/// Size of a channel descriptor: name, buffer, size, write and read offset, flags
+const CHANNEL_SIZE: u32 = 24;
+/// Offsets of the fields in a channel descriptor
+const NAME_OFFSET: u32 = 0;
+const BUFFER_OFFSET: u32 = 4;
+const SIZE_OFFSET: u32 = 8;
+const WRITE_OFFSET: u32 = 12;
+const READ_OFFSET: u32 = 16;
+/// Longest channel name which gets read
+const MAX_NAME_LEN: usize = 32;
+
+pub struct UpChannel {
+    /// Address of the channel descriptor
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+    name: Option<String>,
+}
+
+impl UpChannel {
+    /// Up channel 0 of the control block at `control_block`.
+    pub fn attach(core: &mut dyn Transport, control_block: u32) -> anyhow::Result<Self> {
+        if !rtt_locate::has_control_block(core, control_block) {
+            bail!("no initialized RTT control block at {control_block:#010X}");
+        }
+        let num_up_channels = core.read_word_32((control_block + 16).into())?;
+        if num_up_channels == 0 {
+            bail!("the RTT control block has no up channels");
+        }
+
+        let descriptor = control_block + UP_CHANNELS_OFFSET;
+        let mut fields = [0; (CHANNEL_SIZE / 4) as usize];
+        core.read_32(descriptor.into(), &mut fields)?;
+        let field = |offset: u32| fields[(offset / 4) as usize];
+        Ok(Self {
+            descriptor,
+            buffer: field(BUFFER_OFFSET),
+            size: field(SIZE_OFFSET),
+            name: read_name(core, field(NAME_OFFSET)),
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Read the data the program wrote into the channel into `buf`, and mark it as read.
+    pub fn read(&self, core: &mut dyn Transport, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let write = core.read_word_32((self.descriptor + WRITE_OFFSET).into())?;
+        let read = core.read_word_32((self.descriptor + READ_OFFSET).into())?;
+        if write >= self.size || read >= self.size {
+            bail!("RTT channel offsets out of bounds (write: {write}, read: {read})");
+        }
+
+        let mut total = 0;
+        let mut read_offset = read;
+        // the data wraps around the end of the buffer at most once
+        for _ in 0..2 {
+            let end = match write >= read_offset {
+                true => write,
+                false => self.size,
+            };
+            let len = ((end - read_offset) as usize).min(buf.len() - total);
+            if len == 0 {
+                break;
+            }
+            core.read_8(
+                (self.buffer + read_offset).into(),
+                &mut buf[total..total + len],
+            )?;
+            total += len;
+            read_offset = (read_offset + len as u32) % self.size;
+        }
+
+        if read_offset != read {
+            core.write_word_32((self.descriptor + READ_OFFSET).into(), read_offset)?;
+        }
+        Ok(total)
+    }
+}
+
+fn read_name(core: &mut dyn Transport, address: u32) -> Option<String> {
+    if address == 0 {
+        return None;
+    }
+    let mut bytes = [0; MAX_NAME_LEN];
+    core.read_8(address.into(), &mut bytes).ok()?;
+    let len = bytes.iter().position(|byte| *byte == 0)?;
+    String::from_utf8(bytes[..len].to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::RegisterId;
+
+    use super::*;
+
+    /// Target memory starting at 0
+    struct Memory(Vec<u8>);
+
+    impl Transport for Memory {
+        fn read_core_reg(&mut self, _: RegisterId) -> anyhow::Result<u32> {
+            anyhow::bail!("the test memory has no registers")
+        }
+
+        fn read_8(&mut self, address: u64, data: &mut [u8]) -> anyhow::Result<()> {
+            let start = address as usize;
+            data.copy_from_slice(&self.0[start..start + data.len()]);
+            Ok(())
+        }
+
+        fn write_8(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()> {
+            let start = address as usize;
+            self.0[start..start + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// A control block at 0 with a single up channel named "defmt", whose 8 byte buffer at 0x50
+    /// holds `data` from `read` to `write`
+    fn memory(data: &[u8; 8], write: u32, read: u32) -> Memory {
+        let mut memory = vec![0; 0x58];
+        memory[..16].copy_from_slice(b"SEGGER RTT\0\0\0\0\0\0");
+        let words = [1, 0, 0x30, 0x50, 8, write, read, 0];
+        for (index, word) in words.iter().enumerate() {
+            memory[16 + index * 4..][..4].copy_from_slice(&u32::to_le_bytes(*word));
+        }
+        memory[0x30..0x36].copy_from_slice(b"defmt\0");
+        memory[0x50..].copy_from_slice(data);
+        Memory(memory)
+    }
+
+    #[test]
+    fn reads_wrapped_data() {
+        let mut core = memory(b"efgh-abc", 3, 5);
+        let channel = UpChannel::attach(&mut core, 0).unwrap();
+        assert_eq!(channel.name(), Some("defmt"));
+
+        let mut buf = [0; 16];
+        let n = channel.read(&mut core, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"abcefg");
+        // marked as read
+        assert_eq!(channel.read(&mut core, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_no_more_than_buf() {
+        let mut core = memory(b"abcdefgh", 6, 0);
+        let channel = UpChannel::attach(&mut core, 0).unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(channel.read(&mut core, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(channel.read(&mut core, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ef");
+    }
+}
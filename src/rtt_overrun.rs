@@ -0,0 +1,79 @@
+//! Detect logs the program dropped because its non-blocking RTT up channel was full
+
+use probe_rs::{
+    rtt::{ChannelMode, UpChannel},
+    Core, MemoryInterface as _,
+};
+
+/// Offset of the descriptor of up channel 0 in the RTT control block
+const UP_CHANNEL_0_OFFSET: u32 = 24;
+/// Offset of the write offset in a channel descriptor; the read offset follows it
+const WRITE_OFFSET_OFFSET: u32 = 12;
+
+pub struct OverrunDetector {
+    /// Address of the channel's write and read offsets
+    offsets_address: u32,
+    buffer_size: u32,
+    num_overruns: u32,
+}
+
+impl OverrunDetector {
+    /// Returns `None` if the channel blocks when it is full, because then no logs get lost.
+    pub fn new(
+        core: &mut Core,
+        control_block_address: u32,
+        channel: &UpChannel,
+    ) -> anyhow::Result<Option<Self>> {
+        if channel.mode(core)? == ChannelMode::BlockIfFull {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            offsets_address: control_block_address + UP_CHANNEL_0_OFFSET + WRITE_OFFSET_OFFSET,
+            buffer_size: channel.buffer_size() as u32,
+            num_overruns: 0,
+        }))
+    }
+
+    /// Check whether the channel's buffer is full; call this right before reading it.
+    ///
+    /// A program writing to a full non-blocking channel drops (the rest of) its message, so the
+    /// logs following the buffer's current content are incomplete.
+    pub fn poll(&mut self, core: &mut Core) -> anyhow::Result<bool> {
+        let mut offsets = [0; 2];
+        core.read_32(self.offsets_address.into(), &mut offsets)?;
+        let [write, read] = offsets;
+
+        let overrun = is_full(write, read, self.buffer_size);
+        if overrun {
+            self.num_overruns += 1;
+        }
+        Ok(overrun)
+    }
+
+    pub fn num_overruns(&self) -> u32 {
+        self.num_overruns
+    }
+}
+
+/// The ring buffer keeps one byte free to tell a full buffer from an empty one.
+fn is_full(write: u32, read: u32, buffer_size: u32) -> bool {
+    (write + 1) % buffer_size == read
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::empty(0, 0, false)]
+    #[case::partially_filled(100, 0, false)]
+    #[case::full(1023, 0, true)]
+    #[case::full_wrapped(9, 10, true)]
+    #[case::one_byte_free(8, 10, false)]
+    fn detects_full_buffer(#[case] write: u32, #[case] read: u32, #[case] expected: bool) {
+        assert_eq!(is_full(write, read, 1024), expected);
+    }
+}
@@ -0,0 +1,89 @@
+//! SEGGER's virtual terminals, which firmware multiplexes on RTT up channel 0 (e.g. with
+//! `SEGGER_RTT_SetTerminal`); the output of terminals other than 0 gets prefixed with `[T<n>]`
+
+/// Starts a terminal switch; followed by the terminal ID as hex digit (`0`..`F`)
+const ESCAPE: u8 = 0xFF;
+
+pub struct TerminalDemux {
+    terminal: u8,
+    /// The last chunk ended with `ESCAPE`
+    escape_pending: bool,
+    at_line_start: bool,
+}
+
+impl TerminalDemux {
+    pub fn new() -> Self {
+        Self {
+            terminal: 0,
+            escape_pending: false,
+            at_line_start: true,
+        }
+    }
+
+    /// Strip the terminal switches from `bytes` and prefix the lines of terminals other than 0.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if self.escape_pending {
+                self.escape_pending = false;
+                if let Some(terminal) = (byte as char).to_digit(16) {
+                    self.switch_to(terminal as u8, &mut output);
+                    continue;
+                }
+                // not a terminal switch after all
+                self.write(ESCAPE, &mut output);
+            }
+
+            match byte {
+                ESCAPE => self.escape_pending = true,
+                _ => self.write(byte, &mut output),
+            }
+        }
+        output
+    }
+
+    fn switch_to(&mut self, terminal: u8, output: &mut Vec<u8>) {
+        if terminal == self.terminal {
+            return;
+        }
+        // don't let the output of two terminals share a line
+        if !self.at_line_start {
+            output.push(b'\n');
+            self.at_line_start = true;
+        }
+        self.terminal = terminal;
+    }
+
+    fn write(&mut self, byte: u8, output: &mut Vec<u8>) {
+        if self.at_line_start && self.terminal != 0 {
+            output.extend_from_slice(format!("[T{}] ", self.terminal).as_bytes());
+        }
+        output.push(byte);
+        self.at_line_start = byte == b'\n';
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::no_switch(b"hello\n", "hello\n")]
+    #[case::other_terminal(b"a\n\xff2b\nc\n\xff0d\n", "a\n[T2] b\n[T2] c\nd\n")]
+    #[case::switch_mid_line(b"a\xffAb\n", "a\n[T10] b\n")]
+    #[case::not_a_switch(b"\xffz\n", "\u{fffd}z\n")]
+    fn demultiplexes(#[case] bytes: &[u8], #[case] expected: &str) {
+        let output = TerminalDemux::new().push(bytes);
+        assert_eq!(String::from_utf8_lossy(&output), expected);
+    }
+
+    #[test]
+    fn switches_across_reads() {
+        let mut demux = TerminalDemux::new();
+        let mut output = demux.push(b"a\n\xff");
+        output.extend(demux.push(b"1b\n"));
+        assert_eq!(output, b"a\n[T1] b\n");
+    }
+}
@@ -0,0 +1,141 @@
+//! The logger for defmt frames and host logs, and the terminal it prints to
+
+use std::{
+    env, io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::Level;
+
+use crate::{
+    cli,
+    elf::Elf,
+    hyperlink, output_route, theme,
+    warnings::{self, Warning},
+};
+
+const DEFAULT_LOG_FORMAT_WITH_TIMESTAMP: &str = "{t} {L} {s}\n└─ {m} @ {F}:{l}";
+
+const DEFAULT_LOG_FORMAT_WITHOUT_TIMESTAMP: &str = "{L} {s}\n└─ {m} @ {F}:{l}";
+
+const DEFAULT_HOST_LOG_FORMAT: &str = "(HOST) {L} {s}";
+
+const DEFAULT_VERBOSE_HOST_LOG_FORMAT: &str = "(HOST) {L} {s}\n└─ {m} @ {F}:{l}";
+
+/// Set up the logger for defmt frames and host logs, and check the log format against the ELF.
+pub fn init_logger(elf: &Elf, opts: &cli::Opts) -> anyhow::Result<()> {
+    // the logger is global; a test manifest runs several programs
+    static INITIALIZED: AtomicBool = AtomicBool::new(false);
+    if INITIALIZED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let verbose = opts.verbose;
+    let is_timestamping_available = if let Some(table) = &elf.defmt_table {
+        table.has_timestamp()
+    } else {
+        false
+    };
+
+    let mut log_format = opts.log_format.as_deref();
+    let mut host_log_format = opts.host_log_format.as_deref();
+
+    if log_format.is_none() {
+        log_format = if is_timestamping_available {
+            Some(DEFAULT_LOG_FORMAT_WITH_TIMESTAMP)
+        } else {
+            Some(DEFAULT_LOG_FORMAT_WITHOUT_TIMESTAMP)
+        };
+    }
+
+    if host_log_format.is_none() {
+        if verbose == 0 {
+            host_log_format = Some(DEFAULT_HOST_LOG_FORMAT);
+        } else {
+            host_log_format = Some(DEFAULT_VERBOSE_HOST_LOG_FORMAT);
+        }
+    }
+
+    // with `--json-format lines`, defmt frames bypass the logger and host logs stay plain text
+    let json_schema = opts.json && opts.json_format == cli::JsonFormat::Schema;
+    let logger_info = defmt_decoder::log::init_logger(
+        log_format,
+        host_log_format,
+        json_schema,
+        move |metadata| {
+            if defmt_decoder::log::is_defmt_frame(metadata) {
+                true // We want to display *all* defmt frames.
+            } else {
+                // Log depending on how often the `--verbose` (`-v`) cli-param is supplied:
+                //   * 0: log everything from probe-run, with level "info" or higher
+                //   * 1: log everything from probe-run
+                //   * 2 or more: log everything
+                match verbose {
+                    0 => {
+                        metadata.target().starts_with("probe_run")
+                            && metadata.level() <= Level::Info
+                    }
+                    1 => metadata.target().starts_with("probe_run"),
+                    _ => true,
+                }
+            }
+        },
+    );
+
+    if logger_info.has_timestamp() && !is_timestamping_available {
+        warnings::warn(
+            Warning::TimestampNotImplemented,
+            "logger format contains timestamp but no timestamp implementation \
+            was provided; consider removing the timestamp `{t}` from the \
+            logger format  or provide a `defmt::timestamp!` implementation",
+        )?;
+    } else if !logger_info.has_timestamp() && is_timestamping_available {
+        warnings::warn(
+            Warning::TimestampNotInFormat,
+            "`defmt::timestamp!` implementation was found, but timestamp is not \
+            part of the log format; consider adding the timestamp `{t}` \
+            argument to the log format",
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn configure_terminal_colorization(opts: &cli::Opts) -> anyhow::Result<()> {
+    theme::set(theme::Theme::load(&opts.theme)?);
+
+    if opts.force_color || opts.pty {
+        colored::control::set_override(true);
+    } else if let Ok("dumb") = env::var("TERM").as_deref() {
+        // ! This should be detected by `colored`, but currently is not.
+        // See https://github.com/mackwic/colored/issues/108 and https://github.com/knurling-rs/probe-run/pull/318.
+        colored::control::set_override(false)
+    }
+
+    if opts.pty {
+        merge_stderr_into_stdout()?;
+    }
+    output_route::apply(opts)?;
+    hyperlink::init(opts);
+
+    Ok(())
+}
+
+/// Point stderr to stdout, so that host and target output keep their relative order when
+/// both are collected through a single pipe.
+#[cfg(unix)]
+fn merge_stderr_into_stdout() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd as _;
+
+    // SAFETY: both file descriptors are valid for the lifetime of the process
+    match unsafe { libc::dup2(io::stdout().as_raw_fd(), io::stderr().as_raw_fd()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn merge_stderr_into_stdout() -> io::Result<()> {
+    log::warn!("`--pty` can't merge stderr into stdout on this platform");
+    Ok(())
+}
@@ -0,0 +1,815 @@
+//! Reading the program's logs from RTT or the serial port, and decoding and printing them
+
+use std::{
+    env,
+    io::{self, Write as _},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use defmt_decoder::{DecodeError, Frame, StreamDecoder};
+use probe_rs::{
+    config::MemoryRegion,
+    rtt::{Rtt, ScanRegion, UpChannel},
+    Core,
+};
+use signal_hook::consts::signal;
+
+use super::setup::{catch_return, follow_vtor, ProgramSetup, VTOR_POLL_INTERVAL};
+use crate::{
+    canary::Canary,
+    cli, debug_session,
+    defmt_encoding::{self, MismatchCheck},
+    elf::Elf,
+    grep::{self, Grep, Shown},
+    hang::HangDetector,
+    heartbeat::Heartbeat,
+    hexdump::Hexdump,
+    location_cache::LocationCache,
+    log_annotations::{self, Annotations},
+    metrics, output_queue,
+    poll::Backoff,
+    raw_capture::RawCapture,
+    registers::PC,
+    rtt_locate,
+    rtt_overrun::OverrunDetector,
+    rtt_terminal::TerminalDemux,
+    sampling::{ExceptionStats, Sampler},
+    snapshot, svc,
+    target_info::TargetInfo,
+    theme,
+    timestamp_check::TimestampCheck,
+    vcp::Vcp,
+    warnings::{self, Warning},
+};
+
+/// The longest wait between tries to attach to RTT (see `--rtt-attach-timeout`)
+const RTT_ATTACH_MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait for the program to set up RTT before telling that probe-run waits
+const RTT_ATTACH_NOTICE_AFTER: Duration = Duration::from_millis(500);
+
+/// Lower bound of the RTT read buffer; the buffer grows to the size of the channel's buffer.
+pub const MIN_READ_BUF_SIZE: usize = 1024;
+
+/// Reads the output thread queues without `--output-queue` (see `--decode-thread`)
+const DEFAULT_OUTPUT_QUEUE: usize = 1024;
+
+/// Why printing the logs ended
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The program halted
+    Halted,
+    /// Ctrl-C was pressed
+    Interrupted,
+    /// `--timeout` elapsed
+    TimedOut,
+    /// Ctrl-\ (SIGQUIT) was pressed; the program keeps running
+    Detached,
+    /// The program was silent for `--halt-on-silence` (with `--on-hang exit`)
+    Hung,
+}
+
+pub fn print_logs(
+    core: &mut Core,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    canary: Option<&Canary>,
+    mut vcp: Option<Vcp>,
+    opts: &cli::Opts,
+    mut setup: Option<&mut ProgramSetup>,
+) -> anyhow::Result<Stop> {
+    let current_dir = &env::current_dir()?;
+    let exit = Arc::new(AtomicBool::new(false));
+    let sig_id = signal_hook::flag::register(signal::SIGINT, exit.clone())?;
+    let detach = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    let detach_sig_id = signal_hook::flag::register(signal::SIGQUIT, detach.clone())?;
+    let snapshot = snapshot::Trigger::install()?;
+    let mut exception_stats = opts.exception_stats.then(ExceptionStats::default);
+    let memory_map = &target_info.memory_map;
+
+    let logging_channel = match elf.rtt_buffer_address() {
+        // the logs come from the serial port
+        _ if vcp.is_some() => None,
+        Some(address) => setup_logging_channel(
+            core,
+            memory_map,
+            Some(address),
+            opts.rtt_scan_ram,
+            opts,
+            &exit,
+        )?,
+        None if opts.require_rtt => bail!(
+            "RTT control block (`_SEGGER_RTT` symbol) not found in the ELF, but `--require-rtt` was set"
+        ),
+        None if opts.rtt_scan_ram => {
+            log::info!("`_SEGGER_RTT` symbol not found; scanning RAM for the RTT control block");
+            setup_logging_channel(core, memory_map, None, true, opts, &exit)?
+        }
+        None => {
+            eprintln!("RTT logs not available; blocking until the device halts..");
+            None
+        }
+    };
+
+    let (mut logging_channel, mut overrun_detector) = match logging_channel {
+        Some((channel, control_block_address)) => {
+            let detector = OverrunDetector::new(core, control_block_address, &channel)?;
+            (Some(channel), detector)
+        }
+        None => (None, None),
+    };
+
+    // a serial port has no channel name; it carries defmt frames if the program uses defmt
+    let use_defmt = opts.rtt_decoder == cli::RttDecoder::Auto
+        && match &vcp {
+            Some(_) => elf.defmt_table.is_some(),
+            None => logging_channel
+                .as_ref()
+                .map_or(false, |channel| channel.name() == Some("defmt")),
+        };
+    let mut raw_capture = match (&opts.raw_capture, &logging_channel, &vcp) {
+        (Some(dir), Some(channel), _) => Some(RawCapture::new(dir, channel.name())?),
+        (Some(dir), None, Some(_)) => Some(RawCapture::new(dir, Some("vcp"))?),
+        _ => None,
+    };
+
+    if use_defmt && opts.no_flash {
+        warnings::warn(
+            Warning::NoFlashWithDefmt,
+            "the program was not flashed by probe-run and uses `defmt` logging -- if the ELF doesn't match the program on the device, defmt data will be malformed!",
+        )?;
+    } else if use_defmt && elf.defmt_table.is_none() {
+        bail!("\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data");
+    }
+
+    let defmt_table = if use_defmt {
+        elf.defmt_table.as_ref()
+    } else {
+        None
+    };
+    if let Some(table) = defmt_table {
+        defmt_encoding::announce(table, opts);
+    }
+    let annotations = load_annotations(defmt_table, elf, opts)?;
+    if opts.output_overflow == cli::OutputOverflow::Drop
+        && defmt_table.map_or(false, |table| {
+            !defmt_encoding::effective(table, opts).can_recover()
+        })
+    {
+        bail!("`--output-overflow drop` needs a defmt encoding which recovers from lost data, like `rzcobs`");
+    }
+    let mut dropped_bytes = 0;
+    let deadline = opts
+        .timeout
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut timed_out = false;
+    let mut hung = false;
+
+    print_separator()?;
+
+    thread::scope(|scope| -> anyhow::Result<()> {
+        let new_sink = || Sink::new(defmt_table, annotations.as_ref(), elf, current_dir, opts);
+        let mut output = match (opts.output_queue, opts.decode_thread) {
+            (None, false) => Output::Inline(new_sink()),
+            (capacity, _) => {
+                let capacity = capacity.map_or(DEFAULT_OUTPUT_QUEUE, |capacity| capacity as usize);
+                let (queue, receiver) = output_queue::bounded(capacity, opts.output_overflow);
+                let handle = scope.spawn(move || {
+                    let mut sink = new_sink();
+                    output_queue::drain(receiver, |bytes, lost| sink.received(bytes, lost, opts))?;
+                    sink.finish();
+                    Ok(())
+                });
+                Output::Thread(queue, handle)
+            }
+        };
+
+        // read the whole channel buffer at once, so that a single poll can drain it
+        let mut read_buf = vec![
+            0;
+            logging_channel
+                .as_ref()
+                .map_or(0, UpChannel::buffer_size)
+                .max(MIN_READ_BUF_SIZE)
+        ];
+        let mut was_halted = false;
+        let mut last_vtor_check = Instant::now();
+        let mut heartbeat = opts
+            .heartbeat
+            .map(|secs| Heartbeat::new(Duration::from_secs(secs)));
+        let mut hang_detector = opts
+            .halt_on_silence
+            .map(|secs| HangDetector::new(Duration::from_secs(secs)));
+        let mut sampler = Sampler::new(Duration::from_millis(opts.sample_interval));
+        let mut max_poll_interval = Duration::from_millis(opts.max_poll_interval);
+        if exception_stats.is_some() {
+            max_poll_interval = max_poll_interval.min(sampler.interval());
+        }
+        let mut backoff = Backoff::new(max_poll_interval);
+        while !exit.load(Ordering::Relaxed) && !detach.load(Ordering::Relaxed) {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                timed_out = true;
+                break;
+            }
+            let mut busy = false;
+            if let Some(setup) = setup.as_deref_mut().filter(|_| opts.vtor_follow) {
+                if last_vtor_check.elapsed() >= VTOR_POLL_INTERVAL {
+                    follow_vtor(core, setup)?;
+                    last_vtor_check = Instant::now();
+                }
+            }
+
+            if snapshot.requested()? && !core.core_halted()? {
+                snapshot::take(core, elf, target_info, canary, current_dir, opts)?;
+                busy = true;
+            }
+
+            let read = if let Some(logging_channel) = &mut logging_channel {
+                let overrun = match &mut overrun_detector {
+                    Some(detector) => detector.poll(core)?,
+                    None => false,
+                };
+                match logging_channel.read(core, &mut read_buf) {
+                    Ok(n) => {
+                        if n != 0 {
+                            debug_session::record(
+                                "rtt_read",
+                                || serde_json::json!({ "size": n, "overrun": overrun }),
+                            );
+                        }
+                        Some((n, overrun))
+                    }
+                    Err(e) => {
+                        eprintln!("RTT error: {e}");
+                        break;
+                    }
+                }
+            } else if let Some(vcp) = &mut vcp {
+                match vcp.read(&mut read_buf) {
+                    Ok(n) => Some((n, false)),
+                    Err(e) => {
+                        eprintln!("serial port error: {e}");
+                        break;
+                    }
+                }
+            } else {
+                None
+            };
+
+            if let Some((num_bytes_read, overrun)) = read.filter(|(n, _)| *n != 0) {
+                metrics::bytes_received(num_bytes_read);
+                if overrun {
+                    metrics::rtt_overrun();
+                }
+                busy = true;
+                if let Some(heartbeat) = &mut heartbeat {
+                    heartbeat.output(num_bytes_read);
+                }
+                if let Some(hang_detector) = &mut hang_detector {
+                    hang_detector.output();
+                }
+                let bytes = &read_buf[..num_bytes_read];
+                if let Some(raw_capture) = &mut raw_capture {
+                    raw_capture
+                        .write(bytes)
+                        .context("failed to capture raw RTT data")?;
+                }
+                match &mut output {
+                    Output::Inline(sink) => sink.received(bytes, overrun, opts)?,
+                    // the thread only hangs up if it failed; report its error below
+                    Output::Thread(queue, _) => {
+                        if !queue.send(bytes, overrun) {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let is_halted = core.core_halted()?;
+            if let Some(heartbeat) = &mut heartbeat {
+                heartbeat.beat(is_halted, opts)?;
+            }
+
+            let silence = hang_detector
+                .as_mut()
+                .and_then(|detector| detector.hung(is_halted));
+            if let Some(silence) = silence {
+                let secs = silence.as_secs();
+                match opts.on_hang {
+                    cli::OnHang::Resume => {
+                        log::warn!("no output for {secs} s; the program appears hung here:");
+                        snapshot::take(core, elf, target_info, canary, current_dir, opts)?;
+                        continue;
+                    }
+                    cli::OnHang::Exit => {
+                        log::warn!("no output for {secs} s; halting the program");
+                        hung = true;
+                        break;
+                    }
+                }
+            }
+
+            if let Some(stats) = exception_stats
+                .as_mut()
+                .filter(|_| !is_halted && sampler.due())
+            {
+                let breakpoints = setup.as_deref().map_or(&[][..], |setup| &setup.breakpoints);
+                stats.sample(core, breakpoints)?;
+            }
+
+            // the `--end-symbol` function was called; resume until it returns
+            if let Some(setup) = setup
+                .as_deref_mut()
+                .filter(|setup| is_halted && setup.end_symbol_entry.is_some())
+            {
+                if Some(core.read_core_reg::<u32>(PC)?) == setup.end_symbol_entry {
+                    catch_return(core, setup)?;
+                    core.run()?;
+                    continue;
+                }
+            }
+
+            // the program called a function to `--inject` into
+            if let Some(setup) = setup
+                .as_deref()
+                .filter(|setup| is_halted && !setup.injections.is_empty())
+            {
+                let pc = core.read_core_reg::<u32>(PC)?;
+                if let Some((_, injection)) =
+                    setup.injections.iter().find(|(address, _)| *address == pc)
+                {
+                    injection.apply(core, elf)?;
+                    core.run()?;
+                    continue;
+                }
+            }
+
+            // resume programs which use `svc` for their own purposes
+            if is_halted && opts.svc_exit && svc::read_call(core, elf)? == Some(svc::SvcCall::Other)
+            {
+                core.run()?;
+                continue;
+            }
+
+            if is_halted && was_halted {
+                break;
+            }
+            was_halted = is_halted;
+            // once halted, drain the channel right away
+            backoff.wait(busy || is_halted);
+        }
+        backoff.log_stats();
+
+        match output {
+            Output::Inline(mut sink) => sink.finish(),
+            Output::Thread(queue, handle) => {
+                // hang up and wait until the thread has printed all queued data
+                dropped_bytes = queue.dropped_bytes();
+                drop(queue);
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("output thread panicked"))??;
+            }
+        }
+
+        Ok(())
+    })?;
+
+    signal_hook::low_level::unregister(sig_id);
+    #[cfg(unix)]
+    signal_hook::low_level::unregister(detach_sig_id);
+
+    if let Some(stats) = exception_stats {
+        let json_lines = opts.json && opts.json_format == cli::JsonFormat::Lines;
+        stats.print(elf, json_lines)?;
+    }
+
+    if dropped_bytes > 0 {
+        warnings::warn(
+            Warning::OutputDropped,
+            format_args!(
+                "dropped {dropped_bytes} bytes of RTT data because the output couldn't keep up; \
+                increase `--output-queue` or pass `--output-overflow block`"
+            ),
+        )?;
+    }
+
+    if let Some(detector) = overrun_detector.filter(|detector| detector.num_overruns() > 0) {
+        warnings::warn(
+            Warning::RttOverrun,
+            format_args!(
+                "the program dropped logs {} times because the RTT buffer was full; \
+                increase its size or make the channel block when full",
+                detector.num_overruns()
+            ),
+        )?;
+    }
+
+    Ok(match (exit.load(Ordering::Relaxed), timed_out) {
+        _ if detach.load(Ordering::Relaxed) => Stop::Detached,
+        _ if hung => Stop::Hung,
+        (true, _) => Stop::Interrupted,
+        (false, true) => Stop::TimedOut,
+        (false, false) => Stop::Halted,
+    })
+}
+
+/// Mark the position in the output at which the program dropped logs.
+fn mark_overrun() {
+    log::warn!("RTT buffer full; logs were lost here");
+}
+
+/// Decodes and prints RTT data, either on the polling thread or on a separate thread (see
+/// `--decode-thread` and `--output-queue`).
+enum Output<'scope, 'a> {
+    Inline(Sink<'a>),
+    Thread(
+        output_queue::Queue,
+        thread::ScopedJoinHandle<'scope, anyhow::Result<()>>,
+    ),
+}
+
+/// Decodes and prints the data of the RTT channel
+pub enum Sink<'a> {
+    Defmt(
+        Box<dyn StreamDecoder + 'a>,
+        MismatchCheck,
+        LocationCache<'a>,
+        Option<&'a Annotations>,
+        TimestampCheck,
+        Option<Grep>,
+    ),
+    /// Without `hexdump` and `terminals`, the bytes are printed as they are.
+    Bytes {
+        hexdump: Option<Hexdump>,
+        terminals: Option<TerminalDemux>,
+    },
+}
+
+impl<'a> Sink<'a> {
+    pub fn new(
+        defmt_table: Option<&'a defmt_decoder::Table>,
+        annotations: Option<&'a Annotations>,
+        elf: &'a Elf<'a>,
+        current_dir: &'a Path,
+        opts: &'a cli::Opts,
+    ) -> Self {
+        match defmt_table {
+            Some(table) => {
+                let encoding = defmt_encoding::effective(table, opts);
+                Sink::Defmt(
+                    defmt_encoding::decoder(table, encoding),
+                    MismatchCheck::new(encoding, table.encoding()),
+                    LocationCache::new(elf, current_dir, opts),
+                    annotations,
+                    TimestampCheck::new(opts.strict_timestamps),
+                    Grep::new(opts),
+                )
+            }
+            None => Sink::Bytes {
+                hexdump: (opts.rtt_decoder == cli::RttDecoder::Hexdump)
+                    .then(|| Hexdump::new(opts.hexdump_width.into())),
+                // `--rtt-decoder raw` prints the bytes as they are, terminal switches included
+                terminals: (opts.rtt_decoder == cli::RttDecoder::Auto).then(TerminalDemux::new),
+            },
+        }
+    }
+
+    /// Print `bytes`; `lost` marks that data was lost before them.
+    pub fn received(&mut self, bytes: &[u8], lost: bool, opts: &cli::Opts) -> anyhow::Result<()> {
+        match self {
+            Sink::Defmt(stream_decoder, mismatch, locations, annotations, timestamps, grep) => {
+                stream_decoder.received(bytes);
+                decode_and_print_defmt_logs(
+                    &mut **stream_decoder,
+                    locations,
+                    *annotations,
+                    timestamps,
+                    grep.as_mut(),
+                    opts,
+                    mismatch,
+                )?;
+            }
+            Sink::Bytes { hexdump, terminals } => {
+                // don't hold the lock across polls; the logger prints to stdout, too
+                let mut stdout = io::stdout().lock();
+                match hexdump {
+                    Some(hexdump) => {
+                        for row in hexdump.push(bytes) {
+                            writeln!(stdout, "{row}")?;
+                        }
+                    }
+                    None => match terminals {
+                        Some(terminals) => stdout.write_all(&terminals.push(bytes))?,
+                        None => stdout.write_all(bytes)?,
+                    },
+                }
+                stdout.flush()?;
+            }
+        }
+        if lost {
+            mark_overrun();
+        }
+        Ok(())
+    }
+
+    /// Print what's left once the channel is closed.
+    pub fn finish(&mut self) {
+        if let Sink::Bytes {
+            hexdump: Some(hexdump),
+            ..
+        } = self
+        {
+            if let Some(row) = hexdump.flush() {
+                println!("{row}");
+            }
+        }
+    }
+}
+
+/// Attach to the RTT control block and return its up channel 0 and the control block's address,
+/// or `None` if the program halted or Ctrl-C was pressed before it set up RTT.
+///
+/// The control block is looked up at `rtt_buffer_address`, if known. If its magic string isn't
+/// there, e.g. because a bootloader moved the program's RAM, the RAM around that address gets
+/// scanned for it too, or all of the RAM if `scan_ram` is set. Both are retried with an
+/// exponential backoff until the program sets up RTT or `--rtt-attach-timeout` passes.
+fn setup_logging_channel(
+    core: &mut Core,
+    memory_map: &[MemoryRegion],
+    rtt_buffer_address: Option<u32>,
+    scan_ram: bool,
+    opts: &cli::Opts,
+    exit: &AtomicBool,
+) -> anyhow::Result<Option<(UpChannel, u32)>> {
+    let scan_region = match rtt_buffer_address {
+        _ if scan_ram => Some(ScanRegion::Ram),
+        Some(expected) => rtt_locate::scan_range(memory_map, expected).map(ScanRegion::Range),
+        None => Some(ScanRegion::Ram),
+    };
+    let timeout = Duration::from_secs(opts.rtt_attach_timeout);
+    let started = Instant::now();
+    let mut backoff = Backoff::new(RTT_ATTACH_MAX_DELAY);
+    let mut noticed = false;
+    loop {
+        if let Some(channel) =
+            try_attach_rtt(core, memory_map, rtt_buffer_address, scan_region.as_ref())?
+        {
+            if noticed {
+                log::info!(
+                    "attached to RTT after {:.1} s",
+                    started.elapsed().as_secs_f64()
+                );
+            }
+            return Ok(Some(channel));
+        }
+
+        let waited = started.elapsed();
+        if waited >= timeout {
+            break;
+        }
+        if exit.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        if core.core_halted()? {
+            log::warn!("the program halted before it set up RTT");
+            return Ok(None);
+        }
+        if !noticed && waited >= RTT_ATTACH_NOTICE_AFTER {
+            log::info!(
+                "waiting up to {} s for the program to set up RTT (see `--rtt-attach-timeout`)",
+                opts.rtt_attach_timeout
+            );
+            noticed = true;
+        }
+        backoff.wait(false);
+    }
+
+    let error = anyhow!(probe_rs::rtt::Error::ControlBlockNotFound);
+    let secs = opts.rtt_attach_timeout;
+    match (rtt_buffer_address, &scan_region) {
+        (Some(expected), None) => bail!(
+            "`_SEGGER_RTT` ({expected:#010X}) is not in RAM and there is no RTT control block \
+            at it; pass `--rtt-scan-ram` to scan all of the RAM for it"
+        ),
+        (Some(expected), Some(ScanRegion::Range(_))) => Err(error.context(format!(
+            "no RTT control block at `_SEGGER_RTT` ({expected:#010X}) or near it within {secs} s; \
+            pass `--rtt-scan-ram` to scan all of the RAM for it, or raise `--rtt-attach-timeout` \
+            if the program sets up RTT late"
+        ))),
+        _ => Err(error.context(format!(
+            "no RTT control block within {secs} s; raise `--rtt-attach-timeout` if the program \
+            sets up RTT late"
+        ))),
+    }
+}
+
+/// Look for the RTT control block once: at `_SEGGER_RTT`, then in `scan_region`.
+fn try_attach_rtt(
+    core: &mut Core,
+    memory_map: &[MemoryRegion],
+    rtt_buffer_address: Option<u32>,
+    scan_region: Option<&ScanRegion>,
+) -> anyhow::Result<Option<(UpChannel, u32)>> {
+    if let Some(expected) = rtt_buffer_address {
+        if !rtt_locate::has_control_block(core, expected) {
+            log::trace!("no RTT control block at `_SEGGER_RTT` (yet)");
+        } else {
+            match Rtt::attach_region(core, memory_map, &ScanRegion::Exact(expected)) {
+                Ok(rtt) => return logging_channel(rtt).map(Some),
+                Err(probe_rs::rtt::Error::ControlBlockNotFound) => log::trace!(
+                    "Couldn't attach because the target's RTT control block isn't initialized (yet)"
+                ),
+                Err(e) => return Err(anyhow!(e)),
+            }
+        }
+    }
+
+    let Some(scan_region) = scan_region else {
+        return Ok(None);
+    };
+    log::trace!("scanning {scan_region:?} for the RTT control block");
+    match Rtt::attach_region(core, memory_map, scan_region) {
+        Ok(rtt) => {
+            if let Some(expected) = rtt_buffer_address.filter(|expected| *expected != rtt.ptr()) {
+                warnings::warn(
+                    Warning::RttControlBlockMoved,
+                    format_args!(
+                        "RTT control block found at {:#010X} ({} bytes), not at `_SEGGER_RTT` ({expected:#010X})",
+                        rtt.ptr(),
+                        rtt_locate::offset(expected, rtt.ptr())
+                    ),
+                )?;
+            }
+            logging_channel(rtt).map(Some)
+        }
+        Err(probe_rs::rtt::Error::ControlBlockNotFound) => Ok(None),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
+fn logging_channel(mut rtt: Rtt) -> anyhow::Result<(UpChannel, u32)> {
+    log::debug!("Successfully attached RTT");
+    let channel = rtt
+        .up_channels()
+        .take(0)
+        .ok_or_else(|| anyhow!("RTT up channel 0 not found"))?;
+    Ok((channel, rtt.ptr()))
+}
+
+/// Load `--log-annotations`, if the program logs with defmt.
+pub fn load_annotations(
+    defmt_table: Option<&defmt_decoder::Table>,
+    elf: &Elf,
+    opts: &cli::Opts,
+) -> anyhow::Result<Option<Annotations>> {
+    match (&opts.log_annotations, defmt_table) {
+        (Some(path), Some(_)) => Ok(Some(Annotations::load(path, elf)?)),
+        (Some(_), None) => {
+            log::warn!("ignoring `--log-annotations`; the program doesn't log with defmt");
+            Ok(None)
+        }
+        (None, _) => Ok(None),
+    }
+}
+
+fn decode_and_print_defmt_logs(
+    stream_decoder: &mut dyn StreamDecoder,
+    locations: &mut LocationCache,
+    annotations: Option<&Annotations>,
+    timestamps: &mut TimestampCheck,
+    mut grep: Option<&mut Grep>,
+    opts: &cli::Opts,
+    mismatch: &mut MismatchCheck,
+) -> anyhow::Result<()> {
+    loop {
+        let decoded = stream_decoder.decode();
+        match &decoded {
+            Ok(_) => metrics::frame_decoded(),
+            Err(DecodeError::Malformed) => metrics::frame_malformed(),
+            Err(DecodeError::UnexpectedEof) => {}
+        }
+        match decoded {
+            Ok(frame) => {
+                mismatch.decoded();
+                match grep.as_deref_mut() {
+                    Some(grep) => {
+                        let line = grep::Line::new(&frame, annotations);
+                        let location = locations.get(line.index);
+                        for shown in grep.push_line(line, location) {
+                            match shown {
+                                Shown::Line(line) => print_line(&line, locations, opts)?,
+                                Shown::Separator => print_grep_separator(opts)?,
+                            }
+                        }
+                    }
+                    None => match opts.json_format {
+                        cli::JsonFormat::Lines => print_json_line(&frame, locations, annotations)?,
+                        cli::JsonFormat::Schema => {
+                            forward_to_logger(&frame, locations, annotations)
+                        }
+                    },
+                }
+                timestamps.observe(&frame)?;
+            }
+            Err(DecodeError::UnexpectedEof) => break,
+            // if recovery is possible, skip the current frame and continue with new data
+            Err(DecodeError::Malformed) => mismatch.malformed()?,
+        }
+    }
+
+    Ok(())
+}
+
+fn forward_to_logger(
+    frame: &Frame,
+    locations: &mut LocationCache,
+    annotations: Option<&Annotations>,
+) {
+    let location = locations.get(frame.index());
+    let file = location.map(|location| location.display_path.as_str());
+    let line = location.map(|location| location.line);
+    let module_path = location.map(|location| location.module.as_str());
+    match annotations.and_then(|annotations| annotations.message(frame)) {
+        Some(message) => log_annotations::log_frame(frame, &message, file, line, module_path),
+        None => defmt_decoder::log::log_defmt(frame, file, line, module_path),
+    }
+}
+
+/// Print `frame` as a single, flat JSON object (see `--json-format lines`).
+fn print_json_line(
+    frame: &Frame,
+    locations: &mut LocationCache,
+    annotations: Option<&Annotations>,
+) -> io::Result<()> {
+    write_json_line(&grep::Line::new(frame, annotations), locations)
+}
+
+fn write_json_line(line: &grep::Line, locations: &mut LocationCache) -> io::Result<()> {
+    let location = locations.get(line.index);
+    let json = serde_json::json!({
+        "index": line.index,
+        "timestamp": line.timestamp,
+        "level": line.level.map(|level| level.as_str()),
+        "message": line.message,
+        "file": location.map(|location| &location.json_path),
+        "line": location.map(|location| location.line),
+        "module": location.map(|location| &location.module),
+    });
+
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, &json)?;
+    writeln!(stdout)?;
+    stdout.flush()
+}
+
+/// Print a decoded frame `--grep` passed, like its frame would have been printed.
+fn print_line(
+    line: &grep::Line,
+    locations: &mut LocationCache,
+    opts: &cli::Opts,
+) -> io::Result<()> {
+    match opts.json_format {
+        cli::JsonFormat::Lines => write_json_line(line, locations),
+        cli::JsonFormat::Schema => {
+            let location = locations.get(line.index);
+            log_annotations::log_message(
+                line.level,
+                line.timestamp.as_deref(),
+                &line.message,
+                location.map(|location| location.display_path.as_str()),
+                location.map(|location| location.line),
+                location.map(|location| location.module.as_str()),
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Mark that `--grep` left out lines, like `grep` does.
+fn print_grep_separator(opts: &cli::Opts) -> io::Result<()> {
+    // a JSON consumer sees the gaps in the frame indices
+    if opts.json {
+        return Ok(());
+    }
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", theme::current().separator.paint("--"))?;
+    stdout.flush()
+}
+
+/// Print a line to separate different execution stages.
+pub fn print_separator() -> io::Result<()> {
+    writeln!(
+        io::stderr(),
+        "{}",
+        theme::current().separator.paint(&"─".repeat(80))
+    )
+}
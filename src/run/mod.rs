@@ -0,0 +1,450 @@
+//! Running a program on the device: flash it, run it while printing its logs, and print the
+//! stack usage and backtrace once it halts
+
+use std::{
+    env, fs,
+    io::{self, IsTerminal as _},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use probe_rs::{Core, Session};
+use signal_hook::consts::signal;
+
+use crate::{
+    backtrace::{self, Outcome},
+    canary::Canary,
+    cli,
+    elf::Elf,
+    erase, flash_plan, history, metrics, notify,
+    quirks::{self, Quirks},
+    ram_init,
+    ram_map::RamMap,
+    repl,
+    reset_reason::ResetReason,
+    target_info::{self, TargetInfo},
+    trace,
+    vcp::Vcp,
+    vector_table,
+    warnings::{self, Warning},
+};
+
+mod logger;
+mod logs;
+mod session;
+mod setup;
+
+use session::{attach_flash_probe, flash_program};
+use setup::{analyze_vector_table, restore_rtt_mode, ProgramSetup};
+
+pub use logger::{configure_terminal_colorization, init_logger};
+pub use logs::{load_annotations, print_logs, print_separator, Sink, Stop, MIN_READ_BUF_SIZE};
+pub use session::{attach_to_probe, confirm, lookup_probe_target};
+pub use setup::{delay, detach_from_program, start_program};
+
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Exit code if Ctrl-C interrupted flashing or the stack canary; the shell convention for SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 128 + signal::SIGINT;
+
+/// Flash and run the program in `elf_path`, printing its logs until it halts.
+pub fn run_target_program(
+    elf_path: &Path,
+    chip_name: &str,
+    opts: &cli::Opts,
+) -> anyhow::Result<i32> {
+    // connect to probe and flash firmware
+    let probe_target = lookup_probe_target(elf_path, chip_name, opts)?;
+    let program = Program::load(elf_path, &probe_target, opts)?;
+    if opts.dry_run {
+        program.flash_plan.print();
+        return Ok(cli::EXIT_SUCCESS);
+    }
+
+    let mut sess = attach_to_probe(probe_target.clone(), opts)?;
+    let (_, exit_code) = run_program(&mut sess, &program, probe_target, chip_name, opts)?;
+    Ok(exit_code)
+}
+
+/// A program read from its ELF, and the plan to flash it
+pub struct Program<'a> {
+    elf_path: &'a Path,
+    pub elf_bytes: Vec<u8>,
+    erase_ranges: Vec<Range<u64>>,
+    flash_plan: flash_plan::Plan,
+    /// The size of the loadable segments
+    flash_bytes: u64,
+    /// Why the program doesn't fit the chip, if `--force` flashes it anyway
+    chip_mismatch: Option<String>,
+    quirks: Quirks,
+}
+
+impl<'a> Program<'a> {
+    pub fn load(
+        elf_path: &'a Path,
+        probe_target: &probe_rs::Target,
+        opts: &cli::Opts,
+    ) -> anyhow::Result<Self> {
+        let elf_bytes = fs::read(elf_path)?;
+        let chip_mismatch = target_info::chip_mismatch(&elf_bytes, probe_target, opts.core)?;
+        if let (Some(mismatch), false) = (&chip_mismatch, opts.force) {
+            bail!("{mismatch}\nWas the ELF built for another board? Pass `--force` to flash it anyway.");
+        }
+        let erase_ranges = erase::resolve(&opts.erase_sectors, &elf_bytes, probe_target)?;
+        let flash_plan = flash_plan::plan(&elf_bytes, probe_target, opts.erase_all, &erase_ranges)?;
+        if let Some((num_bytes, estimate)) = flash_plan
+            .mass_erase()
+            .filter(|_| !opts.no_flash && !opts.dry_run)
+        {
+            let question = format!(
+                "`--erase-all` erases all {:.0} KiB of nonvolatile memory of the chip, which may \
+                take up to ~{:.0}s; continue? [y/N] ",
+                num_bytes as f64 / 1024.0,
+                estimate.as_secs_f64()
+            );
+            if !confirm(opts, "--erase-all", &question)? {
+                bail!("mass-erase aborted; nothing was flashed");
+            }
+        }
+        let flash_bytes = flash_plan::loadable_segments(&elf_bytes)?
+            .iter()
+            .map(|segment| segment.end - segment.start)
+            .sum();
+        let quirks = Quirks::load(&probe_target.name, opts)?;
+        Ok(Self {
+            elf_path,
+            elf_bytes,
+            erase_ranges,
+            flash_plan,
+            flash_bytes,
+            chip_mismatch,
+            quirks,
+        })
+    }
+}
+
+/// Flash and run `program` on the device of `sess`, until it halts. Returns its outcome, `None`
+/// if Ctrl-C interrupted the preparations, and the exit code of `probe-run`.
+pub fn run_program(
+    sess: &mut Session,
+    program: &Program,
+    probe_target: probe_rs::Target,
+    chip_name: &str,
+    opts: &cli::Opts,
+) -> anyhow::Result<(Option<Outcome>, i32)> {
+    let Program {
+        elf_path,
+        elf_bytes,
+        erase_ranges: _,
+        flash_plan,
+        flash_bytes,
+        chip_mismatch,
+        quirks,
+    } = program;
+    if !opts.no_flash {
+        flash_plan.log();
+    }
+
+    let reset_reason = ResetReason::read(sess, opts);
+    let interrupt_guard = InterruptGuard::install()?;
+    match &opts.flash_probe {
+        Some(flash_probe) => {
+            let mut flash_sess = attach_flash_probe(flash_probe, probe_target.clone(), opts)?;
+            flash_program(&mut flash_sess, program, opts)?;
+            // release the flash probe before the program runs through `--probe`
+            drop(flash_sess);
+            log::debug!("detached from the flash probe");
+        }
+        None => flash_program(sess, program, opts)?,
+    }
+    if interrupt_guard.interrupted() {
+        return Ok((
+            None,
+            abort_interrupted(&mut sess.core(opts.core)?, "flashing")?,
+        ));
+    }
+    if !opts.no_flash {
+        notify::send(opts, "flashing finished");
+    }
+
+    // the ROM table, which locates the trace buffer, is only accessible through the session
+    let mtb = match opts.itrace {
+        Some(_) => Some(trace::Mtb::find(sess)?),
+        None => None,
+    };
+
+    // attack to core
+    let memory_map = sess.target().memory_map.clone();
+    let core = &mut sess.core(opts.core)?;
+
+    // reset-halt the core; this is necessary for analyzing the vector table and
+    // painting the stack
+    core.reset_and_halt(TIMEOUT)?;
+    quirks.apply(core, quirks::Point::PostReset)?;
+    delay(opts.before_run_delay, "after reset");
+
+    // gather information
+    let (stack_start, reset_fn_address) = analyze_vector_table(core)?;
+    let elf = &Elf::parse(elf_bytes, elf_path, reset_fn_address)?;
+    let mut target_info = TargetInfo::new(elf, memory_map, probe_target, opts.core, stack_start)?;
+
+    init_logger(elf, opts)?;
+    if let Some(mismatch) = chip_mismatch {
+        warnings::warn(Warning::ChipMismatch, mismatch)?;
+    }
+    if let Some(reset_reason) = reset_reason {
+        reset_reason.print(opts)?;
+    }
+    if opts.verify_vector_table {
+        vector_table::verify(core, &elf.vector_table, (stack_start, reset_fn_address))?;
+    }
+    for routine in &opts.exec_routine {
+        routine.run(core, &target_info.memory_map)?;
+    }
+
+    // prepare and check RAM
+    ram_init::zero_fill(core, &target_info, &opts.zero_ram)?;
+    if opts.verify_ram_init {
+        ram_init::verify(core, &target_info.memory_map)?;
+    }
+
+    // install stack canary
+    let canary = Canary::install(core, elf, &target_info)?;
+    if canary.is_none() {
+        log::info!("stack measurement was not set up");
+    }
+    let ram_map = match opts.ram_map {
+        Some(_) => Some(RamMap::paint(core, elf, &target_info, canary.as_ref())?),
+        None => None,
+    };
+    if interrupt_guard.interrupted() {
+        return Ok((None, abort_interrupted(core, "stack painting")?));
+    }
+    drop(interrupt_guard);
+
+    let itrace = match (mtb, &opts.itrace) {
+        (Some(mtb), Some(buffer)) => Some(trace::Itrace::start(core, elf, mtb, buffer)?),
+        _ => None,
+    };
+
+    // open the serial port before the program starts, so that no logs get lost
+    let vcp = match &opts.vcp {
+        Some(port) => Some(Vcp::open(port, opts.vcp_baud)?),
+        None => None,
+    };
+
+    // run program and print logs until there is an exception
+    quirks.apply(core, quirks::Point::PreRun)?;
+    let mut setup = start_program(core, elf, target_info.remap.as_ref(), opts)?;
+    let started = Instant::now();
+    let current_dir = env::current_dir()?;
+    let stop = print_logs(
+        core,
+        elf,
+        &target_info,
+        canary.as_ref(),
+        vcp,
+        opts,
+        Some(&mut setup),
+    ) // blocks until exception
+    .map_err(|e| abort_logging(core, Some(&setup), e))?;
+    // leave the program running, without halting it for the stack usage and backtrace
+    if stop == Stop::Detached {
+        print_separator()?;
+        detach_from_program(core, setup, false)?;
+        log::info!("detached from the device; the program keeps running");
+        return Ok((None, cli::EXIT_SUCCESS));
+    }
+    // `--timeout` ends the program like Ctrl-C does
+    let halted_due_to_signal = stop != Stop::Halted;
+    let duration = started.elapsed();
+    print_separator()?;
+    target_info.hard_fault_handler = setup.hard_fault;
+    target_info.run_until = setup.run_until;
+    target_info.end_symbol_return = setup.end_symbol_return;
+
+    // Ctrl-C was pressed; stop the microcontroller.
+    if halted_due_to_signal {
+        core.halt(TIMEOUT)?;
+    }
+
+    // measuring the stack canary runs code on the target, which would show up in the trace
+    if let Some(itrace) = &itrace {
+        itrace.stop(core)?;
+    }
+    // before the canary's measuring subroutine writes to the stack
+    if let (Some(ram_map), Some(path)) = (&ram_map, &opts.ram_map) {
+        ram_map.export(core, path)?;
+    }
+
+    // analyze stack canary
+    let interrupt_guard = InterruptGuard::install()?;
+    let stack_usage = canary
+        .map(|canary| canary.measure(core, elf, opts.stack_overflow_threshold))
+        .transpose()?;
+    if let Some(usage) = &stack_usage {
+        metrics::stack_usage(usage.min_bytes);
+    }
+    let stack_overflow = stack_usage.as_ref().map_or(false, |usage| usage.overflow);
+    if interrupt_guard.interrupted() {
+        return Ok((None, abort_interrupted(core, "stack measurement")?));
+    }
+    drop(interrupt_guard);
+
+    // after Ctrl-C ended the program, another Ctrl-C terminates `probe-run`
+    let _exit_guard = ExitGuard::install(halted_due_to_signal)?;
+
+    // print the backtrace
+    if stop == Stop::Hung {
+        log::warn!("the program appears hung here:");
+    }
+    let mut backtrace_settings =
+        backtrace::Settings::new(current_dir, halted_due_to_signal, opts, stack_overflow);
+    let mut outcome = backtrace::print(core, elf, &target_info, &mut backtrace_settings)?;
+    match stop {
+        Stop::TimedOut if outcome == Outcome::CtrlC => outcome = Outcome::Timeout,
+        Stop::Hung if outcome == Outcome::CtrlC => outcome = Outcome::Hang,
+        _ => {}
+    }
+    if let Some(itrace) = itrace.filter(|_| outcome.is_fault()) {
+        itrace.print(core, elf, &backtrace_settings)?;
+    }
+
+    if outcome.is_fault() && opts.post_mortem == Some(cli::PostMortem::Repl) {
+        if io::stdin().is_terminal() {
+            notify::send(opts, &format!("{outcome}; waiting in the post-mortem REPL"));
+            repl::run(core, elf, &target_info, &mut backtrace_settings)?;
+        } else {
+            log::warn!("`--post-mortem repl` requires an interactive terminal");
+        }
+    }
+
+    // reset the target, or leave the program running if it was interrupted
+    if opts.no_reset {
+        detach_from_program(core, setup, halted_due_to_signal)?;
+    } else {
+        core.reset_and_halt(TIMEOUT)?;
+    }
+
+    outcome.log();
+    notify::send(opts, &outcome.to_string());
+    metrics::run_ended(outcome, Some(duration));
+
+    // only compare runs which went all the way through
+    let mut exit_code = outcome.exit_code(&opts.exit_code_map);
+    if i32::from(outcome) == cli::EXIT_SUCCESS {
+        let stack_bytes = stack_usage.map(|usage| u64::from(usage.min_bytes));
+        let run = history::Run::new(elf_path, chip_name, *flash_bytes, stack_bytes, duration);
+        if history::update(run, opts)? {
+            exit_code = cli::EXIT_FAILURE;
+        }
+    }
+    Ok((Some(outcome), exit_code))
+}
+
+/// Printing the logs failed while the program may still be running; restore the RTT channel's
+/// mode, so that the program doesn't block on the logs nobody reads anymore.
+pub fn abort_logging(
+    core: &mut Core,
+    setup: Option<&ProgramSetup>,
+    e: anyhow::Error,
+) -> anyhow::Error {
+    if let Some(setup) = setup {
+        if let Err(restore_error) = restore_rtt_mode(core, setup) {
+            log::warn!("failed to restore the mode of the RTT channel: {restore_error}");
+        }
+    }
+    e
+}
+
+/// Defers Ctrl-C while the target is in a transient state, like during flashing or while the
+/// stack canary subroutines run; a second Ctrl-C exits immediately.
+struct InterruptGuard {
+    interrupted: Arc<AtomicBool>,
+    sig_ids: [signal_hook::SigId; 2],
+}
+
+impl InterruptGuard {
+    fn install() -> io::Result<Self> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        // NOTE the order matters: the shutdown only triggers if the flag was set by an earlier Ctrl-C
+        let sig_ids = [
+            signal_hook::flag::register_conditional_shutdown(
+                signal::SIGINT,
+                EXIT_INTERRUPTED,
+                interrupted.clone(),
+            )?,
+            signal_hook::flag::register(signal::SIGINT, interrupted.clone())?,
+        ];
+        Ok(Self {
+            interrupted,
+            sig_ids,
+        })
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for sig_id in self.sig_ids {
+            signal_hook::low_level::unregister(sig_id);
+        }
+    }
+}
+
+/// Makes Ctrl-C terminate `probe-run` right away if `armed`, e.g. after a first Ctrl-C or the
+/// timeout ended the program; until dropped, so that later runs of `probe-run test` aren't affected.
+struct ExitGuard {
+    sig_id: signal_hook::SigId,
+}
+
+impl ExitGuard {
+    fn install(armed: bool) -> io::Result<Self> {
+        let sig_id = signal_hook::flag::register_conditional_default(
+            signal::SIGINT,
+            Arc::new(AtomicBool::new(armed)),
+        )?;
+        Ok(Self { sig_id })
+    }
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.sig_id);
+    }
+}
+
+fn abort_interrupted(core: &mut Core, stage: &str) -> anyhow::Result<i32> {
+    log::warn!("interrupted during {stage}; resetting the target");
+    core.reset_and_halt(TIMEOUT)?;
+    Ok(EXIT_INTERRUPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_guard_ends_with_its_run() {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let sig_id = signal_hook::flag::register(signal::SIGINT, interrupted.clone()).unwrap();
+
+        // the first test of a manifest timed out, the second one is still running
+        drop(ExitGuard::install(true).unwrap());
+        let _second = ExitGuard::install(false).unwrap();
+        // kills the test binary if the first guard is still registered
+        signal_hook::low_level::raise(signal::SIGINT).unwrap();
+
+        assert!(interrupted.load(Ordering::Relaxed));
+        signal_hook::low_level::unregister(sig_id);
+    }
+}
@@ -0,0 +1,267 @@
+//! Attaching to the probe and flashing the program
+
+use std::{
+    fs,
+    io::{self, IsTerminal as _, Write as _},
+    ops::Range,
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::{
+    architecture::arm::ArmError, flashing, DebugProbeError::ProbeSpecific, Permissions, Session,
+};
+
+use super::{Program, TIMEOUT};
+use crate::{
+    cli, debug_session, erase, flash_loader, flash_plan, metrics, probe, quirks, target_info,
+    write_protection,
+};
+
+pub fn lookup_probe_target(
+    elf_path: &Path,
+    chip_name: &str,
+    opts: &cli::Opts,
+) -> anyhow::Result<probe_rs::Target> {
+    if !elf_path.exists() {
+        bail!(
+            "can't find ELF file at `{}`; are you sure you got the right path?",
+            elf_path.display()
+        );
+    }
+
+    // register chip description
+    if let Some(cdp) = &opts.chip_description_path {
+        probe_rs::config::add_target_from_yaml(fs::File::open(cdp)?)?;
+    }
+
+    // look up target and check combat
+    let mut probe_target = probe_rs::config::get_target_by_name(chip_name)?;
+    for path in &opts.flash_loader {
+        flash_loader::register(&mut probe_target, flash_loader::load(path)?)?;
+    }
+    target_info::select_core(&mut probe_target, opts.core, opts.ap)?;
+    target_info::check_processor_target_compatability(&probe_target.cores[opts.core], elf_path)?;
+
+    Ok(probe_target)
+}
+
+pub fn attach_to_probe(
+    probe_target: probe_rs::Target,
+    opts: &cli::Opts,
+) -> anyhow::Result<Session> {
+    match attach_with_permissions(probe_target.clone(), opts, opts.erase_all) {
+        Err(e) if is_access_port_protected(&e) => {
+            if !opts.recover {
+                bail!(
+                    "the chip is protected against debug access (e.g. nRF APPROTECT or STM32 RDP)\n\
+                    Unlocking it requires a mass-erase of all its nonvolatile memory.\n\
+                    To do so, run `probe-run` with `--recover`."
+                );
+            }
+            if !confirm(
+                opts,
+                "--recover",
+                "this erases all flash memory of the chip, including its configuration; continue? [y/N] ",
+            )? {
+                bail!("recovery aborted; the chip is still protected");
+            }
+
+            log::info!("mass-erasing the chip to remove its protection");
+            let sess = attach_with_permissions(probe_target, opts, true).map_err(|e| {
+                anyhow!(e).context(
+                    "failed to unlock the chip; `probe-rs` may not support unlocking this chip \
+                    family (e.g. STM32 RDP), try the vendor's tools instead",
+                )
+            })?;
+            metrics::reconnected();
+            Ok(sess)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Returns `true` if attaching failed because the debug access port is locked.
+fn is_access_port_protected(error: &probe_rs::Error) -> bool {
+    matches!(
+        error,
+        probe_rs::Error::MissingPermissions(_)
+            | probe_rs::Error::Arm(ArmError::MissingPermissions(_))
+    )
+}
+
+/// Ask the `question` of `flag` on the terminal, unless `--yes` was passed.
+pub fn confirm(opts: &cli::Opts, flag: &str, question: &str) -> anyhow::Result<bool> {
+    if opts.yes {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        bail!("`{flag}` needs confirmation; pass `--yes` when not running in a terminal");
+    }
+
+    eprint!("{question}");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn attach_with_permissions(
+    probe_target: probe_rs::Target,
+    opts: &cli::Opts,
+    allow_erase_all: bool,
+) -> Result<Session, probe_rs::Error> {
+    let permissions = match allow_erase_all {
+        false => Permissions::new(),
+        true => Permissions::new().allow_erase_all(),
+    };
+    let probe = probe::open(opts).map_err(probe_rs::Error::Other)?;
+    let probe_name = probe.get_name();
+    let target_name = probe_target.name.clone();
+    let started = Instant::now();
+    let sess = if opts.connect_under_reset {
+        probe.attach_under_reset(probe_target, permissions)
+    } else {
+        let probe_attach = probe.attach(probe_target, permissions);
+        if let Err(probe_rs::Error::Probe(ProbeSpecific(e))) = &probe_attach {
+            // FIXME Using `to_string().contains(...)` is a workaround as the concrete type
+            // of `e` is not public and therefore does not allow downcasting.
+            if e.to_string().contains("JtagNoDeviceConnected") {
+                eprintln!("Info: Jtag cannot find a connected device.");
+                eprintln!("Help:");
+                eprintln!("    Check that the debugger is connected to the chip, if so");
+                eprintln!("    try using probe-run with option `--connect-under-reset`");
+                eprintln!("    or, if using cargo:");
+                eprintln!("        cargo run -- --connect-under-reset");
+                eprintln!("    If using this flag fixed your issue, this error might");
+                eprintln!("    come from the program currently in the chip and using");
+                eprintln!("    `--connect-under-reset` is only a workaround.\n");
+            }
+        }
+        probe_attach
+    };
+    debug_session::record("attach", || {
+        serde_json::json!({
+            "probe": probe_name,
+            "chip": target_name,
+            "under_reset": opts.connect_under_reset,
+            "allow_erase_all": allow_erase_all,
+            "ms": started.elapsed().as_millis() as u64,
+            "error": sess.as_ref().err().map(|e| e.to_string()),
+        })
+    });
+    let sess = sess?;
+    log::debug!("started session");
+    Ok(sess)
+}
+
+/// Check the write protection of the flash, then flash `program` through `sess`.
+pub fn flash_program(
+    sess: &mut Session,
+    program: &Program,
+    opts: &cli::Opts,
+) -> anyhow::Result<()> {
+    if !opts.no_flash {
+        write_protection::check(sess, &program.flash_plan, opts)?;
+        // e.g. stop a watchdog, which would reset the chip in the middle of flashing
+        if program.quirks.any_at(quirks::Point::PostReset) {
+            let core = &mut sess.core(opts.core)?;
+            core.reset_and_halt(TIMEOUT)?;
+            program.quirks.apply(core, quirks::Point::PostReset)?;
+        }
+    }
+    let erase_all = program.flash_plan.mass_erase().is_some();
+    flash(
+        sess,
+        &program.elf_bytes,
+        &program.erase_ranges,
+        erase_all,
+        opts,
+    )
+}
+
+/// Attach to the target through `--flash-probe`, while the session of `--probe` stays open.
+pub fn attach_flash_probe(
+    selector: &str,
+    probe_target: probe_rs::Target,
+    opts: &cli::Opts,
+) -> anyhow::Result<Session> {
+    if opts.probe.as_deref() == Some(selector) {
+        bail!("`--flash-probe` needs to select another probe than `--probe`");
+    }
+    let mut flash_opts = opts.clone();
+    flash_opts.probe = Some(selector.to_string());
+    flash_opts.probe_index = None;
+    log::debug!("flashing through the probe `{selector}`");
+    attach_to_probe(probe_target, &flash_opts).context("failed to attach through `--flash-probe`")
+}
+
+fn flash(
+    sess: &mut Session,
+    elf_bytes: &[u8],
+    erase_ranges: &[Range<u64>],
+    erase_all: bool,
+    opts: &cli::Opts,
+) -> anyhow::Result<()> {
+    if opts.no_flash {
+        log::info!("skipped flashing");
+    } else {
+        let fp = Some(flashing_progress());
+
+        if erase_all {
+            flashing::erase_all(sess, fp.clone())?;
+        }
+
+        let mut options = flashing::DownloadOptions::default();
+        options.dry_run = false;
+        options.progress = fp;
+        options.disable_double_buffering = opts.disable_double_buffering;
+        options.verify = opts.verify;
+
+        let mut loader = sess.target().flash_loader();
+        loader.load_elf_data(&mut &elf_bytes[..])?;
+        let segments = flash_plan::loadable_segments(elf_bytes)?;
+        erase::stage(&mut loader, sess.target(), erase_ranges, &segments)?;
+        loader.commit(sess, options)?;
+        log::info!("success!");
+    }
+    Ok(())
+}
+
+fn flashing_progress() -> flashing::FlashProgress {
+    flashing::FlashProgress::new(|evt| {
+        match evt {
+            // The flash layout has been built and the flashing procedure was initialized.
+            flashing::ProgressEvent::Initialized { flash_layout, .. } => {
+                let pages = flash_layout.pages();
+                let num_pages = pages.len();
+                let num_kb = pages.iter().map(|x| x.size() as f64).sum::<f64>() / 1024.0;
+                log::info!("flashing program ({num_pages} pages / {num_kb:.02} KiB)",);
+            }
+            // A sector has been erased. Sectors (usually) contain multiple pages.
+            flashing::ProgressEvent::SectorErased { size, time } => {
+                debug_session::record(
+                    "flash",
+                    || serde_json::json!({ "op": "erase_sector", "size": size, "us": time.as_micros() as u64 }),
+                );
+                log::debug!(
+                    "Erased sector of size {size} bytes in {} ms",
+                    time.as_millis()
+                )
+            }
+            // A page has been programmed.
+            flashing::ProgressEvent::PageProgrammed { size, time } => {
+                debug_session::record(
+                    "flash",
+                    || serde_json::json!({ "op": "program_page", "size": size, "us": time.as_micros() as u64 }),
+                );
+                log::debug!(
+                    "Programmed page of size {size} bytes in {} ms",
+                    time.as_millis()
+                )
+            }
+            _ => { /* Ignore other events */ }
+        }
+    })
+}
@@ -0,0 +1,324 @@
+//! Preparing the halted core to run the program, and restoring it afterwards: breakpoints, the
+//! RTT mode and the vector table
+
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, MemoryInterface as _};
+
+use crate::{
+    cli, cortexm,
+    elf::Elf,
+    inject::Injection,
+    poke,
+    registers::{LR, PC, SP},
+    remap::Remap,
+    target_args, vtor,
+    warnings::{self, Warning},
+};
+
+/// How often `--vtor-follow` checks whether the program relocated its vector table.
+pub const VTOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Read stack-pointer and reset-handler-address from the vector table.
+///
+/// Assumes that the target was reset-halted.
+///
+/// Returns `(stack_start: u32, reset_fn_address: u32)`
+pub fn analyze_vector_table(core: &mut Core) -> anyhow::Result<(u32, u32)> {
+    let stack_start = core.read_core_reg::<u32>(SP)?;
+    let reset_address = cortexm::set_thumb_bit(core.read_core_reg::<u32>(PC)?);
+    Ok((stack_start, reset_address))
+}
+
+/// What [`start_program`] changed on the target, so that [`detach_from_program`] can undo it
+pub struct ProgramSetup {
+    pub breakpoints: Vec<u32>,
+    /// Address of the HardFault breakpoint, which is also in `breakpoints`
+    pub hard_fault: u32,
+    /// Address of the `--run-until` breakpoint, which is also in `breakpoints`
+    pub run_until: Option<u32>,
+    /// Address of the `--end-symbol` function until it gets called, which is also in
+    /// `breakpoints`
+    pub end_symbol_entry: Option<u32>,
+    /// Address the `--end-symbol` function returns to, which is also in `breakpoints`
+    pub end_symbol_return: Option<u32>,
+    /// The `--inject`ions, by the address of their breakpoint, which is also in `breakpoints`
+    pub injections: Vec<(u32, Injection)>,
+    /// Address and original value of the RTT up channel's flags
+    rtt_channel_flags: Option<(u32, u32)>,
+}
+
+pub fn start_program(
+    core: &mut Core,
+    elf: &Elf,
+    remap: Option<&Remap>,
+    opts: &cli::Opts,
+) -> anyhow::Result<ProgramSetup> {
+    log::debug!("starting device");
+
+    let mut setup = ProgramSetup {
+        breakpoints: vec![],
+        hard_fault: cortexm::clear_thumb_bit(elf.vector_table.hard_fault),
+        run_until: None,
+        end_symbol_entry: None,
+        end_symbol_return: None,
+        injections: vec![],
+        rtt_channel_flags: None,
+    };
+
+    let entry_fn_address = elf.entry_fn_address(opts.entry_symbol.as_deref())?;
+    let args_buffer = target_args::buffer(elf, &opts.target_args);
+    // the log level goes first, so that `--poke` can override it
+    let pokes = opts
+        .target_log_level
+        .map(|level| poke::log_level(elf, level))
+        .transpose()?
+        .into_iter()
+        .chain(opts.poke.iter().cloned())
+        .collect::<Vec<_>>();
+    let accesses_memory = !pokes.is_empty() || !opts.peek.is_empty() || args_buffer.is_some();
+    let mut ran_to_main = false;
+    match (core.available_breakpoint_units()?, elf.rtt_buffer_address()) {
+        (0, Some(_)) => bail!("RTT not supported on device without HW breakpoints"),
+        (0, None) if accesses_memory => bail!("`--poke`, `--peek`, `--target-log-level` and program arguments are not supported on device without HW breakpoints"),
+        (0, None) => warnings::warn(Warning::NoHwBreakpoints, "device doesn't support HW breakpoints; HardFault will NOT make `probe-run` exit with an error code")?,
+        (_, rtt_buffer_address) => {
+            // the program initializes the RTT control block before `main`
+            let sets_rtt_mode = rtt_buffer_address.is_some() && opts.rtt_blocking != cli::RttBlocking::Keep;
+            match entry_fn_address {
+                Some(address) if sets_rtt_mode || accesses_memory => {
+                    run_to_main(core, address)?;
+                    ran_to_main = true;
+                }
+                // the runtime would overwrite memory accessed at the reset vector
+                None if accesses_memory => bail!("`--poke`, `--peek`, `--target-log-level` and program arguments need the program's `main`, but the ELF has none; pass its entry function with `--entry-symbol`"),
+                None if sets_rtt_mode => warnings::warn(Warning::NoEntryFunction, "`main` symbol not found; starting the program at the reset vector without setting the RTT channel's mode")?,
+                _ => {}
+            }
+            if let Some(rtt_buffer_address) = rtt_buffer_address.filter(|_| sets_rtt_mode && ran_to_main) {
+                setup.rtt_channel_flags = set_rtt_mode(core, rtt_buffer_address, opts.rtt_blocking)?;
+            }
+            poke::apply(core, elf, &pokes, &opts.peek)?;
+            if let Some(args_buffer) = args_buffer {
+                target_args::write(core, args_buffer, &opts.target_args)?;
+            }
+        }
+    }
+
+    // before `main`, VTOR may still point to the vector table of a bootloader
+    if let Some(hard_fault) = ran_to_main
+        .then(|| vtor::hard_fault_handler(core))
+        .flatten()
+    {
+        if hard_fault != setup.hard_fault {
+            log::info!("the vector table was relocated; catching HardFaults at {hard_fault:#010X}");
+            setup.hard_fault = hard_fault;
+        }
+    }
+    core.set_hw_breakpoint(setup.hard_fault.into())?;
+    setup.breakpoints.push(setup.hard_fault);
+
+    // if the flash is also mapped to address 0, the core may run the handler through the alias
+    if let Some(alias) = remap.and_then(|remap| remap.executed_address(setup.hard_fault)) {
+        match core.set_hw_breakpoint(alias.into()) {
+            Ok(()) => {
+                log::debug!("also catching HardFaults at the flash alias {alias:#010X}");
+                setup.breakpoints.push(alias);
+            }
+            Err(e) => log::debug!("can't catch HardFaults at the flash alias {alias:#010X}: {e}"),
+        }
+    }
+
+    if opts.catch_panics {
+        match elf.panic_fn_address() {
+            Some(panic_fn_address) => {
+                core.set_hw_breakpoint(panic_fn_address.into())?;
+                setup.breakpoints.push(panic_fn_address);
+            }
+            None => warnings::warn(
+                Warning::PanicHandlerNotFound,
+                "panic handler not found; `--catch-panics` has no effect",
+            )?,
+        }
+    }
+
+    if let Some(name) = &opts.run_until {
+        let address = elf
+            .find_symbol(name)
+            .ok_or_else(|| anyhow!("`--run-until` symbol `{name}` not found"))?
+            .start;
+        core.set_hw_breakpoint(address.into())?;
+        setup.breakpoints.push(address);
+        setup.run_until = Some(address);
+    }
+
+    if let Some(name) = &opts.end_symbol {
+        let address = elf
+            .find_symbol(name)
+            .ok_or_else(|| anyhow!("`--end-symbol` symbol `{name}` not found"))?
+            .start;
+        if ran_to_main && entry_fn_address == Some(address) {
+            // the core is halted at its beginning already
+            catch_return(core, &mut setup)?;
+        } else {
+            core.set_hw_breakpoint(address.into())?;
+            setup.breakpoints.push(address);
+            setup.end_symbol_entry = Some(address);
+        }
+    }
+
+    for injection in &opts.inject {
+        let address = injection.address(elf)?;
+        core.set_hw_breakpoint(address.into())?;
+        setup.breakpoints.push(address);
+        setup.injections.push((address, injection.clone()));
+    }
+
+    if opts.svc_exit {
+        match elf.vector_table.svcall {
+            Some(svcall) => {
+                let svcall = cortexm::clear_thumb_bit(svcall);
+                core.set_hw_breakpoint(svcall.into())?;
+                setup.breakpoints.push(svcall);
+            }
+            None => warnings::warn(
+                Warning::NoSvcallEntry,
+                "vector table has no SVCall entry; `--svc-exit` has no effect",
+            )?,
+        }
+    }
+
+    core.run()?;
+    delay(opts.settle_delay, "after starting the program");
+
+    Ok(setup)
+}
+
+/// Give the board `ms` milliseconds to settle (see `--before-run-delay` and `--settle-delay`).
+pub fn delay(ms: u64, when: &str) {
+    if ms > 0 {
+        log::debug!("waiting {ms} ms {when}");
+        thread::sleep(Duration::from_millis(ms));
+    }
+}
+
+/// Undo what [`start_program`] changed, instead of resetting the target (see `--no-reset`).
+///
+/// The breakpoints are cleared and the RTT channel gets its original flags back. A halted core is
+/// resumed if `resume` is set.
+pub fn detach_from_program(
+    core: &mut Core,
+    setup: ProgramSetup,
+    resume: bool,
+) -> anyhow::Result<()> {
+    for &breakpoint in &setup.breakpoints {
+        core.clear_hw_breakpoint(breakpoint.into())?;
+    }
+    restore_rtt_mode(core, &setup)?;
+    if resume {
+        core.run()?;
+    }
+    Ok(())
+}
+
+/// Move the HardFault breakpoint if the program relocated its vector table (see `--vtor-follow`).
+pub fn follow_vtor(core: &mut Core, setup: &mut ProgramSetup) -> anyhow::Result<()> {
+    let Some(hard_fault) = vtor::hard_fault_handler(core) else {
+        return Ok(());
+    };
+    if hard_fault == setup.hard_fault {
+        return Ok(());
+    }
+
+    core.clear_hw_breakpoint(setup.hard_fault.into())?;
+    core.set_hw_breakpoint(hard_fault.into())?;
+    setup
+        .breakpoints
+        .retain(|&address| address != setup.hard_fault);
+    setup.breakpoints.push(hard_fault);
+    setup.hard_fault = hard_fault;
+
+    log::info!("the program relocated its vector table; catching HardFaults at {hard_fault:#010X}");
+    Ok(())
+}
+
+/// Set a breakpoint on the return address of the `--end-symbol` function, which the core is halted
+/// at the beginning of.
+pub fn catch_return(core: &mut Core, setup: &mut ProgramSetup) -> anyhow::Result<()> {
+    if let Some(entry) = setup.end_symbol_entry.take() {
+        core.clear_hw_breakpoint(entry.into())?;
+        setup.breakpoints.retain(|&address| address != entry);
+    }
+
+    let lr = core.read_core_reg::<u32>(LR)?;
+    if lr & cortexm::EXC_RETURN_MARKER == cortexm::EXC_RETURN_MARKER {
+        bail!("the `--end-symbol` function was entered as an exception handler, not called");
+    }
+    let address = cortexm::clear_thumb_bit(lr);
+    core.set_hw_breakpoint(address.into())?;
+    setup.breakpoints.push(address);
+    setup.end_symbol_return = Some(address);
+    log::debug!("ending the run once the `--end-symbol` function returns to {address:#010X}");
+    Ok(())
+}
+
+/// Run the program up to the beginning of `fn main()`, after the runtime initialized RAM
+fn run_to_main(core: &mut Core, main_fn_address: u32) -> anyhow::Result<()> {
+    // set and wait for a hardware breakpoint at the beginning of `fn main()`
+    core.set_hw_breakpoint(main_fn_address.into())?;
+    core.run()?;
+    core.wait_for_core_halted(Duration::from_secs(5))?;
+
+    // clear the breakpoint we set before
+    core.clear_hw_breakpoint(main_fn_address.into())?;
+
+    Ok(())
+}
+
+/// Set the mode of RTT up channel 0 as `--rtt-blocking` asks.
+///
+/// Returns the address and original value of the channel's flags, if they changed.
+fn set_rtt_mode(
+    core: &mut Core,
+    rtt_buffer_address: u32,
+    blocking: cli::RttBlocking,
+) -> anyhow::Result<Option<(u32, u32)>> {
+    // calculate address of up-channel-flags inside the rtt control block
+    const OFFSET: u32 = 44;
+    let rtt_buffer_address = rtt_buffer_address + OFFSET;
+
+    // read flags
+    let channel_flags = &mut [0];
+    core.read_32(rtt_buffer_address.into(), channel_flags)?;
+    let Some(modified_channel_flags) = rtt_channel_flags(channel_flags[0], blocking) else {
+        return Ok(None);
+    };
+    // write flags back
+    core.write_word_32(rtt_buffer_address.into(), modified_channel_flags)?;
+
+    Ok(Some((rtt_buffer_address, channel_flags[0])))
+}
+
+/// The channel flags with the mode `blocking` asks for; `None` if they need no change.
+fn rtt_channel_flags(flags: u32, blocking: cli::RttBlocking) -> Option<u32> {
+    const MODE_MASK: u32 = 0b11;
+    const MODE_NO_BLOCK_TRIM: u32 = 0b01;
+    const MODE_BLOCK_IF_FULL: u32 = 0b10;
+
+    let mode = match (blocking, flags & MODE_MASK) {
+        (cli::RttBlocking::Force, _) => MODE_BLOCK_IF_FULL,
+        (cli::RttBlocking::Never, MODE_BLOCK_IF_FULL) => MODE_NO_BLOCK_TRIM,
+        (cli::RttBlocking::Never, _) | (cli::RttBlocking::Keep, _) => return None,
+    };
+    Some((flags & !MODE_MASK) | mode).filter(|&modified| modified != flags)
+}
+
+/// Give the RTT channel its original mode back, e.g. so that a program whose logs nobody reads
+/// anymore doesn't block.
+pub fn restore_rtt_mode(core: &mut Core, setup: &ProgramSetup) -> anyhow::Result<()> {
+    if let Some((address, flags)) = setup.rtt_channel_flags {
+        core.write_word_32(address.into(), flags)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Which exception the program is handling, sampled from the IPSR (see `--exception-stats`)
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write as _},
+};
+
+use probe_rs::Core;
+
+use crate::{cortexm, elf::Elf, registers::XPSR, sampling};
+
+/// The exception number in the xPSR; `0` in thread mode
+const IPSR_MASK: u32 = 0x1FF;
+
+#[derive(Default)]
+pub struct ExceptionStats {
+    /// Per exception number, the samples in which the core was handling it
+    samples: BTreeMap<u16, u64>,
+    /// Per exception number, how often a sample found it after a sample of another one
+    entries: BTreeMap<u16, u64>,
+    num_samples: u64,
+    last: Option<u16>,
+}
+
+impl ExceptionStats {
+    pub fn sample(&mut self, core: &mut Core, breakpoints: &[u32]) -> anyhow::Result<()> {
+        let xpsr = sampling::while_halted(core, breakpoints, |core| {
+            Ok(core.read_core_reg::<u32>(XPSR)?)
+        })?;
+        self.record((xpsr & IPSR_MASK) as u16);
+        Ok(())
+    }
+
+    fn record(&mut self, exception: u16) {
+        *self.samples.entry(exception).or_default() += 1;
+        if self.last != Some(exception) {
+            *self.entries.entry(exception).or_default() += 1;
+        }
+        self.num_samples += 1;
+        self.last = Some(exception);
+    }
+
+    /// Print a table of the sampled exceptions, most frequent first.
+    pub fn print(&self, elf: &Elf, json_lines: bool) -> io::Result<()> {
+        let mut rows = self
+            .samples
+            .iter()
+            .map(|(&number, &samples)| Row {
+                number,
+                name: cortexm::exception_name(number),
+                handler: handler_name(elf, number),
+                samples,
+                entries: self.entries.get(&number).copied().unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.number.cmp(&b.number)));
+
+        if json_lines {
+            let exceptions = rows
+                .iter()
+                .map(|row| {
+                    serde_json::json!({
+                        "number": row.number,
+                        "name": row.name,
+                        "handler": row.handler,
+                        "samples": row.samples,
+                        "entries": row.entries,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let event = serde_json::json!({
+                "event": "exception_stats",
+                "samples": self.num_samples,
+                "exceptions": exceptions,
+            });
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer(&mut stdout, &event)?;
+            writeln!(stdout)?;
+            return stdout.flush();
+        }
+
+        let mut stderr = io::stderr().lock();
+        writeln!(
+            stderr,
+            "exception statistics ({} samples):",
+            self.num_samples
+        )?;
+        writeln!(
+            stderr,
+            "  {:<16} {:>8} {:>7} {:>8}  handler",
+            "exception", "samples", "share", "entries"
+        )?;
+        for row in rows {
+            let share = row.samples as f64 / self.num_samples as f64 * 100.0;
+            writeln!(
+                stderr,
+                "  {:<16} {:>8} {:>6.1}% {:>8}  {}",
+                row.name,
+                row.samples,
+                share,
+                row.entries,
+                match row.number {
+                    0 => "-",
+                    _ => row.handler.unwrap_or("<unknown>"),
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Row<'elf> {
+    number: u16,
+    name: String,
+    handler: Option<&'elf str>,
+    samples: u64,
+    entries: u64,
+}
+
+/// The name of the function the vector table points to for `exception`
+fn handler_name<'elf>(elf: &Elf<'elf>, exception: u16) -> Option<&'elf str> {
+    if exception == 0 {
+        return None;
+    }
+    let handler = *elf.vector_table.entries.get(usize::from(exception))?;
+    elf.function_name(cortexm::clear_thumb_bit(handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_samples_and_entries() {
+        let mut stats = ExceptionStats::default();
+        for exception in [0, 0, 31, 31, 0, 15, 31] {
+            stats.record(exception);
+        }
+
+        assert_eq!(stats.num_samples, 7);
+        assert_eq!(stats.samples[&0], 3);
+        assert_eq!(stats.samples[&31], 3);
+        // an exception that is still active in the next sample counts once
+        assert_eq!(stats.entries[&31], 2);
+        assert_eq!(stats.entries[&15], 1);
+    }
+}
@@ -0,0 +1,60 @@
+//! Statistics sampled from the running program by briefly halting it, every
+//! `--sample-interval` milliseconds
+
+use std::time::{Duration, Instant};
+
+use probe_rs::Core;
+
+use crate::run::TIMEOUT;
+
+mod exceptions;
+
+pub use exceptions::ExceptionStats;
+
+/// Decides when the next sample is due
+pub struct Sampler {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns `true` if a sample is due, and starts the next interval.
+    pub fn due(&mut self) -> bool {
+        if self.last.elapsed() < self.interval {
+            return false;
+        }
+        self.last = Instant::now();
+        true
+    }
+}
+
+/// Halt the running program, `read` its state and resume it.
+///
+/// The program may have halted on one of the `breakpoints` by itself just before, e.g. on a
+/// HardFault; then it stays halted, so that the run ends as it would without sampling.
+pub fn while_halted<T>(
+    core: &mut Core,
+    breakpoints: &[u32],
+    read: impl FnOnce(&mut Core) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let pc = core.halt(TIMEOUT)?.pc;
+    let result = read(core);
+    if !breakpoints
+        .iter()
+        .any(|&breakpoint| u64::from(breakpoint) == pc)
+    {
+        core.run()?;
+    }
+    result
+}
@@ -0,0 +1,198 @@
+//! The log vocabulary of a program: every defmt format string with its index, level and argument
+//! types, as JSON for tools that don't link to `defmt-decoder` (see `export-schema`)
+
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context as _};
+use defmt_parser::{Fragment, ParserMode, Type};
+use serde::Deserialize;
+
+use crate::elf::Elf;
+
+/// Bumped on incompatible changes of the output
+const SCHEMA_VERSION: u32 = 1;
+
+/// Tags whose data is a format string
+const FORMAT_TAGS: &[&str] = &[
+    "prim",
+    "derived",
+    "write",
+    "timestamp",
+    "println",
+    "trace",
+    "debug",
+    "info",
+    "warn",
+    "error",
+];
+const LEVEL_TAGS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
+/// A symbol of the `.defmt` section, as the `defmt` macros encode it
+#[derive(Deserialize)]
+struct Symbol {
+    tag: String,
+    data: String,
+    crate_name: Option<String>,
+}
+
+/// Write the schema of the ELF at `elf_path` to `out`, or to stdout.
+pub fn export(elf_path: &Path, out: Option<&Path>) -> anyhow::Result<()> {
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = Elf::parse_offline(&elf_bytes, elf_path)?;
+    let table = elf
+        .defmt_table
+        .as_ref()
+        .ok_or_else(|| anyhow!("`{}` doesn't use defmt", elf_path.display()))?;
+
+    let entries = elf
+        .defmt_symbols()
+        .into_iter()
+        .filter_map(|(index, raw)| entry(index, raw, &elf).transpose())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let num_entries = entries.len();
+    let schema = serde_json::json!({
+        "schema": SCHEMA_VERSION,
+        "encoding": format!("{:?}", table.encoding()).to_lowercase(),
+        "has_timestamp": table.has_timestamp(),
+        "entries": entries,
+    });
+
+    match out {
+        Some(path) => {
+            let json = serde_json::to_string_pretty(&schema)?;
+            fs::write(path, json + "\n")
+                .with_context(|| format!("failed to write `{}`", path.display()))?;
+            log::info!(
+                "wrote {num_entries} defmt format strings to `{}`",
+                path.display()
+            );
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &schema)?;
+            writeln!(stdout)?;
+        }
+    }
+    Ok(())
+}
+
+/// The schema entry of a `.defmt` symbol; `None` for symbols which aren't in the table, like
+/// bitflags values and the symbols of third-party tools.
+fn entry(index: usize, raw: &str, elf: &Elf) -> anyhow::Result<Option<serde_json::Value>> {
+    let symbol: Symbol = serde_json::from_str(raw)
+        .with_context(|| format!("failed to parse defmt symbol `{raw}`"))?;
+    let Some(tag) = symbol.tag.strip_prefix("defmt_") else {
+        return Ok(None);
+    };
+    if tag == "bitflags_value" {
+        return Ok(None);
+    }
+
+    let args = match FORMAT_TAGS.contains(&tag) {
+        true => Some(
+            arg_types(&symbol.data)
+                .with_context(|| format!("failed to parse the format string of entry {index}"))?,
+        ),
+        false => None,
+    };
+    let location = elf
+        .defmt_locations
+        .as_ref()
+        .and_then(|locations| locations.get(&(index as u64)))
+        .map(|location| {
+            serde_json::json!({
+                "file": location.file,
+                "line": location.line,
+                "module": location.module,
+            })
+        });
+
+    Ok(Some(serde_json::json!({
+        "index": index,
+        "tag": tag,
+        "level": LEVEL_TAGS.contains(&tag).then_some(tag),
+        "format": symbol.data,
+        "args": args,
+        "crate": symbol.crate_name,
+        "location": location,
+    })))
+}
+
+/// The types of the arguments of `format`, by argument index, in format string syntax
+///
+/// An argument which is displayed several times has one type; the bitfields of an argument are
+/// merged into the range which gets transmitted.
+fn arg_types(format: &str) -> anyhow::Result<Vec<String>> {
+    let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)?;
+    let mut args: Vec<Option<Type>> = vec![];
+    for fragment in fragments {
+        let Fragment::Parameter(parameter) = fragment else {
+            continue;
+        };
+        if args.len() <= parameter.index {
+            args.resize(parameter.index + 1, None);
+        }
+        match (&mut args[parameter.index], parameter.ty) {
+            (Some(Type::BitField(range)), Type::BitField(other)) => {
+                *range = range.start.min(other.start)..range.end.max(other.end);
+            }
+            (arg @ None, ty) => *arg = Some(ty),
+            (Some(_), _) => {}
+        }
+    }
+    // the parser rejects format strings with arguments which aren't displayed, so there are no gaps
+    Ok(args.iter().flatten().map(type_name).collect())
+}
+
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::BitField(range) => format!("{}..{}", range.start, range.end),
+        Type::Bool => "bool".into(),
+        Type::Char => "char".into(),
+        Type::Debug => "__internal_Debug".into(),
+        Type::Display => "__internal_Display".into(),
+        Type::FormatSequence => "__internal_FormatSequence".into(),
+        Type::F32 => "f32".into(),
+        Type::F64 => "f64".into(),
+        Type::Format => "?".into(),
+        Type::FormatArray(len) => format!("[?;{len}]"),
+        Type::FormatSlice => "[?]".into(),
+        Type::I8 => "i8".into(),
+        Type::I16 => "i16".into(),
+        Type::I32 => "i32".into(),
+        Type::I64 => "i64".into(),
+        Type::I128 => "i128".into(),
+        Type::Isize => "isize".into(),
+        Type::IStr => "istr".into(),
+        Type::Str => "str".into(),
+        Type::U8 => "u8".into(),
+        Type::U16 => "u16".into(),
+        Type::U32 => "u32".into(),
+        Type::U64 => "u64".into(),
+        Type::U128 => "u128".into(),
+        Type::Usize => "usize".into(),
+        Type::U8Slice => "[u8]".into(),
+        Type::U8Array(len) => format!("[u8;{len}]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::no_args("hello", &[])]
+    #[case::typed("x = {=u8}, y = {=[u8;4]:x}", &["u8", "[u8;4]"])]
+    #[case::format("{}", &["?"])]
+    #[case::bitfields("{0=0..4} {0=8..12}", &["0..12"])]
+    #[case::explicit_indices("{1=str} {0=bool}", &["bool", "str"])]
+    fn parses_arg_types(#[case] format: &str, #[case] expected: &[&str]) {
+        assert_eq!(arg_types(format).unwrap(), expected);
+    }
+}
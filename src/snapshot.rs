@@ -0,0 +1,162 @@
+//! "Where is it now?": a backtrace, the stack pointer and the stack usage of the running program,
+//! on `SIGUSR1` or when Enter is pressed, without ending the run
+
+use std::{
+    io::{self, Write as _},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use probe_rs::Core;
+
+use crate::{
+    backtrace,
+    canary::Canary,
+    cli::{JsonFormat, Opts},
+    elf::Elf,
+    registers::{PC, SP},
+    run::TIMEOUT,
+    target_info::TargetInfo,
+};
+
+/// Requests for a snapshot
+pub struct Trigger {
+    signal: Arc<AtomicBool>,
+    #[cfg(unix)]
+    sig_id: signal_hook::SigId,
+    /// Pressing Enter takes a snapshot; only if stdin is a terminal
+    #[cfg(unix)]
+    stdin: bool,
+}
+
+impl Trigger {
+    pub fn install() -> io::Result<Self> {
+        let signal = Arc::new(AtomicBool::new(false));
+        Ok(Self {
+            #[cfg(unix)]
+            sig_id: signal_hook::flag::register(signal_hook::consts::SIGUSR1, signal.clone())?,
+            #[cfg(unix)]
+            stdin: io::IsTerminal::is_terminal(&io::stdin()),
+            signal,
+        })
+    }
+
+    /// Returns `true` if a snapshot was requested since the last call.
+    pub fn requested(&self) -> io::Result<bool> {
+        let signaled = self.signal.swap(false, Ordering::Relaxed);
+        Ok(signaled | self.enter_pressed()?)
+    }
+
+    /// Consume the lines typed since the last call, without blocking.
+    #[cfg(unix)]
+    fn enter_pressed(&self) -> io::Result<bool> {
+        if !self.stdin {
+            return Ok(false);
+        }
+        let mut pressed = false;
+        while stdin_readable()? {
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                // end of input; don't poll a closed stdin again
+                break;
+            }
+            pressed = true;
+        }
+        Ok(pressed)
+    }
+
+    #[cfg(not(unix))]
+    fn enter_pressed(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Trigger {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.sig_id);
+    }
+}
+
+/// Returns `true` if a line can be read from stdin without blocking; the terminal only hands
+/// input over once Enter was pressed.
+#[cfg(unix)]
+fn stdin_readable() -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd as _;
+
+    let mut fd = libc::pollfd {
+        fd: io::stdin().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a single valid `pollfd`; a timeout of 0 returns immediately
+    match unsafe { libc::poll(&mut fd, 1, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(fd.revents & libc::POLLIN != 0),
+    }
+}
+
+/// Halt the running program, print where it is, and resume it.
+pub fn take(
+    core: &mut Core,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    canary: Option<&Canary>,
+    current_dir: &Path,
+    opts: &Opts,
+) -> anyhow::Result<()> {
+    core.halt(TIMEOUT)?;
+    let result = print(core, elf, target_info, canary, current_dir, opts);
+    // resume even if printing failed, as the snapshot must not end the run
+    core.run()?;
+    result
+}
+
+fn print(
+    core: &mut Core,
+    elf: &Elf,
+    target_info: &TargetInfo,
+    canary: Option<&Canary>,
+    current_dir: &Path,
+    opts: &Opts,
+) -> anyhow::Result<()> {
+    let pc = core.read_core_reg::<u32>(PC)?;
+    let sp = core.read_core_reg::<u32>(SP)?;
+    // without a canary, the current depth is all there is to know
+    let stack_depth = target_info.stack_start.saturating_sub(sp);
+    let stack_used = canary
+        .map(|canary| canary.used_so_far(core, elf))
+        .transpose()?;
+
+    let mut settings = backtrace::Settings::new(current_dir.to_path_buf(), false, opts, false);
+    backtrace::print_snapshot(core, elf, target_info, &mut settings)?;
+
+    match (opts.json, opts.json_format) {
+        (true, JsonFormat::Lines) => {
+            let event = serde_json::json!({
+                "event": "snapshot",
+                "pc": pc,
+                "sp": sp,
+                "stack_depth": stack_depth,
+                "stack_used": stack_used,
+            });
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer(&mut stdout, &event)?;
+            writeln!(stdout)?;
+            stdout.flush()?;
+        }
+        _ => {
+            let stack_used = stack_used
+                .map(|bytes| format!(", at least {bytes} bytes used so far"))
+                .unwrap_or_default();
+            log::info!(
+                "snapshot: PC = {pc:#010X}, SP = {sp:#010X}, stack depth {stack_depth} bytes{stack_used}; resuming"
+            );
+        }
+    }
+    Ok(())
+}
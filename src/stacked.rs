@@ -1,6 +1,6 @@
 use std::{mem, ops::Range};
 
-use probe_rs::{Core, MemoryInterface};
+use crate::transport::Transport;
 
 /// Registers stacked on exception entry.
 #[derive(Debug)]
@@ -45,7 +45,7 @@ impl Stacked {
     ///
     /// This performs bound checks and returns `None` if a invalid memory read is requested
     pub fn read(
-        core: &mut Core<'_>,
+        core: &mut dyn Transport,
         sp: u32,
         fpu: bool,
         ram_bounds: Range<u32>,
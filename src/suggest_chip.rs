@@ -0,0 +1,334 @@
+//! `suggest-chip` and `--chip auto`: find the chips whose memory map fits the program
+//!
+//! Matching the program against every chip of the registry takes a while, so the chip `--chip auto`
+//! picks is cached per project in `.probe-run/chip-auto.json`, along with the footprint of the
+//! program it was picked for. The target description of the chip is still looked up in the
+//! registry on every run.
+
+use std::{fs, ops::Range, path::Path};
+
+use anyhow::{anyhow, bail};
+use object::{
+    elf::{FileHeader32, PT_LOAD},
+    read::elf::{FileHeader as _, ProgramHeader as _},
+    Endianness, Object as _, ObjectSection as _,
+};
+use probe_rs::{config::MemoryRegion, CoreType};
+use serde::{Deserialize, Serialize};
+
+use crate::{elf::Elf, flash_plan};
+
+/// How many chips `--chip auto` lists if it can't pick one
+const MAX_LISTED_CHIPS: usize = 10;
+const AUTO_CACHE_PATH: &str = ".probe-run/chip-auto.json";
+
+/// Where the program lives, according to the ELF
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Footprint {
+    /// Contents of the flash, at their load addresses
+    flash: Vec<Range<u64>>,
+    /// `.data` and `.bss`, at their run-time addresses
+    ram: Vec<Range<u64>>,
+    vector_table: u64,
+    initial_stack_pointer: u64,
+}
+
+/// How well a memory map fits a [`Footprint`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Fit {
+    /// The program fits into the chip's flash and RAM
+    Plausible,
+    /// ... and the vector table is at the start of the flash, and the stack at the end of the RAM
+    Exact,
+}
+
+struct Suggestion {
+    family: String,
+    chip: String,
+    fit: Fit,
+}
+
+/// The chip `--chip auto` picked for a footprint, with the registry of this version of probe-rs
+#[derive(Serialize, Deserialize)]
+struct AutoCache {
+    probe_rs_version: String,
+    footprint: Footprint,
+    chip: String,
+}
+
+impl AutoCache {
+    fn load(path: &Path, footprint: &Footprint) -> Option<String> {
+        let json = fs::read_to_string(path).ok()?;
+        let cache = serde_json::from_str::<Self>(&json).ok()?;
+        (cache.probe_rs_version == env!("PROBE_RS_VERSION") && cache.footprint == *footprint)
+            .then_some(cache.chip)
+    }
+
+    fn store(path: &Path, footprint: Footprint, chip: &str) -> anyhow::Result<()> {
+        let cache = Self {
+            probe_rs_version: env!("PROBE_RS_VERSION").to_string(),
+            footprint,
+            chip: chip.to_string(),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&cache)?)?;
+        Ok(())
+    }
+}
+
+/// Print the chips the program in `elf_path` fits, best matches first.
+///
+/// Returns `false` if no chip fits.
+pub fn print(elf_path: &Path) -> anyhow::Result<bool> {
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = Elf::parse_offline(&elf_bytes, elf_path)?;
+    let suggestions = suggest(&footprint(&elf_bytes, &elf)?)?;
+    if suggestions.is_empty() {
+        println!("no chip in the registry fits the memory layout of the program");
+        return Ok(false);
+    }
+
+    for (fit, heading) in [
+        (
+            Fit::Exact,
+            "vector table at the start of flash and stack at the end of RAM:",
+        ),
+        (Fit::Plausible, "program fits into flash and RAM:"),
+    ] {
+        let mut families: Vec<(&str, Vec<&str>)> = vec![];
+        for suggestion in suggestions.iter().filter(|s| s.fit == fit) {
+            match families.last_mut() {
+                Some((family, chips)) if *family == suggestion.family => {
+                    chips.push(&suggestion.chip)
+                }
+                _ => families.push((&suggestion.family, vec![&suggestion.chip])),
+            }
+        }
+        if families.is_empty() {
+            continue;
+        }
+
+        println!("{heading}");
+        for (family, chips) in families {
+            println!("  {family}: {}", chips.join(", "));
+        }
+    }
+    Ok(true)
+}
+
+/// Pick the chip for `--chip auto`, if exactly one fits the program exactly.
+pub fn auto(elf_path: &Path) -> anyhow::Result<String> {
+    let elf_bytes = fs::read(elf_path)?;
+    let elf = Elf::parse_offline(&elf_bytes, elf_path)?;
+    let footprint = footprint(&elf_bytes, &elf)?;
+    if let Some(chip) = AutoCache::load(Path::new(AUTO_CACHE_PATH), &footprint) {
+        log::info!("`--chip auto` picked {chip} (cached)");
+        return Ok(chip);
+    }
+
+    let exact = suggest(&footprint)?
+        .into_iter()
+        .filter(|suggestion| suggestion.fit == Fit::Exact)
+        .map(|suggestion| suggestion.chip)
+        .collect::<Vec<_>>();
+
+    match &exact[..] {
+        [chip] => {
+            log::info!("`--chip auto` picked {chip}");
+            if let Err(e) = AutoCache::store(Path::new(AUTO_CACHE_PATH), footprint, chip) {
+                log::debug!("failed to cache the chip `--chip auto` picked: {e}");
+            }
+            Ok(chip.clone())
+        }
+        [] => bail!(
+            "`--chip auto` found no chip which fits the program exactly; \
+            see `probe-run suggest-chip {}` for candidates",
+            elf_path.display()
+        ),
+        chips => {
+            let mut list = chips[..chips.len().min(MAX_LISTED_CHIPS)].join(", ");
+            if chips.len() > MAX_LISTED_CHIPS {
+                list += &format!(" and {} more", chips.len() - MAX_LISTED_CHIPS);
+            }
+            bail!("`--chip auto` found {} chips which fit the program: {list}; pick one with `--chip`", chips.len())
+        }
+    }
+}
+
+fn suggest(footprint: &Footprint) -> anyhow::Result<Vec<Suggestion>> {
+    let mut suggestions = vec![];
+    for family in probe_rs::config::families()? {
+        for chip in &family.variants {
+            // the ELF has a Cortex-M vector table
+            let is_arm = chip
+                .cores
+                .iter()
+                .all(|core| core.core_type != CoreType::Riscv);
+            if !is_arm {
+                continue;
+            }
+
+            if let Some(fit) = fit(&chip.memory_map, footprint) {
+                suggestions.push(Suggestion {
+                    family: family.name.clone(),
+                    chip: chip.name.clone(),
+                    fit,
+                });
+            }
+        }
+    }
+
+    // a stable sort keeps the registry's order of families and chips
+    suggestions.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.fit));
+    Ok(suggestions)
+}
+
+fn footprint(elf_bytes: &[u8], elf: &Elf) -> anyhow::Result<Footprint> {
+    let vector_table = elf
+        .section_by_name(".vector_table")
+        .ok_or_else(|| anyhow!("`.vector_table` section is missing"))?
+        .address();
+
+    Ok(Footprint {
+        flash: flash_plan::loadable_segments(elf_bytes)?,
+        ram: ram_segments(elf_bytes)?,
+        vector_table,
+        initial_stack_pointer: u64::from(elf.vector_table.initial_stack_pointer),
+    })
+}
+
+/// The run-time address ranges of the `PT_LOAD` segments which live in RAM, i.e. which are
+/// copied there (`.data`) or have no contents in the ELF (`.bss`).
+fn ram_segments(elf_bytes: &[u8]) -> anyhow::Result<Vec<Range<u64>>> {
+    let header = FileHeader32::<Endianness>::parse(elf_bytes)?;
+    let endian = header.endian()?;
+
+    let segments = header
+        .program_headers(endian, elf_bytes)?
+        .iter()
+        .filter(|segment| {
+            segment.p_type(endian) == PT_LOAD
+                && segment.p_memsz(endian) != 0
+                && (segment.p_vaddr(endian) != segment.p_paddr(endian)
+                    || segment.p_filesz(endian) == 0)
+        })
+        .map(|segment| {
+            let start = u64::from(segment.p_vaddr(endian));
+            start..start + u64::from(segment.p_memsz(endian))
+        })
+        .collect();
+    Ok(segments)
+}
+
+fn fit(memory_map: &[MemoryRegion], footprint: &Footprint) -> Option<Fit> {
+    let nvm = memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Nvm(region) => Some(&region.range),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let ram = memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Ram(region) => Some(&region.range),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let within = |regions: &[&Range<u64>], range: &Range<u64>| {
+        regions
+            .iter()
+            .any(|region| region.start <= range.start && range.end <= region.end)
+    };
+    let fits = footprint.flash.iter().all(|range| within(&nvm, range))
+        && footprint.ram.iter().all(|range| within(&ram, range));
+    // the stack grows down from its initial value, which may be the end of the RAM
+    let stack_region = ram.iter().find(|region| {
+        region.start < footprint.initial_stack_pointer
+            && footprint.initial_stack_pointer <= region.end
+    });
+    let (true, Some(stack_region)) = (fits, stack_region) else {
+        return None;
+    };
+
+    let vector_table_at_start = nvm
+        .iter()
+        .any(|region| region.start == footprint.vector_table);
+    match vector_table_at_start && stack_region.end == footprint.initial_stack_pointer {
+        true => Some(Fit::Exact),
+        false => Some(Fit::Plausible),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use probe_rs::config::{NvmRegion, RamRegion};
+    use rstest::rstest;
+
+    use super::*;
+
+    /// 1 MiB flash and 256 KiB RAM, like the nRF52840
+    fn memory_map() -> Vec<MemoryRegion> {
+        vec![
+            MemoryRegion::Nvm(NvmRegion {
+                name: None,
+                range: 0..0x10_0000,
+                is_boot_memory: true,
+                cores: vec![],
+            }),
+            MemoryRegion::Ram(RamRegion {
+                name: None,
+                range: 0x2000_0000..0x2004_0000,
+                is_boot_memory: false,
+                cores: vec![],
+            }),
+        ]
+    }
+
+    fn footprint(initial_stack_pointer: u64, flash_end: u64) -> Footprint {
+        Footprint {
+            flash: std::iter::once(0..flash_end).collect(),
+            ram: std::iter::once(0x2000_0000..0x2000_0100).collect(),
+            vector_table: 0,
+            initial_stack_pointer,
+        }
+    }
+
+    #[rstest]
+    #[case::exact(footprint(0x2004_0000, 0x8000), Some(Fit::Exact))]
+    #[case::smaller_stack(footprint(0x2002_0000, 0x8000), Some(Fit::Plausible))]
+    #[case::too_much_flash(footprint(0x2004_0000, 0x20_0000), None)]
+    #[case::stack_outside_ram(footprint(0x2008_0000, 0x8000), None)]
+    fn fits_memory_map(#[case] footprint: Footprint, #[case] expected: Option<Fit>) {
+        assert_eq!(fit(&memory_map(), &footprint), expected);
+    }
+
+    #[test]
+    fn caches_chip_per_footprint_and_probe_rs_version() {
+        let dir = std::env::temp_dir().join(format!("probe-run-chip-auto-{}", std::process::id()));
+        let path = dir.join("chip-auto.json");
+        AutoCache::store(&path, footprint(0x2004_0000, 0x8000), "nRF52840_xxAA").unwrap();
+
+        let cached = AutoCache::load(&path, &footprint(0x2004_0000, 0x8000));
+        assert_eq!(cached.as_deref(), Some("nRF52840_xxAA"));
+        // another program
+        assert_eq!(
+            AutoCache::load(&path, &footprint(0x2004_0000, 0x9000)),
+            None
+        );
+
+        // the registry of another probe-rs
+        let json = fs::read_to_string(&path).unwrap();
+        let json = json.replace(env!("PROBE_RS_VERSION"), "0.19.0");
+        fs::write(&path, json).unwrap();
+        assert_eq!(
+            AutoCache::load(&path, &footprint(0x2004_0000, 0x8000)),
+            None
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//! Exit requests from the target program via reserved `SVC` immediates
+//!
+//! With `--svc-exit`, `probe-run` sets a breakpoint on the `SVCall` handler. When it is hit, the
+//! `svc` instruction which caused the exception gets inspected:
+//!
+//! - `svc #0xEE` requests to exit; the exit status is passed in `r0`
+//! - `svc #0xEF` requests to abort
+//! - any other `svc` is none of our business and the program gets resumed
+//!
+//! Unlike `bkpt`, this does not interfere with breakpoints used for other purposes.
+
+use crate::{
+    backtrace::Outcome,
+    cortexm,
+    elf::Elf,
+    registers::{LR, MSP, PC, PSP},
+    transport::Transport,
+};
+
+/// `svc` immediate requesting to exit with the status in `r0`
+const EXIT_IMMEDIATE: u8 = 0xEE;
+/// `svc` immediate requesting to abort
+const ABORT_IMMEDIATE: u8 = 0xEF;
+
+/// Encoding of `svc #imm8`: `0b1101_1111_iiii_iiii`
+const SVC_OPCODE: u16 = 0xDF00;
+const SVC_OPCODE_MASK: u16 = 0xFF00;
+
+/// `EXC_RETURN` bit which indicates that the exception frame was stacked on the PSP
+const EXC_RETURN_SPSEL: u32 = 1 << 2;
+
+/// Offsets of the stacked `r0` and return address in the exception frame
+const STACKED_R0_OFFSET: u32 = 0;
+const STACKED_PC_OFFSET: u32 = 24;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SvcCall {
+    Exit {
+        status: u32,
+    },
+    Abort,
+    /// `svc` with an immediate that isn't reserved by `probe-run`
+    Other,
+}
+
+impl SvcCall {
+    /// The outcome of the program, if it requested to stop
+    pub fn outcome(self) -> Option<Outcome> {
+        match self {
+            SvcCall::Exit { status } => Some(Outcome::Exit(status)),
+            SvcCall::Abort => Some(Outcome::Abort),
+            SvcCall::Other => None,
+        }
+    }
+}
+
+/// Inspect the `svc` call, if the (halted) core is at the entry of the `SVCall` handler.
+pub fn read_call(core: &mut dyn Transport, elf: &Elf) -> anyhow::Result<Option<SvcCall>> {
+    let svcall = match elf.vector_table.svcall {
+        Some(svcall) => svcall,
+        None => return Ok(None),
+    };
+    let pc = core.read_core_reg(PC)?;
+    if !cortexm::subroutine_eq(pc, svcall) {
+        return Ok(None);
+    }
+
+    // the exception frame lives on the stack which was active when `svc` got executed
+    let exc_return = core.read_core_reg(LR)?;
+    let frame = match exc_return & EXC_RETURN_SPSEL {
+        0 => core.read_core_reg(MSP)?,
+        _ => core.read_core_reg(PSP)?,
+    };
+
+    let return_address = core.read_word_32(frame.wrapping_add(STACKED_PC_OFFSET).into())?;
+    // a bogus return address of 0 or 1 can't follow an `svc`
+    let svc_address = match return_address.checked_sub(2) {
+        Some(address) => address,
+        None => {
+            log::debug!("no `svc` instruction in front of {return_address:#010X}");
+            return Ok(Some(SvcCall::Other));
+        }
+    };
+    let mut instruction = [0; 2];
+    core.read_8(svc_address.into(), &mut instruction)?;
+
+    let call = match svc_immediate(u16::from_le_bytes(instruction)) {
+        Some(EXIT_IMMEDIATE) => SvcCall::Exit {
+            status: core.read_word_32(frame.wrapping_add(STACKED_R0_OFFSET).into())?,
+        },
+        Some(ABORT_IMMEDIATE) => SvcCall::Abort,
+        Some(_) => SvcCall::Other,
+        None => {
+            log::debug!("no `svc` instruction in front of {return_address:#010X}");
+            return Ok(Some(SvcCall::Other));
+        }
+    };
+    log::debug!("`svc` at {svc_address:#010X}: {call:?}");
+    Ok(Some(call))
+}
+
+/// The immediate of `instruction`, if it is an `svc`
+fn svc_immediate(instruction: u16) -> Option<u8> {
+    match instruction & SVC_OPCODE_MASK {
+        SVC_OPCODE => Some(instruction as u8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::exit(0xDFEE, Some(EXIT_IMMEDIATE))]
+    #[case::abort(0xDFEF, Some(ABORT_IMMEDIATE))]
+    #[case::other_svc(0xDF00, Some(0))]
+    #[case::bkpt(0xBEAB, None)]
+    #[case::nop(0xBF00, None)]
+    fn decodes_svc_immediate(#[case] instruction: u16, #[case] expected: Option<u8>) {
+        assert_eq!(svc_immediate(instruction), expected);
+    }
+
+    #[rstest]
+    #[case::exit(SvcCall::Exit { status: 3 }, Some(Outcome::Exit(3)))]
+    #[case::abort(SvcCall::Abort, Some(Outcome::Abort))]
+    #[case::other(SvcCall::Other, None)]
+    fn maps_call_to_outcome(#[case] call: SvcCall, #[case] expected: Option<Outcome>) {
+        assert_eq!(call.outcome(), expected);
+    }
+}
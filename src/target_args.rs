@@ -0,0 +1,75 @@
+//! Arguments for the program, given after the ELF path on the command line.
+//!
+//! They get written into the program's `__probe_run_args` buffer before `main` starts, each one
+//! terminated by a NUL byte, followed by another NUL byte which ends the list.
+
+use std::ops::Range;
+
+use anyhow::bail;
+use probe_rs::{Core, MemoryInterface as _};
+
+use crate::elf::Elf;
+
+/// The buffer the program provides for its arguments
+pub const SYMBOL: &str = "__probe_run_args";
+
+/// Locate the buffer for `args`; `None` if there are no arguments or the program takes none.
+pub fn buffer(elf: &Elf, args: &[String]) -> Option<Range<u32>> {
+    if args.is_empty() {
+        return None;
+    }
+    let buffer = elf.find_symbol(SYMBOL);
+    if buffer.is_none() {
+        log::debug!("the program has no `{SYMBOL}` buffer; ignoring the arguments after the ELF");
+    }
+    buffer
+}
+
+/// Write `args` into `buffer`.
+pub fn write(core: &mut Core, buffer: Range<u32>, args: &[String]) -> anyhow::Result<()> {
+    let bytes = encode(args)?;
+    let size = (buffer.end - buffer.start) as usize;
+    if bytes.len() > size {
+        bail!(
+            "the arguments take {} bytes, but `{SYMBOL}` only has room for {size}",
+            bytes.len()
+        );
+    }
+
+    core.write_8(buffer.start.into(), &bytes)?;
+    log::debug!("wrote {} arguments into `{SYMBOL}`", args.len());
+    Ok(())
+}
+
+fn encode(args: &[String]) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for arg in args {
+        if arg.is_empty() {
+            bail!("empty arguments can't be passed to the program; an empty string ends the list");
+        }
+        if arg.contains('\0') {
+            bail!("argument `{}` contains a NUL byte", arg.escape_debug());
+        }
+        bytes.extend_from_slice(arg.as_bytes());
+        bytes.push(0);
+    }
+    bytes.push(0);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_nul_terminated_list() {
+        let args = ["--seed".to_string(), "42".to_string()];
+        assert_eq!(encode(&args).unwrap(), b"--seed\x0042\x00\x00");
+    }
+
+    #[test]
+    fn rejects_unencodable_arguments() {
+        assert!(encode(&["a\0b".to_string()]).is_err());
+        assert!(encode(&["".to_string()]).is_err());
+    }
+}
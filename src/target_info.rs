@@ -4,20 +4,39 @@ use std::{
     path::Path,
 };
 
+use anyhow::bail;
 use object::{Object, ObjectSection as _};
 use probe_rs::{
     config::Core,
     config::{MemoryRegion, RamRegion},
     CoreType,
 };
+use probe_rs_target::CoreAccessOptions;
 
-use crate::elf::Elf;
+use crate::{
+    arm_attributes::{self, Arch},
+    cortexm,
+    elf::Elf,
+    flash_plan,
+    remap::Remap,
+    warnings::{self, Warning},
+};
 
 pub struct TargetInfo {
     /// RAM region that contains the call stack
     pub active_ram_region: Option<RamRegion>,
+    /// Address the `--end-symbol` function returns to, which ends the run
+    pub end_symbol_return: Option<u32>,
+    /// The HardFault handler the program uses; the ELF's, unless the vector table was relocated
+    pub hard_fault_handler: u32,
     pub memory_map: Vec<MemoryRegion>,
     pub probe_target: probe_rs::Target,
+    /// Index of the debugged core in `probe_target` (see `--core`)
+    pub core: usize,
+    /// The alias of the boot flash the core may execute the program through
+    pub remap: Option<Remap>,
+    /// Address of the function which ends the run (see `--run-until`)
+    pub run_until: Option<u32>,
     pub stack_info: Option<StackInfo>,
     pub stack_start: u32,
 }
@@ -25,34 +44,188 @@ pub struct TargetInfo {
 pub struct StackInfo {
     /// Valid values of the stack pointer (that don't collide with other data).
     pub range: RangeInclusive<u32>,
+    /// The program keeps data below the stack in its RAM region, which an overflow corrupts
     pub data_below_stack: bool,
+    /// The RAM regions of the chip and the program's data in them, e.g. the stack in DTCM and
+    /// the statics in AXI SRAM on an STM32H7
+    pub regions: Vec<RamData>,
+    /// The index of the region the stack is in
+    pub stack_region: usize,
+}
+
+impl StackInfo {
+    /// The name of the RAM region the stack is in, e.g. `DTCM`
+    pub fn region_name(&self) -> Option<&str> {
+        self.regions[self.stack_region].name.as_deref()
+    }
+}
+
+/// The program's data in one RAM region, merged with directly adjacent ones
+#[derive(Debug, PartialEq, Eq)]
+pub struct RamData {
+    pub name: Option<String>,
+    pub range: Range<u64>,
+    /// From the lowest to the highest address the ELF's sections use in the region
+    pub data: Option<RangeInclusive<u32>>,
 }
 
 impl TargetInfo {
+    pub fn core_type(&self) -> CoreType {
+        // NOTE(indexing): `select_core` checked the index.
+        self.probe_target.cores[self.core].core_type
+    }
+
     pub fn new(
         elf: &Elf,
         memory_map: Vec<MemoryRegion>,
         probe_target: probe_rs::Target,
+        core: usize,
         stack_start: u32,
     ) -> anyhow::Result<Self> {
-        let active_ram_region =
-            extract_active_ram_region(&probe_target, elf.vector_table.initial_stack_pointer);
-        let stack_info = active_ram_region
-            .as_ref()
-            .and_then(|ram_region| extract_stack_info(elf, &ram_region.range));
+        let ram_regions = merged_ram_regions(&probe_target);
+        let initial_stack_pointer = elf.vector_table.initial_stack_pointer;
+        let active_ram_region = extract_active_ram_region(&ram_regions, initial_stack_pointer);
+        let sections = elf_sections(elf);
+        let stack_info = extract_stack_info(&sections, &ram_regions, initial_stack_pointer);
+        let remap = Remap::find(
+            &memory_map,
+            cortexm::clear_thumb_bit(elf.vector_table.hard_fault),
+        );
 
         Ok(Self {
             active_ram_region,
+            end_symbol_return: None,
+            hard_fault_handler: elf.vector_table.hard_fault,
             memory_map,
             probe_target,
+            core,
+            remap,
+            run_until: None,
             stack_info,
             stack_start,
         })
     }
 }
 
+/// A register which records why the chip last reset, e.g. nRF's RESETREAS or STM32's RCC_CSR
+pub struct ResetReasonRegister {
+    pub name: &'static str,
+    pub address: u32,
+    /// The cause each flag bit stands for
+    pub flags: &'static [(u32, &'static str)],
+    /// The cause if no flag is set
+    pub no_flag: Option<&'static str>,
+}
+
+const NRF_RESETREAS: ResetReasonRegister = ResetReasonRegister {
+    name: "RESETREAS",
+    address: 0x4000_0400,
+    flags: &[
+        (0, "pin reset"),
+        (1, "watchdog"),
+        (2, "soft reset"),
+        (3, "CPU lockup"),
+        (16, "wake-up from System OFF by GPIO"),
+        (17, "wake-up from System OFF by LPCOMP"),
+        (18, "debug interface"),
+        (19, "wake-up from System OFF by NFC"),
+        (20, "wake-up from System OFF by VBUS"),
+    ],
+    no_flag: Some("power-on or brownout reset"),
+};
+
+const STM32_RCC_CSR_FLAGS: &[(u32, &str)] = &[
+    (25, "brownout or option byte loader reset"),
+    (26, "pin reset"),
+    (27, "power-on reset"),
+    (28, "soft reset"),
+    (29, "independent watchdog"),
+    (30, "window watchdog"),
+    (31, "low-power reset"),
+];
+
+/// RCC_CSR of the STM32F0, F1 and F3
+const STM32F1_RCC_CSR: ResetReasonRegister = ResetReasonRegister {
+    name: "RCC_CSR",
+    address: 0x4002_1024,
+    flags: STM32_RCC_CSR_FLAGS,
+    no_flag: None,
+};
+
+/// RCC_CSR of the STM32F2, F4 and F7
+const STM32F4_RCC_CSR: ResetReasonRegister = ResetReasonRegister {
+    name: "RCC_CSR",
+    address: 0x4002_3874,
+    flags: STM32_RCC_CSR_FLAGS,
+    no_flag: None,
+};
+
+/// The reset reason registers of the chip families `probe-run` knows, by target name prefix
+const RESET_REASON_REGISTERS: &[(&str, ResetReasonRegister)] = &[
+    ("nrf51", NRF_RESETREAS),
+    ("nrf52", NRF_RESETREAS),
+    ("stm32f0", STM32F1_RCC_CSR),
+    ("stm32f1", STM32F1_RCC_CSR),
+    ("stm32f2", STM32F4_RCC_CSR),
+    ("stm32f3", STM32F1_RCC_CSR),
+    ("stm32f4", STM32F4_RCC_CSR),
+    ("stm32f7", STM32F4_RCC_CSR),
+];
+
+/// Look up the reset reason register of the chip `target_name`, if its family is known.
+pub fn reset_reason_register(target_name: &str) -> Option<&'static ResetReasonRegister> {
+    let target_name = target_name.to_ascii_lowercase();
+    RESET_REASON_REGISTERS
+        .iter()
+        .find(|(prefix, _)| target_name.starts_with(prefix))
+        .map(|(_, register)| register)
+}
+
+/// Check that the chip has the core `index` (`--core`), and make it use the access port `ap`
+/// (`--ap`), for chips with several cores or access ports.
+pub fn select_core(
+    probe_target: &mut probe_rs::Target,
+    index: usize,
+    ap: Option<u8>,
+) -> anyhow::Result<()> {
+    let num_cores = probe_target.cores.len();
+    let Some(core) = probe_target.cores.get_mut(index) else {
+        let cores = probe_target
+            .cores
+            .iter()
+            .enumerate()
+            .map(|(index, core)| format!("{index}: {} ({:?})", core.name, core.core_type))
+            .collect::<Vec<_>>();
+        bail!(
+            "`--core {index}` is out of range; the chip `{}` has {num_cores} core(s): {}",
+            probe_target.name,
+            cores.join(", ")
+        );
+    };
+
+    if let Some(ap) = ap {
+        match &mut core.core_access_options {
+            CoreAccessOptions::Arm(options) => {
+                log::debug!(
+                    "accessing core {index} ({}) through AP {ap} instead of AP {}",
+                    core.name,
+                    options.ap
+                );
+                options.ap = ap;
+            }
+            CoreAccessOptions::Riscv(_) => {
+                bail!("`--ap` selects an ARM access port, but core {index} is a RISC-V core")
+            }
+        }
+    }
+    if num_cores > 1 {
+        log::debug!("debugging core {index} ({}) of {num_cores}", core.name);
+    }
+    Ok(())
+}
+
 /// Check if the compilation target and processor fit and emit a warning if not.
-pub fn check_processor_target_compatability(core: &Core, elf_path: &Path) {
+pub fn check_processor_target_compatability(core: &Core, elf_path: &Path) -> anyhow::Result<()> {
     let target = elf_path.iter().find_map(|a| {
         let b = a.to_string_lossy();
         match b.starts_with("thumbv") {
@@ -62,8 +235,8 @@ pub fn check_processor_target_compatability(core: &Core, elf_path: &Path) {
     });
     let target = match target {
         Some(target) => target,
-        None => return, // NOTE(return) If probe-run is not called through `cargo run` the elf_path
-                        // might not contain the compilation target. In that case we return early.
+        None => return Ok(()), // NOTE(return) If probe-run is not called through `cargo run` the elf_path
+                               // might not contain the compilation target. In that case we return early.
     };
 
     // NOTE(indexing): There *must* always be at least one core.
@@ -79,16 +252,16 @@ pub fn check_processor_target_compatability(core: &Core, elf_path: &Path) {
         }
         CoreType::Armv7a | CoreType::Armv8a => {
             log::warn!("Unsupported architecture ({core_type:?}");
-            return;
+            return Ok(());
         }
         // NOTE(return) Since we do not get any info about instruction
         // set support from probe-rs we do not know which compilation
         // targets fit.
-        CoreType::Riscv => return,
+        CoreType::Riscv => return Ok(()),
     };
 
     if matches {
-        return;
+        return Ok(());
     }
     let recommendation = match core_type {
         CoreType::Armv6m => "must be 'thumbv6m-none-eabi'",
@@ -102,76 +275,379 @@ pub fn check_processor_target_compatability(core: &Core, elf_path: &Path) {
         CoreType::Armv7a | CoreType::Armv8a => unreachable!(),
         CoreType::Riscv => unreachable!(),
     };
-    log::warn!("Compilation target ({target}) and core type ({core_type:?}) do not match. Your compilation target {recommendation}.");
+    warnings::warn(
+        Warning::TargetMismatch,
+        format_args!("Compilation target ({target}) and core type ({core_type:?}) do not match. Your compilation target {recommendation}."),
+    )
+}
+
+/// Check that the program was built for the chip: that the loadable segments of the ELF lie in the
+/// chip's memory, and that its core can execute the architecture of the ELF's build attributes.
+///
+/// Returns a description of the mismatches, if any, e.g. because the ELF was built for another
+/// board.
+pub fn chip_mismatch(
+    elf_bytes: &[u8],
+    probe_target: &probe_rs::Target,
+    core: usize,
+) -> anyhow::Result<Option<String>> {
+    let segments = flash_plan::loadable_segments(elf_bytes)?;
+    let arch = arm_attributes::cpu_arch(elf_bytes);
+    // NOTE(indexing): `select_core` checked the index.
+    let core_type = probe_target.cores[core].core_type;
+    let mismatches = chip_mismatches(&segments, &probe_target.memory_map, arch, core_type);
+    if mismatches.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "the program doesn't fit the chip `{}`:\n  - {}",
+        probe_target.name,
+        mismatches.join("\n  - ")
+    )))
 }
 
+fn chip_mismatches(
+    segments: &[Range<u64>],
+    memory_map: &[MemoryRegion],
+    arch: Option<Arch>,
+    core_type: CoreType,
+) -> Vec<String> {
+    let regions = memory_map
+        .iter()
+        .map(|region| match region {
+            MemoryRegion::Nvm(region) => &region.range,
+            MemoryRegion::Ram(region) => &region.range,
+            MemoryRegion::Generic(region) => &region.range,
+        })
+        .collect::<Vec<_>>();
+    // NOTE the alias of the boot flash at address 0 (see `Remap`) can't be flashed
+    let in_memory = |address: u64| regions.iter().any(|region| region.contains(&address));
+
+    let mut mismatches = vec![];
+    let outside = segments
+        .iter()
+        .filter(|segment| !(in_memory(segment.start) && in_memory(segment.end - 1)));
+    let start = outside.clone().map(|segment| segment.start).min();
+    let end = outside.map(|segment| segment.end).max();
+    if let (Some(start), Some(end)) = (start, end) {
+        let flash = memory_map
+            .iter()
+            .filter_map(|region| match region {
+                MemoryRegion::Nvm(region) => Some(format!(
+                    "{:#010X}..{:#010X}",
+                    region.range.start, region.range.end
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        mismatches.push(format!(
+            "its contents at {start:#010X}..{end:#010X} are outside the chip's memory (flash: {flash})"
+        ));
+    }
+
+    if let Some(arch) = arch {
+        let runs = match core_type {
+            CoreType::Armv6m => arch == Arch::V6M,
+            CoreType::Armv7m => arch <= Arch::V7M,
+            CoreType::Armv7em => arch <= Arch::V7EM,
+            CoreType::Armv8m => true,
+            CoreType::Armv7a | CoreType::Armv8a | CoreType::Riscv => false,
+        };
+        if !runs {
+            mismatches.push(format!(
+                "it was built for {arch}, but the chip's core is {core_type:?}"
+            ));
+        }
+    }
+    mismatches
+}
+
+/// The RAM regions of `target`; directly adjacent regions get merged, as the linker script may
+/// treat them as one contiguous RAM.
+fn merged_ram_regions(target: &probe_rs::Target) -> Vec<RamRegion> {
+    let ram_regions = target
+        .memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Ram(ram_region) => Some(ram_region.clone()),
+            _ => None,
+        })
+        .collect();
+    merge_adjacent_ram_regions(ram_regions)
+}
+
+/// Find the RAM region which contains the call stack.
+///
+/// Chips like the STM32H7 have several RAM regions (e.g. DTCM, AXI SRAM, SRAM1..3); only the one
+/// the initial stack pointer points into is relevant.
 fn extract_active_ram_region(
-    target: &probe_rs::Target,
+    ram_regions: &[RamRegion],
     initial_stack_pointer: u32,
 ) -> Option<RamRegion> {
-    target
-        .memory_map
+    let ram_region = ram_regions
         .iter()
-        .find_map(|region| match region {
-            MemoryRegion::Ram(ram_region) => {
-                // NOTE stack is full descending; meaning the stack pointer can be
-                // `ORIGIN(RAM) + LENGTH(RAM)`
-                let inclusive_range = ram_region.range.start..=ram_region.range.end;
-                if inclusive_range.contains(&initial_stack_pointer.into()) {
-                    log::debug!(
-                        "RAM region: 0x{:08X}-0x{:08X}",
-                        ram_region.range.start,
-                        ram_region.range.end - 1
-                    );
-                    Some(ram_region)
-                } else {
-                    None
-                }
+        .find(|ram_region| contains_stack_pointer(&ram_region.range, initial_stack_pointer))?;
+
+    log::debug!(
+        "RAM region{}: 0x{:08X}-0x{:08X}",
+        display_name(ram_region.name.as_deref()),
+        ram_region.range.start,
+        ram_region.range.end - 1
+    );
+    Some(ram_region.clone())
+}
+
+// NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) + LENGTH(RAM)`,
+// but not `ORIGIN(RAM)`
+fn contains_stack_pointer(range: &Range<u64>, stack_pointer: u32) -> bool {
+    let sp = u64::from(stack_pointer);
+    range.start < sp && sp <= range.end
+}
+
+/// ` `name`` for log messages, or nothing
+fn display_name(name: Option<&str>) -> String {
+    name.map(|name| format!(" `{name}`")).unwrap_or_default()
+}
+
+fn merge_adjacent_ram_regions(mut ram_regions: Vec<RamRegion>) -> Vec<RamRegion> {
+    ram_regions.sort_by_key(|ram_region| ram_region.range.start);
+
+    let mut merged: Vec<RamRegion> = vec![];
+    for ram_region in ram_regions {
+        match merged.last_mut() {
+            Some(previous) if previous.range.end == ram_region.range.start => {
+                previous.range.end = ram_region.range.end;
+                previous.name = match (previous.name.take(), ram_region.name) {
+                    (Some(previous), Some(next)) => Some(format!("{previous}+{next}")),
+                    (previous, next) => previous.or(next),
+                };
             }
-            _ => None,
+            _ => merged.push(ram_region),
+        }
+    }
+    merged
+}
+
+/// The name and address range of the ELF's non-empty sections
+fn elf_sections(elf: &Elf) -> Vec<(String, RangeInclusive<u32>)> {
+    elf.sections()
+        .filter_map(|section| {
+            let size: u32 = section.size().try_into().expect("expected 32-bit ELF");
+            if size == 0 {
+                return None;
+            }
+            let lowest_address: u32 = section.address().try_into().expect("expected 32-bit ELF");
+            let name = section.name().unwrap_or("<unknown>").to_string();
+            Some((name, lowest_address..=lowest_address + size - 1))
         })
-        .cloned()
+        .collect()
 }
 
-fn extract_stack_info(elf: &Elf, ram_range: &Range<u64>) -> Option<StackInfo> {
-    // How does it work?
-    // - the upper end of the stack is the initial SP, minus one
-    // - the lower end of the stack is the highest address any section in the elf file uses, plus one
+/// The program's data in each of the `ram_regions`
+fn ram_data(sections: &[(String, RangeInclusive<u32>)], ram_regions: &[RamRegion]) -> Vec<RamData> {
+    ram_regions
+        .iter()
+        .map(|ram_region| {
+            let in_region = sections
+                .iter()
+                .filter(|(_, range)| ram_region.range.contains(&u64::from(*range.end())))
+                .map(|(_, range)| range);
+            let data = in_region.fold(None, |data: Option<RangeInclusive<u32>>, range| {
+                Some(match data {
+                    Some(data) => *data.start().min(range.start())..=*data.end().max(range.end()),
+                    None => range.clone(),
+                })
+            });
+            RamData {
+                name: ram_region.name.clone(),
+                range: ram_region.range.clone(),
+                data,
+            }
+        })
+        .collect()
+}
 
-    let initial_stack_pointer = elf.vector_table.initial_stack_pointer;
+/// Lay out the stack in the RAM region the initial stack pointer points into: from above the
+/// program's data below the initial SP in that region up to the initial SP.
+///
+/// Data in other RAM regions doesn't limit the stack, e.g. the statics in AXI SRAM when the
+/// stack is in the DTCM of an STM32H7.
+fn extract_stack_info(
+    sections: &[(String, RangeInclusive<u32>)],
+    ram_regions: &[RamRegion],
+    initial_stack_pointer: u32,
+) -> Option<StackInfo> {
+    let regions = ram_data(sections, ram_regions);
+    for region in &regions {
+        log::debug!(
+            "RAM region{} at {:#010X?}: data at {:#010X?}",
+            display_name(region.name.as_deref()),
+            region.range,
+            region.data
+        );
+    }
+    let stack_region = regions
+        .iter()
+        .position(|region| contains_stack_pointer(&region.range, initial_stack_pointer))?;
+    let ram_range = &regions[stack_region].range;
 
     // SP points one word (4-byte) past the end of the stack.
     let mut stack_range =
         ram_range.start.try_into().unwrap_or(u32::MAX)..=initial_stack_pointer - 4;
 
-    for section in elf.sections() {
-        let size: u32 = section.size().try_into().expect("expected 32-bit ELF");
-        if size == 0 {
+    for (name, section_range) in sections {
+        if !ram_range.contains(&u64::from(*section_range.end())) {
             continue;
         }
+        log::debug!("section `{name}` is in RAM at {section_range:#010X?}");
 
-        let lowest_address: u32 = section.address().try_into().expect("expected 32-bit ELF");
-        let highest_address = lowest_address + size - 1;
-        let section_range = lowest_address..=highest_address;
-        let name = section.name().unwrap_or("<unknown>");
-
-        if ram_range.contains(&(*section_range.end() as u64)) {
-            log::debug!("section `{name}` is in RAM at {section_range:#010X?}");
-
-            if section_range.contains(stack_range.end()) {
-                log::debug!(
-                    "initial SP is in section `{name}`, cannot determine valid stack range",
-                );
-                return None;
-            } else if stack_range.contains(section_range.end()) {
-                stack_range = section_range.end() + 1..=*stack_range.end();
-            }
+        if section_range.contains(stack_range.end()) {
+            log::debug!("initial SP is in section `{name}`, cannot determine valid stack range");
+            return None;
+        } else if stack_range.contains(section_range.end()) {
+            stack_range = section_range.end() + 1..=*stack_range.end();
         }
     }
 
     log::debug!("valid SP range: {stack_range:#010X?}");
     Some(StackInfo {
-        data_below_stack: *stack_range.start() as u64 > ram_range.start,
+        data_below_stack: u64::from(*stack_range.start()) > ram_range.start,
         range: stack_range,
+        regions,
+        stack_region,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use probe_rs::config::NvmRegion;
+    use rstest::rstest;
+
+    fn nrf52840_memory_map() -> Vec<MemoryRegion> {
+        vec![
+            MemoryRegion::Nvm(NvmRegion {
+                name: None,
+                range: 0..0x10_0000,
+                is_boot_memory: true,
+                cores: vec![],
+            }),
+            MemoryRegion::Ram(ram_region("RAM", 0x2000_0000..0x2004_0000)),
+        ]
+    }
+
+    #[rstest]
+    #[case::fits(0..0x1000, Some(Arch::V7EM), CoreType::Armv7em, 0)]
+    #[case::older_arch(0..0x1000, Some(Arch::V6M), CoreType::Armv7em, 0)]
+    #[case::no_attributes(0..0x1000, None, CoreType::Armv6m, 0)]
+    #[case::stm32_flash(0x0800_0000..0x0800_1000, Some(Arch::V7EM), CoreType::Armv7em, 1)]
+    #[case::too_large(0xF_F000..0x10_1000, Some(Arch::V7EM), CoreType::Armv7em, 1)]
+    #[case::newer_arch(0..0x1000, Some(Arch::V7EM), CoreType::Armv6m, 1)]
+    #[case::both(0x0800_0000..0x0800_1000, Some(Arch::V8MMain), CoreType::Armv7em, 2)]
+    fn detects_chip_mismatches(
+        #[case] segment: Range<u64>,
+        #[case] arch: Option<Arch>,
+        #[case] core_type: CoreType,
+        #[case] expected: usize,
+    ) {
+        let mismatches = chip_mismatches(&[segment], &nrf52840_memory_map(), arch, core_type);
+        assert_eq!(mismatches.len(), expected, "{mismatches:?}");
+    }
+
+    fn ram_region(name: &str, range: Range<u64>) -> RamRegion {
+        RamRegion {
+            name: Some(name.to_string()),
+            range,
+            is_boot_memory: false,
+            cores: vec![],
+        }
+    }
+
+    #[test]
+    fn merges_adjacent_ram_regions() {
+        let ram_regions = vec![
+            ram_region("SRAM2", 0x3002_0000..0x3004_0000),
+            ram_region("DTCM", 0x2000_0000..0x2002_0000),
+            ram_region("SRAM1", 0x3000_0000..0x3002_0000),
+        ];
+
+        let expected = vec![
+            ram_region("DTCM", 0x2000_0000..0x2002_0000),
+            ram_region("SRAM1+SRAM2", 0x3000_0000..0x3004_0000),
+        ];
+        assert_eq!(expected, merge_adjacent_ram_regions(ram_regions));
+    }
+
+    fn section(name: &str, range: RangeInclusive<u32>) -> (String, RangeInclusive<u32>) {
+        (name.to_string(), range)
+    }
+
+    fn stm32h7_ram_regions() -> Vec<RamRegion> {
+        merge_adjacent_ram_regions(vec![
+            ram_region("DTCM", 0x2000_0000..0x2002_0000),
+            ram_region("AXISRAM", 0x2400_0000..0x2408_0000),
+            ram_region("SRAM1", 0x3000_0000..0x3002_0000),
+            ram_region("SRAM2", 0x3002_0000..0x3004_0000),
+        ])
+    }
+
+    #[test]
+    fn stack_in_other_region_than_data() {
+        let sections = [
+            section(".text", 0x0800_0000..=0x0800_3fff),
+            section(".data", 0x2400_0000..=0x2400_00ff),
+            section(".bss", 0x2400_0100..=0x2400_0fff),
+            section(".sram1", 0x3000_0000..=0x3000_03ff),
+        ];
+        let info = extract_stack_info(&sections, &stm32h7_ram_regions(), 0x2002_0000).unwrap();
+
+        assert_eq!(info.range, 0x2000_0000..=0x2001_fffc);
+        assert!(!info.data_below_stack);
+        assert_eq!(info.region_name(), Some("DTCM"));
+        let data = info
+            .regions
+            .iter()
+            .map(|region| region.data.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            [
+                None,
+                Some(0x2400_0000..=0x2400_0fff),
+                Some(0x3000_0000..=0x3000_03ff)
+            ]
+        );
+    }
+
+    #[test]
+    fn stack_above_data_in_same_region() {
+        let sections = [
+            section(".data", 0x2400_0000..=0x2400_00ff),
+            section(".bss", 0x2400_0100..=0x2400_0fff),
+        ];
+        let info = extract_stack_info(&sections, &stm32h7_ram_regions(), 0x2408_0000).unwrap();
+
+        assert_eq!(info.range, 0x2400_1000..=0x2407_fffc);
+        assert!(info.data_below_stack);
+        assert_eq!(info.region_name(), Some("AXISRAM"));
+    }
+
+    #[test]
+    fn stack_pointer_in_section() {
+        let sections = [section(".bss", 0x2000_0000..=0x2001_ffff)];
+        assert!(extract_stack_info(&sections, &stm32h7_ram_regions(), 0x2001_0000).is_none());
+    }
+
+    #[test]
+    fn selects_core_and_access_port() {
+        let mut target = probe_rs::config::get_target_by_name("STM32H745ZITx").unwrap();
+        select_core(&mut target, 1, Some(3)).unwrap();
+        match &target.cores[1].core_access_options {
+            CoreAccessOptions::Arm(options) => assert_eq!(options.ap, 3),
+            CoreAccessOptions::Riscv(_) => panic!("expected an ARM core"),
+        }
+        assert!(select_core(&mut target, 2, None).is_err());
+    }
+}
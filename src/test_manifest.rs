@@ -0,0 +1,371 @@
+//! `probe-run test <manifest>`: run several programs one after another on the same device, each
+//! with its own timeout and expected outcome, and summarize how they went
+//!
+//! ``` toml
+//! timeout = 30
+//!
+//! [[test]]
+//! elf = "target/thumbv7em-none-eabihf/debug/blink"
+//!
+//! [[test]]
+//! name = "overflow"
+//! elf = "target/thumbv7em-none-eabihf/debug/overflow"
+//! expect = "overflow"
+//! timeout = 5
+//! ```
+
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+use crate::{
+    backtrace::{Outcome, OUTCOME_NAMES},
+    cli::{self, JsonFormat, Opts},
+    elf::Elf,
+    run::{
+        attach_to_probe, init_logger, lookup_probe_target, print_separator, run_program, Program,
+        EXIT_INTERRUPTED,
+    },
+    target_info,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    /// Seconds, for the tests without their own timeout
+    timeout: Option<u64>,
+    #[serde(rename = "test")]
+    tests: Vec<TestFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestFile {
+    name: Option<String>,
+    elf: PathBuf,
+    expect: Option<String>,
+    timeout: Option<u64>,
+}
+
+pub struct Manifest {
+    pub tests: Vec<Test>,
+}
+
+pub struct Test {
+    pub name: String,
+    /// Relative to the working directory
+    pub elf: PathBuf,
+    pub expect: Expected,
+    /// Seconds; overrides `--timeout`
+    pub timeout: Option<u64>,
+}
+
+/// The outcome a test expects: an outcome name like `--exit-code-map` uses, or `exit=<status>`
+/// for `--svc-exit`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expected {
+    Outcome(&'static str),
+    Exit(u32),
+}
+
+impl FromStr for Expected {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(status) = s.strip_prefix("exit=") {
+            let status = status
+                .parse()
+                .map_err(|_| anyhow!("`{status}` is not a valid exit status"))?;
+            return Ok(Expected::Exit(status));
+        }
+        OUTCOME_NAMES
+            .into_iter()
+            .find(|name| *name == s)
+            .map(Expected::Outcome)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown outcome `{s}`; expected `exit=<status>` or one of {}",
+                    OUTCOME_NAMES.join(", ")
+                )
+            })
+    }
+}
+
+impl Expected {
+    pub fn matches(self, outcome: Outcome) -> bool {
+        match (self, outcome) {
+            (Expected::Exit(expected), Outcome::Exit(status)) => expected == status,
+            (Expected::Outcome(expected), outcome) => outcome.name() == Some(expected),
+            (Expected::Exit(_), _) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Outcome(name) => f.write_str(name),
+            Expected::Exit(status) => write!(f, "exit={status}"),
+        }
+    }
+}
+
+/// Load the manifest at `path`: JSON if its extension is `.json`, TOML otherwise.
+pub fn load(path: &Path) -> anyhow::Result<Manifest> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read the test manifest `{}`", path.display()))?;
+    let manifest = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => parse_json(&contents),
+        _ => parse_toml(&contents),
+    }
+    .with_context(|| format!("invalid test manifest `{}`", path.display()))?;
+    // the ELFs are relative to the manifest
+    let dir = path.parent().unwrap_or(Path::new(""));
+    manifest.resolve(dir)
+}
+
+fn parse_toml(contents: &str) -> anyhow::Result<ManifestFile> {
+    Ok(toml::from_str(contents)?)
+}
+
+fn parse_json(contents: &str) -> anyhow::Result<ManifestFile> {
+    Ok(serde_json::from_str(contents)?)
+}
+
+impl ManifestFile {
+    fn resolve(self, dir: &Path) -> anyhow::Result<Manifest> {
+        if self.tests.is_empty() {
+            bail!("the manifest lists no tests");
+        }
+        let tests = self
+            .tests
+            .into_iter()
+            .map(|test| {
+                let name = match test.name {
+                    Some(name) => name,
+                    None => test
+                        .elf
+                        .file_name()
+                        .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+                };
+                let expect = match test.expect {
+                    Some(expect) => expect
+                        .parse()
+                        .with_context(|| format!("invalid `expect` of test `{name}`"))?,
+                    None => Expected::Outcome("ok"),
+                };
+                Ok(Test {
+                    name,
+                    elf: dir.join(test.elf),
+                    expect,
+                    timeout: test.timeout.or(self.timeout),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Manifest { tests })
+    }
+}
+
+/// Run the tests of `manifest` one after another in one session, and print a summary.
+pub fn run(manifest: &Manifest, chip_name: &str, opts: &Opts) -> anyhow::Result<i32> {
+    if opts.dry_run {
+        bail!("`--dry-run` is not supported by `test`");
+    }
+    let probe_target = lookup_probe_target(&manifest.tests[0].elf, chip_name, opts)?;
+    let programs = manifest
+        .tests
+        .iter()
+        .map(|test| {
+            target_info::check_processor_target_compatability(
+                &probe_target.cores[opts.core],
+                &test.elf,
+            )?;
+            Program::load(&test.elf, &probe_target, opts)
+                .with_context(|| format!("failed to load the program of test `{}`", test.name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // all programs share the logger; its format is chosen for the first one
+    let first_elf = Elf::parse_offline(&programs[0].elf_bytes, &manifest.tests[0].elf)?;
+    init_logger(&first_elf, opts)?;
+
+    let mut sess = attach_to_probe(probe_target.clone(), opts)?;
+    let mut results = manifest
+        .tests
+        .iter()
+        .map(|test| TestResult {
+            test,
+            outcome: None,
+            duration: Duration::ZERO,
+        })
+        .collect::<Vec<_>>();
+    let mut interrupted = false;
+    for (result, program) in results.iter_mut().zip(&programs) {
+        log::info!("running test `{}`", result.test.name);
+        let mut test_opts = opts.clone();
+        test_opts.timeout = result.test.timeout.or(opts.timeout);
+
+        let started = Instant::now();
+        let (outcome, _) = run_program(
+            &mut sess,
+            program,
+            probe_target.clone(),
+            chip_name,
+            &test_opts,
+        )?;
+        result.duration = started.elapsed();
+        result.outcome = outcome;
+        // stop at Ctrl-C, unless the test expected it
+        if matches!(outcome, None | Some(Outcome::CtrlC)) && !result.passed() {
+            interrupted = true;
+            break;
+        }
+    }
+
+    print_separator()?;
+    print_summary(&results, opts)?;
+    Ok(match results.iter().all(TestResult::passed) {
+        true => cli::EXIT_SUCCESS,
+        false if interrupted => EXIT_INTERRUPTED,
+        false => cli::EXIT_FAILURE,
+    })
+}
+
+/// How a test went; `outcome` is `None` if the test didn't run
+pub struct TestResult<'a> {
+    pub test: &'a Test,
+    pub outcome: Option<Outcome>,
+    pub duration: Duration,
+}
+
+impl TestResult<'_> {
+    pub fn passed(&self) -> bool {
+        self.outcome
+            .map_or(false, |outcome| self.test.expect.matches(outcome))
+    }
+}
+
+/// Print a table of the `results`, or a JSON event per test with `--json-format lines`.
+pub fn print_summary(results: &[TestResult], opts: &Opts) -> io::Result<()> {
+    if let (true, JsonFormat::Lines) = (opts.json, opts.json_format) {
+        let mut stdout = io::stdout().lock();
+        for result in results {
+            let event = serde_json::json!({
+                "event": "test",
+                "name": result.test.name,
+                "expected": result.test.expect.to_string(),
+                "outcome": result.outcome.map(outcome_name),
+                "passed": result.passed(),
+                "duration_ms": result.duration.as_millis() as u64,
+            });
+            serde_json::to_writer(&mut stdout, &event)?;
+            writeln!(stdout)?;
+        }
+        return stdout.flush();
+    }
+
+    let name_width = results
+        .iter()
+        .map(|result| result.test.name.len())
+        .chain(["test".len()])
+        .max()
+        .unwrap_or_default();
+    let mut stderr = io::stderr().lock();
+    writeln!(
+        stderr,
+        "{:<name_width$}  {:<12}  {:<12}  {:>8}  result",
+        "test", "expected", "outcome", "time"
+    )?;
+    for result in results {
+        let outcome = result
+            .outcome
+            .map_or_else(|| "not run".to_string(), outcome_name);
+        let verdict = match (result.outcome, result.passed()) {
+            (None, _) => "skipped",
+            (Some(_), true) => "ok",
+            (Some(_), false) => "FAILED",
+        };
+        writeln!(
+            stderr,
+            "{:<name_width$}  {:<12}  {outcome:<12}  {:>7.1}s  {verdict}",
+            result.test.name,
+            result.test.expect.to_string(),
+            result.duration.as_secs_f64(),
+        )?;
+    }
+
+    let passed = results.iter().filter(|result| result.passed()).count();
+    writeln!(stderr, "\n{passed} of {} tests passed", results.len())
+}
+
+fn outcome_name(outcome: Outcome) -> String {
+    match outcome {
+        Outcome::Exit(status) => format!("exit={status}"),
+        outcome => outcome.name().unwrap_or_default().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn loads_toml_with_defaults() {
+        let manifest = parse_toml(
+            "timeout = 30\n\
+            [[test]]\n\
+            elf = \"debug/blink\"\n\
+            [[test]]\n\
+            name = \"overflow\"\n\
+            elf = \"debug/overflow\"\n\
+            expect = \"overflow\"\n\
+            timeout = 5\n",
+        )
+        .unwrap()
+        .resolve(Path::new("firmware"))
+        .unwrap();
+
+        let blink = &manifest.tests[0];
+        assert_eq!(blink.name, "blink");
+        assert_eq!(blink.elf, Path::new("firmware/debug/blink"));
+        assert_eq!(blink.expect, Expected::Outcome("ok"));
+        assert_eq!(blink.timeout, Some(30));
+        assert_eq!(manifest.tests[1].timeout, Some(5));
+    }
+
+    #[test]
+    fn loads_json() {
+        let manifest = parse_json(r#"{"test": [{"elf": "blink", "expect": "exit=3"}]}"#)
+            .unwrap()
+            .resolve(Path::new(""))
+            .unwrap();
+        assert_eq!(manifest.tests[0].expect, Expected::Exit(3));
+    }
+
+    #[rstest]
+    #[case::name("panic", Outcome::Panic, true)]
+    #[case::other_name("panic", Outcome::HardFault, false)]
+    #[case::exit_status("exit=0", Outcome::Exit(0), true)]
+    #[case::other_status("exit=0", Outcome::Exit(1), false)]
+    #[case::timeout("timeout", Outcome::Timeout, true)]
+    fn matches_outcome(#[case] expect: &str, #[case] outcome: Outcome, #[case] expected: bool) {
+        assert_eq!(
+            expect.parse::<Expected>().unwrap().matches(outcome),
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_outcome() {
+        assert!("crash".parse::<Expected>().is_err());
+    }
+}
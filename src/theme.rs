@@ -0,0 +1,262 @@
+//! Styles of the text `probe-run` colorizes itself (see `--theme`)
+//!
+//! The colors of log levels are chosen by `defmt-decoder` and are not covered by the theme.
+
+use std::{fs, path::Path, str::FromStr, sync::OnceLock};
+
+use anyhow::{anyhow, bail, Context as _};
+use colored::{Color, ColoredString, Colorize as _};
+use serde::Deserialize;
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Names of the built-in themes
+pub const BUILTIN_THEMES: [&str; 3] = ["default", "no-dim", "colorblind"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Theme {
+    /// The line separating the execution stages
+    pub separator: Style,
+    /// The `stack backtrace:` header
+    pub backtrace_header: Style,
+    /// Backtrace frames of functions in the current crate
+    pub local_frame: Style,
+    /// Less relevant path prefixes, like the location of the cargo registry
+    pub path_prefix: Style,
+    /// Crate names and toolchains in paths
+    pub crate_name: Style,
+    /// Error messages
+    pub error: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            separator: Style::DIMMED,
+            backtrace_header: Style::DIMMED,
+            local_frame: Style::BOLD,
+            path_prefix: Style::DIMMED,
+            crate_name: Style::BOLD,
+            error: Style {
+                color: Some(Color::Red),
+                ..Style::BOLD
+            },
+        }
+    }
+}
+
+impl Theme {
+    /// Load the built-in theme `name_or_path`, or else the theme file at that path.
+    pub fn load(name_or_path: &str) -> anyhow::Result<Self> {
+        if let Some(theme) = Self::builtin(name_or_path) {
+            return Ok(theme);
+        }
+
+        let path = Path::new(name_or_path);
+        if !path.exists() {
+            bail!(
+                "`{name_or_path}` is neither a built-in theme ({}) nor a theme file",
+                BUILTIN_THEMES.join(", ")
+            );
+        }
+        let contents = fs::read_to_string(path)?;
+        contents
+            .parse()
+            .with_context(|| format!("invalid theme file `{}`", path.display()))
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        let theme = match name {
+            "default" => Self::default(),
+            // for terminals where dimmed text is hard to read
+            "no-dim" => Self {
+                separator: Style::NORMAL,
+                backtrace_header: Style::NORMAL,
+                path_prefix: Style::NORMAL,
+                ..Self::default()
+            },
+            // avoids red; highlights don't rely on color alone
+            "colorblind" => Self {
+                local_frame: Style {
+                    underline: true,
+                    ..Style::BOLD
+                },
+                error: Style {
+                    color: Some(Color::BrightYellow),
+                    underline: true,
+                    ..Style::BOLD
+                },
+                ..Self::default()
+            },
+            _ => return None,
+        };
+        Some(theme)
+    }
+}
+
+/// A theme file: a base theme and styles overriding it, eg.
+///
+/// ``` toml
+/// base = "no-dim"
+/// error = "bright magenta bold"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeFile {
+    base: Option<String>,
+    separator: Option<String>,
+    backtrace_header: Option<String>,
+    local_frame: Option<String>,
+    path_prefix: Option<String>,
+    crate_name: Option<String>,
+    error: Option<String>,
+}
+
+impl FromStr for Theme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file = toml::from_str::<ThemeFile>(s)?;
+        let mut theme = match file.base.as_deref() {
+            Some(base) => {
+                Self::builtin(base).ok_or_else(|| anyhow!("unknown base theme `{base}`"))?
+            }
+            None => Self::default(),
+        };
+
+        for (style, value) in [
+            (&mut theme.separator, file.separator),
+            (&mut theme.backtrace_header, file.backtrace_header),
+            (&mut theme.local_frame, file.local_frame),
+            (&mut theme.path_prefix, file.path_prefix),
+            (&mut theme.crate_name, file.crate_name),
+            (&mut theme.error, file.error),
+        ] {
+            if let Some(value) = value {
+                *style = value.parse()?;
+            }
+        }
+
+        Ok(theme)
+    }
+}
+
+/// A color and text attributes, eg. `bright blue bold`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub color: Option<Color>,
+    pub bold: bool,
+    pub dimmed: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    const NORMAL: Self = Self {
+        color: None,
+        bold: false,
+        dimmed: false,
+        italic: false,
+        underline: false,
+    };
+    const BOLD: Self = Self {
+        bold: true,
+        ..Self::NORMAL
+    };
+    const DIMMED: Self = Self {
+        dimmed: true,
+        ..Self::NORMAL
+    };
+
+    pub fn paint(&self, text: &str) -> ColoredString {
+        let mut text = text.normal();
+        if let Some(color) = self.color {
+            text = text.color(color);
+        }
+        if self.bold {
+            text = text.bold();
+        }
+        if self.dimmed {
+            text = text.dimmed();
+        }
+        if self.italic {
+            text = text.italic();
+        }
+        if self.underline {
+            text = text.underline();
+        }
+        text
+    }
+}
+
+impl FromStr for Style {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut style = Style::NORMAL;
+        let mut words = s.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "normal" => {}
+                "bold" => style.bold = true,
+                "dimmed" => style.dimmed = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "bright" => {
+                    let color = words.next().unwrap_or_default();
+                    style.color = Some(parse_color(&format!("bright {color}"))?);
+                }
+                color => style.color = Some(parse_color(color)?),
+            }
+        }
+        Ok(style)
+    }
+}
+
+fn parse_color(s: &str) -> anyhow::Result<Color> {
+    s.parse()
+        .map_err(|()| anyhow!("unknown color or attribute `{s}`"))
+}
+
+/// Make `theme` the one returned by [`current`]; only the first call has an effect.
+pub fn set(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+pub fn current() -> &'static Theme {
+    THEME.get_or_init(Theme::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::empty("", Style::NORMAL)]
+    #[case::attribute("dimmed", Style::DIMMED)]
+    #[case::color_and_attribute("red bold", Style { color: Some(Color::Red), ..Style::BOLD })]
+    #[case::bright_color("bright blue underline", Style { color: Some(Color::BrightBlue), underline: true, ..Style::NORMAL })]
+    fn should_parse_style(#[case] input: &str, #[case] expected: Style) {
+        assert_eq!(input.parse::<Style>().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::unknown_word("blinking")]
+    #[case::bright_without_color("bright")]
+    fn should_reject_malformed_style(#[case] input: &str) {
+        assert!(input.parse::<Style>().is_err());
+    }
+
+    #[test]
+    fn theme_file_overrides_base() {
+        let theme = "base = \"no-dim\"\nerror = \"bright magenta\""
+            .parse::<Theme>()
+            .unwrap();
+
+        assert_eq!(theme.separator, Style::NORMAL);
+        assert_eq!(theme.local_frame, Style::BOLD);
+        assert_eq!(theme.error.color, Some(Color::BrightMagenta));
+    }
+}
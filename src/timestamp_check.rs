@@ -0,0 +1,108 @@
+//! Notice defmt timestamps which go backwards or don't change, e.g. because the program's
+//! `defmt::timestamp!` reads a timer which isn't running (see `--strict-timestamps`)
+
+use anyhow::bail;
+use defmt_decoder::Frame;
+
+use crate::warnings::{self, Warning};
+
+/// After how many frames with the same timestamp it counts as frozen
+const FROZEN_AFTER: u32 = 50;
+
+pub struct TimestampCheck {
+    /// The numbers in the last timestamp, e.g. `[12, 345]` for `12.345`
+    last: Option<Vec<u64>>,
+    /// How many frames in a row had the last timestamp
+    repeated: u32,
+    /// The timestamps were reported already; only the first problem is reported.
+    reported: bool,
+    strict: bool,
+}
+
+impl TimestampCheck {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            last: None,
+            repeated: 0,
+            reported: false,
+            strict,
+        }
+    }
+
+    /// Check the timestamp of `frame` against the ones before it.
+    pub fn observe(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        let Some(timestamp) = frame.display_timestamp().map(|ts| ts.to_string()) else {
+            return Ok(());
+        };
+        let numbers = numbers(&timestamp);
+        if numbers.is_empty() {
+            return Ok(());
+        }
+
+        let problem = match &self.last {
+            Some(last) if numbers < *last => Some(format!(
+                "the defmt timestamp went backwards, to {timestamp}"
+            )),
+            Some(last) if numbers == *last => {
+                self.repeated += 1;
+                (self.repeated + 1 == FROZEN_AFTER).then(|| {
+                    format!("the defmt timestamp stayed at {timestamp} for {FROZEN_AFTER} frames")
+                })
+            }
+            _ => {
+                self.repeated = 0;
+                None
+            }
+        };
+        self.last = Some(numbers);
+
+        match problem {
+            Some(problem) if !self.reported => {
+                self.reported = true;
+                self.report(&problem)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn report(&self, problem: &str) -> anyhow::Result<()> {
+        let warning = Warning::TimestampNotMonotonic;
+        if self.strict {
+            bail!("[{warning}] {problem} (`--strict-timestamps`; see `--explain {warning}`)");
+        }
+        warnings::warn(warning, problem)
+    }
+}
+
+/// The numbers in a displayed timestamp, most significant first; compared in order, they order
+/// timestamps of the same format, whose fractions have a fixed width, like `1.000250` or
+/// `00:01:02.500`.
+fn numbers(timestamp: &str) -> Vec<u64> {
+    timestamp
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|digits| !digits.is_empty())
+        .filter_map(|digits| digits.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::integer("12345", vec![12345])]
+    #[case::fraction("1.000250", vec![1, 250])]
+    #[case::time("00:01:02.500", vec![0, 1, 2, 500])]
+    #[case::none("<invalid>", vec![])]
+    fn extracts_numbers(#[case] timestamp: &str, #[case] expected: Vec<u64>) {
+        assert_eq!(numbers(timestamp), expected);
+    }
+
+    #[test]
+    fn orders_fixed_width_fractions() {
+        assert!(numbers("0.999999") < numbers("1.000000"));
+        assert!(numbers("00:00:59.999") < numbers("00:01:00.000"));
+    }
+}
@@ -0,0 +1,151 @@
+//! `--itrace`: record the program's last branches in a trace buffer and list them after a fault
+
+use std::{
+    io::{self, Write as _},
+    ops::Range,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use probe_rs::Core;
+
+use crate::{backtrace, cli, elf::Elf, theme};
+
+mod mtb;
+
+pub use mtb::Mtb;
+
+/// How many of the most recent branches the listing shows
+const MAX_BRANCHES: usize = 32;
+
+/// Where the trace hardware writes to: a symbol, e.g. a `static` array, or `<start>..<end>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BufferSpec {
+    Symbol(String),
+    Range(Range<u32>),
+}
+
+impl FromStr for BufferSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("..") {
+            Some((start, end)) => {
+                let range = cli::parse_u32(start)?..cli::parse_u32(end)?;
+                if range.is_empty() {
+                    bail!("`{s}` is an empty address range");
+                }
+                Ok(BufferSpec::Range(range))
+            }
+            None => Ok(BufferSpec::Symbol(s.to_string())),
+        }
+    }
+}
+
+impl BufferSpec {
+    fn resolve(&self, elf: &Elf) -> anyhow::Result<Range<u32>> {
+        match self {
+            BufferSpec::Range(range) => Ok(range.clone()),
+            BufferSpec::Symbol(name) => elf
+                .find_symbol(name)
+                .ok_or_else(|| anyhow!("symbol `{name}` not found in the ELF")),
+        }
+    }
+}
+
+/// Instruction trace into a buffer in the target's RAM
+pub struct Itrace {
+    mtb: Mtb,
+    buffer: Range<u32>,
+}
+
+impl Itrace {
+    /// Start tracing into the buffer given by `spec`.
+    pub fn start(core: &mut Core, elf: &Elf, mtb: Mtb, spec: &BufferSpec) -> anyhow::Result<Self> {
+        let buffer = spec.resolve(elf)?;
+        mtb.start(core, &buffer)?;
+        log::info!(
+            "tracing instructions into {buffer:#010X?} ({} branches)",
+            (buffer.end - buffer.start) / 8
+        );
+        Ok(Self { mtb, buffer })
+    }
+
+    pub fn stop(&self, core: &mut Core) -> anyhow::Result<()> {
+        self.mtb.stop(core)
+    }
+
+    /// Print the most recent branches, with the functions they left and entered.
+    pub fn print(
+        &self,
+        core: &mut Core,
+        elf: &Elf,
+        settings: &backtrace::Settings,
+    ) -> anyhow::Result<()> {
+        let branches = self.mtb.read(core, &self.buffer)?;
+        let branches = &branches[branches.len().saturating_sub(MAX_BRANCHES)..];
+        if branches.is_empty() {
+            log::warn!("the instruction trace is empty");
+            return Ok(());
+        }
+
+        let addresses = branches
+            .iter()
+            .flat_map(|branch| [branch.source, branch.destination])
+            .collect::<Vec<_>>();
+        let subroutines = backtrace::subroutines(&addresses, elf, settings);
+
+        let mut stderr = io::stderr().lock();
+        writeln!(
+            stderr,
+            "{}",
+            theme::current().backtrace_header.paint(&format!(
+                "instruction trace (last {} branches, oldest first):",
+                branches.len()
+            ))
+        )?;
+        for (index, (branch, pair)) in branches.iter().zip(subroutines.chunks(2)).enumerate() {
+            let (source, destination) = (&pair[0], &pair[1]);
+            let marker = match (branch.start, branch.exception) {
+                (true, _) => " <trace start>",
+                (false, true) => " <exception>",
+                (false, false) => "",
+            };
+            writeln!(
+                stderr,
+                "{index:>4}: {:#010x} {} -> {:#010x} {}{marker}",
+                branch.source,
+                source.name.as_deref().unwrap_or("<unknown>"),
+                branch.destination,
+                destination.name.as_deref().unwrap_or("<unknown>"),
+            )?;
+            if let Some(location) = &destination.location {
+                writeln!(
+                    stderr,
+                    "        at {}",
+                    backtrace::format_location(location, settings)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::symbol("MTB_BUFFER", BufferSpec::Symbol("MTB_BUFFER".to_string()))]
+    #[case::range("0x20000000..0x20000400", BufferSpec::Range(0x2000_0000..0x2000_0400))]
+    fn should_parse_buffer_spec(#[case] input: &str, #[case] expected: BufferSpec) {
+        assert_eq!(input.parse::<BufferSpec>().unwrap(), expected);
+    }
+
+    #[test]
+    fn should_reject_empty_range() {
+        assert!("0x20000400..0x20000400".parse::<BufferSpec>().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! The CoreSight Micro Trace Buffer (MTB) of Cortex-M0+ and some Armv8-M cores
+//!
+//! The MTB records every non-sequential change of the program counter as a packet of two words,
+//! source and destination address, into a window of the system SRAM.
+
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+use probe_rs::{
+    architecture::arm::{component, memory::PeripheralType, DpAddress},
+    Core, MemoryInterface as _, Session,
+};
+
+/// Offsets of the MTB registers
+const POSITION: u64 = 0x000;
+const MASTER: u64 = 0x004;
+const FLOW: u64 = 0x008;
+const BASE: u64 = 0x00C;
+
+const POSITION_WRAP: u32 = 1 << 2;
+const POSITION_POINTER_MASK: u32 = !0b111;
+const MASTER_EN: u32 = 1 << 31;
+
+/// Smallest buffer the MTB supports; `MASTER.MASK = 0` selects it
+const MIN_BUFFER_SIZE: u32 = 16;
+
+/// A change of the program counter, as recorded by the MTB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Branch {
+    pub source: u32,
+    pub destination: u32,
+    /// The branch was an exception entry or return (the packet's A bit).
+    pub exception: bool,
+    /// The first packet after tracing started (the packet's S bit).
+    pub start: bool,
+}
+
+pub struct Mtb {
+    /// Address of the MTB registers
+    registers: u64,
+}
+
+impl Mtb {
+    /// Look the MTB up in the CoreSight ROM table; the table is only accessible through the session.
+    pub fn find(sess: &mut Session) -> anyhow::Result<Self> {
+        let components = sess.get_arm_components(DpAddress::Default)?;
+
+        if let Ok(mtb) = component::find_component(&components, PeripheralType::Mtb) {
+            let registers = mtb.component.id().component_address();
+            log::debug!("MTB registers at {registers:#010X}");
+            return Ok(Self { registers });
+        }
+
+        match component::find_component(&components, PeripheralType::Etb) {
+            Ok(_) => bail!(
+                "this chip traces instructions with an ETM and ETB; `--itrace` only supports the \
+                Micro Trace Buffer (MTB) so far"
+            ),
+            Err(_) => bail!(
+                "`--itrace` requires a Micro Trace Buffer (MTB), which this chip doesn't have"
+            ),
+        }
+    }
+
+    /// Trace into `buffer`, which must be a power-of-two-sized window of the MTB's SRAM, aligned to its size.
+    pub fn start(&self, core: &mut Core, buffer: &Range<u32>) -> anyhow::Result<()> {
+        let sram_base = core.read_word_32(self.registers + BASE)?;
+        let offset = buffer.start.checked_sub(sram_base).ok_or_else(|| {
+            anyhow!(
+                "the trace buffer {buffer:#010X?} starts below the MTB's SRAM at {sram_base:#010X}"
+            )
+        })?;
+        let mask = buffer_mask(buffer, offset)?;
+
+        core.write_word_32(self.registers + MASTER, 0)?;
+        core.write_word_32(self.registers + POSITION, offset)?;
+        core.write_word_32(self.registers + FLOW, 0)?;
+        core.write_word_32(self.registers + MASTER, MASTER_EN | mask)?;
+        Ok(())
+    }
+
+    /// Stop tracing, so that `probe-run`'s own code doesn't push the program's branches out.
+    pub fn stop(&self, core: &mut Core) -> anyhow::Result<()> {
+        let master = core.read_word_32(self.registers + MASTER)?;
+        core.write_word_32(self.registers + MASTER, master & !MASTER_EN)?;
+        Ok(())
+    }
+
+    /// Read the recorded branches from `buffer`, oldest first.
+    pub fn read(&self, core: &mut Core, buffer: &Range<u32>) -> anyhow::Result<Vec<Branch>> {
+        let sram_base = core.read_word_32(self.registers + BASE)?;
+        let position = core.read_word_32(self.registers + POSITION)?;
+
+        let mut words = vec![0; (buffer.end - buffer.start) as usize / 4];
+        core.read_32(buffer.start.into(), &mut words)?;
+
+        let buffer_offset = buffer.start - sram_base;
+        let next_packet = (position & POSITION_POINTER_MASK).wrapping_sub(buffer_offset)
+            % (buffer.end - buffer.start);
+        Ok(decode(
+            &words,
+            next_packet as usize / 4,
+            position & POSITION_WRAP != 0,
+        ))
+    }
+}
+
+/// The `MASTER.MASK` value for `buffer`, at `offset` into the MTB's SRAM.
+fn buffer_mask(buffer: &Range<u32>, offset: u32) -> anyhow::Result<u32> {
+    let size = buffer.end - buffer.start;
+    if size < MIN_BUFFER_SIZE || !size.is_power_of_two() || offset % size != 0 {
+        bail!(
+            "the trace buffer {buffer:#010X?} must be a power of two and at least \
+            {MIN_BUFFER_SIZE} bytes in size, and aligned to its size"
+        );
+    }
+    Ok(size.trailing_zeros() - MIN_BUFFER_SIZE.trailing_zeros())
+}
+
+/// Decode the packets in `words`; `next` is the index of the word the MTB writes next.
+fn decode(words: &[u32], next: usize, wrapped: bool) -> Vec<Branch> {
+    // after a wrap, the oldest packet is the one that gets overwritten next
+    let (older, newer) = words.split_at(next);
+    let packets = match wrapped {
+        true => [newer, older].concat(),
+        false => older.to_vec(),
+    };
+
+    packets
+        .chunks_exact(2)
+        .map(|packet| Branch {
+            source: packet[0] & !1,
+            destination: packet[1] & !1,
+            exception: packet[0] & 1 != 0,
+            start: packet[1] & 1 != 0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_packets_in_order() {
+        let words = [0x100 | 1, 0x200 | 1, 0x210, 0x300, 0xdead, 0xbeef];
+
+        let branches = decode(&words, 4, false);
+
+        assert_eq!(
+            branches,
+            [
+                Branch {
+                    source: 0x100,
+                    destination: 0x200,
+                    exception: true,
+                    start: true,
+                },
+                Branch {
+                    source: 0x210,
+                    destination: 0x300,
+                    exception: false,
+                    start: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_wrapped_buffer_oldest_first() {
+        let words = [0x500, 0x600, 0x100, 0x200, 0x300, 0x400];
+
+        let sources = decode(&words, 2, true)
+            .iter()
+            .map(|branch| branch.source)
+            .collect::<Vec<_>>();
+
+        assert_eq!(sources, [0x100, 0x300, 0x500]);
+    }
+
+    #[test]
+    fn computes_mask_of_aligned_buffer() {
+        assert_eq!(buffer_mask(&(0x2000_0400..0x2000_0800), 0x400).unwrap(), 6);
+        assert!(buffer_mask(&(0x2000_0200..0x2000_0600), 0x200).is_err());
+        assert!(buffer_mask(&(0x2000_0000..0x2000_0300), 0).is_err());
+    }
+}
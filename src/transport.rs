@@ -0,0 +1,76 @@
+//! Access to the halted target's registers and memory, either through the probe or through a
+//! debug server which owns it (see `--via`)
+//!
+//! The unwinder and everything else which only inspects the target goes through [`Transport`].
+
+use probe_rs::{Core, RegisterId};
+
+use crate::debug_session;
+
+pub trait Transport {
+    fn read_core_reg(&mut self, reg: RegisterId) -> anyhow::Result<u32>;
+
+    fn read_8(&mut self, address: u64, data: &mut [u8]) -> anyhow::Result<()>;
+
+    fn write_8(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()>;
+
+    fn read_word_32(&mut self, address: u64) -> anyhow::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_8(address, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_32(&mut self, address: u64, data: &mut [u32]) -> anyhow::Result<()> {
+        let mut bytes = vec![0; data.len() * 4];
+        self.read_8(address, &mut bytes)?;
+        for (word, bytes) in data.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    fn write_word_32(&mut self, address: u64, value: u32) -> anyhow::Result<()> {
+        self.write_8(address, &value.to_le_bytes())
+    }
+}
+
+impl Transport for Core<'_> {
+    fn read_core_reg(&mut self, reg: RegisterId) -> anyhow::Result<u32> {
+        Ok(Core::read_core_reg(self, reg)?)
+    }
+
+    fn read_8(&mut self, address: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        record("read", address, data.len());
+        Ok(probe_rs::MemoryInterface::read_8(self, address, data)?)
+    }
+
+    fn write_8(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()> {
+        record("write", address, data.len());
+        Ok(probe_rs::MemoryInterface::write_8(self, address, data)?)
+    }
+
+    fn read_word_32(&mut self, address: u64) -> anyhow::Result<u32> {
+        record("read", address, 4);
+        Ok(probe_rs::MemoryInterface::read_word_32(self, address)?)
+    }
+
+    fn read_32(&mut self, address: u64, data: &mut [u32]) -> anyhow::Result<()> {
+        record("read", address, data.len() * 4);
+        Ok(probe_rs::MemoryInterface::read_32(self, address, data)?)
+    }
+
+    fn write_word_32(&mut self, address: u64, value: u32) -> anyhow::Result<()> {
+        record("write", address, 4);
+        Ok(probe_rs::MemoryInterface::write_word_32(
+            self, address, value,
+        )?)
+    }
+}
+
+/// Record a memory access through the probe for `--debug-session`.
+fn record(kind: &str, address: u64, size: usize) {
+    debug_session::record(
+        kind,
+        || serde_json::json!({ "address": format!("{address:#010x}"), "size": size }),
+    );
+}
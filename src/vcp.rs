@@ -0,0 +1,53 @@
+//! `--vcp`: read the program's logs from a serial port, e.g. the virtual COM port of the debug
+//! probe the program writes to over UART (like `defmt-bbq` does), instead of from RTT
+//!
+//! Flashing, resets and backtraces still go over the probe.
+
+use std::{
+    io::{self, Read as _},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use serialport::{ClearBuffer, SerialPort};
+
+/// How long a read waits for data; keeps the loop of `print_logs` responsive
+const READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+pub struct Vcp {
+    port: Box<dyn SerialPort>,
+}
+
+impl Vcp {
+    /// Open the serial port `path` and drop what arrived before, e.g. from the previous program.
+    pub fn open(path: &str, baud_rate: u32) -> anyhow::Result<Self> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .with_context(|| {
+                let ports = serialport::available_ports()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|port| port.port_name)
+                    .collect::<Vec<_>>();
+                match ports.is_empty() {
+                    true => format!("failed to open the serial port `{path}` of `--vcp`"),
+                    false => format!(
+                        "failed to open the serial port `{path}` of `--vcp`; available ports: {}",
+                        ports.join(", ")
+                    ),
+                }
+            })?;
+        port.clear(ClearBuffer::Input)?;
+        log::debug!("reading logs from serial port `{path}` at {baud_rate} baud");
+        Ok(Self { port })
+    }
+
+    /// Read the data which arrived, if any; waits for it no longer than [`READ_TIMEOUT`].
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.port.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            result => result,
+        }
+    }
+}
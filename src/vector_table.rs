@@ -0,0 +1,120 @@
+//! `--verify-vector-table`: after flashing, compare the vector table on the target with the ELF's
+//!
+//! Flash verification passes when the program landed in flash, but the chip may still boot other
+//! code: a dual-bank chip may boot from the other bank, or a bootloader may map its own table.
+//! The initial stack pointer and reset handler the core loaded on reset tell what actually boots.
+
+use std::fmt::Write as _;
+
+use probe_rs::{Core, MemoryInterface as _};
+
+use crate::{
+    cortexm,
+    warnings::{self, Warning},
+};
+
+/// An entry of the vector table which differs between the ELF and the target
+#[derive(Debug, PartialEq, Eq)]
+struct Difference {
+    index: usize,
+    elf: u32,
+    target: u32,
+}
+
+/// Compare the vector table of `elf` with the one at its address on the target, and with the
+/// initial stack pointer and reset handler the core loaded (`booted`); warn if they differ.
+///
+/// Assumes that the target was reset-halted.
+pub fn verify(
+    core: &mut Core,
+    elf: &cortexm::VectorTable,
+    booted: (u32, u32),
+) -> anyhow::Result<()> {
+    let mut on_target = vec![0; elf.entries.len()];
+    core.read_32(elf.address.into(), &mut on_target)?;
+
+    let differences = diff(&elf.entries, &on_target);
+    let (stack_start, reset) = booted;
+    let booted_elf =
+        stack_start == elf.initial_stack_pointer && cortexm::subroutine_eq(reset, elf.reset);
+    if differences.is_empty() && booted_elf {
+        log::debug!(
+            "the vector table at {:#010x} matches the ELF's ({} entries)",
+            elf.address,
+            elf.entries.len()
+        );
+        return Ok(());
+    }
+
+    let mut message = String::new();
+    if !differences.is_empty() {
+        let _ = write!(
+            message,
+            "the vector table at {:#010x} differs from the ELF's:\n{}",
+            elf.address,
+            render(&differences)
+        );
+    }
+    if !booted_elf {
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        let _ = write!(
+            message,
+            "the core booted with SP = {stack_start:#010x} and PC = {reset:#010x}, but the ELF \
+            has SP = {:#010x} and Reset = {:#010x}",
+            elf.initial_stack_pointer, elf.reset
+        );
+    }
+    message.push_str(
+        "\nthe chip may not run the flashed program: check for bank swapping on dual-bank chips, \
+        a bootloader, or boot pins selecting another boot memory",
+    );
+    warnings::warn(Warning::VectorTableMismatch, message)
+}
+
+fn diff(elf: &[u32], target: &[u32]) -> Vec<Difference> {
+    elf.iter()
+        .zip(target)
+        .enumerate()
+        .filter(|(_, (elf, target))| elf != target)
+        .map(|(index, (&elf, &target))| Difference { index, elf, target })
+        .collect()
+}
+
+/// One line per difference, e.g. `  Reset        ELF 0x00000401  target 0x08000101`
+fn render(differences: &[Difference]) -> String {
+    differences
+        .iter()
+        .map(|Difference { index, elf, target }| {
+            let name = match index {
+                0 => "SP".to_string(),
+                _ => cortexm::exception_name(*index as u16),
+            };
+            format!("  {name:<12} ELF {elf:#010x}  target {target:#010x}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_differing_entries() {
+        let elf = [0x2000_4000, 0x0000_0401, 0x0000_0501, 0x0000_0601];
+        let target = [0x2000_4000, 0x0800_0101, 0x0000_0501, 0xFFFF_FFFF];
+        assert_eq!(
+            render(&diff(&elf, &target)),
+            "  Reset        ELF 0x00000401  target 0x08000101\n  \
+            HardFault    ELF 0x00000601  target 0xffffffff"
+        );
+    }
+
+    #[test]
+    fn finds_no_differences_in_equal_tables() {
+        let table = [0x2000_4000, 0x0000_0401];
+        assert!(diff(&table, &table).is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+//! Find the HardFault handler the program actually uses. It differs from the one in the ELF's
+//! vector table if a bootloader or the program itself relocated the table by writing VTOR.
+
+use probe_rs::{Core, CoreType, MemoryInterface as _};
+
+use crate::cortexm;
+
+/// Vector Table Offset Register
+const VTOR: u32 = 0xE000_ED08;
+/// Offset of the HardFault handler (entry 3) in the vector table
+const HARD_FAULT_OFFSET: u32 = 3 * 4;
+
+/// Read the HardFault handler from the vector table VTOR points to, with the thumb bit cleared.
+///
+/// Returns `None` if the core has no VTOR or the entry is not a handler (yet).
+pub fn hard_fault_handler(core: &mut Core) -> Option<u32> {
+    if !matches!(
+        core.core_type(),
+        CoreType::Armv6m | CoreType::Armv7m | CoreType::Armv7em | CoreType::Armv8m
+    ) {
+        return None;
+    }
+
+    let read = |core: &mut Core| -> anyhow::Result<(u32, u32)> {
+        let vtor = core.read_word_32(VTOR.into())?;
+        let entry = core.read_word_32((vtor + HARD_FAULT_OFFSET).into())?;
+        Ok((vtor, entry))
+    };
+    match read(core) {
+        Ok((vtor, entry)) if is_handler(entry) => {
+            log::debug!("vector table at {vtor:#010X}; HardFault handler at {entry:#010X}");
+            Some(cortexm::clear_thumb_bit(entry))
+        }
+        Ok((vtor, entry)) => {
+            log::debug!("vector table at {vtor:#010X} has no HardFault handler ({entry:#010X})");
+            None
+        }
+        Err(e) => {
+            log::debug!("failed to read the vector table through VTOR: {e}");
+            None
+        }
+    }
+}
+
+/// Handlers are thumb functions; erased flash and zeroed RAM are no handlers.
+fn is_handler(entry: u32) -> bool {
+    cortexm::is_thumb_bit_set(entry) && entry != u32::MAX
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::thumb_function(0x0000_8161, true)]
+    #[case::ram_function(0x2000_0101, true)]
+    #[case::zeroed(0, false)]
+    #[case::erased(0xFFFF_FFFF, false)]
+    #[case::no_thumb_bit(0x0000_8160, false)]
+    fn recognizes_handlers(#[case] entry: u32, #[case] expected: bool) {
+        assert_eq!(is_handler(entry), expected);
+    }
+}
@@ -0,0 +1,267 @@
+//! Warnings with stable codes, which can be explained with `--explain` and turned into errors
+//! with `--deny`
+
+use std::{fmt, str::FromStr, sync::OnceLock};
+
+use anyhow::{anyhow, bail};
+
+static DENIED: OnceLock<Vec<Deny>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+    TimestampNotImplemented,
+    TimestampNotInFormat,
+    NoFlashWithDefmt,
+    InsufficientDwarf,
+    IncompleteLocations,
+    CanaryNotPlaced,
+    NoHwBreakpoints,
+    PanicHandlerNotFound,
+    NoSvcallEntry,
+    TargetMismatch,
+    RttControlBlockMoved,
+    RttOverrun,
+    NoEntryFunction,
+    OutputDropped,
+    ChipMismatch,
+    TimestampNotMonotonic,
+    VectorTableMismatch,
+}
+
+impl Warning {
+    pub const ALL: [Warning; 17] = [
+        Warning::TimestampNotImplemented,
+        Warning::TimestampNotInFormat,
+        Warning::NoFlashWithDefmt,
+        Warning::InsufficientDwarf,
+        Warning::IncompleteLocations,
+        Warning::CanaryNotPlaced,
+        Warning::NoHwBreakpoints,
+        Warning::PanicHandlerNotFound,
+        Warning::NoSvcallEntry,
+        Warning::TargetMismatch,
+        Warning::RttControlBlockMoved,
+        Warning::RttOverrun,
+        Warning::NoEntryFunction,
+        Warning::OutputDropped,
+        Warning::ChipMismatch,
+        Warning::TimestampNotMonotonic,
+        Warning::VectorTableMismatch,
+    ];
+
+    /// The stable code, e.g. `W003`. Codes are never reused for other warnings.
+    pub fn code(self) -> &'static str {
+        match self {
+            Warning::TimestampNotImplemented => "W001",
+            Warning::TimestampNotInFormat => "W002",
+            Warning::NoFlashWithDefmt => "W003",
+            Warning::InsufficientDwarf => "W004",
+            Warning::IncompleteLocations => "W005",
+            Warning::CanaryNotPlaced => "W006",
+            Warning::NoHwBreakpoints => "W007",
+            Warning::PanicHandlerNotFound => "W008",
+            Warning::NoSvcallEntry => "W009",
+            Warning::TargetMismatch => "W010",
+            Warning::RttControlBlockMoved => "W011",
+            Warning::RttOverrun => "W012",
+            Warning::NoEntryFunction => "W013",
+            Warning::OutputDropped => "W014",
+            Warning::ChipMismatch => "W015",
+            Warning::TimestampNotMonotonic => "W016",
+            Warning::VectorTableMismatch => "W017",
+        }
+    }
+
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Warning::TimestampNotImplemented => {
+                "The log format contains a timestamp `{t}`, but the firmware has no \
+                `defmt::timestamp!` implementation, so there is nothing to print.\n\n\
+                Remove `{t}` from the log format or add a `defmt::timestamp!` implementation."
+            }
+            Warning::TimestampNotInFormat => {
+                "The firmware has a `defmt::timestamp!` implementation, but the log format doesn't \
+                contain the timestamp `{t}`, so timestamps are transferred but never printed.\n\n\
+                Add `{t}` to the log format."
+            }
+            Warning::NoFlashWithDefmt => {
+                "With `--no-flash`, the program on the chip may differ from the ELF, whose defmt \
+                table is used to decode the logs. The output can then be malformed or wrong.\n\n\
+                Flash the program, or make sure the ELF matches the program on the chip."
+            }
+            Warning::InsufficientDwarf => {
+                "The ELF contains defmt logs, but not enough debug info to tell where in the \
+                source code they are, so log locations are not printed.\n\n\
+                Compile the program with `debug = 2` in the Cargo profile."
+            }
+            Warning::IncompleteLocations => {
+                "The debug info contains the location of some, but not all defmt logs. The \
+                missing ones are looked up in the DWARF debug info instead, which finds the \
+                declaration of the log statement or the start of the code around it, so their \
+                line numbers may be a bit off; logs without either are printed without location. \
+                This is a bug in defmt or probe-run; please report it."
+            }
+            Warning::CanaryNotPlaced => {
+                "The stack is smaller than the subroutines which paint and measure the stack \
+                canary, so stack overflows can not be detected."
+            }
+            Warning::NoHwBreakpoints => {
+                "The chip has no hardware breakpoints, so probe-run can't stop the program on a \
+                HardFault; a faulting program will hang instead of making probe-run exit with an \
+                error code."
+            }
+            Warning::PanicHandlerNotFound => {
+                "`--catch-panics` sets a breakpoint on the `#[panic_handler]`, but neither \
+                `rust_begin_unwind` nor `core::panicking::panic_fmt` is in the ELF's symbol table.\n\n\
+                Check that the ELF is not stripped."
+            }
+            Warning::NoSvcallEntry => {
+                "`--svc-exit` sets a breakpoint on the SVCall handler, but the vector table is too \
+                short to contain it."
+            }
+            Warning::TargetMismatch => {
+                "The compilation target of the program (e.g. `thumbv7em-none-eabihf`) doesn't match \
+                the core of the chip. The program may fault on instructions the core doesn't \
+                support.\n\nBuild the program for the recommended target."
+            }
+            Warning::RttControlBlockMoved => {
+                "The RTT control block was not found at the address of `_SEGGER_RTT`, but \
+                somewhere else in RAM, e.g. because a bootloader started the program with a \
+                different RAM layout, the program was loaded to RAM or the ELF doesn't match the \
+                program on the chip."
+            }
+            Warning::RttOverrun => {
+                "The program logged faster than probe-run could read, and its RTT channel doesn't \
+                block when full, so the program dropped logs. The output marks where logs were \
+                lost; defmt output may also contain a malformed frame there.\n\n\
+                Increase the size of the RTT buffer or make the channel block when full. probe-run \
+                does the latter itself, unless it attached to a running program."
+            }
+            Warning::NoEntryFunction => {
+                "The ELF has no `main` symbol, so probe-run can't stop the program after the \
+                runtime initialized RAM, which is when it sets the mode of the RTT channel. The \
+                program starts at the reset vector instead, and its RTT channel keeps the mode \
+                the program sets.\n\n\
+                Pass the name of the program's entry function with `--entry-symbol`."
+            }
+            Warning::OutputDropped => {
+                "The output of probe-run, e.g. a pipe into a slow program, couldn't keep up with \
+                the program's logs, so `--output-overflow drop` dropped RTT data on the host. The \
+                output marks where data was dropped.\n\n\
+                Increase `--output-queue`, or pass `--output-overflow block` to make the program \
+                wait for the output instead."
+            }
+            Warning::ChipMismatch => {
+                "`--force` flashed a program which doesn't fit the chip: parts of it lie outside \
+                the chip's memory, or it was built for an architecture the chip's core can't \
+                execute. The ELF was probably built for another board."
+            }
+            Warning::TimestampNotMonotonic => {
+                "The defmt timestamps of the program went backwards, or stayed the same for many \
+                logs in a row, so they don't tell when the logs happened. Usually the \
+                `defmt::timestamp!` implementation reads a timer which isn't running yet, or \
+                which overflows; after a reset of the program, the timestamps start over.\n\n\
+                Check the timer the timestamp reads. `--strict-timestamps` fails the run instead."
+            }
+            Warning::VectorTableMismatch => {
+                "`--verify-vector-table` found that the vector table on the target, or the \
+                initial stack pointer and reset handler the core booted with, differ from the \
+                ELF's. The flash may hold the program, but the chip boots other code: dual-bank \
+                chips can boot from the bank the program wasn't flashed to, a bootloader can map \
+                its own vector table, or the boot pins select another boot memory.\n\n\
+                Check the option bytes for bank swapping (e.g. `BFB2` / `SWAP_BANK` on STM32s), \
+                the boot pins and the bootloader."
+            }
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Warning {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Warning::ALL
+            .into_iter()
+            .find(|warning| warning.code().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("unknown warning code `{s}`"))
+    }
+}
+
+/// Warnings selected by `--deny`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deny {
+    All,
+    Warning(Warning),
+}
+
+impl FromStr for Deny {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Deny::All),
+            _ => Ok(Deny::Warning(s.parse()?)),
+        }
+    }
+}
+
+/// Make the `denied` warnings errors; only the first call has an effect.
+pub fn set_denied(denied: Vec<Deny>) {
+    let _ = DENIED.set(denied);
+}
+
+fn is_denied(warning: Warning) -> bool {
+    DENIED.get().map_or(false, |denied| {
+        denied
+            .iter()
+            .any(|deny| *deny == Deny::All || *deny == Deny::Warning(warning))
+    })
+}
+
+/// Log `message` as `warning`, or fail with it if the warning is denied.
+pub fn warn(warning: Warning, message: impl fmt::Display) -> anyhow::Result<()> {
+    if is_denied(warning) {
+        bail!("[{warning}] {message} (denied with `--deny`; see `--explain {warning}`)");
+    }
+    log::warn!("[{warning}] {message}");
+    Ok(())
+}
+
+/// Print the explanation of `warning`.
+pub fn explain(warning: Warning) {
+    println!("{warning}: {}", warning.explanation());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn codes_are_unique() {
+        let codes = Warning::ALL.map(Warning::code);
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+    }
+
+    #[rstest]
+    #[case::all("all", Deny::All)]
+    #[case::code("W003", Deny::Warning(Warning::NoFlashWithDefmt))]
+    #[case::lowercase_code("w004", Deny::Warning(Warning::InsufficientDwarf))]
+    fn should_parse_deny(#[case] input: &str, #[case] expected: Deny) {
+        assert_eq!(input.parse::<Deny>().unwrap(), expected);
+    }
+
+    #[test]
+    fn should_reject_unknown_code() {
+        assert!("W999".parse::<Deny>().is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! STM32 flash sectors protected against writes (WRP) or reads (PCROP) by the option bytes, which
+//! make flashing fail; checked against the flash plan before flashing (see `--clear-wrp`)
+
+use std::{fmt, ops::Range, thread, time::Instant};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{config::MemoryRegion, Core, MemoryInterface as _, Session};
+
+use crate::{cli::Opts, flash_plan::Plan, run::TIMEOUT};
+
+/// Where the main flash of STM32 chips starts
+const FLASH_BASE: u64 = 0x0800_0000;
+
+const STM32F1_FLASH_WRPR: u32 = 0x4002_2020;
+/// Bits 0..=30 protect 4 KiB each; bit 31 protects the rest of the flash
+const STM32F1_WRP_BLOCK_SIZE: u64 = 0x1000;
+
+const STM32F4_FLASH_OPTKEYR: u32 = 0x4002_3C08;
+const STM32F4_FLASH_SR: u32 = 0x4002_3C0C;
+const STM32F4_FLASH_OPTCR: u32 = 0x4002_3C14;
+/// Like `OPTCR`, for the second bank of 2 MiB chips
+const STM32F4_FLASH_OPTCR1: u32 = 0x4002_3C18;
+const STM32F4_OPT_KEYS: [u32; 2] = [0x0819_2A3B, 0x4C5D_6E7F];
+const STM32F4_OPTCR_OPTLOCK: u32 = 1 << 0;
+const STM32F4_OPTCR_OPTSTRT: u32 = 1 << 1;
+/// One bit per sector; a cleared bit protects the sector, unless `SPRMOD` is set
+const STM32F4_OPTCR_NWRP: u32 = 0xFFF << 16;
+/// The `nWRP` bits select sectors for PCROP instead, set bits protecting them
+const STM32F4_OPTCR_SPRMOD: u32 = 1 << 31;
+const STM32F4_SR_BSY: u32 = 1 << 16;
+/// The sizes of the 12 sectors of each bank
+const STM32F4_SECTOR_SIZES: [u64; 12] = [
+    0x4000, 0x4000, 0x4000, 0x4000, 0x1_0000, 0x2_0000, 0x2_0000, 0x2_0000, 0x2_0000, 0x2_0000,
+    0x2_0000, 0x2_0000,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Family {
+    Stm32F1,
+    Stm32F4,
+}
+
+/// Families by target name prefix
+const FAMILIES: &[(&str, Family)] = &[("stm32f1", Family::Stm32F1), ("stm32f4", Family::Stm32F4)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Write protection
+    Wrp,
+    /// Proprietary code readout protection, which also prevents erasing
+    Pcrop,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Wrp => "write-protected (WRP)",
+            Kind::Pcrop => "read-protected (PCROP)",
+        })
+    }
+}
+
+/// A protected sector
+#[derive(Debug, PartialEq, Eq)]
+struct Sector {
+    /// As the reference manual numbers it; for STM32F1, the number of the `WRP` bit
+    index: u32,
+    range: Range<u64>,
+}
+
+impl fmt::Display for Sector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sector {} ({:#010X}..{:#010X})",
+            self.index, self.range.start, self.range.end
+        )
+    }
+}
+
+/// Fail if flashing would erase protected sectors, or remove their protection with `--clear-wrp`.
+pub fn check(sess: &mut Session, plan: &Plan, opts: &Opts) -> anyhow::Result<()> {
+    let Some(family) = family(&sess.target().name) else {
+        return Ok(());
+    };
+    let Some(flash) = main_flash(&sess.target().memory_map) else {
+        return Ok(());
+    };
+    let core = &mut sess.core(opts.core)?;
+    let (kind, sectors) = read(core, family, &flash)?;
+
+    let conflicts = sectors
+        .iter()
+        .filter(|sector| plan.erases(&sector.range))
+        .collect::<Vec<_>>();
+    if conflicts.is_empty() {
+        if !sectors.is_empty() {
+            log::debug!("{} sectors are {kind}, but not flashed", sectors.len());
+        }
+        return Ok(());
+    }
+
+    let list = conflicts
+        .iter()
+        .map(|sector| format!("  {sector}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !opts.clear_wrp {
+        bail!(
+            "the program is flashed to sectors which are {kind} by the option bytes:\n{list}\n\
+            Pass `--clear-wrp` to remove the protection."
+        );
+    }
+    if kind == Kind::Pcrop {
+        bail!(
+            "the program is flashed to sectors which are {kind} by the option bytes:\n{list}\n\
+            PCROP can only be removed by lowering the readout protection level, which \
+            mass-erases the chip; use STM32CubeProgrammer for that."
+        );
+    }
+    if family != Family::Stm32F4 {
+        bail!(
+            "the program is flashed to sectors which are {kind} by the option bytes:\n{list}\n\
+            `--clear-wrp` only supports STM32F4 chips; use STM32CubeProgrammer to remove the \
+            protection."
+        );
+    }
+    if !crate::run::confirm(
+        opts,
+        "--clear-wrp",
+        &format!("remove the write protection of all flash sectors?\n{list}\ncontinue? [y/N] "),
+    )? {
+        bail!("the write protection was left in place; the sectors can't be flashed");
+    }
+
+    clear_stm32f4(core, &flash)?;
+    log::info!("removed the write protection of all flash sectors");
+    Ok(())
+}
+
+fn family(target_name: &str) -> Option<Family> {
+    let target_name = target_name.to_ascii_lowercase();
+    FAMILIES
+        .iter()
+        .find(|(prefix, _)| target_name.starts_with(prefix))
+        .map(|(_, family)| *family)
+}
+
+/// The address range of the flash the option bytes protect, which may span several regions
+fn main_flash(memory_map: &[MemoryRegion]) -> Option<Range<u64>> {
+    let mut flash: Option<Range<u64>> = None;
+    for region in memory_map {
+        let MemoryRegion::Nvm(region) = region else {
+            continue;
+        };
+        match &mut flash {
+            None if region.range.start == FLASH_BASE => flash = Some(region.range.clone()),
+            Some(flash) if region.range.start == flash.end => flash.end = region.range.end,
+            _ => {}
+        }
+    }
+    flash
+}
+
+fn read(
+    core: &mut Core,
+    family: Family,
+    flash: &Range<u64>,
+) -> anyhow::Result<(Kind, Vec<Sector>)> {
+    match family {
+        Family::Stm32F1 => {
+            let wrpr = core.read_word_32(STM32F1_FLASH_WRPR.into())?;
+            Ok((Kind::Wrp, decode_stm32f1(wrpr, flash)))
+        }
+        Family::Stm32F4 => {
+            let optcr = core.read_word_32(STM32F4_FLASH_OPTCR.into())?;
+            let optcr1 = match has_second_bank(flash) {
+                true => Some(core.read_word_32(STM32F4_FLASH_OPTCR1.into())?),
+                false => None,
+            };
+            Ok(decode_stm32f4(optcr, optcr1, flash))
+        }
+    }
+}
+
+/// Cleared bits of `FLASH_WRPR` protect their block
+fn decode_stm32f1(wrpr: u32, flash: &Range<u64>) -> Vec<Sector> {
+    (0..32)
+        .filter(|bit| wrpr & (1 << bit) == 0)
+        .filter_map(|bit| {
+            let start = flash.start + u64::from(bit) * STM32F1_WRP_BLOCK_SIZE;
+            let end = match bit {
+                31 => flash.end,
+                _ => (start + STM32F1_WRP_BLOCK_SIZE).min(flash.end),
+            };
+            (start < end).then_some(Sector {
+                index: bit,
+                range: start..end,
+            })
+        })
+        .collect()
+}
+
+fn decode_stm32f4(optcr: u32, optcr1: Option<u32>, flash: &Range<u64>) -> (Kind, Vec<Sector>) {
+    let kind = match optcr & STM32F4_OPTCR_SPRMOD {
+        0 => Kind::Wrp,
+        _ => Kind::Pcrop,
+    };
+    let bank_size = STM32F4_SECTOR_SIZES.iter().sum::<u64>();
+
+    let mut sectors = vec![];
+    for (bank, register) in [Some(optcr), optcr1].into_iter().enumerate() {
+        let Some(register) = register else { continue };
+        let mut start = flash.start + bank as u64 * bank_size;
+        for (bit, size) in STM32F4_SECTOR_SIZES.iter().enumerate() {
+            let is_set = register & (1 << (16 + bit)) != 0;
+            let protected = match kind {
+                Kind::Wrp => !is_set,
+                Kind::Pcrop => is_set,
+            };
+            let range = start..(start + size).min(flash.end);
+            if protected && !range.is_empty() {
+                sectors.push(Sector {
+                    index: (bank * 12 + bit) as u32,
+                    range,
+                });
+            }
+            start += size;
+        }
+    }
+    (kind, sectors)
+}
+
+/// Chips with 2 MiB of flash have a second bank of 12 sectors
+fn has_second_bank(flash: &Range<u64>) -> bool {
+    flash.end - flash.start > STM32F4_SECTOR_SIZES.iter().sum::<u64>()
+}
+
+/// Set all `nWRP` bits and program the option bytes.
+fn clear_stm32f4(core: &mut Core, flash: &Range<u64>) -> anyhow::Result<()> {
+    // the core must not run from flash while the option bytes are programmed
+    core.halt(TIMEOUT)?;
+    for key in STM32F4_OPT_KEYS {
+        core.write_word_32(STM32F4_FLASH_OPTKEYR.into(), key)?;
+    }
+
+    let mut registers = vec![STM32F4_FLASH_OPTCR];
+    if has_second_bank(flash) {
+        registers.push(STM32F4_FLASH_OPTCR1);
+    }
+    for register in registers {
+        let value = core.read_word_32(register.into())? | STM32F4_OPTCR_NWRP;
+        core.write_word_32(register.into(), value)?;
+    }
+    let optcr = core.read_word_32(STM32F4_FLASH_OPTCR.into())?;
+    core.write_word_32(STM32F4_FLASH_OPTCR.into(), optcr | STM32F4_OPTCR_OPTSTRT)?;
+
+    let start = Instant::now();
+    while core.read_word_32(STM32F4_FLASH_SR.into())? & STM32F4_SR_BSY != 0 {
+        if start.elapsed() > TIMEOUT * 5 {
+            return Err(anyhow!("timed out programming the option bytes"));
+        }
+        thread::sleep(TIMEOUT / 100);
+    }
+
+    let optcr = core.read_word_32(STM32F4_FLASH_OPTCR.into())?;
+    core.write_word_32(STM32F4_FLASH_OPTCR.into(), optcr | STM32F4_OPTCR_OPTLOCK)?;
+    if optcr & STM32F4_OPTCR_NWRP != STM32F4_OPTCR_NWRP {
+        bail!("the write protection is still in place after programming the option bytes");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const F1_FLASH: Range<u64> = FLASH_BASE..FLASH_BASE + 0x8_0000;
+    const F4_FLASH: Range<u64> = FLASH_BASE..FLASH_BASE + 0x10_0000;
+
+    #[test]
+    fn decodes_stm32f1_blocks() {
+        let sectors = decode_stm32f1(!0b11 & !(1 << 31), &F1_FLASH);
+        assert_eq!(
+            sectors,
+            [
+                Sector {
+                    index: 0,
+                    range: 0x0800_0000..0x0800_1000
+                },
+                Sector {
+                    index: 1,
+                    range: 0x0800_1000..0x0800_2000
+                },
+                Sector {
+                    index: 31,
+                    range: 0x0801_F000..0x0808_0000
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::unprotected(0x0FFF_AAED, Kind::Wrp, &[])]
+    #[case::wrp_sectors_0_and_4(0x0FEE_AAED, Kind::Wrp, &[0, 4])]
+    #[case::pcrop_sector_5(0x8020_AAED, Kind::Pcrop, &[5])]
+    fn decodes_stm32f4_sectors(
+        #[case] optcr: u32,
+        #[case] expected_kind: Kind,
+        #[case] expected: &[u32],
+    ) {
+        let (kind, sectors) = decode_stm32f4(optcr, None, &F4_FLASH);
+        assert_eq!(kind, expected_kind);
+        assert_eq!(
+            sectors
+                .iter()
+                .map(|sector| sector.index)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn stm32f4_sector_ranges() {
+        let (_, sectors) = decode_stm32f4(0x0BFF_AAED, None, &F4_FLASH);
+        assert_eq!(
+            sectors,
+            [Sector {
+                index: 10,
+                range: 0x080C_0000..0x080E_0000
+            }]
+        );
+    }
+}
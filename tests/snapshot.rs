@@ -53,7 +53,15 @@ fn wait_and_terminate(handle: &Child) {
 }
 
 fn run_command(args: &[&str]) -> (os_pipe::PipeReader, Child) {
-    let mut cmd = vec!["run", "--", "--chip", "nRF52840_xxAA", "--shorten-paths"];
+    // the reset reason depends on what the board went through before the test
+    let mut cmd = vec![
+        "run",
+        "--",
+        "--chip",
+        "nRF52840_xxAA",
+        "--shorten-paths",
+        "--no-reset-reason",
+    ];
     cmd.extend(&args[1..]);
 
     let path = format!("tests/test_elfs/{}", args[0]);